@@ -0,0 +1,155 @@
+//! Getting attachment bytes out of the local store or the server, and
+//! onto disk.
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, Result};
+
+use crate::crypto::vault::Vault;
+use crate::db::{self, Attachment, DbPool, Email, EmailPart, Folder};
+use crate::email::SessionPool;
+
+/// Directory under the app cache that attachments are opened from. It is
+/// emptied when the app exits.
+pub const OPEN_DIR: &str = "attachments";
+
+/// Size of the pieces `get_attachment` streams to the frontend.
+pub const CHUNK_SIZE: usize = 256 * 1024;
+
+/// Attachments listed in `Email::attachments`.
+pub fn list(email: &Email) -> Vec<Attachment> {
+    email
+        .attachments
+        .as_deref()
+        .and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default()
+}
+
+pub fn find(email: &Email, attachment_id: &str) -> Option<Attachment> {
+    list(email).into_iter().find(|attachment| attachment.id == attachment_id)
+}
+
+/// The decoded bytes of an attachment. The part is downloaded and cached
+/// on first use.
+pub async fn load(pool: &DbPool, vault: &Vault, sessions: &Arc<SessionPool>, email: &Email, attachment: &Attachment) -> Result<Vec<u8>> {
+    let mut conn = pool.acquire().await?;
+    if let Some(EmailPart { data: Some(data), .. }) = db::parts::find_by_section(&mut conn, email.id, &attachment.id).await? {
+        return Ok(data);
+    }
+
+    let uid = email.uid.ok_or_else(|| anyhow!("Message is not on the server"))?;
+    let folder: Folder = sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+        .bind(email.folder_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    let account = crate::email::load_account(&mut conn, vault, email.account_id).await?;
+    drop(conn);
+
+    let handler = crate::email::handler_for(&account, sessions).ok_or_else(|| anyhow!("Unsupported protocol for attachments"))?;
+    let data = handler.fetch_part(&account, &folder, uid, &attachment.id).await?;
+
+    let mut conn = pool.acquire().await?;
+    db::parts::save(&mut conn, email.id, attachment, &data).await?;
+    Ok(data)
+}
+
+/// A file name from the sender's metadata that stays inside the directory
+/// it is joined to.
+pub fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') { '_' } else { c })
+        .collect();
+    // No hidden files, and nothing that is all dots
+    let name = name.trim().trim_start_matches('.').trim();
+    if name.is_empty() {
+        "attachment".to_string()
+    } else {
+        name.chars().take(200).collect()
+    }
+}
+
+/// `dir/name`, or `dir/name (1).ext` and so on if that is taken.
+pub fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .expect("some suffix is free")
+}
+
+/// Writes `data` under a fresh subdirectory of `dir` that only the user
+/// can read, so another program cannot swap the file before it is opened.
+pub fn write_private(dir: &Path, name: &str, data: &[u8]) -> Result<PathBuf> {
+    let subdir = dir.join(uuid::Uuid::new_v4().to_string());
+    let mut builder = std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(&subdir)?;
+
+    let path = subdir.join(safe_file_name(name));
+    std::fs::write(&path, data)?;
+    Ok(path)
+}
+
+/// Removes the files opened during this run.
+pub fn clean_open_dir(dir: &Path) {
+    if !dir.exists() {
+        return;
+    }
+    if let Err(e) = std::fs::remove_dir_all(dir) {
+        tracing::warn!("Failed to remove opened attachments in {}: {}", dir.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_safe_file_name() {
+        assert_eq!(safe_file_name("report.pdf"), "report.pdf");
+        assert_eq!(safe_file_name("../../.bashrc"), "_.._.bashrc");
+        assert_eq!(safe_file_name("..\\..\\evil.exe"), "_.._evil.exe");
+        assert_eq!(safe_file_name(".hidden"), "hidden");
+        assert_eq!(safe_file_name("a\u{0}b\nc:d.txt"), "a_b_c_d.txt");
+        assert_eq!(safe_file_name(" .. "), "attachment");
+        assert_eq!(safe_file_name(""), "attachment");
+    }
+
+    #[test]
+    fn test_unique_path_and_private_write() {
+        let dir = std::env::temp_dir().join(format!("slopmail-test-{}", uuid::Uuid::new_v4()));
+        let first = write_private(&dir, "../notes.txt", b"one").unwrap();
+        assert!(first.starts_with(&dir));
+        assert_eq!(first.file_name().unwrap(), "_notes.txt");
+        assert_eq!(std::fs::read(&first).unwrap(), b"one");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(first.parent().unwrap()).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let folder = first.parent().unwrap();
+        assert_eq!(unique_path(folder, "_notes.txt"), folder.join("_notes (1).txt"));
+        std::fs::write(folder.join("_notes (1).txt"), b"two").unwrap();
+        assert_eq!(unique_path(folder, "_notes.txt"), folder.join("_notes (2).txt"));
+        assert_eq!(unique_path(folder, "README"), folder.join("README"));
+
+        clean_open_dir(&dir);
+        assert!(!dir.exists());
+    }
+}
@@ -0,0 +1,280 @@
+//! What commands return when they fail. Each error serializes as
+//! `{code, message, details}`, so the frontend can tell a wrong password
+//! from a timeout and offer the right fix.
+
+use std::io;
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use tokio_rustls::rustls;
+
+use crate::crypto::vault;
+use crate::email::imap::ImapError;
+use crate::email::net::Unreachable;
+use crate::email::smtp::SmtpError;
+use crate::email::tls::CertificateError;
+use crate::email::ProtocolError;
+
+/// The message and details every kind of error carries.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub message: String,
+    /// Whatever the frontend needs to act on the error, such as the
+    /// fingerprint of a certificate to pin.
+    pub details: Option<Value>,
+}
+
+impl Failure {
+    fn new(message: String, details: Option<Value>) -> Self {
+        Failure { message, details }
+    }
+}
+
+impl From<&str> for Failure {
+    fn from(message: &str) -> Self {
+        Failure::new(message.to_string(), None)
+    }
+}
+
+impl From<String> for Failure {
+    fn from(message: String) -> Self {
+        Failure::new(message, None)
+    }
+}
+
+#[derive(Debug, Clone, thiserror::Error, Serialize)]
+#[serde(tag = "code", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Error {
+    /// The server turned the password or token down.
+    #[error("{}", .0.message)]
+    AuthFailed(Failure),
+    /// The server, or the proxy in front of it, couldn't be reached, or the
+    /// connection dropped.
+    #[error("{}", .0.message)]
+    Network(Failure),
+    #[error("{}", .0.message)]
+    Timeout(Failure),
+    /// The server's certificate was refused. Details give the host and the
+    /// fingerprint to pin.
+    #[error("{}", .0.message)]
+    Tls(Failure),
+    /// The server refused a command or said something we don't follow.
+    #[error("{}", .0.message)]
+    Protocol(Failure),
+    #[error("{}", .0.message)]
+    NotFound(Failure),
+    /// The vault needs the master password first.
+    #[error("{}", .0.message)]
+    Locked(Failure),
+    #[error("{}", .0.message)]
+    Conflict(Failure),
+    #[error("{}", .0.message)]
+    QuotaExceeded(Failure),
+    /// The server is down or busy for now, and may take it later.
+    #[error("{}", .0.message)]
+    Unavailable(Failure),
+    #[error("{}", .0.message)]
+    InvalidInput(Failure),
+    #[error("{}", .0.message)]
+    Unsupported(Failure),
+    /// The action is risky and the user must agree to it first. Details
+    /// say why.
+    #[error("{}", .0.message)]
+    NeedsConfirmation(Failure),
+    #[error("{}", .0.message)]
+    Internal(Failure),
+}
+
+impl Error {
+    fn failure_mut(&mut self) -> &mut Failure {
+        match self {
+            Error::AuthFailed(failure)
+            | Error::Network(failure)
+            | Error::Timeout(failure)
+            | Error::Tls(failure)
+            | Error::Protocol(failure)
+            | Error::NotFound(failure)
+            | Error::Locked(failure)
+            | Error::Conflict(failure)
+            | Error::QuotaExceeded(failure)
+            | Error::Unavailable(failure)
+            | Error::InvalidInput(failure)
+            | Error::Unsupported(failure)
+            | Error::NeedsConfirmation(failure)
+            | Error::Internal(failure) => failure,
+        }
+    }
+}
+
+/// Sorts a failure by the first cause we know, keeping the whole chain of
+/// context as the message.
+impl From<anyhow::Error> for Error {
+    fn from(error: anyhow::Error) -> Self {
+        let message = format!("{:#}", error);
+        if let Some(known) = find::<Error>(&error) {
+            let mut known = known.clone();
+            known.failure_mut().message = message;
+            return known;
+        }
+        let failure = |details: Option<Value>| Failure::new(message.clone(), details);
+
+        if let Some(e) = find::<ProtocolError>(&error) {
+            return match e {
+                ProtocolError::MessageNotFound { folder, uid } => Error::NotFound(failure(Some(json!({ "folder": folder, "uid": uid })))),
+                ProtocolError::FolderNotFound(folder) => Error::NotFound(failure(Some(json!({ "folder": folder })))),
+                ProtocolError::AuthFailed => Error::AuthFailed(failure(None)),
+                ProtocolError::QuotaExceeded => Error::QuotaExceeded(failure(None)),
+                ProtocolError::AlreadyExists => Error::Conflict(failure(None)),
+                ProtocolError::Unavailable => Error::Unavailable(failure(None)),
+            };
+        }
+        if find::<vault::Locked>(&error).is_some() {
+            return Error::Locked(failure(None));
+        }
+        if let Some(e) = find::<CertificateError>(&error) {
+            return Error::Tls(failure(Some(match e {
+                CertificateError::Untrusted { host, fingerprint, .. } => json!({ "host": host, "fingerprint": fingerprint }),
+                CertificateError::PinMismatch { host, expected, fingerprint } => {
+                    json!({ "host": host, "fingerprint": fingerprint, "expected": expected })
+                }
+            })));
+        }
+        if let Some(e) = find::<ImapError>(&error) {
+            let details = match e {
+                ImapError::No { code: Some(code), .. } => Some(json!({ "response_code": code })),
+                _ => None,
+            };
+            return Error::Protocol(failure(details));
+        }
+        if let Some(SmtpError::Rejected { code, .. }) = find::<SmtpError>(&error) {
+            return Error::Protocol(failure(Some(json!({ "reply_code": code }))));
+        }
+        if let Some(e) = find::<sqlx::Error>(&error) {
+            return match e {
+                sqlx::Error::RowNotFound => Error::NotFound(failure(None)),
+                sqlx::Error::Database(e) if e.is_unique_violation() => Error::Conflict(failure(None)),
+                _ => Error::Internal(failure(None)),
+            };
+        }
+        if find::<tokio::time::error::Elapsed>(&error).is_some() {
+            return Error::Timeout(failure(None));
+        }
+        if let Some(e) = find::<reqwest::Error>(&error) {
+            return match e.is_timeout() {
+                true => Error::Timeout(failure(None)),
+                false => Error::Network(failure(None)),
+            };
+        }
+        if let Some(e) = find::<io::Error>(&error) {
+            if e.get_ref().is_some_and(|inner| inner.is::<rustls::Error>()) {
+                return Error::Tls(failure(None));
+            }
+            match e.kind() {
+                io::ErrorKind::TimedOut => return Error::Timeout(failure(None)),
+                io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::AddrNotAvailable
+                | io::ErrorKind::BrokenPipe
+                | io::ErrorKind::UnexpectedEof
+                | io::ErrorKind::HostUnreachable
+                | io::ErrorKind::NetworkUnreachable => return Error::Network(failure(None)),
+                _ => {}
+            }
+        }
+        if find::<Unreachable>(&error).is_some() {
+            return Error::Network(failure(None));
+        }
+        Error::Internal(failure(None))
+    }
+}
+
+impl From<sqlx::Error> for Error {
+    fn from(error: sqlx::Error) -> Self {
+        anyhow::Error::from(error).into()
+    }
+}
+
+/// `error` or any of its causes as a `T`, through anyhow's context too.
+fn find<T: std::error::Error + Send + Sync + 'static>(error: &anyhow::Error) -> Option<&T> {
+    error.downcast_ref::<T>().or_else(|| error.chain().find_map(|cause| cause.downcast_ref::<T>()))
+}
+
+/// Says what a command was doing when it failed, as `anyhow::Context`
+/// does, and sorts the failure into an `Error`.
+pub trait Context<T> {
+    fn context(self, doing: &'static str) -> Result<T, Error>;
+    fn with_context(self, doing: impl FnOnce() -> String) -> Result<T, Error>;
+}
+
+impl<T, E: Into<anyhow::Error>> Context<T> for Result<T, E> {
+    fn context(self, doing: &'static str) -> Result<T, Error> {
+        self.map_err(|e| e.into().context(doing).into())
+    }
+
+    fn with_context(self, doing: impl FnOnce() -> String) -> Result<T, Error> {
+        self.map_err(|e| e.into().context(doing()).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::imap::{test_server, ImapClient};
+    use tokio::net::TcpListener;
+
+    fn json(error: Error) -> Value {
+        serde_json::to_value(error).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_classify() {
+        // A wrong password, however far down the context
+        let port = test_server::serve("IMAP4rev1 SASL-IR AUTH=OAUTHBEARER", vec![
+            ("AUTHENTICATE OAUTHBEARER ", vec!["{tag} NO Invalid credentials"]),
+        ]).await;
+        let mut account = test_server::account(port);
+        account.auth_method = crate::db::AuthMethod::Oauth2;
+        account.access_token = Some("expired".to_string());
+        let error = ImapClient::connect(&account).await.err().unwrap();
+        let value = json(Err::<(), _>(error).context("Failed to sync").unwrap_err());
+        assert_eq!(value["code"], "AUTH_FAILED");
+        assert!(value["message"].as_str().unwrap().ends_with("Invalid credentials"));
+
+        let port = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap().port();
+        let error = ImapClient::connect(&test_server::account(port)).await.err().unwrap();
+        let value = json(Err::<(), _>(error).context("Failed to sync").unwrap_err());
+        assert_eq!(value["code"], "NETWORK");
+        assert!(value["message"].as_str().unwrap().starts_with("Failed to sync: Failed to connect to 127.0.0.1"));
+
+        // The server hanging up mid-command
+        let port = test_server::serve("IMAP4rev1", vec![("SELECT \"INBOX\"", vec!["* BYE Server shutting down"])]).await;
+        let mut client = ImapClient::connect(&test_server::account(port)).await.unwrap();
+        let error = client.select("INBOX").await.err().unwrap();
+        assert_eq!(json(Err::<(), _>(error).context("Failed to sync").unwrap_err())["code"], "NETWORK");
+
+        let error: Error = anyhow::Error::new(CertificateError::Untrusted {
+            host: "mail.example.com".to_string(),
+            fingerprint: "AB".repeat(32),
+            reason: "unknown issuer".to_string(),
+        })
+        .into();
+        assert_eq!(json(error), json!({
+            "code": "TLS",
+            "message": format!("The certificate of mail.example.com isn't trusted (unknown issuer). Its SHA-256 fingerprint is {}", "AB".repeat(32)),
+            "details": { "host": "mail.example.com", "fingerprint": "AB".repeat(32) },
+        }));
+
+        let error: Error = anyhow::Error::new(ProtocolError::FolderNotFound("Archive".to_string())).context("Failed to move").into();
+        assert_eq!(json(error)["details"], json!({ "folder": "Archive" }));
+        let error: Error = anyhow::Error::new(vault::Locked).into();
+        assert!(matches!(error, Error::Locked(_)));
+        let error: Error = sqlx::Error::RowNotFound.into();
+        assert!(matches!(error, Error::NotFound(_)));
+
+        // Our own errors keep their kind when more context is added
+        let error = Err::<(), _>(Error::Conflict("Folder has pending changes".into())).context("Failed to delete").unwrap_err();
+        assert_eq!(json(error), json!({ "code": "CONFLICT", "message": "Failed to delete: Folder has pending changes", "details": null }));
+    }
+}
@@ -1,12 +1,24 @@
 use serde::{Deserialize, Serialize};
-use tauri::State;
+use tauri::ipc::{Channel, InvokeResponseBody};
+use tauri::{AppHandle, Manager, State};
+use tauri_plugin_shell::ShellExt;
+use std::collections::HashMap;
 use std::sync::Arc;
 use anyhow::Result;
 
-use crate::db::{DbPool, Account, Folder, Email, ComposeEmail, EmailAddress};
-use crate::email::{EmailProtocol, ImapHandler, SmtpHandler};
+use crate::attachments;
+use crate::crypto::{self, age_keys, autocrypt, certstore, keyring, vault::Vault};
+use crate::db::{self, labels, DbPool, Account, AgeKey, AuthMethod, AllowScope, Attachment, AutocryptRecommendation, CertificatePin, Folder, FolderType, Email, ComposeEmail, EmailAddress, KeyTrust, Label, LabelSource, PendingOp, PgpKey, RemoteContentRule, SecureContent, SmimeCertificate};
+use crate::email::diagnostics::{self, ConnectionReport};
+use crate::email::{auth, discover, dns, folders, net, oauth, pins, warnings, Flag, ProtocolError, SessionPool, SmtpHandler};
+use crate::offline::{LocalAction, OfflineQueue, ReplayReport};
+use crate::render::{self, RenderOptions, SanitizedHtml};
 
-pub type AppState = Arc<DbPool>;
+mod error;
+pub use error::{Error, Failure};
+use error::Context;
+
+pub type AppState = DbPool;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AddAccountRequest {
@@ -19,7 +31,22 @@ pub struct AddAccountRequest {
     smtp_port: Option<i32>,
     username: String,
     password: String,
+    #[serde(default)]
+    auth_method: AuthMethod,
     use_ssl: bool,
+    #[serde(default)]
+    tls_ca_bundle: Option<String>,
+    #[serde(default)]
+    proxy_url: Option<String>,
+    authserv_id: Option<String>,
+}
+
+/// Settings found for an address, and how far to trust them.
+#[derive(Debug, Serialize)]
+pub struct DiscoveredAccount {
+    source: discover::Source,
+    confidence: u8,
+    settings: AddAccountRequest,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -32,13 +59,21 @@ pub struct TestAccountRequest {
     username: String,
     password: String,
     use_ssl: bool,
+    #[serde(default)]
+    tls_ca_bundle: Option<String>,
+    #[serde(default)]
+    proxy_url: Option<String>,
+    /// Fingerprints to trust, by host, for servers whose certificates the
+    /// user accepted while setting up.
+    #[serde(default)]
+    certificate_pins: HashMap<String, String>,
 }
 
 #[tauri::command]
 pub async fn add_account(
     pool: State<'_, AppState>,
     request: AddAccountRequest,
-) -> Result<Account, String> {
+) -> Result<Account, Error> {
     let account = Account {
         id: 0, // Will be set by database
         name: request.name,
@@ -51,9 +86,16 @@ pub async fn add_account(
         jmap_url: None,
         username: request.username,
         password_encrypted: request.password, // TODO: Encrypt with master password
+        auth_method: request.auth_method,
         use_ssl: request.use_ssl,
+        tls_ca_bundle: request.tls_ca_bundle,
+        proxy_url: request.proxy_url,
+        authserv_id: request.authserv_id,
+        autocrypt_prefer_encrypt: false,
         created_at: chrono::Utc::now(),
         updated_at: chrono::Utc::now(),
+        access_token: None,
+        certificate_pins: Vec::new(),
     };
 
     // For now, return a mock account
@@ -64,15 +106,40 @@ pub async fn add_account(
 }
 
 #[tauri::command]
-pub async fn get_accounts(pool: State<'_, AppState>) -> Result<Vec<Account>, String> {
+pub async fn get_accounts(pool: State<'_, AppState>) -> Result<Vec<Account>, Error> {
     // For now, return empty list
     Ok(vec![])
 }
 
+/// Removes the account with its folders, messages and pending changes,
+/// logging out of its open sessions first.
+#[tauri::command]
+pub async fn delete_account(
+    pool: State<'_, AppState>,
+    sessions: State<'_, Arc<SessionPool>>,
+    account_id: i64,
+) -> Result<(), Error> {
+    sessions.close_account(account_id).await;
+    let result = sqlx::query("DELETE FROM accounts WHERE id = ?")
+        .bind(account_id)
+        .execute(pool.as_ref())
+        .await
+        .context("Failed to delete account")?;
+    if result.rows_affected() == 0 {
+        return Err(Error::NotFound("Account not found".into()));
+    }
+    Ok(())
+}
+
+/// Checks the servers step by step, reporting how far each got and why it
+/// stopped.
 #[tauri::command]
-pub async fn test_account_connection(request: TestAccountRequest) -> Result<bool, String> {
+pub async fn test_account_connection(
+    pool: State<'_, AppState>,
+    request: TestAccountRequest,
+) -> Result<ConnectionReport, Error> {
     let protocol = request.protocol.clone();
-    let account = Account {
+    let mut account = Account {
         id: 0,
         name: "Test".to_string(),
         email: "test@example.com".to_string(),
@@ -84,78 +151,474 @@ pub async fn test_account_connection(request: TestAccountRequest) -> Result<bool
         jmap_url: None,
         username: request.username,
         password_encrypted: request.password,
+        auth_method: AuthMethod::Password,
         use_ssl: request.use_ssl,
+        tls_ca_bundle: request.tls_ca_bundle,
+        proxy_url: request.proxy_url,
+        authserv_id: None,
+        autocrypt_prefer_encrypt: false,
         created_at: chrono::Utc::now(),
         updated_at: chrono::Utc::now(),
+        access_token: None,
+        certificate_pins: request
+            .certificate_pins
+            .into_iter()
+            .map(|(host, fingerprint)| CertificatePin { account_id: 0, host, fingerprint, created_at: chrono::Utc::now() })
+            .collect(),
     };
+    let mut conn = pool.acquire().await?;
+    net::apply_global(&mut conn, &mut account).await
+        .context("Failed to get proxy")?;
 
     match protocol.as_str() {
-        "IMAP" => {
-            let handler = ImapHandler::new();
-            handler.test_connection(&account).await
-                .map_err(|e| format!("IMAP connection test failed: {}", e))
-        }
-        _ => Err("Unsupported protocol for testing".to_string()),
+        "IMAP" => Ok(diagnostics::check(&account).await),
+        _ => Err(Error::Unsupported("Unsupported protocol for testing".into())),
     }
 }
 
+/// The server certificates the account trusts by fingerprint.
+#[tauri::command]
+pub async fn get_certificate_pins(pool: State<'_, AppState>, account_id: i64) -> Result<Vec<CertificatePin>, Error> {
+    let mut conn = pool.acquire().await?;
+    pins::list(&mut conn, account_id).await
+        .context("Failed to get pinned certificates")
+}
+
+/// Trusts the certificate `host` presented, by the fingerprint a refused
+/// connection reported. Later connections fail if it changes.
+#[tauri::command]
+pub async fn pin_certificate(
+    pool: State<'_, AppState>,
+    account_id: i64,
+    host: String,
+    fingerprint: String,
+) -> Result<CertificatePin, Error> {
+    let mut conn = pool.acquire().await?;
+    pins::pin(&mut conn, account_id, &host, &fingerprint).await
+        .context("Failed to pin certificate")
+}
+
+#[tauri::command]
+pub async fn unpin_certificate(
+    pool: State<'_, AppState>,
+    sessions: State<'_, Arc<SessionPool>>,
+    account_id: i64,
+    host: String,
+) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    pins::unpin(&mut conn, account_id, &host).await
+        .context("Failed to unpin certificate")?;
+    // Open sessions trusted the certificate through the pin
+    sessions.close_account(account_id).await;
+    Ok(())
+}
+
+/// Sets the PEM CA certificates the account's servers may chain to besides
+/// the public ones, or clears them with `None`.
+#[tauri::command]
+pub async fn set_account_ca_bundle(
+    pool: State<'_, AppState>,
+    account_id: i64,
+    ca_bundle: Option<String>,
+) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    pins::set_ca_bundle(&mut conn, account_id, ca_bundle.as_deref()).await
+        .context("Failed to set CA bundle")
+}
+
+/// Sends the account's traffic through a proxy: a socks5://, tor:// or
+/// http:// URL. `None` goes back to the global proxy.
+#[tauri::command]
+pub async fn set_account_proxy(
+    pool: State<'_, AppState>,
+    account_id: i64,
+    proxy_url: Option<String>,
+) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    net::set_account_proxy(&mut conn, account_id, proxy_url.as_deref()).await
+        .context("Failed to set proxy")
+}
+
+/// The proxy for accounts that have none of their own.
+#[tauri::command]
+pub async fn get_global_proxy(pool: State<'_, AppState>) -> Result<Option<String>, Error> {
+    let mut conn = pool.acquire().await?;
+    net::global_proxy(&mut conn).await
+        .context("Failed to get proxy")
+}
+
+#[tauri::command]
+pub async fn set_global_proxy(pool: State<'_, AppState>, proxy_url: Option<String>) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    net::set_global_proxy(&mut conn, proxy_url.as_deref()).await
+        .context("Failed to set proxy")
+}
+
+/// Candidate settings for `email`, the likeliest first, from the bundled
+/// ISPDB, the domain's autoconfig file, SRV records and host name guesses.
+#[tauri::command]
+pub async fn discover_account_settings(pool: State<'_, AppState>, email: String) -> Result<Vec<DiscoveredAccount>, Error> {
+    let connector = global_connector(&pool).await?;
+    let resolver = connector.resolver().context("Failed to set up DNS")?;
+    let fetcher = discover::SystemFetcher::new(&connector).context("Failed to set up HTTP")?;
+    let resolver = resolver.as_ref().map(|resolver| resolver as &dyn dns::DnsResolver);
+    let candidates = discover::discover(&email, resolver, &fetcher).await
+        .context("Failed to discover settings")?;
+
+    let name = email.trim().split('@').next().unwrap_or_default().to_string();
+    Ok(candidates
+        .into_iter()
+        .map(|candidate| DiscoveredAccount {
+            source: candidate.source,
+            confidence: candidate.confidence,
+            settings: AddAccountRequest {
+                name: name.clone(),
+                email: email.trim().to_string(),
+                protocol: "IMAP".to_string(),
+                imap_server: Some(candidate.imap_server),
+                imap_port: Some(candidate.imap_port as i32),
+                smtp_server: candidate.smtp_server,
+                smtp_port: candidate.smtp_port.map(i32::from),
+                username: candidate.username,
+                password: String::new(),
+                auth_method: candidate.auth_method,
+                use_ssl: true,
+                tls_ca_bundle: None,
+                proxy_url: None,
+                authserv_id: None,
+            },
+        })
+        .collect())
+}
+
+/// Signs an account in with its OAuth2 provider in the browser, and
+/// switches it to OAuth2. `provider` is "google" or "microsoft", or found
+/// from the account's address and servers.
+#[tauri::command]
+pub async fn authorize_oauth_account(
+    app: AppHandle,
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    account_id: i64,
+    provider: Option<String>,
+) -> Result<Account, Error> {
+    let mut account: Account = sqlx::query_as("SELECT * FROM accounts WHERE id = ?")
+        .bind(account_id)
+        .fetch_optional(pool.as_ref())
+        .await?
+        .ok_or_else(|| Error::NotFound("Account not found".into()))?;
+    let mut conn = pool.acquire().await?;
+    net::apply_global(&mut conn, &mut account).await.context("Failed to get proxy")?;
+    drop(conn);
+    let connector = net::Connector::for_account(&account)?;
+    let name = match provider {
+        Some(name) => name,
+        None => oauth::detect(&account).ok_or_else(|| Error::Unsupported("No OAuth2 provider is known for this account".into()))?.to_string(),
+    };
+    let provider = oauth::provider(&name)?;
+
+    let token = oauth::authorize(&provider, &account.email, &connector, |url| {
+        shell_open(&app, url).map_err(|e| anyhow::anyhow!("Failed to open the browser: {}", e))
+    })
+    .await
+    .context("Failed to sign in")?;
+
+    let mut conn = pool.acquire().await?;
+    oauth::save(&mut conn, &vault, account_id, &provider, &token).await
+        .context("Failed to save sign-in")?;
+    sqlx::query_as("SELECT * FROM accounts WHERE id = ?")
+        .bind(account_id)
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(Error::from)
+}
+
 #[tauri::command]
 pub async fn sync_folders(
     pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
     account_id: i64,
-) -> Result<Vec<Folder>, String> {
-    // For now, return mock folders
-    Ok(vec![
-        Folder {
-            id: 1,
-            account_id,
-            name: "INBOX".to_string(),
-            display_name: "Inbox".to_string(),
-            folder_type: "INBOX".to_string(),
-            message_count: 0,
-            unread_count: 0,
-            uid_validity: None,
-            uid_next: None,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        },
-        Folder {
-            id: 2,
-            account_id,
-            name: "SENT".to_string(),
-            display_name: "Sent".to_string(),
-            folder_type: "SENT".to_string(),
-            message_count: 0,
-            unread_count: 0,
-            uid_validity: None,
-            uid_next: None,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        }
-    ])
+) -> Result<Vec<Folder>, Error> {
+    let account = load_account(&pool, &vault, account_id).await?;
+    let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for folders".into()))?;
+
+    let mut listed = handler.fetch_folders(&account).await
+        .context("Failed to fetch folders")?;
+    let overrides: Vec<(String, FolderType)> =
+        sqlx::query_as("SELECT folder_name, folder_type FROM folder_type_overrides WHERE account_id = ?")
+            .bind(account_id)
+            .fetch_all(pool.as_ref())
+            .await?;
+    crate::email::folders::apply_overrides(&mut listed, &overrides);
+
+    let mut tx = pool.begin().await?;
+    let mut folders = Vec::new();
+    for folder in listed {
+        let folder: Folder = sqlx::query_as(
+            "INSERT INTO folders (account_id, name, display_name, folder_type, delimiter, parent_name, is_subscribed, is_selectable) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?) \
+             ON CONFLICT (account_id, name) DO UPDATE SET display_name = excluded.display_name, \
+             folder_type = excluded.folder_type, delimiter = excluded.delimiter, parent_name = excluded.parent_name, \
+             is_subscribed = excluded.is_subscribed, is_selectable = excluded.is_selectable, updated_at = CURRENT_TIMESTAMP \
+             RETURNING *",
+        )
+        .bind(account_id)
+        .bind(&folder.name)
+        .bind(&folder.display_name)
+        .bind(folder.folder_type)
+        .bind(&folder.delimiter)
+        .bind(&folder.parent_name)
+        .bind(folder.is_subscribed)
+        .bind(folder.is_selectable)
+        .fetch_one(&mut *tx)
+        .await
+        .context("Failed to store folder")?;
+        folders.push(folder);
+    }
+    tx.commit().await?;
+    Ok(folders)
 }
 
 #[tauri::command]
 pub async fn get_folders(
     pool: State<'_, AppState>,
     account_id: i64,
-) -> Result<Vec<Folder>, String> {
-    // For now, return mock folders
-    Ok(vec![
-        Folder {
-            id: 1,
-            account_id,
-            name: "INBOX".to_string(),
-            display_name: "Inbox".to_string(),
-            folder_type: "INBOX".to_string(),
-            message_count: 0,
-            unread_count: 0,
-            uid_validity: None,
-            uid_next: None,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
+) -> Result<Vec<Folder>, Error> {
+    sqlx::query_as("SELECT * FROM folders WHERE account_id = ? ORDER BY name")
+        .bind(account_id)
+        .fetch_all(pool.as_ref())
+        .await
+        .context("Failed to load folders")
+}
+
+#[tauri::command]
+pub async fn create_folder(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    account_id: i64,
+    parent_id: Option<i64>,
+    name: String,
+) -> Result<Folder, Error> {
+    let account = load_account(&pool, &vault, account_id).await?;
+    let parent = match parent_id {
+        Some(parent_id) => Some(load_folder(&pool, parent_id).await?),
+        None => None,
+    };
+    let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for folders".into()))?;
+
+    let folder = handler.create_folder(&account, parent.as_ref(), name.trim()).await
+        .context("Failed to create folder")?;
+    sqlx::query_as(
+        "INSERT INTO folders (account_id, name, display_name, folder_type, delimiter, parent_name, is_subscribed) \
+         VALUES (?, ?, ?, ?, ?, ?, ?) RETURNING *",
+    )
+    .bind(account_id)
+    .bind(&folder.name)
+    .bind(&folder.display_name)
+    .bind(folder.folder_type)
+    .bind(&folder.delimiter)
+    .bind(&folder.parent_name)
+    .bind(folder.is_subscribed)
+    .fetch_one(pool.as_ref())
+    .await
+    .context("Failed to store folder")
+}
+
+/// Renames a folder on the server and locally. Subfolders are renamed with
+/// it; emails keep their folder ids, so nothing else moves. Returns the
+/// account's folders.
+#[tauri::command]
+pub async fn rename_folder(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    folder_id: i64,
+    name: String,
+) -> Result<Vec<Folder>, Error> {
+    let folder = load_folder(&pool, folder_id).await?;
+    if folder.folder_type == FolderType::Inbox {
+        return Err(Error::InvalidInput("The inbox cannot be renamed".into()));
+    }
+    let account = load_account(&pool, &vault, folder.account_id).await?;
+    let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for folders".into()))?;
+
+    let new_name = handler.rename_folder(&account, &folder, name.trim()).await
+        .context("Failed to rename folder")?;
+
+    let delimiter = folder.delimiter.as_deref().and_then(|d| d.chars().next());
+    let mut tx = pool.begin().await?;
+    let all: Vec<Folder> = sqlx::query_as("SELECT * FROM folders WHERE account_id = ?")
+        .bind(folder.account_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    for other in all {
+        let Some(renamed) = folders::renamed(&other.name, &folder.name, &new_name, delimiter) else {
+            continue;
+        };
+        let parent_name = other.parent_name.as_deref().map(|parent| {
+            folders::renamed(parent, &folder.name, &new_name, delimiter).unwrap_or_else(|| parent.to_string())
+        });
+        let display_name = folders::leaf_name(&renamed, delimiter).to_string();
+        sqlx::query("UPDATE folders SET name = ?, display_name = ?, parent_name = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(&renamed)
+            .bind(display_name)
+            .bind(parent_name)
+            .bind(other.id)
+            .execute(&mut *tx)
+            .await
+            .context("Failed to rename folder")?;
+        sqlx::query("UPDATE folder_type_overrides SET folder_name = ? WHERE account_id = ? AND folder_name = ?")
+            .bind(&renamed)
+            .bind(folder.account_id)
+            .bind(&other.name)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    get_folders(pool, folder.account_id).await
+}
+
+/// Deletes a folder and its local emails. A folder that still has
+/// subfolders stays behind as a non-selectable placeholder, as on the
+/// server.
+#[tauri::command]
+pub async fn delete_folder(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    folder_id: i64,
+) -> Result<(), Error> {
+    let folder = load_folder(&pool, folder_id).await?;
+    if folder.folder_type == FolderType::Inbox {
+        return Err(Error::InvalidInput("The inbox cannot be deleted".into()));
+    }
+    let pending: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM pending_ops WHERE folder_id = ?1 OR target_folder_id = ?1")
+        .bind(folder_id)
+        .fetch_one(pool.as_ref())
+        .await?;
+    if pending > 0 {
+        return Err(Error::Conflict("This folder has changes that have not reached the server yet".into()));
+    }
+    let account = load_account(&pool, &vault, folder.account_id).await?;
+    let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for folders".into()))?;
+
+    match handler.delete_folder(&account, &folder).await {
+        Ok(()) => {}
+        // Already gone on the server; finish the job locally
+        Err(e) if matches!(e.downcast_ref::<ProtocolError>(), Some(ProtocolError::FolderNotFound(_))) => {}
+        Err(e) => return Err(e.context("Failed to delete folder").into()),
+    }
+
+    let mut tx = pool.begin().await?;
+    let children: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM folders WHERE account_id = ? AND parent_name = ?")
+        .bind(folder.account_id)
+        .bind(&folder.name)
+        .fetch_one(&mut *tx)
+        .await?;
+    if children > 0 {
+        sqlx::query("DELETE FROM emails WHERE folder_id = ?")
+            .bind(folder_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE folders SET is_selectable = 0, message_count = 0, unread_count = 0, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+            .bind(folder_id)
+            .execute(&mut *tx)
+            .await?;
+    } else {
+        sqlx::query("DELETE FROM folders WHERE id = ?")
+            .bind(folder_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query("DELETE FROM folder_type_overrides WHERE account_id = ? AND folder_name = ?")
+        .bind(folder.account_id)
+        .bind(&folder.name)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await.context("Failed to delete folder")
+}
+
+#[tauri::command]
+pub async fn subscribe_folder(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    folder_id: i64,
+    subscribed: bool,
+) -> Result<Folder, Error> {
+    let folder = load_folder(&pool, folder_id).await?;
+    let account = load_account(&pool, &vault, folder.account_id).await?;
+    let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for folders".into()))?;
+
+    handler.subscribe_folder(&account, &folder, subscribed).await
+        .context("Failed to update subscription")?;
+    sqlx::query_as("UPDATE folders SET is_subscribed = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ? RETURNING *")
+        .bind(subscribed)
+        .bind(folder_id)
+        .fetch_one(pool.as_ref())
+        .await
+        .context("Failed to update subscription")
+}
+
+/// Overrides the detected type of a folder, or clears the override with
+/// `None` so the next sync detects it again. Returns the account's folders,
+/// since the role may move away from another folder.
+#[tauri::command]
+pub async fn set_folder_type(
+    pool: State<'_, AppState>,
+    folder_id: i64,
+    folder_type: Option<FolderType>,
+) -> Result<Vec<Folder>, Error> {
+    let folder: Folder = sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+        .bind(folder_id)
+        .fetch_optional(pool.as_ref())
+        .await?
+        .ok_or_else(|| Error::NotFound("Folder not found".into()))?;
+    if folder.folder_type == FolderType::Inbox || folder_type == Some(FolderType::Inbox) {
+        return Err(Error::InvalidInput("The inbox cannot be reassigned".into()));
+    }
+
+    let mut tx = pool.begin().await?;
+    match folder_type {
+        Some(folder_type) => {
+            if folder_type != FolderType::Custom {
+                sqlx::query("DELETE FROM folder_type_overrides WHERE account_id = ? AND folder_type = ?")
+                    .bind(folder.account_id)
+                    .bind(folder_type)
+                    .execute(&mut *tx)
+                    .await?;
+                sqlx::query("UPDATE folders SET folder_type = 'CUSTOM', updated_at = CURRENT_TIMESTAMP WHERE account_id = ? AND folder_type = ?")
+                    .bind(folder.account_id)
+                    .bind(folder_type)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query("INSERT OR REPLACE INTO folder_type_overrides (account_id, folder_name, folder_type) VALUES (?, ?, ?)")
+                .bind(folder.account_id)
+                .bind(&folder.name)
+                .bind(folder_type)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query("UPDATE folders SET folder_type = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+                .bind(folder_type)
+                .bind(folder_id)
+                .execute(&mut *tx)
+                .await?;
         }
-    ])
+        None => {
+            sqlx::query("DELETE FROM folder_type_overrides WHERE account_id = ? AND folder_name = ?")
+                .bind(folder.account_id)
+                .bind(&folder.name)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+    tx.commit().await.context("Failed to set folder type")?;
+
+    get_folders(pool, folder.account_id).await
 }
 
 #[tauri::command]
@@ -165,7 +628,7 @@ pub async fn fetch_emails(
     folder_id: i64,
     limit: Option<u32>,
     offset: Option<u32>,
-) -> Result<Vec<Email>, String> {
+) -> Result<Vec<Email>, Error> {
     // For now, return mock emails
     Ok(vec![
         Email {
@@ -196,8 +659,14 @@ pub async fn fetch_emails(
             is_deleted: false,
             uid: None,
             mod_seq: None,
+            trackers_blocked: None,
+            auth_verdict: None,
+            auth_results: None,
             created_at: chrono::Utc::now(),
             updated_at: chrono::Utc::now(),
+            keywords: vec![],
+            labels: vec![],
+            warnings: vec![],
         }
     ])
 }
@@ -208,66 +677,1145 @@ pub async fn get_emails(
     folder_id: i64,
     limit: Option<u32>,
     offset: Option<u32>,
-) -> Result<Vec<Email>, String> {
-    // For now, return mock emails
-    Ok(vec![
-        Email {
-            id: 1,
-            folder_id,
-            account_id: 1,
-            message_id: "test-1@example.com".to_string(),
-            thread_id: None,
-            subject: "Welcome to SlopMail!".to_string(),
-            from_address: "team@slopmail.dev".to_string(),
-            from_name: Some("SlopMail Team".to_string()),
-            to_addresses: serde_json::to_string(&vec![EmailAddress {
-                name: None,
-                address: "user@example.com".to_string(),
-            }]).unwrap(),
-            cc_addresses: None,
-            bcc_addresses: None,
-            body_text: Some("Welcome to SlopMail! This is a test email to demonstrate the email client functionality.".to_string()),
-            body_html: None,
-            attachments: None,
-            size_bytes: 150,
-            internal_date: chrono::Utc::now(),
-            received_date: chrono::Utc::now(),
-            is_read: false,
-            is_flagged: false,
-            is_answered: false,
-            is_draft: false,
-            is_deleted: false,
-            uid: None,
-            mod_seq: None,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
+) -> Result<Vec<Email>, Error> {
+    let mut conn = pool.acquire().await?;
+    let mut emails: Vec<Email> = sqlx::query_as(
+        "SELECT * FROM emails WHERE folder_id = ? AND is_deleted = 0 \
+         ORDER BY internal_date DESC LIMIT ? OFFSET ?",
+    )
+    .bind(folder_id)
+    .bind(limit.unwrap_or(50))
+    .bind(offset.unwrap_or(0))
+    .fetch_all(&mut *conn)
+    .await
+    .context("Failed to load emails")?;
+
+    for email in &mut emails {
+        db::load_details(&mut conn, email).await
+            .context("Failed to load email labels")?;
+    }
+    Ok(emails)
+}
+
+/// Emails carrying a label, across all folders of its account.
+#[tauri::command]
+pub async fn get_label_emails(
+    pool: State<'_, AppState>,
+    label_id: i64,
+    limit: Option<u32>,
+    offset: Option<u32>,
+) -> Result<Vec<Email>, Error> {
+    let mut conn = pool.acquire().await?;
+    let mut emails: Vec<Email> = sqlx::query_as(
+        "SELECT e.* FROM emails e WHERE e.is_deleted = 0 AND ( \
+           EXISTS (SELECT 1 FROM email_labels el WHERE el.email_id = e.id AND el.label_id = ?1) \
+           OR EXISTS (SELECT 1 FROM labels l JOIN email_keywords k ON k.keyword = l.remote_name COLLATE NOCASE \
+                      WHERE l.id = ?1 AND l.source = 'KEYWORD' AND l.account_id = e.account_id AND k.email_id = e.id)) \
+         ORDER BY e.internal_date DESC LIMIT ?2 OFFSET ?3",
+    )
+    .bind(label_id)
+    .bind(limit.unwrap_or(50))
+    .bind(offset.unwrap_or(0))
+    .fetch_all(&mut *conn)
+    .await
+    .context("Failed to load emails")?;
+
+    for email in &mut emails {
+        db::load_details(&mut conn, email).await
+            .context("Failed to load email labels")?;
+    }
+    Ok(emails)
+}
+
+/// The email's HTML body, sanitized for display. `None` when the email has
+/// no HTML part. Remote content loads when `load_remote` says so, or else
+/// when the sender is on the allow-list; trackers are blocked regardless.
+#[tauri::command]
+pub async fn render_email(
+    pool: State<'_, AppState>,
+    email_id: i64,
+    load_remote: Option<bool>,
+) -> Result<Option<SanitizedHtml>, Error> {
+    let mut conn = pool.acquire().await?;
+    let email = db::load_email(&mut conn, email_id).await?
+        .ok_or_else(|| Error::NotFound("Email not found".into()))?;
+    let Some(body_html) = &email.body_html else {
+        return Ok(None);
+    };
+
+    let account_email: String = sqlx::query_scalar("SELECT email FROM accounts WHERE id = ?")
+        .bind(email.account_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    let mut recipients = vec![account_email];
+    for list in [Some(&email.to_addresses), email.cc_addresses.as_ref(), email.bcc_addresses.as_ref()].into_iter().flatten() {
+        let addresses: Vec<EmailAddress> = serde_json::from_str(list).unwrap_or_default();
+        recipients.extend(addresses.into_iter().map(|address| address.address));
+    }
+    let load_remote = match load_remote {
+        Some(load_remote) => load_remote,
+        None => db::remote_content_allowed(&mut conn, &email.from_address).await?,
+    };
+
+    let rendered = render::sanitize(body_html, &RenderOptions { load_remote, recipients, email_id: Some(email_id) })
+        .context("Failed to render email")?;
+    if email.trackers_blocked != Some(rendered.trackers_blocked as i64) {
+        sqlx::query("UPDATE emails SET trackers_blocked = ? WHERE id = ?")
+            .bind(rendered.trackers_blocked as i64)
+            .bind(email_id)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(Some(rendered))
+}
+
+/// Checks that an email comes from its sender, from the server's
+/// Authentication-Results and, when `verify_dkim` is set and no proxy is in
+/// the way, our own DKIM verification, and stores the verdict on the email.
+#[tauri::command]
+pub async fn check_email_auth(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_id: i64,
+    verify_dkim: Option<bool>,
+) -> Result<Email, Error> {
+    let email = load_email(&pool, email_id).await?;
+    let (account, message) = fetch_message(&pool, &vault, &sessions, &email).await?;
+    // Behind a proxy the DKIM keys can't be looked up without leaking
+    let resolver = match verify_dkim {
+        Some(true) => net::Connector::for_account(&account)?.resolver().context("Failed to set up DNS")?,
+        _ => None,
+    };
+    let (verdict, results) = auth::check(
+        &message,
+        &account,
+        &email.from_address,
+        resolver.as_ref().map(|resolver| resolver as &dyn dns::DnsResolver),
+    ).await;
+
+    sqlx::query("UPDATE emails SET auth_verdict = ?, auth_results = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+        .bind(verdict)
+        .bind(serde_json::to_string(&results).context("Failed to store the results")?)
+        .bind(email_id)
+        .execute(pool.as_ref())
+        .await
+        .context("Failed to store sender check")?;
+    load_email(&pool, email_id).await
+}
+
+/// Decrypts and verifies an OpenPGP, S/MIME or age email with the keyring's
+/// keys and the certificate store. `passphrase` opens age mail encrypted
+/// with one. `None` when the email is neither encrypted nor signed. The HTML
+/// body comes back sanitized, with remote content blocked.
+#[tauri::command]
+pub async fn open_secure_email(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_id: i64,
+    passphrase: Option<String>,
+) -> Result<Option<SecureContent>, Error> {
+    let email = load_email(&pool, email_id).await?;
+    let (account, message) = fetch_message(&pool, &vault, &sessions, &email).await?;
+
+    let mut conn = pool.acquire().await?;
+    let stored = crypto::StoredKeys::load(&mut conn, &vault, None).await
+        .context("Failed to load keys")?;
+    let Some(opened) = crypto::open(&message, &email.from_address, &stored.keys(passphrase.as_deref())).context("Failed to open email")? else {
+        return Ok(None);
+    };
+    if let Err(e) = certstore::collect(&mut conn, &opened.certificates).await {
+        tracing::warn!("Failed to keep certificates of email {}: {}", email_id, e);
+    }
+    if opened.encrypted && opened.is_mime {
+        if let Err(e) = autocrypt::process_gossip(&mut conn, account.id, &message, &opened.content).await {
+            tracing::warn!("Failed to process Autocrypt gossip of email {}: {}", email_id, e);
         }
-    ])
+    }
+
+    let (body_text, body_html) = crypto::bodies(&opened);
+    let body_html = match body_html {
+        Some(html) => {
+            let options = RenderOptions { email_id: Some(email_id), ..Default::default() };
+            let rendered = render::sanitize(&html, &options).context("Failed to render email")?;
+            Some(rendered.html)
+        }
+        None => None,
+    };
+    Ok(Some(SecureContent {
+        encrypted: opened.encrypted,
+        signatures: opened.signatures,
+        body_text,
+        body_html,
+    }))
 }
 
+/// Unlocks the vault with the master password, which is set on first use.
 #[tauri::command]
-pub async fn send_email(
+pub async fn unlock_vault(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    password: String,
+) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    vault.unlock(&mut conn, &password).await
+        .context("Failed to unlock")
+}
+
+#[tauri::command]
+pub async fn lock_vault(vault: State<'_, Arc<Vault>>) -> Result<(), Error> {
+    vault.lock();
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn is_vault_unlocked(vault: State<'_, Arc<Vault>>) -> Result<bool, Error> {
+    Ok(vault.is_unlocked())
+}
+
+#[tauri::command]
+pub async fn get_pgp_keys(pool: State<'_, AppState>) -> Result<Vec<PgpKey>, Error> {
+    let mut conn = pool.acquire().await?;
+    keyring::list(&mut conn).await
+        .context("Failed to load keys")
+}
+
+/// Imports an ASCII-armored key or keyring. `passphrase` unlocks protected
+/// secret keys; the keyring keeps them under the master password instead.
+#[tauri::command]
+pub async fn import_pgp_keys(
     pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    armored: String,
+    passphrase: Option<String>,
+) -> Result<Vec<PgpKey>, Error> {
+    let mut conn = pool.acquire().await?;
+    keyring::import(&mut conn, &vault, armored.as_bytes(), passphrase.as_deref()).await
+        .context("Failed to import keys")
+}
+
+/// Generates a key pair for an account's identity.
+#[tauri::command]
+pub async fn generate_pgp_key(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
     account_id: i64,
-    to: Vec<String>,
-    subject: String,
-    body_text: Option<String>,
-    body_html: Option<String>,
-) -> Result<String, String> {
-    // For now, just return a mock message ID
-    Ok(format!("<mock-{}@slopmail.dev>", uuid::Uuid::new_v4()))
+) -> Result<PgpKey, Error> {
+    let mut conn = pool.acquire().await?;
+    let account: Account = sqlx::query_as("SELECT * FROM accounts WHERE id = ?")
+        .bind(account_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    let user_id = format!("{} <{}>", account.name, account.email);
+    keyring::generate(&mut conn, &vault, &user_id).await
+        .context("Failed to generate key")
+}
+
+#[tauri::command]
+pub async fn set_pgp_key_trust(
+    pool: State<'_, AppState>,
+    fingerprint: String,
+    trust: KeyTrust,
+) -> Result<PgpKey, Error> {
+    let mut conn = pool.acquire().await?;
+    keyring::set_trust(&mut conn, &fingerprint, trust).await
+        .context("Failed to set trust")
+}
+
+#[tauri::command]
+pub async fn revoke_pgp_key(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    fingerprint: String,
+    reason: Option<String>,
+) -> Result<PgpKey, Error> {
+    let mut conn = pool.acquire().await?;
+    keyring::revoke(&mut conn, &vault, &fingerprint, reason.as_deref().unwrap_or_default()).await
+        .context("Failed to revoke key")
+}
+
+/// ASCII-armored keys. Secret keys come along, protected by `passphrase`,
+/// only when one is given.
+#[tauri::command]
+pub async fn export_pgp_keys(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    fingerprints: Vec<String>,
+    passphrase: Option<String>,
+) -> Result<String, Error> {
+    let mut conn = pool.acquire().await?;
+    keyring::export(&mut conn, &vault, &fingerprints, passphrase.as_deref()).await
+        .context("Failed to export keys")
+}
+
+#[tauri::command]
+pub async fn delete_pgp_key(pool: State<'_, AppState>, fingerprint: String) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    keyring::delete(&mut conn, &fingerprint).await
+        .context("Failed to delete key")
+}
+
+/// Usable keys for a recipient, best first, for the composer.
+#[tauri::command]
+pub async fn lookup_pgp_keys(pool: State<'_, AppState>, address: String) -> Result<Vec<PgpKey>, Error> {
+    let mut conn = pool.acquire().await?;
+    keyring::lookup(&mut conn, &address).await
+        .context("Failed to look up keys")
+}
+
+#[tauri::command]
+pub async fn get_smime_certificates(pool: State<'_, AppState>) -> Result<Vec<SmimeCertificate>, Error> {
+    let mut conn = pool.acquire().await?;
+    certstore::list(&mut conn).await
+        .context("Failed to load certificates")
+}
+
+/// Imports an S/MIME identity from a PKCS#12 (.p12/.pfx) file. The private
+/// key is kept under the master password rather than `password`.
+#[tauri::command]
+pub async fn import_smime_identity(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    data: Vec<u8>,
+    password: String,
+) -> Result<SmimeCertificate, Error> {
+    let mut conn = pool.acquire().await?;
+    certstore::import_identity(&mut conn, &vault, &data, &password).await
+        .context("Failed to import identity")
+}
+
+/// Imports PEM, DER or PKCS#7 certificates, as trust anchors when `trusted`.
+#[tauri::command]
+pub async fn import_smime_certificates(
+    pool: State<'_, AppState>,
+    data: Vec<u8>,
+    trusted: bool,
+) -> Result<Vec<SmimeCertificate>, Error> {
+    let mut conn = pool.acquire().await?;
+    certstore::import(&mut conn, &data, trusted).await
+        .context("Failed to import certificates")
+}
+
+#[tauri::command]
+pub async fn set_smime_certificate_trusted(
+    pool: State<'_, AppState>,
+    fingerprint: String,
+    trusted: bool,
+) -> Result<SmimeCertificate, Error> {
+    let mut conn = pool.acquire().await?;
+    certstore::set_trusted(&mut conn, &fingerprint, trusted).await
+        .context("Failed to set trust")
+}
+
+#[tauri::command]
+pub async fn delete_smime_certificate(pool: State<'_, AppState>, fingerprint: String) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    certstore::delete(&mut conn, &fingerprint).await
+        .context("Failed to delete certificate")
+}
+
+/// Current certificates for a recipient, for the composer.
+#[tauri::command]
+pub async fn lookup_smime_certificates(pool: State<'_, AppState>, address: String) -> Result<Vec<SmimeCertificate>, Error> {
+    let mut conn = pool.acquire().await?;
+    certstore::lookup(&mut conn, &address).await
+        .context("Failed to look up certificates")
+}
+
+#[tauri::command]
+pub async fn get_age_keys(pool: State<'_, AppState>) -> Result<Vec<AgeKey>, Error> {
+    let mut conn = pool.acquire().await?;
+    age_keys::list(&mut conn).await
+        .context("Failed to load age keys")
+}
+
+/// Generates an age identity for an account.
+#[tauri::command]
+pub async fn generate_age_key(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    account_id: i64,
+) -> Result<AgeKey, Error> {
+    let mut conn = pool.acquire().await?;
+    let account: Account = sqlx::query_as("SELECT * FROM accounts WHERE id = ?")
+        .bind(account_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    age_keys::generate(&mut conn, &vault, &account.name, Some(&account.email)).await
+        .context("Failed to generate key")
+}
+
+/// Imports an age identity file or an SSH private key. `passphrase` unlocks
+/// a protected SSH key, and is kept with it.
+#[tauri::command]
+pub async fn import_age_identity(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    text: String,
+    passphrase: Option<String>,
+    name: String,
+    address: Option<String>,
+) -> Result<Vec<AgeKey>, Error> {
+    let mut conn = pool.acquire().await?;
+    age_keys::import_identity(&mut conn, &vault, &text, passphrase.as_deref(), &name, address.as_deref()).await
+        .context("Failed to import identity")
+}
+
+/// Adds someone's age recipient or SSH public key.
+#[tauri::command]
+pub async fn add_age_recipient(
+    pool: State<'_, AppState>,
+    recipient: String,
+    name: String,
+    address: Option<String>,
+) -> Result<AgeKey, Error> {
+    let mut conn = pool.acquire().await?;
+    age_keys::add_recipient(&mut conn, &recipient, &name, address.as_deref()).await
+        .context("Failed to add recipient")
+}
+
+#[tauri::command]
+pub async fn delete_age_key(pool: State<'_, AppState>, recipient: String) -> Result<(), Error> {
+    let mut conn = pool.acquire().await?;
+    age_keys::delete(&mut conn, &recipient).await
+        .context("Failed to delete key")
+}
+
+/// age keys for a recipient, for the composer.
+#[tauri::command]
+pub async fn lookup_age_keys(pool: State<'_, AppState>, address: String) -> Result<Vec<AgeKey>, Error> {
+    let mut conn = pool.acquire().await?;
+    age_keys::lookup(&mut conn, &address).await
+        .context("Failed to look up keys")
+}
+
+/// Encrypts a file to attach with age, to the keys of `recipients` or with
+/// `passphrase`. Returns the path of the `.age` file, which is written to
+/// the private directory emptied when the app exits.
+#[tauri::command]
+pub async fn encrypt_file_with_age(
+    app: AppHandle,
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    path: String,
+    recipients: Vec<String>,
+    passphrase: Option<String>,
+) -> Result<String, Error> {
+    let path = std::path::PathBuf::from(path);
+    let data = tokio::fs::read(&path).await
+        .context("Failed to read file")?;
+    let encrypted = match passphrase.as_deref() {
+        Some(passphrase) => crypto::age::encrypt_with_passphrase(&data, passphrase, false),
+        None => {
+            let mut conn = pool.acquire().await?;
+            let (age_identities, age_recipients) = age_keys::keys(&mut conn, &vault).await
+                .context("Failed to load age keys")?;
+            let keys = crypto::Keys { age_identities: &age_identities, age_recipients: &age_recipients, ..Default::default() };
+            let recipients: Vec<&str> = recipients.iter().map(String::as_str).collect();
+            crypto::encrypt_age(&data, &recipients, false, &keys)
+        }
+    }
+    .context("Failed to encrypt file")?;
+
+    let name = format!("{}.age", path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default());
+    let dir = app.path().app_cache_dir().context("Failed to find the cache directory")?.join(attachments::OPEN_DIR);
+    let encrypted_path = attachments::write_private(&dir, &name, &encrypted)
+        .context("Failed to write encrypted file")?;
+    Ok(encrypted_path.to_string_lossy().into_owned())
+}
+
+/// Streams an age-encrypted attachment decrypted, like `get_attachment`,
+/// with our identities or `passphrase`. The attachment comes back named
+/// without its `.age` suffix.
+#[tauri::command]
+pub async fn get_age_attachment(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_id: i64,
+    attachment_id: String,
+    passphrase: Option<String>,
+    channel: Channel<InvokeResponseBody>,
+) -> Result<Attachment, Error> {
+    let (email, attachment) = load_attachment(&pool, email_id, &attachment_id).await?;
+    let data = attachments::load(&pool, &vault, &sessions, &email, &attachment).await
+        .context("Failed to download attachment")?;
+    if !crypto::age::is_encrypted(&data) {
+        return Err(Error::InvalidInput("The attachment isn't age-encrypted".into()));
+    }
+    let mut conn = pool.acquire().await?;
+    let (age_identities, _) = age_keys::keys(&mut conn, &vault).await
+        .context("Failed to load age keys")?;
+    let decrypted = crypto::age::decrypt(&data, &age_identities, passphrase.as_deref())
+        .context("Failed to decrypt attachment")?;
+    for chunk in decrypted.chunks(attachments::CHUNK_SIZE) {
+        channel.send(InvokeResponseBody::Raw(chunk.to_vec()))
+            .context("Failed to send attachment")?;
+    }
+
+    let filename = match attachment.filename.len().checked_sub(4) {
+        Some(stem) if attachment.filename.to_ascii_lowercase().ends_with(".age") => attachment.filename[..stem].to_string(),
+        _ => attachment.filename.clone(),
+    };
+    Ok(Attachment {
+        filename,
+        content_type: "application/octet-stream".to_string(),
+        size_bytes: decrypted.len() as i64,
+        ..attachment
+    })
+}
+
+/// Whether Autocrypt suggests encrypting mail from the account to
+/// `recipients`.
+#[tauri::command]
+pub async fn get_encrypt_recommendation(
+    pool: State<'_, AppState>,
+    account_id: i64,
+    recipients: Vec<String>,
+    reply_to_encrypted: Option<bool>,
+) -> Result<AutocryptRecommendation, Error> {
+    let mut conn = pool.acquire().await?;
+    let account: Account = sqlx::query_as("SELECT * FROM accounts WHERE id = ?")
+        .bind(account_id)
+        .fetch_one(&mut *conn)
+        .await?;
+    autocrypt::recommend(&mut conn, &account, &recipients, reply_to_encrypted.unwrap_or(false)).await
+        .context("Failed to get recommendation")
+}
+
+/// Sets whether the account's Autocrypt header asks peers to encrypt.
+#[tauri::command]
+pub async fn set_autocrypt_prefer_encrypt(
+    pool: State<'_, AppState>,
+    account_id: i64,
+    prefer_encrypt: bool,
+) -> Result<(), Error> {
+    sqlx::query("UPDATE accounts SET autocrypt_prefer_encrypt = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+        .bind(prefer_encrypt)
+        .bind(account_id)
+        .execute(pool.as_ref())
+        .await
+        .context("Failed to update account")?;
+    Ok(())
+}
+
+/// Streams an attachment's bytes over `channel`, downloading the part
+/// first if it is not cached.
+#[tauri::command]
+pub async fn get_attachment(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_id: i64,
+    attachment_id: String,
+    channel: Channel<InvokeResponseBody>,
+) -> Result<Attachment, Error> {
+    let (email, attachment) = load_attachment(&pool, email_id, &attachment_id).await?;
+    let data = attachments::load(&pool, &vault, &sessions, &email, &attachment).await
+        .context("Failed to download attachment")?;
+    for chunk in data.chunks(attachments::CHUNK_SIZE) {
+        channel.send(InvokeResponseBody::Raw(chunk.to_vec()))
+            .context("Failed to send attachment")?;
+    }
+    Ok(attachment)
+}
+
+/// Saves an attachment into `dir` under a safe name, renaming rather than
+/// overwriting. Returns the path written.
+#[tauri::command]
+pub async fn save_attachment(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_id: i64,
+    attachment_id: String,
+    dir: String,
+) -> Result<String, Error> {
+    let (email, attachment) = load_attachment(&pool, email_id, &attachment_id).await?;
+    let data = attachments::load(&pool, &vault, &sessions, &email, &attachment).await
+        .context("Failed to download attachment")?;
+    let path = attachments::unique_path(std::path::Path::new(&dir), &attachments::safe_file_name(&attachment.filename));
+    tokio::fs::write(&path, data).await
+        .context("Failed to save attachment")?;
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Saves every attachment that is not an inline image into `dir`, renaming
+/// rather than overwriting. Returns the paths written.
+#[tauri::command]
+pub async fn save_all_attachments(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_id: i64,
+    dir: String,
+) -> Result<Vec<String>, Error> {
+    let email = load_email(&pool, email_id).await?;
+    let dir = std::path::PathBuf::from(dir);
+    let mut saved = Vec::new();
+    for attachment in attachments::list(&email).into_iter().filter(|attachment| !attachment.is_inline) {
+        let data = attachments::load(&pool, &vault, &sessions, &email, &attachment).await
+            .with_context(|| format!("Failed to download {}", attachment.filename))?;
+        let path = attachments::unique_path(&dir, &attachments::safe_file_name(&attachment.filename));
+        tokio::fs::write(&path, data).await
+            .with_context(|| format!("Failed to save {}", attachment.filename))?;
+        saved.push(path.to_string_lossy().into_owned());
+    }
+    Ok(saved)
+}
+
+/// Opens an attachment with the system's handler for its type, from a
+/// private directory that is emptied when the app exits. Attachments that
+/// can run code need `confirmed`.
+#[tauri::command]
+pub async fn open_attachment(
+    app: AppHandle,
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_id: i64,
+    attachment_id: String,
+    confirmed: bool,
+) -> Result<(), Error> {
+    let (email, attachment) = load_attachment(&pool, email_id, &attachment_id).await?;
+    // Programs and macro documents only run once the user has agreed to it
+    if let Some(warning) = warnings::attachment_warning(&attachment.filename, &attachment.content_type) {
+        if !confirmed {
+            return Err(Error::NeedsConfirmation(Failure {
+                message: format!("{}: {}", warning.subject, warning.detail),
+                details: serde_json::to_value(&warning).ok(),
+            }));
+        }
+    }
+    let data = attachments::load(&pool, &vault, &sessions, &email, &attachment).await
+        .context("Failed to download attachment")?;
+    let dir = app.path().app_cache_dir().context("Failed to find the cache directory")?.join(attachments::OPEN_DIR);
+    let path = attachments::write_private(&dir, &attachment.filename, &data)
+        .context("Failed to open attachment")?;
+
+    shell_open(&app, path.to_string_lossy())
+        .context("Failed to open attachment")
+}
+
+#[tauri::command]
+pub async fn get_remote_content_rules(pool: State<'_, AppState>) -> Result<Vec<RemoteContentRule>, Error> {
+    sqlx::query_as("SELECT * FROM remote_content_allowlist ORDER BY scope, value")
+        .fetch_all(pool.as_ref())
+        .await
+        .context("Failed to load allow-list")
+}
+
+/// Lets remote content load in mail from a sender or domain.
+#[tauri::command]
+pub async fn allow_remote_content(
+    pool: State<'_, AppState>,
+    scope: AllowScope,
+    value: String,
+) -> Result<RemoteContentRule, Error> {
+    let value = value.trim().to_ascii_lowercase();
+    let value = match scope {
+        AllowScope::Sender => value,
+        AllowScope::Domain => value.trim_start_matches('@').trim_start_matches("*.").to_string(),
+    };
+    let valid = match scope {
+        AllowScope::Sender => value.split_once('@').is_some_and(|(local, domain)| !local.is_empty() && !domain.is_empty()),
+        AllowScope::Domain => !value.is_empty() && !value.contains('@'),
+    };
+    if !valid {
+        return Err(Error::InvalidInput(format!("Not a valid {}: {}", if scope == AllowScope::Sender { "address" } else { "domain" }, value).into()));
+    }
+
+    sqlx::query_as(
+        "INSERT INTO remote_content_allowlist (scope, value) VALUES (?, ?) \
+         ON CONFLICT (scope, value) DO UPDATE SET value = excluded.value RETURNING *",
+    )
+    .bind(scope)
+    .bind(&value)
+    .fetch_one(pool.as_ref())
+    .await
+    .context("Failed to update allow-list")
+}
+
+#[tauri::command]
+pub async fn remove_remote_content_rule(
+    pool: State<'_, AppState>,
+    rule_id: i64,
+) -> Result<(), Error> {
+    sqlx::query("DELETE FROM remote_content_allowlist WHERE id = ?")
+        .bind(rule_id)
+        .execute(pool.as_ref())
+        .await
+        .context("Failed to update allow-list")?;
+    Ok(())
+}
+
+/// Signs and encrypts the email as it asks, sends it over SMTP with the
+/// account's Autocrypt header, and returns its Message-ID. `passphrase`
+/// unlocks a protected OpenPGP signing key.
+#[tauri::command]
+pub async fn send_email(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    email: ComposeEmail,
+    passphrase: Option<String>,
+) -> Result<String, Error> {
+    let account = load_account(&pool, &vault, email.account_id).await?;
+    let mut conn = pool.acquire().await?;
+    let body = crypto::protect_outgoing(&mut conn, &vault, &email, &account.email, passphrase.as_deref()).await
+        .context("Failed to sign or encrypt email")?;
+
+    let mut handler = SmtpHandler::new();
+    match autocrypt::outgoing_header(&mut conn, &vault, &account).await {
+        Ok(Some(header)) => handler = handler.with_autocrypt(header),
+        Ok(None) => {}
+        // A locked vault shouldn't stop the mail, only the header
+        Err(e) => tracing::warn!("Failed to make the Autocrypt header of account {}: {}", account.id, e),
+    }
+    handler.send_message(&account, &email, &body).await
+        .context("Failed to send email")
 }
 
 #[tauri::command]
 pub async fn mark_email_read(
+    queue: State<'_, Arc<OfflineQueue>>,
+    email_id: i64,
+    read: bool,
+) -> Result<Email, Error> {
+    let action = if read {
+        LocalAction::SetFlags { add: vec![Flag::Seen], remove: vec![] }
+    } else {
+        LocalAction::SetFlags { add: vec![], remove: vec![Flag::Seen] }
+    };
+    queue.record(email_id, action).await
+        .context("Failed to mark email")
+}
+
+#[tauri::command]
+pub async fn set_email_flags(
+    queue: State<'_, Arc<OfflineQueue>>,
+    email_ids: Vec<i64>,
+    add: Vec<Flag>,
+    remove: Vec<Flag>,
+) -> Result<Vec<Email>, Error> {
+    let mut updated = Vec::new();
+    for email_id in email_ids {
+        let action = LocalAction::SetFlags { add: add.clone(), remove: remove.clone() };
+        let email = queue.record(email_id, action).await
+            .context("Failed to update email flags")?;
+        updated.push(email);
+    }
+    Ok(updated)
+}
+
+#[tauri::command]
+pub async fn sync_email_flags(
+    queue: State<'_, Arc<OfflineQueue>>,
+    folder_id: i64,
+) -> Result<usize, Error> {
+    queue.sync_flags(folder_id).await
+        .context("Failed to sync email flags")
+}
+
+#[tauri::command]
+pub async fn move_emails(
+    queue: State<'_, Arc<OfflineQueue>>,
+    email_ids: Vec<i64>,
+    target_folder_id: i64,
+) -> Result<Vec<Email>, Error> {
+    let mut moved = Vec::new();
+    for email_id in email_ids {
+        let email = queue.record(email_id, LocalAction::Move { target_folder_id }).await
+            .context("Failed to move email")?;
+        moved.push(email);
+    }
+    Ok(moved)
+}
+
+#[tauri::command]
+pub async fn archive_emails(
+    pool: State<'_, AppState>,
+    queue: State<'_, Arc<OfflineQueue>>,
+    email_ids: Vec<i64>,
+) -> Result<Vec<Email>, Error> {
+    let mut archived = Vec::new();
+    for email_id in email_ids {
+        let archive_id: Option<i64> = sqlx::query_scalar(
+            "SELECT f.id FROM folders f JOIN emails e ON e.account_id = f.account_id \
+             WHERE e.id = ? AND f.folder_type = 'ARCHIVE'",
+        )
+        .bind(email_id)
+        .fetch_optional(pool.as_ref())
+        .await
+        .context("Failed to look up archive folder")?;
+        let target_folder_id = archive_id.ok_or_else(|| Error::NotFound("This account has no Archive folder".into()))?;
+
+        let email = queue.record(email_id, LocalAction::Move { target_folder_id }).await
+            .context("Failed to archive email")?;
+        archived.push(email);
+    }
+    Ok(archived)
+}
+
+/// Copies go straight to the server: the copies only exist once it has
+/// assigned them UIDs.
+#[tauri::command]
+pub async fn copy_emails(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    email_ids: Vec<i64>,
+    target_folder_id: i64,
+) -> Result<Vec<Email>, Error> {
+    let target: Folder = sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+        .bind(target_folder_id)
+        .fetch_optional(pool.as_ref())
+        .await?
+        .ok_or_else(|| Error::NotFound("Target folder not found".into()))?;
+    let account = load_account(&pool, &vault, target.account_id).await?;
+    let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for copying".into()))?;
+
+    let mut sources: Vec<Email> = Vec::new();
+    for email_id in email_ids {
+        let email: Email = sqlx::query_as("SELECT * FROM emails WHERE id = ? AND account_id = ?")
+            .bind(email_id)
+            .bind(account.id)
+            .fetch_optional(pool.as_ref())
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("Email {} not found in this account", email_id).into()))?;
+        sources.push(email);
+    }
+
+    let mut copies = Vec::new();
+    let mut folder_ids: Vec<i64> = sources.iter().map(|e| e.folder_id).collect();
+    folder_ids.sort_unstable();
+    folder_ids.dedup();
+    for folder_id in folder_ids {
+        let folder: Folder = sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+            .bind(folder_id)
+            .fetch_one(pool.as_ref())
+            .await?;
+        let batch: Vec<&Email> = sources.iter().filter(|e| e.folder_id == folder_id && e.uid.is_some()).collect();
+        let uids: Vec<i64> = batch.iter().filter_map(|e| e.uid).collect();
+        if uids.is_empty() {
+            continue;
+        }
+
+        let mapping = handler.copy_emails(&account, &folder, &uids, &target).await
+            .context("Failed to copy emails")?;
+        for email in batch {
+            // Without COPYUID the copy gets its UID on the next sync
+            let uid = mapping.iter().find(|m| Some(m.source_uid) == email.uid).map(|m| m.target_uid);
+            let copy: Email = sqlx::query_as(
+                "INSERT INTO emails (account_id, folder_id, message_id, thread_id, subject, from_address, from_name, \
+                 to_addresses, cc_addresses, bcc_addresses, body_text, body_html, attachments, size_bytes, internal_date, \
+                 is_read, is_flagged, is_answered, is_draft, uid) \
+                 SELECT account_id, ?, message_id, thread_id, subject, from_address, from_name, \
+                 to_addresses, cc_addresses, bcc_addresses, body_text, body_html, attachments, size_bytes, internal_date, \
+                 is_read, is_flagged, is_answered, is_draft, ? FROM emails WHERE id = ? \
+                 ON CONFLICT (account_id, folder_id, message_id) DO UPDATE SET uid = excluded.uid \
+                 RETURNING *",
+            )
+            .bind(target.id)
+            .bind(uid)
+            .bind(email.id)
+            .fetch_one(pool.as_ref())
+            .await
+            .context("Failed to store copied email")?;
+            copies.push(copy);
+        }
+    }
+    Ok(copies)
+}
+
+#[tauri::command]
+pub async fn delete_email(
+    queue: State<'_, Arc<OfflineQueue>>,
+    email_id: i64,
+) -> Result<Email, Error> {
+    queue.record(email_id, LocalAction::Delete).await
+        .context("Failed to delete email")
+}
+
+#[tauri::command]
+pub async fn get_pending_ops(
+    queue: State<'_, Arc<OfflineQueue>>,
+    account_id: i64,
+) -> Result<Vec<PendingOp>, Error> {
+    queue.pending(account_id).await
+        .context("Failed to load pending operations")
+}
+
+#[tauri::command]
+pub async fn replay_pending_ops(
+    queue: State<'_, Arc<OfflineQueue>>,
+) -> Result<ReplayReport, Error> {
+    queue.replay().await
+        .context("Failed to replay pending operations")
+}
+
+#[tauri::command]
+pub async fn get_labels(
     pool: State<'_, AppState>,
     account_id: i64,
-    email_id: String,
-) -> Result<(), String> {
-    // For now, just return success
+) -> Result<Vec<Label>, Error> {
+    sqlx::query_as("SELECT * FROM labels WHERE account_id = ? ORDER BY name COLLATE NOCASE")
+        .bind(account_id)
+        .fetch_all(pool.as_ref())
+        .await
+        .context("Failed to load labels")
+}
+
+/// Creates a local tag. Gmail labels and keywords appear through sync.
+#[tauri::command]
+pub async fn create_label(
+    pool: State<'_, AppState>,
+    account_id: i64,
+    name: String,
+    color: Option<String>,
+) -> Result<Label, Error> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::InvalidInput("Label name cannot be empty".into()));
+    }
+    if color.as_deref().is_some_and(|color| !labels::is_valid_color(color)) {
+        return Err(Error::InvalidInput("Label color must look like #rrggbb".into()));
+    }
+
+    sqlx::query_as("INSERT INTO labels (account_id, name, color, source) VALUES (?, ?, ?, ?) RETURNING *")
+        .bind(account_id)
+        .bind(name)
+        .bind(color)
+        .bind(LabelSource::Local)
+        .fetch_one(pool.as_ref())
+        .await
+        .context("Failed to create label")
+}
+
+/// Recolors any label; only local tags can be renamed.
+#[tauri::command]
+pub async fn update_label(
+    pool: State<'_, AppState>,
+    label_id: i64,
+    name: Option<String>,
+    color: Option<String>,
+) -> Result<Label, Error> {
+    let label = load_label(&pool, label_id).await?;
+    if name.is_some() && label.source != LabelSource::Local {
+        return Err(Error::InvalidInput("Only local tags can be renamed".into()));
+    }
+    if color.as_deref().is_some_and(|color| !labels::is_valid_color(color)) {
+        return Err(Error::InvalidInput("Label color must look like #rrggbb".into()));
+    }
+    let name = name.map(|name| name.trim().to_string()).unwrap_or(label.name);
+    if name.is_empty() {
+        return Err(Error::InvalidInput("Label name cannot be empty".into()));
+    }
+
+    sqlx::query_as("UPDATE labels SET name = ?, color = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ? RETURNING *")
+        .bind(name)
+        .bind(color.or(label.color))
+        .bind(label_id)
+        .fetch_one(pool.as_ref())
+        .await
+        .context("Failed to update label")
+}
+
+#[tauri::command]
+pub async fn delete_label(
+    pool: State<'_, AppState>,
+    label_id: i64,
+) -> Result<(), Error> {
+    let label = load_label(&pool, label_id).await?;
+    if label.source != LabelSource::Local {
+        return Err(Error::InvalidInput("Only local tags can be deleted".into()));
+    }
+    sqlx::query("DELETE FROM labels WHERE id = ?")
+        .bind(label_id)
+        .execute(pool.as_ref())
+        .await
+        .context("Failed to delete label")?;
     Ok(())
 }
 
+/// Adds or removes a label. Keyword labels go through the offline queue like
+/// any other flag change; Gmail labels are stored on the server first.
+#[tauri::command]
+pub async fn set_email_label(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    queue: State<'_, Arc<OfflineQueue>>,
+    email_ids: Vec<i64>,
+    label_id: i64,
+    assigned: bool,
+) -> Result<Vec<Email>, Error> {
+    let label = load_label(&pool, label_id).await?;
+
+    let mut emails: Vec<Email> = Vec::new();
+    for email_id in email_ids {
+        let email: Email = sqlx::query_as("SELECT * FROM emails WHERE id = ? AND account_id = ?")
+            .bind(email_id)
+            .bind(label.account_id)
+            .fetch_optional(pool.as_ref())
+            .await?
+            .ok_or_else(|| Error::NotFound(format!("Email {} not found in this account", email_id).into()))?;
+        emails.push(email);
+    }
+
+    match label.source {
+        LabelSource::Keyword => {
+            let keyword = Flag::parse(label.remote_name.as_deref().unwrap_or_default())?;
+            let mut updated = Vec::new();
+            for email in emails {
+                let action = if assigned {
+                    LocalAction::SetFlags { add: vec![keyword.clone()], remove: vec![] }
+                } else {
+                    LocalAction::SetFlags { add: vec![], remove: vec![keyword.clone()] }
+                };
+                let email = queue.record(email.id, action).await
+                    .context("Failed to update label")?;
+                updated.push(email);
+            }
+            return Ok(updated);
+        }
+        LabelSource::Gmail => {
+            let account = load_account(&pool, &vault, label.account_id).await?;
+            let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for labels".into()))?;
+            let remote_name = label.remote_name.clone().unwrap_or_default();
+            let (add, remove) = if assigned { (vec![remote_name], vec![]) } else { (vec![], vec![remote_name]) };
+
+            let mut folder_ids: Vec<i64> = emails.iter().map(|e| e.folder_id).collect();
+            folder_ids.sort_unstable();
+            folder_ids.dedup();
+            for folder_id in folder_ids {
+                let folder: Folder = sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+                    .bind(folder_id)
+                    .fetch_one(pool.as_ref())
+                    .await?;
+                let uids: Vec<i64> = emails.iter().filter(|e| e.folder_id == folder_id).filter_map(|e| e.uid).collect();
+                if uids.is_empty() {
+                    continue;
+                }
+                handler.set_labels(&account, &folder, &uids, &add, &remove).await
+                    .context("Failed to update label")?;
+            }
+        }
+        LabelSource::Local => {}
+    }
+
+    let mut conn = pool.acquire().await?;
+    let mut updated = Vec::new();
+    for email in emails {
+        let result = if assigned {
+            labels::assign(&mut conn, email.id, label.id).await
+        } else {
+            labels::remove(&mut conn, email.id, label.id).await
+        };
+        result.context("Failed to update label")?;
+        let email = db::load_email(&mut conn, email.id).await?
+            .ok_or_else(|| Error::NotFound("Email was deleted".into()))?;
+        updated.push(email);
+    }
+    Ok(updated)
+}
+
+/// Pulls Gmail labels for a folder. Returns how many messages changed.
+#[tauri::command]
+pub async fn sync_labels(
+    pool: State<'_, AppState>,
+    vault: State<'_, Arc<Vault>>,
+    sessions: State<'_, Arc<SessionPool>>,
+    folder_id: i64,
+) -> Result<usize, Error> {
+    let folder: Folder = sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+        .bind(folder_id)
+        .fetch_optional(pool.as_ref())
+        .await?
+        .ok_or_else(|| Error::NotFound("Folder not found".into()))?;
+    let account = load_account(&pool, &vault, folder.account_id).await?;
+    let handler = crate::email::handler_for(&account, &sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for labels".into()))?;
+
+    let server = handler.fetch_labels(&account, &folder).await
+        .context("Failed to sync labels")?;
+    let mut tx = pool.begin().await?;
+    let updated = labels::apply_gmail_labels(&mut tx, folder_id, &server).await
+        .context("Failed to sync labels")?;
+    tx.commit().await?;
+    Ok(updated)
+}
+
+/// Hands a URL or file to the system's default handler.
+fn shell_open(app: &AppHandle, target: impl Into<String>) -> anyhow::Result<()> {
+    // Superseded by tauri-plugin-opener, which we do not ship yet
+    #[allow(deprecated)]
+    app.shell().open(target, None)?;
+    Ok(())
+}
+
+/// The account, signed in to its OAuth2 provider if it uses one.
+async fn load_account(pool: &DbPool, vault: &Vault, account_id: i64) -> Result<Account, Error> {
+    let mut conn = pool.acquire().await?;
+    crate::email::load_account(&mut conn, vault, account_id).await.map_err(Error::from)
+}
+
+/// Goes through the global proxy, for traffic that belongs to no account.
+async fn global_connector(pool: &DbPool) -> Result<net::Connector, Error> {
+    let mut conn = pool.acquire().await?;
+    let proxy_url = net::global_proxy(&mut conn).await.context("Failed to get proxy")?;
+    let proxy = proxy_url.as_deref().map(net::Proxy::parse).transpose()?;
+    Ok(net::Connector::new(proxy))
+}
+
+async fn load_folder(pool: &DbPool, folder_id: i64) -> Result<Folder, Error> {
+    sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+        .bind(folder_id)
+        .fetch_optional(pool.as_ref())
+        .await?
+        .ok_or_else(|| Error::NotFound("Folder not found".into()))
+}
+
+async fn load_email(pool: &DbPool, email_id: i64) -> Result<Email, Error> {
+    let mut conn = pool.acquire().await?;
+    db::load_email(&mut conn, email_id).await?
+        .ok_or_else(|| Error::NotFound("Email not found".into()))
+}
+
+async fn load_attachment(pool: &DbPool, email_id: i64, attachment_id: &str) -> Result<(Email, Attachment), Error> {
+    let email = load_email(pool, email_id).await?;
+    let attachment = attachments::find(&email, attachment_id).ok_or_else(|| Error::NotFound("Attachment not found".into()))?;
+    Ok((email, attachment))
+}
+
+/// The whole message `email` was parsed from, fetched from the server.
+async fn fetch_message(pool: &DbPool, vault: &Vault, sessions: &Arc<SessionPool>, email: &Email) -> Result<(Account, Vec<u8>), Error> {
+    let uid = email.uid.ok_or_else(|| Error::NotFound("Email is not on the server".into()))?;
+    let folder: Folder = sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+        .bind(email.folder_id)
+        .fetch_one(pool.as_ref())
+        .await?;
+    let account = load_account(pool, vault, email.account_id).await?;
+    let handler = crate::email::handler_for(&account, sessions).ok_or_else(|| Error::Unsupported("Unsupported protocol for fetching messages".into()))?;
+
+    let message = handler.fetch_message(&account, &folder, uid).await
+        .context("Failed to fetch message")?;
+
+    // Every message we see may teach us the sender's Autocrypt key
+    let mut conn = pool.acquire().await?;
+    if let Err(e) = autocrypt::process(&mut conn, account.id, &message).await {
+        tracing::warn!("Failed to process Autocrypt headers of email {}: {}", email.id, e);
+    }
+    Ok((account, message))
+}
+
+async fn load_label(pool: &DbPool, label_id: i64) -> Result<Label, Error> {
+    sqlx::query_as("SELECT * FROM labels WHERE id = ?")
+        .bind(label_id)
+        .fetch_optional(pool.as_ref())
+        .await?
+        .ok_or_else(|| Error::NotFound("Label not found".into()))
+}
+
 // Keep the original greet command for testing
 #[tauri::command]
 pub fn greet(name: &str) -> String {
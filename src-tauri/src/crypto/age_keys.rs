@@ -0,0 +1,181 @@
+//! The age side of the keyring: our identities, whose secrets are sealed by
+//! the vault, and the recipients of people we encrypt to.
+
+use anyhow::{anyhow, Result};
+use sqlx::SqliteConnection;
+
+use super::age::{self, Identity};
+use super::vault::Vault;
+use crate::db::AgeKey;
+
+const COLUMNS: &str = "recipient, name, address, identity IS NOT NULL AS has_identity, created_at, updated_at";
+
+pub async fn list(conn: &mut SqliteConnection) -> Result<Vec<AgeKey>> {
+    let keys = sqlx::query_as(&format!("SELECT {} FROM age_keys ORDER BY has_identity DESC, address, name", COLUMNS))
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(keys)
+}
+
+pub async fn get(conn: &mut SqliteConnection, recipient: &str) -> Result<Option<AgeKey>> {
+    let key = sqlx::query_as(&format!("SELECT {} FROM age_keys WHERE recipient = ?", COLUMNS))
+        .bind(recipient)
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(key)
+}
+
+/// Stores a key. An identity we already have keeps its secret when the
+/// recipient is added again.
+async fn save(
+    conn: &mut SqliteConnection,
+    vault: Option<&Vault>,
+    recipient: &str,
+    name: &str,
+    address: Option<&str>,
+    identity: Option<&Identity>,
+) -> Result<AgeKey> {
+    let (sealed_identity, sealed_passphrase) = match (identity, vault) {
+        (Some(identity), Some(vault)) => (
+            Some(vault.seal(identity.secret.as_bytes())?),
+            identity.passphrase.as_ref().map(|passphrase| vault.seal(passphrase.as_bytes())).transpose()?,
+        ),
+        _ => (None, None),
+    };
+    let address = address.map(|address| address.trim().to_lowercase()).filter(|address| !address.is_empty());
+    sqlx::query(
+        "INSERT INTO age_keys (recipient, name, address, identity, passphrase) VALUES (?, ?, ?, ?, ?) \
+         ON CONFLICT(recipient) DO UPDATE SET name = COALESCE(NULLIF(excluded.name, ''), age_keys.name), address = COALESCE(excluded.address, age_keys.address), \
+         identity = COALESCE(excluded.identity, age_keys.identity), \
+         passphrase = CASE WHEN excluded.identity IS NULL THEN age_keys.passphrase ELSE excluded.passphrase END, \
+         updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(recipient)
+    .bind(name)
+    .bind(address)
+    .bind(sealed_identity)
+    .bind(sealed_passphrase)
+    .execute(&mut *conn)
+    .await?;
+    get(conn, recipient).await?.ok_or_else(|| anyhow!("Key vanished while saving"))
+}
+
+/// Generates an X25519 identity for `address`.
+pub async fn generate(conn: &mut SqliteConnection, vault: &Vault, name: &str, address: Option<&str>) -> Result<AgeKey> {
+    let identity = age::generate();
+    save(conn, Some(vault), &identity.recipient, name, address, Some(&identity)).await
+}
+
+/// Imports an age identity file or an SSH private key. A protected SSH key
+/// keeps `passphrase` with it, under the master password.
+pub async fn import_identity(
+    conn: &mut SqliteConnection,
+    vault: &Vault,
+    text: &str,
+    passphrase: Option<&str>,
+    name: &str,
+    address: Option<&str>,
+) -> Result<Vec<AgeKey>> {
+    let mut imported = Vec::new();
+    for identity in age::read_identities(text, passphrase)? {
+        imported.push(save(conn, Some(vault), &identity.recipient, name, address, Some(&identity)).await?);
+    }
+    Ok(imported)
+}
+
+/// Adds someone's age recipient or SSH public key, to encrypt to.
+pub async fn add_recipient(conn: &mut SqliteConnection, recipient: &str, name: &str, address: Option<&str>) -> Result<AgeKey> {
+    let recipient = age::parse_recipient(recipient)?;
+    save(conn, None, &recipient, name, address, None).await
+}
+
+pub async fn delete(conn: &mut SqliteConnection, recipient: &str) -> Result<()> {
+    sqlx::query("DELETE FROM age_keys WHERE recipient = ?")
+        .bind(recipient)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// The keys for `address`.
+pub async fn lookup(conn: &mut SqliteConnection, address: &str) -> Result<Vec<AgeKey>> {
+    let keys = sqlx::query_as(&format!("SELECT {} FROM age_keys WHERE address = ? ORDER BY created_at", COLUMNS))
+        .bind(address.trim().to_lowercase())
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(keys)
+}
+
+/// Our identities, unsealed, and every (address, recipient) pair, for
+/// `crypto::open` and `crypto::encrypt_age`.
+pub async fn keys(conn: &mut SqliteConnection, vault: &Vault) -> Result<(Vec<Identity>, Vec<(String, String)>)> {
+    #[derive(sqlx::FromRow)]
+    struct Row {
+        recipient: String,
+        address: Option<String>,
+        identity: Option<Vec<u8>>,
+        passphrase: Option<Vec<u8>>,
+    }
+    let rows: Vec<Row> = sqlx::query_as("SELECT recipient, address, identity, passphrase FROM age_keys ORDER BY created_at")
+        .fetch_all(&mut *conn)
+        .await?;
+
+    let unseal = |sealed: &[u8]| -> Result<String> { Ok(String::from_utf8(vault.open(sealed)?)?) };
+    let (mut identities, mut recipients) = (Vec::new(), Vec::new());
+    for row in rows {
+        if let Some(identity) = &row.identity {
+            identities.push(Identity {
+                recipient: row.recipient.clone(),
+                secret: unseal(identity)?,
+                passphrase: row.passphrase.as_deref().map(unseal).transpose()?,
+            });
+        }
+        if let Some(address) = row.address {
+            recipients.push((address, row.recipient));
+        }
+    }
+    Ok((identities, recipients))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, Keys};
+
+    #[tokio::test]
+    async fn test_age_keys() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let vault = Vault::new();
+        assert!(generate(&mut conn, &vault, "Alice", Some("alice@example.com")).await.is_err());
+        vault.unlock(&mut conn, "master").await.unwrap();
+
+        let alice = generate(&mut conn, &vault, "Alice", Some("Alice@Example.com")).await.unwrap();
+        assert!(alice.has_identity);
+        assert_eq!(alice.address.as_deref(), Some("alice@example.com"));
+
+        // Bob hands over his recipient, and later his identity file
+        let bob = age::generate();
+        assert!(add_recipient(&mut conn, "age1nope", "Bob", Some("bob@example.org")).await.is_err());
+        let added = add_recipient(&mut conn, &bob.recipient, "Bob", Some("bob@example.org")).await.unwrap();
+        assert!(!added.has_identity);
+        assert_eq!(lookup(&mut conn, " BOB@example.org").await.unwrap().len(), 1);
+
+        let (identities, recipients) = keys(&mut conn, &vault).await.unwrap();
+        assert_eq!((identities.len(), recipients.len()), (1, 2));
+        let crypto_keys = Keys { age_identities: &identities, age_recipients: &recipients, ..Keys::default() };
+        let sent = crypto::encrypt_age(b"Hi", &["bob@example.org"], true, &crypto_keys).unwrap();
+        assert_eq!(age::decrypt(&sent, std::slice::from_ref(&bob), None).unwrap(), b"Hi");
+        assert!(crypto::encrypt_age(b"Hi", &["carol@example.org"], true, &crypto_keys).is_err());
+
+        let imported = import_identity(&mut conn, &vault, &bob.secret, None, "Bob", None).await.unwrap();
+        assert!(imported[0].has_identity);
+        assert_eq!(imported[0].address.as_deref(), Some("bob@example.org"));
+        let (identities, _) = keys(&mut conn, &vault).await.unwrap();
+        assert!(identities.contains(&bob));
+
+        delete(&mut conn, &bob.recipient).await.unwrap();
+        assert!(lookup(&mut conn, "bob@example.org").await.unwrap().is_empty());
+        assert_eq!(list(&mut conn).await.unwrap().len(), 1);
+    }
+}
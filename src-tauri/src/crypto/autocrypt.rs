@@ -0,0 +1,375 @@
+//! Autocrypt Level 1: learning peers' keys from the `Autocrypt` headers on
+//! their mail and the `Autocrypt-Gossip` headers inside encrypted mail,
+//! advertising our own key, and recommending whether to encrypt.
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Duration, Utc};
+use sqlx::SqliteConnection;
+
+use super::keyring;
+use super::openpgp;
+use super::vault::Vault;
+use crate::db::{Account, AutocryptRecommendation, EncryptRecommendation, PreferEncrypt, RecipientRecommendation};
+use crate::email::mime;
+
+/// A peer's key is stale once we've seen mail from them without it for this
+/// long.
+const STALE_AFTER_DAYS: i64 = 35;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub addr: String, // Lowercased
+    pub prefer_encrypt: PreferEncrypt,
+    pub keydata: Vec<u8>,
+}
+
+/// Parses an `Autocrypt` or `Autocrypt-Gossip` header value. Headers with
+/// unknown critical attributes don't count.
+pub fn parse_header(value: &str) -> Option<Header> {
+    let (mut addr, mut prefer_encrypt, mut keydata) = (None, PreferEncrypt::NoPreference, None);
+    for attribute in value.split(';').filter(|attribute| !attribute.trim().is_empty()) {
+        let (name, value) = attribute.split_once('=')?;
+        let name = name.trim().to_ascii_lowercase();
+        match name.as_str() {
+            "addr" => addr = Some(value.trim().to_lowercase()),
+            "prefer-encrypt" if value.trim().eq_ignore_ascii_case("mutual") => prefer_encrypt = PreferEncrypt::Mutual,
+            "prefer-encrypt" => {}
+            "keydata" => {
+                let value: String = value.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+                keydata = Some(STANDARD.decode(value).ok()?);
+            }
+            // Attributes starting with an underscore are optional
+            _ if name.starts_with('_') => {}
+            _ => return None,
+        }
+    }
+    Some(Header { addr: addr?, prefer_encrypt, keydata: keydata? })
+}
+
+/// Our own header's value, with the key folded over continuation lines.
+pub fn header_value(address: &str, keydata: &[u8], prefer_encrypt: bool) -> String {
+    let mut value = format!("addr={};", address);
+    if prefer_encrypt {
+        value.push_str(" prefer-encrypt=mutual;");
+    }
+    value.push_str(" keydata=");
+    let keydata = STANDARD.encode(keydata);
+    for line in keydata.as_bytes().chunks(76) {
+        value.push_str("\r\n ");
+        value.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+    }
+    value
+}
+
+/// The Autocrypt header to send from `account`, if we have a key for it.
+pub async fn outgoing_header(conn: &mut SqliteConnection, vault: &Vault, account: &Account) -> Result<Option<String>> {
+    let Some(key) = keyring::own_key(conn, vault, &account.email).await? else {
+        return Ok(None);
+    };
+    let keydata = openpgp::autocrypt_key(&key, &account.email)?;
+    Ok(Some(header_value(&account.email.to_lowercase(), &keydata, account.autocrypt_prefer_encrypt)))
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, sqlx::FromRow)]
+struct Peer {
+    last_seen: Option<DateTime<Utc>>,
+    autocrypt_timestamp: Option<DateTime<Utc>>,
+    public_key: Option<Vec<u8>>,
+    prefer_encrypt: PreferEncrypt,
+    gossip_timestamp: Option<DateTime<Utc>>,
+    gossip_key: Option<Vec<u8>>,
+}
+
+async fn load(conn: &mut SqliteConnection, account_id: i64, address: &str) -> Result<Option<Peer>> {
+    let peer = sqlx::query_as(
+        "SELECT last_seen, autocrypt_timestamp, public_key, prefer_encrypt, gossip_timestamp, gossip_key \
+         FROM autocrypt_peers WHERE account_id = ? AND address = ?",
+    )
+    .bind(account_id)
+    .bind(address)
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(peer)
+}
+
+async fn save(conn: &mut SqliteConnection, account_id: i64, address: &str, peer: &Peer) -> Result<()> {
+    sqlx::query(
+        "INSERT OR REPLACE INTO autocrypt_peers \
+         (account_id, address, last_seen, autocrypt_timestamp, public_key, prefer_encrypt, gossip_timestamp, gossip_key) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(account_id)
+    .bind(address)
+    .bind(peer.last_seen)
+    .bind(peer.autocrypt_timestamp)
+    .bind(&peer.public_key)
+    .bind(peer.prefer_encrypt)
+    .bind(peer.gossip_timestamp)
+    .bind(&peer.gossip_key)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// The message's Date, but never in the future.
+fn message_date(headers: &str) -> DateTime<Utc> {
+    let now = Utc::now();
+    mime::header_value(headers, "Date")
+        .and_then(|date| DateTime::parse_from_rfc2822(&date).ok())
+        .map_or(now, |date| date.with_timezone(&Utc).min(now))
+}
+
+/// Applies the Level 1 update rules for a message sent at `date`, with its
+/// Autocrypt header if it had exactly one valid one. False when the message
+/// is older than what we know.
+fn update(peer: &mut Peer, date: DateTime<Utc>, header: Option<&Header>) -> bool {
+    if peer.autocrypt_timestamp.is_some_and(|timestamp| date < timestamp) {
+        return false;
+    }
+    if peer.last_seen.map_or(true, |last_seen| date > last_seen) {
+        peer.last_seen = Some(date);
+    }
+    if let Some(header) = header {
+        peer.autocrypt_timestamp = Some(date);
+        peer.public_key = Some(header.keydata.clone());
+        peer.prefer_encrypt = header.prefer_encrypt;
+    }
+    true
+}
+
+/// Updates the sender's peer state from `message`, a whole message, as it
+/// comes in.
+pub async fn process(conn: &mut SqliteConnection, account_id: i64, message: &[u8]) -> Result<()> {
+    let (headers, _) = mime::split_message(message);
+    let headers = String::from_utf8_lossy(headers);
+    // Bounces and other reports don't speak for the sender
+    let content_type = mime::header_value(&headers, "Content-Type").unwrap_or_default();
+    if mime::essence(&content_type) == "multipart/report" {
+        return Ok(());
+    }
+    let Some(from) = mime::header_value(&headers, "From").and_then(|from| mime::addresses(&from).into_iter().next()) else {
+        return Ok(());
+    };
+    let from = from.to_lowercase();
+
+    let valid: Vec<Header> = mime::header_values(&headers, "Autocrypt")
+        .iter()
+        .filter_map(|value| parse_header(value))
+        .filter(|header| header.addr == from)
+        .collect();
+    let header = match &valid[..] {
+        [header] => Some(header),
+        _ => None,
+    };
+
+    let mut peer = load(conn, account_id, &from).await?.unwrap_or_default();
+    if update(&mut peer, message_date(&headers), header) {
+        save(conn, account_id, &from, &peer).await?;
+    }
+    Ok(())
+}
+
+/// Learns gossiped keys from the headers of a decrypted message's inner
+/// part. Only keys for the message's own recipients are taken.
+pub async fn process_gossip(conn: &mut SqliteConnection, account_id: i64, message: &[u8], inner: &[u8]) -> Result<()> {
+    let (headers, _) = mime::split_message(message);
+    let headers = String::from_utf8_lossy(headers);
+    let (inner_headers, _) = mime::split_message(inner);
+    let inner_headers = String::from_utf8_lossy(inner_headers);
+
+    let recipients: Vec<String> = ["To", "Cc"]
+        .iter()
+        .flat_map(|name| mime::header_values(&headers, name))
+        .flat_map(|value| mime::addresses(&value))
+        .map(|address| address.to_lowercase())
+        .collect();
+    let date = message_date(&headers);
+    for header in mime::header_values(&inner_headers, "Autocrypt-Gossip").iter().filter_map(|value| parse_header(value)) {
+        if !recipients.contains(&header.addr) {
+            continue;
+        }
+        let mut peer = load(conn, account_id, &header.addr).await?.unwrap_or_default();
+        if peer.gossip_timestamp.map_or(true, |timestamp| date > timestamp) {
+            peer.gossip_timestamp = Some(date);
+            peer.gossip_key = Some(header.keydata);
+            save(conn, account_id, &header.addr, &peer).await?;
+        }
+    }
+    Ok(())
+}
+
+/// The key Autocrypt would encrypt to, and whether it was only gossiped.
+fn target_key(peer: &Peer) -> Option<(&[u8], bool)> {
+    match (&peer.public_key, &peer.gossip_key) {
+        (Some(key), _) => Some((key, false)),
+        (None, Some(key)) => Some((key, true)),
+        (None, None) => None,
+    }
+}
+
+/// The preliminary recommendation for one recipient. `usable` says whether
+/// the target key can be encrypted to at all.
+fn preliminary(peer: &Peer, usable: bool, own_prefer_encrypt: bool) -> EncryptRecommendation {
+    let Some((_, gossiped)) = target_key(peer) else {
+        return EncryptRecommendation::Disable;
+    };
+    if !usable {
+        return EncryptRecommendation::Disable;
+    }
+    let stale = match (peer.autocrypt_timestamp, peer.last_seen) {
+        (Some(timestamp), Some(last_seen)) => timestamp < last_seen - Duration::days(STALE_AFTER_DAYS),
+        _ => false,
+    };
+    if gossiped || stale {
+        EncryptRecommendation::Discourage
+    } else if own_prefer_encrypt && peer.prefer_encrypt == PreferEncrypt::Mutual {
+        EncryptRecommendation::Encrypt
+    } else {
+        EncryptRecommendation::Available
+    }
+}
+
+/// The recommendation for the whole message: the weakest of the
+/// recipients', except that replies to encrypted mail stay encrypted.
+fn overall(recipients: &[RecipientRecommendation], reply_to_encrypted: bool) -> EncryptRecommendation {
+    let weakest = recipients
+        .iter()
+        .map(|recipient| recipient.recommendation)
+        .min()
+        .unwrap_or(EncryptRecommendation::Disable);
+    match weakest {
+        EncryptRecommendation::Disable => EncryptRecommendation::Disable,
+        _ if reply_to_encrypted => EncryptRecommendation::Encrypt,
+        weakest => weakest,
+    }
+}
+
+/// Whether to encrypt mail from `account` to `recipients`.
+pub async fn recommend(
+    conn: &mut SqliteConnection,
+    account: &Account,
+    recipients: &[String],
+    reply_to_encrypted: bool,
+) -> Result<AutocryptRecommendation> {
+    let mut recommendations = Vec::new();
+    for address in recipients {
+        let address = address.trim().to_lowercase();
+        let recommendation = match load(conn, account.id, &address).await? {
+            Some(peer) => {
+                let usable = target_key(&peer).is_some_and(|(key, _)| openpgp::can_encrypt(key));
+                preliminary(&peer, usable, account.autocrypt_prefer_encrypt)
+            }
+            None => EncryptRecommendation::Disable,
+        };
+        recommendations.push(RecipientRecommendation { address, recommendation });
+    }
+    Ok(AutocryptRecommendation {
+        recommendation: overall(&recommendations, reply_to_encrypted),
+        recipients: recommendations,
+    })
+}
+
+/// The keys Autocrypt knows for `addresses`, to encrypt to alongside the
+/// keyring's.
+pub async fn peer_keys(conn: &mut SqliteConnection, account_id: i64, addresses: &[&str]) -> Result<Vec<Vec<u8>>> {
+    let mut keys = Vec::new();
+    for address in addresses {
+        if let Some(peer) = load(conn, account_id, &address.to_lowercase()).await? {
+            keys.extend(target_key(&peer).map(|(key, _)| key.to_vec()));
+        }
+    }
+    Ok(keys)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_header() {
+        let header = parse_header("addr=Alice@Example.com; prefer-encrypt=mutual; _extra=1; keydata=\r\n aGVs\r\n bG8=").unwrap();
+        assert_eq!(header, Header { addr: "alice@example.com".to_string(), prefer_encrypt: PreferEncrypt::Mutual, keydata: b"hello".to_vec() });
+        assert_eq!(parse_header("addr=a@b; prefer-encrypt=maybe; keydata=aGVsbG8=").unwrap().prefer_encrypt, PreferEncrypt::NoPreference);
+        assert_eq!(parse_header("addr=a@b; critical=1; keydata=aGVsbG8="), None);
+        assert_eq!(parse_header("addr=a@b"), None);
+        assert_eq!(parse_header("addr=a@b; keydata=!!!"), None);
+
+        let value = header_value("alice@example.com", &[7; 200], true);
+        assert!(value.split("\r\n").all(|line| line.len() <= 78));
+        assert_eq!(parse_header(&value.replace("\r\n", "")).unwrap().keydata, vec![7; 200]);
+    }
+
+    #[test]
+    fn test_recommendation() {
+        let now = Utc::now();
+        let peer = Peer {
+            last_seen: Some(now),
+            autocrypt_timestamp: Some(now),
+            public_key: Some(b"key".to_vec()),
+            prefer_encrypt: PreferEncrypt::Mutual,
+            ..Peer::default()
+        };
+        assert_eq!(preliminary(&peer, true, true), EncryptRecommendation::Encrypt);
+        assert_eq!(preliminary(&peer, true, false), EncryptRecommendation::Available);
+        assert_eq!(preliminary(&peer, false, true), EncryptRecommendation::Disable);
+        assert_eq!(preliminary(&Peer::default(), true, true), EncryptRecommendation::Disable);
+        let stale = Peer { autocrypt_timestamp: Some(now - Duration::days(36)), ..peer.clone() };
+        assert_eq!(preliminary(&stale, true, true), EncryptRecommendation::Discourage);
+        let gossiped = Peer { public_key: None, gossip_key: Some(b"key".to_vec()), ..peer };
+        assert_eq!(preliminary(&gossiped, true, true), EncryptRecommendation::Discourage);
+
+        let recipient = |recommendation| RecipientRecommendation { address: String::new(), recommendation };
+        use EncryptRecommendation::*;
+        assert_eq!(overall(&[recipient(Encrypt), recipient(Encrypt)], false), Encrypt);
+        assert_eq!(overall(&[recipient(Encrypt), recipient(Available)], false), Available);
+        assert_eq!(overall(&[recipient(Discourage), recipient(Available)], true), Encrypt);
+        assert_eq!(overall(&[recipient(Encrypt), recipient(Disable)], true), Disable);
+        assert_eq!(overall(&[], false), Disable);
+    }
+
+    #[tokio::test]
+    async fn test_process() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        sqlx::query("UPDATE accounts SET name = 'Bob', email = 'bob@example.org' WHERE id = 1")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+
+        let message = |date: &str, autocrypt: &str| {
+            format!("From: Alice <Alice@example.com>\r\nTo: bob@example.org\r\nDate: {}\r\n{}Subject: Hi\r\n\r\nHello", date, autocrypt)
+        };
+        let with_key = "Autocrypt: addr=alice@example.com; prefer-encrypt=mutual; keydata=a2V5MQ==\r\n";
+        process(&mut conn, 1, message("Mon, 2 Jan 2023 10:00:00 +0000", with_key).as_bytes()).await.unwrap();
+        let peer = load(&mut conn, 1, "alice@example.com").await.unwrap().unwrap();
+        assert_eq!((peer.public_key.as_deref(), peer.prefer_encrypt), (Some(&b"key1"[..]), PreferEncrypt::Mutual));
+        assert_eq!(peer.last_seen, peer.autocrypt_timestamp);
+
+        // An older message with another key changes nothing; a newer one
+        // without a header only moves last_seen
+        let older = "Autocrypt: addr=alice@example.com; keydata=a2V5Mg==\r\n";
+        process(&mut conn, 1, message("Sun, 1 Jan 2023 10:00:00 +0000", older).as_bytes()).await.unwrap();
+        process(&mut conn, 1, message("Fri, 3 Mar 2023 10:00:00 +0000", "").as_bytes()).await.unwrap();
+        let later = load(&mut conn, 1, "alice@example.com").await.unwrap().unwrap();
+        assert_eq!((later.public_key, later.autocrypt_timestamp), (peer.public_key, peer.autocrypt_timestamp));
+        assert!(later.last_seen > peer.last_seen);
+
+        // Two headers, or one for somebody else, count as none
+        let twice = format!("{}Autocrypt: addr=alice@example.com; keydata=a2V5Mw==\r\n", with_key);
+        process(&mut conn, 1, message("Sat, 4 Mar 2023 10:00:00 +0000", &twice).as_bytes()).await.unwrap();
+        let forged = "Autocrypt: addr=mallory@example.com; keydata=a2V5Mw==\r\n";
+        process(&mut conn, 1, message("Sun, 5 Mar 2023 10:00:00 +0000", forged).as_bytes()).await.unwrap();
+        assert_eq!(load(&mut conn, 1, "alice@example.com").await.unwrap().unwrap().public_key.as_deref(), Some(&b"key1"[..]));
+        assert_eq!(load(&mut conn, 1, "mallory@example.com").await.unwrap(), None);
+
+        let inner = b"Autocrypt-Gossip: addr=bob@example.org; keydata=Z29zc2lw\r\n\
+            Autocrypt-Gossip: addr=eve@example.net; keydata=Z29zc2lw\r\nContent-Type: text/plain\r\n\r\nHi";
+        process_gossip(&mut conn, 1, message("Mon, 6 Mar 2023 10:00:00 +0000", "").as_bytes(), inner).await.unwrap();
+        let bob = load(&mut conn, 1, "bob@example.org").await.unwrap().unwrap();
+        assert_eq!((bob.gossip_key.as_deref(), bob.public_key), (Some(&b"gossip"[..]), None));
+        assert_eq!(load(&mut conn, 1, "eve@example.net").await.unwrap(), None);
+        assert_eq!(peer_keys(&mut conn, 1, &["Alice@example.com", "bob@example.org", "carol@example.net"]).await.unwrap(), vec![b"key1".to_vec(), b"gossip".to_vec()]);
+    }
+}
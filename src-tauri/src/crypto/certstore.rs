@@ -0,0 +1,216 @@
+//! The S/MIME certificate store: our identities, the trust anchors
+//! signatures must chain to, and certificates collected from signed mail.
+//! Certificates are kept in the clear; private keys are sealed by the vault.
+
+use anyhow::{anyhow, Result};
+use sqlx::SqliteConnection;
+
+use super::smime::{self, Identity, X509Data};
+use super::vault::Vault;
+use crate::db::SmimeCertificate;
+
+const COLUMNS: &str = "fingerprint, subject, issuer, \
+    (SELECT json_group_array(address) FROM smime_certificate_addresses a WHERE a.fingerprint = smime_certificates.fingerprint) AS addresses, \
+    not_before, not_after, is_ca, trusted, private_key IS NOT NULL AS has_private_key, created_at, updated_at";
+
+/// Certificates that are valid now.
+const CURRENT: &str = "datetime(not_before) <= datetime('now') AND datetime(not_after) > datetime('now')";
+
+pub async fn list(conn: &mut SqliteConnection) -> Result<Vec<SmimeCertificate>> {
+    let certificates = sqlx::query_as(&format!(
+        "SELECT {} FROM smime_certificates ORDER BY has_private_key DESC, trusted DESC, subject",
+        COLUMNS
+    ))
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(certificates)
+}
+
+pub async fn get(conn: &mut SqliteConnection, fingerprint: &str) -> Result<Option<SmimeCertificate>> {
+    let certificate = sqlx::query_as(&format!("SELECT {} FROM smime_certificates WHERE fingerprint = ?", COLUMNS))
+        .bind(fingerprint.to_ascii_uppercase())
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(certificate)
+}
+
+/// Stores `certificate`. One we already have keeps its private key unless
+/// given another, and only gains trust here.
+async fn save(conn: &mut SqliteConnection, certificate: &X509Data, sealed_key: Option<Vec<u8>>, trusted: bool) -> Result<SmimeCertificate> {
+    sqlx::query(
+        "INSERT INTO smime_certificates (fingerprint, subject, issuer, not_before, not_after, is_ca, trusted, certificate, private_key) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT(fingerprint) DO UPDATE SET trusted = smime_certificates.trusted OR excluded.trusted, \
+         private_key = COALESCE(excluded.private_key, smime_certificates.private_key), updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(&certificate.fingerprint)
+    .bind(&certificate.subject)
+    .bind(&certificate.issuer)
+    .bind(certificate.not_before)
+    .bind(certificate.not_after)
+    .bind(certificate.is_ca)
+    .bind(trusted)
+    .bind(&certificate.data)
+    .bind(sealed_key)
+    .execute(&mut *conn)
+    .await?;
+
+    for address in &certificate.addresses {
+        sqlx::query("INSERT OR IGNORE INTO smime_certificate_addresses (fingerprint, address) VALUES (?, ?)")
+            .bind(&certificate.fingerprint)
+            .bind(address)
+            .execute(&mut *conn)
+            .await?;
+    }
+    get(conn, &certificate.fingerprint).await?.ok_or_else(|| anyhow!("Certificate vanished while saving"))
+}
+
+/// Imports the identity in a PKCS#12 file, along with the certificates
+/// that came with it. None of them become trust anchors.
+pub async fn import_identity(conn: &mut SqliteConnection, vault: &Vault, data: &[u8], password: &str) -> Result<SmimeCertificate> {
+    let (identity, others) = smime::read_pkcs12(data, password)?;
+    let sealed_key = vault.seal(&identity.private_key)?;
+    for der in &others {
+        save(conn, &smime::x509_data(der)?, None, false).await?;
+    }
+    save(conn, &smime::x509_data(&identity.certificate)?, Some(sealed_key), false).await
+}
+
+/// Imports PEM or DER certificates or a PKCS#7 bundle, as trust anchors
+/// when `trusted`.
+pub async fn import(conn: &mut SqliteConnection, data: &[u8], trusted: bool) -> Result<Vec<SmimeCertificate>> {
+    let mut imported = Vec::new();
+    for certificate in smime::read_certificates(data)? {
+        imported.push(save(conn, &certificate, None, trusted).await?);
+    }
+    Ok(imported)
+}
+
+/// Keeps the certificates signed mail carried, for encrypting to their
+/// owners and building paths with. Ones we can't parse are skipped.
+pub async fn collect(conn: &mut SqliteConnection, certificates: &[Vec<u8>]) -> Result<()> {
+    for der in certificates {
+        if let Ok(certificate) = smime::x509_data(der) {
+            save(conn, &certificate, None, false).await?;
+        }
+    }
+    Ok(())
+}
+
+pub async fn set_trusted(conn: &mut SqliteConnection, fingerprint: &str, trusted: bool) -> Result<SmimeCertificate> {
+    sqlx::query("UPDATE smime_certificates SET trusted = ?, updated_at = CURRENT_TIMESTAMP WHERE fingerprint = ?")
+        .bind(trusted)
+        .bind(fingerprint.to_ascii_uppercase())
+        .execute(&mut *conn)
+        .await?;
+    get(conn, fingerprint).await?.ok_or_else(|| anyhow!("No certificate {}", fingerprint))
+}
+
+pub async fn delete(conn: &mut SqliteConnection, fingerprint: &str) -> Result<()> {
+    sqlx::query("DELETE FROM smime_certificate_addresses WHERE fingerprint = ?")
+        .bind(fingerprint.to_ascii_uppercase())
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM smime_certificates WHERE fingerprint = ?")
+        .bind(fingerprint.to_ascii_uppercase())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Current certificates for `address`, the longest-lived first.
+pub async fn lookup(conn: &mut SqliteConnection, address: &str) -> Result<Vec<SmimeCertificate>> {
+    let certificates = sqlx::query_as(&format!(
+        "SELECT {} FROM smime_certificates \
+         WHERE fingerprint IN (SELECT fingerprint FROM smime_certificate_addresses WHERE address = ?) AND {} \
+         ORDER BY datetime(not_after) DESC",
+        COLUMNS, CURRENT
+    ))
+    .bind(address.trim().to_lowercase())
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(certificates)
+}
+
+/// Identities, certificates and trust anchors for `crypto::open` and
+/// `crypto::protect_smime`. Expired identities are kept for old mail, but
+/// current ones for `address` come first, to sign with.
+pub async fn keys(
+    conn: &mut SqliteConnection,
+    vault: &Vault,
+    address: Option<&str>,
+) -> Result<(Vec<Identity>, Vec<Vec<u8>>, Vec<Vec<u8>>)> {
+    let rows: Vec<(Vec<u8>, Option<Vec<u8>>, bool)> = sqlx::query_as(&format!(
+        "SELECT certificate, private_key, trusted FROM smime_certificates \
+         ORDER BY fingerprint IN (SELECT fingerprint FROM smime_certificate_addresses WHERE address = ?) DESC, \
+         {} DESC, datetime(not_after) DESC",
+        CURRENT
+    ))
+    .bind(address.unwrap_or_default().trim().to_lowercase())
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let (mut identities, mut certificates, mut anchors) = (Vec::new(), Vec::new(), Vec::new());
+    for (certificate, sealed_key, trusted) in rows {
+        if let Some(sealed_key) = sealed_key {
+            identities.push(Identity { certificate: certificate.clone(), private_key: vault.open(&sealed_key)? });
+        }
+        if trusted {
+            anchors.push(certificate.clone());
+        }
+        certificates.push(certificate);
+    }
+    Ok((identities, certificates, anchors))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, smime::tests::{ca, identity}, Keys};
+    use crate::db::SignatureStatus;
+    use der::EncodePem;
+
+    #[tokio::test]
+    async fn test_certstore() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let vault = Vault::new();
+        vault.unlock(&mut conn, "master").await.unwrap();
+
+        let ca = ca();
+        let pem = ca.0.to_pem(der::pem::LineEnding::LF).unwrap();
+        let imported = import(&mut conn, pem.as_bytes(), true).await.unwrap();
+        assert!(imported[0].is_ca && imported[0].trusted);
+
+        // Alice's identity, and Bob's certificate collected from his mail, twice
+        let alice = identity("alice@example.org", &ca);
+        let sealed_key = vault.seal(&alice.private_key).unwrap();
+        let saved = save(&mut conn, &smime::x509_data(&alice.certificate).unwrap(), Some(sealed_key), false).await.unwrap();
+        assert!(saved.has_private_key);
+        assert_eq!(saved.addresses, r#"["alice@example.org"]"#);
+        let bob = identity("bob@example.org", &ca);
+        collect(&mut conn, &[bob.certificate.clone(), b"junk".to_vec()]).await.unwrap();
+        collect(&mut conn, std::slice::from_ref(&bob.certificate)).await.unwrap();
+        assert_eq!(list(&mut conn).await.unwrap().len(), 3);
+        let found = lookup(&mut conn, " Bob@Example.org").await.unwrap();
+        assert_eq!((found.len(), found[0].trusted), (1, false));
+
+        let (identities, certificates, anchors) = keys(&mut conn, &vault, Some("alice@example.org")).await.unwrap();
+        assert_eq!((identities, certificates.len(), anchors.len()), (vec![alice], 3, 1));
+        let (identities, certificates, anchors) = keys(&mut conn, &vault, None).await.unwrap();
+        let sent = crypto::protect_smime(b"Content-Type: text/plain\r\n\r\nHi", true, true, &["bob@example.org"],
+            &Keys { identities: &identities, x509_certificates: &certificates, trust_anchors: &anchors, ..Keys::default() }).unwrap();
+        let bob_identities = [bob.clone()];
+        let bob_keys = Keys { identities: &bob_identities, trust_anchors: &anchors, ..Keys::default() };
+        let opened = crypto::open(&sent, "alice@example.org", &bob_keys).unwrap().unwrap();
+        assert!(opened.encrypted);
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Good);
+        assert_eq!(opened.signatures[0].signer.as_deref(), Some("alice@example.org"));
+
+        let fingerprint = &imported[0].fingerprint;
+        assert!(!set_trusted(&mut conn, &fingerprint.to_lowercase(), false).await.unwrap().trusted);
+        assert!(keys(&mut conn, &vault, None).await.unwrap().2.is_empty());
+        delete(&mut conn, &found[0].fingerprint).await.unwrap();
+        assert!(lookup(&mut conn, "bob@example.org").await.unwrap().is_empty());
+    }
+}
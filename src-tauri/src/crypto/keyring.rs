@@ -0,0 +1,232 @@
+//! The local OpenPGP keyring. Certificates live in `pgp_keys` with their key
+//! material sealed by the vault; secret keys are stored unprotected inside
+//! the seal, so the master password is what guards them.
+
+use anyhow::{anyhow, Result};
+use sqlx::SqliteConnection;
+
+use super::openpgp;
+use super::vault::Vault;
+use super::CertData;
+use crate::db::{KeyTrust, PgpKey};
+
+const COLUMNS: &str = "fingerprint, user_ids, has_secret, trust, key_created_at, expires_at, revoked, created_at, updated_at";
+
+/// Keys we may encrypt to or believe: not revoked, expired or distrusted.
+const USABLE: &str = "NOT revoked AND trust != 'NEVER' AND (expires_at IS NULL OR datetime(expires_at) > datetime('now'))";
+
+const PREFERRED: &str = "CASE trust WHEN 'ULTIMATE' THEN 0 WHEN 'FULL' THEN 1 WHEN 'MARGINAL' THEN 2 ELSE 3 END, key_created_at DESC";
+
+pub async fn list(conn: &mut SqliteConnection) -> Result<Vec<PgpKey>> {
+    let keys = sqlx::query_as(&format!("SELECT {} FROM pgp_keys ORDER BY has_secret DESC, user_ids", COLUMNS))
+        .fetch_all(&mut *conn)
+        .await?;
+    Ok(keys)
+}
+
+pub async fn get(conn: &mut SqliteConnection, fingerprint: &str) -> Result<Option<PgpKey>> {
+    let key = sqlx::query_as(&format!("SELECT {} FROM pgp_keys WHERE fingerprint = ?", COLUMNS))
+        .bind(fingerprint.to_ascii_uppercase())
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(key)
+}
+
+async fn key_data(conn: &mut SqliteConnection, vault: &Vault, fingerprint: &str) -> Result<Vec<u8>> {
+    let sealed: Vec<u8> = sqlx::query_scalar("SELECT key_data FROM pgp_keys WHERE fingerprint = ?")
+        .bind(fingerprint.to_ascii_uppercase())
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| anyhow!("No key {}", fingerprint))?;
+    vault.open(&sealed)
+}
+
+/// Stores `cert`, replacing what we had for it. New keys get `trust`;
+/// known ones keep theirs.
+async fn save(conn: &mut SqliteConnection, vault: &Vault, cert: &CertData, trust: KeyTrust) -> Result<PgpKey> {
+    sqlx::query(
+        "INSERT INTO pgp_keys (fingerprint, user_ids, has_secret, trust, key_created_at, expires_at, revoked, key_data) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT(fingerprint) DO UPDATE SET user_ids = excluded.user_ids, has_secret = excluded.has_secret, \
+         expires_at = excluded.expires_at, revoked = excluded.revoked, key_data = excluded.key_data, \
+         updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(&cert.fingerprint)
+    .bind(serde_json::to_string(&cert.user_ids)?)
+    .bind(cert.has_secret)
+    .bind(trust)
+    .bind(cert.created_at)
+    .bind(cert.expires_at)
+    .bind(cert.revoked)
+    .bind(vault.seal(&cert.data)?)
+    .execute(&mut *conn)
+    .await?;
+
+    sqlx::query("DELETE FROM pgp_key_addresses WHERE fingerprint = ?")
+        .bind(&cert.fingerprint)
+        .execute(&mut *conn)
+        .await?;
+    for address in &cert.addresses {
+        sqlx::query("INSERT OR IGNORE INTO pgp_key_addresses (fingerprint, address) VALUES (?, ?)")
+            .bind(&cert.fingerprint)
+            .bind(address.to_lowercase())
+            .execute(&mut *conn)
+            .await?;
+    }
+    get(conn, &cert.fingerprint).await?.ok_or_else(|| anyhow!("Key vanished while saving"))
+}
+
+/// Imports every certificate in `armored`, a key or keyring, merging with
+/// keys we already have. Protected secret keys need their `passphrase`.
+pub async fn import(conn: &mut SqliteConnection, vault: &Vault, armored: &[u8], passphrase: Option<&str>) -> Result<Vec<PgpKey>> {
+    let mut imported = Vec::new();
+    for cert in openpgp::read_certs(armored, passphrase)? {
+        let cert = match get(conn, &cert.fingerprint).await? {
+            Some(_) => openpgp::merge(&key_data(conn, vault, &cert.fingerprint).await?, &cert.data)?,
+            None => cert,
+        };
+        let trust = if cert.has_secret { KeyTrust::Ultimate } else { KeyTrust::Unknown };
+        imported.push(save(conn, vault, &cert, trust).await?);
+    }
+    Ok(imported)
+}
+
+/// Generates a key pair for `user_id`, e.g. "Alice <alice@example.com>".
+pub async fn generate(conn: &mut SqliteConnection, vault: &Vault, user_id: &str) -> Result<PgpKey> {
+    // Fail before the slow part if we couldn't store the result
+    vault.seal(&[])?;
+    let cert = openpgp::generate(user_id)?;
+    save(conn, vault, &cert, KeyTrust::Ultimate).await
+}
+
+pub async fn set_trust(conn: &mut SqliteConnection, fingerprint: &str, trust: KeyTrust) -> Result<PgpKey> {
+    sqlx::query("UPDATE pgp_keys SET trust = ?, updated_at = CURRENT_TIMESTAMP WHERE fingerprint = ?")
+        .bind(trust)
+        .bind(fingerprint.to_ascii_uppercase())
+        .execute(&mut *conn)
+        .await?;
+    get(conn, fingerprint).await?.ok_or_else(|| anyhow!("No key {}", fingerprint))
+}
+
+/// Revokes one of our own keys. Export it afterwards to tell others.
+pub async fn revoke(conn: &mut SqliteConnection, vault: &Vault, fingerprint: &str, reason: &str) -> Result<PgpKey> {
+    let cert = openpgp::revoke(&key_data(conn, vault, fingerprint).await?, reason)?;
+    save(conn, vault, &cert, KeyTrust::Ultimate).await
+}
+
+/// The armored keys. Secret keys are included, protected by `passphrase`,
+/// only when one is given.
+pub async fn export(conn: &mut SqliteConnection, vault: &Vault, fingerprints: &[String], passphrase: Option<&str>) -> Result<String> {
+    let mut armored = String::new();
+    for fingerprint in fingerprints {
+        let data = openpgp::export(&key_data(conn, vault, fingerprint).await?, passphrase)?;
+        armored.push_str(&String::from_utf8(data)?);
+    }
+    Ok(armored)
+}
+
+pub async fn delete(conn: &mut SqliteConnection, fingerprint: &str) -> Result<()> {
+    sqlx::query("DELETE FROM pgp_key_addresses WHERE fingerprint = ?")
+        .bind(fingerprint.to_ascii_uppercase())
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM pgp_keys WHERE fingerprint = ?")
+        .bind(fingerprint.to_ascii_uppercase())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Usable keys for `address`, most trusted and then newest first.
+pub async fn lookup(conn: &mut SqliteConnection, address: &str) -> Result<Vec<PgpKey>> {
+    let keys = sqlx::query_as(&format!(
+        "SELECT {} FROM pgp_keys \
+         WHERE fingerprint IN (SELECT fingerprint FROM pgp_key_addresses WHERE address = ?) AND {} \
+         ORDER BY {}",
+        COLUMNS, USABLE, PREFERRED
+    ))
+    .bind(address.trim().to_lowercase())
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(keys)
+}
+
+/// Our best usable key for `address`, with its secret keys.
+pub async fn own_key(conn: &mut SqliteConnection, vault: &Vault, address: &str) -> Result<Option<Vec<u8>>> {
+    match lookup(conn, address).await?.into_iter().find(|key| key.has_secret) {
+        Some(key) => Ok(Some(key_data(conn, vault, &key.fingerprint).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Certificates and secret keys for `crypto::open` and `crypto::protect`,
+/// leaving out keys that aren't usable.
+pub async fn keys(conn: &mut SqliteConnection, vault: &Vault) -> Result<(Vec<Vec<u8>>, Vec<Vec<u8>>)> {
+    let rows: Vec<(bool, Vec<u8>)> = sqlx::query_as(&format!(
+        "SELECT has_secret, key_data FROM pgp_keys WHERE {} ORDER BY {}",
+        USABLE, PREFERRED
+    ))
+    .fetch_all(&mut *conn)
+    .await?;
+    let (mut certificates, mut secret_keys) = (Vec::new(), Vec::new());
+    for (has_secret, sealed) in rows {
+        let data = vault.open(&sealed)?;
+        if has_secret {
+            secret_keys.push(data);
+        } else {
+            certificates.push(data);
+        }
+    }
+    Ok((certificates, secret_keys))
+}
+
+#[cfg(all(test, feature = "openpgp"))]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, Keys};
+
+    #[tokio::test]
+    async fn test_keyring() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let vault = Vault::new();
+        assert!(generate(&mut conn, &vault, "Alice <alice@example.com>").await.is_err());
+        vault.unlock(&mut conn, "master").await.unwrap();
+
+        let alice = generate(&mut conn, &vault, "Alice <alice@example.com>").await.unwrap();
+        assert!(alice.has_secret);
+        assert_eq!(alice.trust, KeyTrust::Ultimate);
+        assert_eq!(alice.user_ids, r#"["Alice <alice@example.com>"]"#);
+
+        // Bob's key arrives from elsewhere, with a passphrase on its secret
+        let bob = crypto::openpgp::generate("Bob <bob@example.org>").unwrap();
+        let bob_public = String::from_utf8(crypto::openpgp::export(&bob.data, None).unwrap()).unwrap();
+        let bob_secret = crypto::openpgp::export(&bob.data, Some("bob")).unwrap();
+        assert!(import(&mut conn, &vault, &bob_secret, None).await.is_err());
+        assert!(import(&mut conn, &vault, &bob_secret, Some("wrong")).await.is_err());
+        let imported = import(&mut conn, &vault, bob_public.as_bytes(), None).await.unwrap();
+        assert_eq!((imported.len(), imported[0].has_secret), (1, false));
+        assert_eq!(lookup(&mut conn, " Bob@Example.org").await.unwrap().len(), 1);
+
+        // Mail Alice encrypts to Bob opens with Bob's key
+        let (certificates, secret_keys) = keys(&mut conn, &vault).await.unwrap();
+        let sent = crypto::protect(b"Content-Type: text/plain\r\n\r\nHi", true, true, &["bob@example.org"],
+            &Keys { certificates: &certificates, secret_keys: &secret_keys, ..Keys::default() }).unwrap();
+        let bob_keys = [bob.data.clone()];
+        let opened = crypto::open(&sent, "alice@example.com", &Keys { secret_keys: &bob_keys, ..Keys::default() }).unwrap().unwrap();
+        assert!(opened.encrypted);
+
+        set_trust(&mut conn, &bob.fingerprint, KeyTrust::Never).await.unwrap();
+        assert!(lookup(&mut conn, "bob@example.org").await.unwrap().is_empty());
+        assert_eq!(keys(&mut conn, &vault).await.unwrap().0.len(), 0);
+
+        let revoked = revoke(&mut conn, &vault, &alice.fingerprint, "Lost my laptop").await.unwrap();
+        assert!(revoked.revoked);
+        assert!(lookup(&mut conn, "alice@example.com").await.unwrap().is_empty());
+        let exported = export(&mut conn, &vault, std::slice::from_ref(&alice.fingerprint), None).await.unwrap();
+        assert!(exported.starts_with("-----BEGIN PGP PUBLIC KEY BLOCK-----"));
+
+        delete(&mut conn, &bob.fingerprint).await.unwrap();
+        assert_eq!(list(&mut conn).await.unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,595 @@
+//! End-to-end encryption: opening PGP/MIME (RFC 3156), inline PGP, S/MIME
+//! and age messages for display, and signing or encrypting outgoing ones.
+//!
+//! The OpenPGP work itself is done by Sequoia behind the `openpgp` feature;
+//! without it everything here still parses, but opening or protecting a
+//! PGP message fails.
+
+pub mod age;
+pub mod age_keys;
+pub mod autocrypt;
+pub mod certstore;
+pub mod keyring;
+pub mod smime;
+pub mod vault;
+
+#[cfg(feature = "openpgp")]
+mod openpgp;
+#[cfg(not(feature = "openpgp"))]
+#[path = "openpgp_disabled.rs"]
+mod openpgp;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use sqlx::SqliteConnection;
+
+use crate::db::{ComposeEmail, SignatureInfo};
+use crate::email::mime;
+use vault::Vault;
+
+/// How deeply signed and encrypted entities may nest before we stop
+/// unwrapping them.
+const MAX_LAYERS: usize = 8;
+
+/// Certificates and secret keys to work with. OpenPGP ones are binary or
+/// ASCII-armored, and each entry may hold a whole keyring; X.509 ones are
+/// DER.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Keys<'a> {
+    /// Certificates for verifying signatures and encrypting to.
+    pub certificates: &'a [Vec<u8>],
+    /// Our own keys, for decrypting and signing.
+    pub secret_keys: &'a [Vec<u8>],
+    /// Unlocks protected secret keys, or an age message encrypted with a
+    /// passphrase.
+    pub passphrase: Option<&'a str>,
+    /// Our S/MIME identities, the first one signing.
+    pub identities: &'a [smime::Identity],
+    /// X.509 certificates for encrypting to and building paths with.
+    pub x509_certificates: &'a [Vec<u8>],
+    /// The X.509 certificates S/MIME signatures must chain to.
+    pub trust_anchors: &'a [Vec<u8>],
+    /// Our age identities.
+    pub age_identities: &'a [age::Identity],
+    /// (address, recipient) pairs for encrypting with age.
+    pub age_recipients: &'a [(String, String)],
+}
+
+/// Everything `Keys` borrows, loaded out of the keyring, the certificate
+/// store and the age keys.
+#[derive(Default)]
+pub struct StoredKeys {
+    certificates: Vec<Vec<u8>>,
+    secret_keys: Vec<Vec<u8>>,
+    identities: Vec<smime::Identity>,
+    x509_certificates: Vec<Vec<u8>>,
+    trust_anchors: Vec<Vec<u8>>,
+    age_identities: Vec<age::Identity>,
+    age_recipients: Vec<(String, String)>,
+}
+
+impl StoredKeys {
+    /// Opens the secret keys with `vault`, which must be unlocked. The
+    /// S/MIME identity for `address` comes first, so it is the one signing.
+    pub async fn load(conn: &mut SqliteConnection, vault: &Vault, address: Option<&str>) -> Result<Self> {
+        let (certificates, secret_keys) = keyring::keys(conn, vault).await.context("Failed to load keys")?;
+        let (identities, x509_certificates, trust_anchors) = certstore::keys(conn, vault, address).await
+            .context("Failed to load certificates")?;
+        let (age_identities, age_recipients) = age_keys::keys(conn, vault).await.context("Failed to load age keys")?;
+        Ok(Self { certificates, secret_keys, identities, x509_certificates, trust_anchors, age_identities, age_recipients })
+    }
+
+    pub fn keys<'a>(&'a self, passphrase: Option<&'a str>) -> Keys<'a> {
+        Keys {
+            certificates: &self.certificates,
+            secret_keys: &self.secret_keys,
+            passphrase,
+            identities: &self.identities,
+            x509_certificates: &self.x509_certificates,
+            trust_anchors: &self.trust_anchors,
+            age_identities: &self.age_identities,
+            age_recipients: &self.age_recipients,
+        }
+    }
+}
+
+/// What was inside an encrypted or signed message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpenedMessage {
+    /// A MIME entity when `is_mime`, otherwise the plain text of an inline
+    /// PGP or age message.
+    pub content: Vec<u8>,
+    pub is_mime: bool,
+    pub encrypted: bool,
+    pub signatures: Vec<SignatureInfo>,
+    /// X.509 certificates carried by S/MIME signatures, worth keeping.
+    pub certificates: Vec<Vec<u8>>,
+}
+
+/// One certificate out of a keyring, with what the keyring lists about it.
+#[derive(Debug, Clone)]
+pub struct CertData {
+    pub fingerprint: String,
+    pub user_ids: Vec<String>,
+    pub addresses: Vec<String>, // Lowercased, from the user IDs
+    pub has_secret: bool,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked: bool,
+    /// The binary certificate, with any secret keys unencrypted.
+    pub data: Vec<u8>,
+}
+
+const ENCRYPTED_PROTOCOL: &str = "application/pgp-encrypted";
+const SIGNATURE_PROTOCOL: &str = "application/pgp-signature";
+const SMIME_SIGNATURE_PROTOCOLS: [&str; 2] = ["application/pkcs7-signature", "application/x-pkcs7-signature"];
+
+/// How a message is protected, if it is.
+#[derive(Debug, PartialEq, Eq)]
+enum Protection<'a> {
+    /// multipart/encrypted: the armored OpenPGP message.
+    Encrypted(&'a [u8]),
+    /// multipart/signed: the signed entity and its detached signature.
+    Signed(&'a [u8], &'a [u8]),
+    /// An armored PGP message or cleartext-signed message in a text body.
+    Inline(Vec<u8>),
+    /// application/pkcs7-mime: CMS enveloped or signed data.
+    Pkcs7(Vec<u8>),
+    /// multipart/signed with a CMS signature: the signed entity and the
+    /// signature.
+    Pkcs7Signed(&'a [u8], Vec<u8>),
+    /// An armored age file in a text body.
+    Age(Vec<u8>),
+}
+
+/// The protected part of `message`, a whole message or MIME entity.
+fn protection(message: &[u8]) -> Result<Option<Protection<'_>>> {
+    let (headers, body) = mime::split_message(message);
+    let headers = String::from_utf8_lossy(headers);
+    let content_type = mime::header_value(&headers, "Content-Type").unwrap_or_else(|| "text/plain".to_string());
+    let protocol = mime::parameter(&content_type, "protocol").unwrap_or_default().to_ascii_lowercase();
+    let parts = || match mime::parameter(&content_type, "boundary") {
+        Some(boundary) => mime::multipart_parts(body, &boundary),
+        None => Vec::new(),
+    };
+
+    match mime::essence(&content_type).as_str() {
+        "multipart/encrypted" if protocol == ENCRYPTED_PROTOCOL => {
+            let [_, encrypted] = parts()[..] else {
+                bail!("Malformed PGP/MIME encrypted message");
+            };
+            Ok(Some(Protection::Encrypted(mime::split_message(encrypted).1)))
+        }
+        "multipart/signed" if protocol == SIGNATURE_PROTOCOL => {
+            let [signed, signature] = parts()[..] else {
+                bail!("Malformed PGP/MIME signed message");
+            };
+            Ok(Some(Protection::Signed(signed, mime::split_message(signature).1)))
+        }
+        "multipart/signed" if SMIME_SIGNATURE_PROTOCOLS.contains(&protocol.as_str()) => {
+            let [signed, signature] = parts()[..] else {
+                bail!("Malformed S/MIME signed message");
+            };
+            let (signature_headers, signature) = mime::split_message(signature);
+            let encoding = mime::header_value(&String::from_utf8_lossy(signature_headers), "Content-Transfer-Encoding");
+            Ok(Some(Protection::Pkcs7Signed(signed, mime::decode_transfer(encoding.as_deref(), signature)?)))
+        }
+        "application/pkcs7-mime" | "application/x-pkcs7-mime" => {
+            let smime_type = mime::parameter(&content_type, "smime-type").unwrap_or_default();
+            if smime_type.eq_ignore_ascii_case("certs-only") {
+                return Ok(None);
+            }
+            let encoding = mime::header_value(&headers, "Content-Transfer-Encoding");
+            Ok(Some(Protection::Pkcs7(mime::decode_transfer(encoding.as_deref(), body)?)))
+        }
+        "text/plain" => {
+            let encoding = mime::header_value(&headers, "Content-Transfer-Encoding");
+            let text = mime::decode_transfer(encoding.as_deref(), body)?;
+            if let Some(block) = armored_block(&text) {
+                return Ok(Some(Protection::Inline(block.to_vec())));
+            }
+            Ok(age::armored_block(&text).map(|block| Protection::Age(block.to_vec())))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The first armored PGP message or cleartext-signed message in `text`,
+/// from its BEGIN line through the END line that closes it.
+fn armored_block(text: &[u8]) -> Option<&[u8]> {
+    const BLOCKS: [(&[u8], &[u8]); 2] = [
+        (b"-----BEGIN PGP MESSAGE-----", b"-----END PGP MESSAGE-----"),
+        (b"-----BEGIN PGP SIGNED MESSAGE-----", b"-----END PGP SIGNATURE-----"),
+    ];
+    let find = |haystack: &[u8], needle: &[u8]| haystack.windows(needle.len()).position(|window| window == needle);
+    BLOCKS
+        .iter()
+        .filter_map(|(begin, end)| {
+            let start = find(text, begin)?;
+            let at_line_start = start == 0 || text[start - 1] == b'\n';
+            let stop = start + find(&text[start..], end)? + end.len();
+            at_line_start.then_some((start, stop))
+        })
+        .min()
+        .map(|(start, stop)| &text[start..stop])
+}
+
+/// Decrypts and verifies `message`, a whole message or MIME entity from
+/// `sender`. `None` when it is neither encrypted nor signed.
+pub fn open(message: &[u8], sender: &str, keys: &Keys) -> Result<Option<OpenedMessage>> {
+    open_layer(message, sender, keys, 0)
+}
+
+/// Opens `message`, found inside `depth` other protected entities.
+fn open_layer(message: &[u8], sender: &str, keys: &Keys, depth: usize) -> Result<Option<OpenedMessage>> {
+    match protection(message)? {
+        None => Ok(None),
+        Some(_) if depth == MAX_LAYERS => bail!("More than {} layers of signatures and encryption", MAX_LAYERS),
+        Some(Protection::Encrypted(data)) => {
+            let mut decrypted = openpgp::decrypt(data, keys)?;
+            decrypted.encrypted = true;
+            open_nested(decrypted, sender, keys, depth).map(Some)
+        }
+        Some(Protection::Signed(signed, signature)) => {
+            let signatures = openpgp::verify_detached(&mime::to_crlf(signed), signature, keys)?;
+            Ok(Some(OpenedMessage {
+                content: signed.to_vec(),
+                is_mime: true,
+                encrypted: false,
+                signatures,
+                certificates: Vec::new(),
+            }))
+        }
+        Some(Protection::Inline(block)) => openpgp::decrypt(&block, keys).map(Some),
+        Some(Protection::Pkcs7(data)) => open_nested(smime::open(&data, sender, keys)?, sender, keys, depth).map(Some),
+        Some(Protection::Pkcs7Signed(signed, signature)) => {
+            let mut verified = smime::verify_detached(&mime::to_crlf(signed), &signature, sender, keys)?;
+            verified.content = signed.to_vec();
+            open_nested(verified, sender, keys, depth).map(Some)
+        }
+        Some(Protection::Age(block)) => {
+            let content = age::decrypt(&block, keys.age_identities, keys.passphrase)?;
+            let (headers, _) = mime::split_message(&content);
+            let is_mime = mime::header_value(&String::from_utf8_lossy(headers), "Content-Type").is_some();
+            let decrypted = OpenedMessage { content, is_mime, encrypted: true, signatures: Vec::new(), certificates: Vec::new() };
+            if is_mime {
+                open_nested(decrypted, sender, keys, depth).map(Some)
+            } else {
+                Ok(Some(decrypted))
+            }
+        }
+    }
+}
+
+/// `layer`, a MIME entity just decrypted or verified, opened in turn: a
+/// signed entity may be encrypted, or the other way round (RFC 3156 6.1,
+/// RFC 8551 3.7).
+fn open_nested(layer: OpenedMessage, sender: &str, keys: &Keys, depth: usize) -> Result<OpenedMessage> {
+    let mut opened = open_layer(&layer.content, sender, keys, depth + 1)?.unwrap_or_else(|| OpenedMessage {
+        content: layer.content.clone(),
+        is_mime: true,
+        encrypted: false,
+        signatures: Vec::new(),
+        certificates: Vec::new(),
+    });
+    opened.encrypted |= layer.encrypted;
+    opened.signatures.extend(layer.signatures);
+    opened.certificates.extend(layer.certificates);
+    Ok(opened)
+}
+
+/// Signs and/or encrypts `entity`, a MIME entity, into a multipart/signed
+/// or multipart/encrypted entity. Encryption is to the certificates in
+/// `keys` for `recipients` and to our own key, so sent mail stays readable;
+/// signing and encrypting makes one OpenPGP message, as RFC 3156 6.2 allows.
+pub fn protect(entity: &[u8], sign: bool, encrypt: bool, recipients: &[&str], keys: &Keys) -> Result<Vec<u8>> {
+    let entity = mime::to_crlf(entity);
+    if encrypt {
+        let encrypted = openpgp::encrypt(&entity, recipients, sign, keys)?;
+        Ok(encrypted_entity(&encrypted))
+    } else if sign {
+        let signature = openpgp::sign_detached(&entity, keys)?;
+        Ok(signed_entity(&entity, &signature))
+    } else {
+        Ok(entity)
+    }
+}
+
+/// Signs and/or encrypts `entity` with S/MIME. Signing comes first, so the
+/// signature is hidden inside the encryption.
+pub fn protect_smime(entity: &[u8], sign: bool, encrypt: bool, recipients: &[&str], keys: &Keys) -> Result<Vec<u8>> {
+    let mut entity = mime::to_crlf(entity);
+    if sign {
+        let signature = smime::sign_detached(&entity, keys)?;
+        entity = smime_signed_entity(&entity, &signature);
+    }
+    if encrypt {
+        entity = smime_encrypted_entity(&smime::encrypt(&entity, recipients, keys)?);
+    }
+    Ok(entity)
+}
+
+/// `data` encrypted with age to the keys in `keys` for `recipients`, and to
+/// our own identities so sent mail stays readable.
+pub fn encrypt_age(data: &[u8], recipients: &[&str], armor: bool, keys: &Keys) -> Result<Vec<u8>> {
+    let mut keys_to: Vec<&str> = Vec::new();
+    for recipient in recipients {
+        let address = recipient.trim().to_lowercase();
+        let found: Vec<&str> = keys.age_recipients.iter().filter(|(to, _)| *to == address).map(|(_, key)| key.as_str()).collect();
+        if found.is_empty() {
+            bail!("No age key for {}", address);
+        }
+        keys_to.extend(found);
+    }
+    keys_to.extend(keys.age_identities.iter().map(|identity| identity.recipient.as_str()));
+    keys_to.sort_unstable();
+    keys_to.dedup();
+    age::encrypt(data, &keys_to, armor)
+}
+
+/// `protect_compose` with the keys in `vault`, which only needs to be
+/// unlocked when `compose` is signed or encrypted to keys. `address` picks
+/// the S/MIME identity that signs. OpenPGP mail is also encrypted to the
+/// keys Autocrypt learned for the recipients.
+pub async fn protect_outgoing(
+    conn: &mut SqliteConnection,
+    vault: &Vault,
+    compose: &ComposeEmail,
+    address: &str,
+    passphrase: Option<&str>,
+) -> Result<Vec<u8>> {
+    let to_passphrase = compose.age && compose.age_passphrase.is_some();
+    let mut stored = if compose.sign || (compose.encrypt && !to_passphrase) {
+        StoredKeys::load(conn, vault, Some(address)).await?
+    } else {
+        StoredKeys::default()
+    };
+    if compose.encrypt && !compose.age && !compose.smime {
+        let peer_keys = autocrypt::peer_keys(conn, compose.account_id, &recipients(compose)).await?;
+        stored.certificates.extend(peer_keys);
+    }
+    protect_compose(compose, &stored.keys(passphrase))
+}
+
+fn recipients(compose: &ComposeEmail) -> Vec<&str> {
+    compose
+        .to
+        .iter()
+        .chain(compose.cc.iter().flatten())
+        .chain(compose.bcc.iter().flatten())
+        .map(|address| address.address.as_str())
+        .collect()
+}
+
+/// The body entity for `compose`, signed and encrypted as it asks.
+pub fn protect_compose(compose: &ComposeEmail, keys: &Keys) -> Result<Vec<u8>> {
+    let entity = mime::compose_entity(compose)?;
+    let recipients = recipients(compose);
+    if compose.age {
+        if compose.sign {
+            bail!("age can't sign messages");
+        }
+        if !compose.encrypt {
+            return Ok(mime::to_crlf(&entity));
+        }
+        let encrypted = match compose.age_passphrase.as_deref() {
+            Some(passphrase) => age::encrypt_with_passphrase(&mime::to_crlf(&entity), passphrase, true)?,
+            None => encrypt_age(&mime::to_crlf(&entity), &recipients, true, keys)?,
+        };
+        Ok(age_encrypted_entity(&encrypted))
+    } else if compose.smime {
+        protect_smime(&entity, compose.sign, compose.encrypt, &recipients, keys)
+    } else {
+        protect(&entity, compose.sign, compose.encrypt, &recipients, keys)
+    }
+}
+
+fn encrypted_entity(encrypted: &[u8]) -> Vec<u8> {
+    let mut data = b"Content-Type: application/octet-stream; name=\"encrypted.asc\"\r\n\r\n".to_vec();
+    data.extend_from_slice(&mime::to_crlf(encrypted));
+    mime::multipart(
+        &format!("multipart/encrypted; protocol=\"{}\"", ENCRYPTED_PROTOCOL),
+        &[b"Content-Type: application/pgp-encrypted\r\n\r\nVersion: 1\r\n", &data],
+    )
+}
+
+fn signed_entity(entity: &[u8], signature: &[u8]) -> Vec<u8> {
+    let mut data = b"Content-Type: application/pgp-signature; name=\"signature.asc\"\r\n\r\n".to_vec();
+    data.extend_from_slice(&mime::to_crlf(signature));
+    mime::multipart(
+        &format!("multipart/signed; micalg={}; protocol=\"{}\"", openpgp::MICALG, SIGNATURE_PROTOCOL),
+        &[entity, &data],
+    )
+}
+
+fn smime_signed_entity(entity: &[u8], signature: &[u8]) -> Vec<u8> {
+    let mut data = b"Content-Type: application/pkcs7-signature; name=\"smime.p7s\"\r\n\
+        Content-Transfer-Encoding: base64\r\nContent-Disposition: attachment; filename=\"smime.p7s\"\r\n\r\n"
+        .to_vec();
+    data.extend_from_slice(mime::encode_base64(signature).as_bytes());
+    mime::multipart(
+        &format!("multipart/signed; micalg={}; protocol=\"{}\"", smime::MICALG, SMIME_SIGNATURE_PROTOCOLS[0]),
+        &[entity, &data],
+    )
+}
+
+fn smime_encrypted_entity(enveloped: &[u8]) -> Vec<u8> {
+    let mut data = b"Content-Type: application/pkcs7-mime; smime-type=enveloped-data; name=\"smime.p7m\"\r\n\
+        Content-Transfer-Encoding: base64\r\nContent-Disposition: attachment; filename=\"smime.p7m\"\r\n\r\n"
+        .to_vec();
+    data.extend_from_slice(mime::encode_base64(enveloped).as_bytes());
+    data
+}
+
+/// A text/plain entity around an armored age file, which `age -d` opens
+/// for anyone reading it elsewhere.
+fn age_encrypted_entity(encrypted: &[u8]) -> Vec<u8> {
+    let mut data = b"Content-Type: text/plain; charset=us-ascii\r\n\r\n".to_vec();
+    data.extend_from_slice(&mime::to_crlf(encrypted));
+    data
+}
+
+/// The text and HTML bodies of an opened message.
+pub fn bodies(opened: &OpenedMessage) -> (Option<String>, Option<String>) {
+    if !opened.is_mime {
+        return (Some(String::from_utf8_lossy(&opened.content).into_owned()), None);
+    }
+    let Some(message) = mail_parser::MessageParser::default().parse(&opened.content) else {
+        return (None, None);
+    };
+    let is_html = |part: &mail_parser::MessagePart| matches!(part.body, mail_parser::PartType::Html(_));
+    let body_text = message.body_text(0).map(|text| text.into_owned());
+    let body_html = match message.html_part(0) {
+        Some(part) if is_html(part) => message.body_html(0).map(|html| html.into_owned()),
+        _ => None,
+    };
+    (body_text, body_html)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIGNED: &[u8] = b"Content-Type: multipart/signed; micalg=pgp-sha256;\r\n \
+        protocol=\"application/pgp-signature\"; boundary=\"b1\"\r\n\r\n\
+        --b1\r\nContent-Type: text/plain\r\n\r\nHello\r\n\r\n--b1\r\n\
+        Content-Type: application/pgp-signature\r\n\r\n-----BEGIN PGP SIGNATURE-----\r\nsig\r\n-----END PGP SIGNATURE-----\r\n\
+        --b1--\r\n";
+
+    #[test]
+    fn test_detect_pgp_mime() {
+        assert_eq!(
+            protection(SIGNED).unwrap(),
+            Some(Protection::Signed(
+                b"Content-Type: text/plain\r\n\r\nHello\r\n",
+                b"-----BEGIN PGP SIGNATURE-----\r\nsig\r\n-----END PGP SIGNATURE-----"
+            ))
+        );
+
+        let encrypted = encrypted_entity(b"-----BEGIN PGP MESSAGE-----\nabc\n-----END PGP MESSAGE-----\n");
+        assert_eq!(
+            protection(&encrypted).unwrap(),
+            Some(Protection::Encrypted(b"-----BEGIN PGP MESSAGE-----\r\nabc\r\n-----END PGP MESSAGE-----\r\n"))
+        );
+        let signed = signed_entity(b"Content-Type: text/plain\r\n\r\nHi", b"SIG");
+        assert_eq!(protection(&signed).unwrap(), Some(Protection::Signed(b"Content-Type: text/plain\r\n\r\nHi", b"SIG")));
+        assert!(String::from_utf8_lossy(&signed).contains("micalg="));
+
+        let smime = smime_signed_entity(b"Content-Type: text/plain\r\n\r\nHi", b"SIG");
+        assert_eq!(protection(&smime).unwrap(), Some(Protection::Pkcs7Signed(b"Content-Type: text/plain\r\n\r\nHi", b"SIG".to_vec())));
+        let enveloped = smime_encrypted_entity(b"CMS");
+        assert_eq!(protection(&enveloped).unwrap(), Some(Protection::Pkcs7(b"CMS".to_vec())));
+        let certs_only = b"Content-Type: application/pkcs7-mime; smime-type=certs-only\r\n\r\nx";
+        assert_eq!(protection(certs_only).unwrap(), None);
+        let broken = b"Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\"; boundary=b\r\n\r\n--b\r\n\r\nx\r\n--b--\r\n";
+        assert!(protection(broken).is_err());
+    }
+
+    #[test]
+    fn test_detect_inline() {
+        let message = b"Content-Type: text/plain\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\n\
+            Hi,\r\n-----BEGIN PGP SIGNED MESSAGE-----\r\nHash: SHA256\r\n\r\nsigned=3D\r\n\
+            -----BEGIN PGP SIGNATURE-----\r\nsig\r\n-----END PGP SIGNATURE-----\r\nBye";
+        assert_eq!(
+            protection(message).unwrap(),
+            Some(Protection::Inline(
+                b"-----BEGIN PGP SIGNED MESSAGE-----\r\nHash: SHA256\r\n\r\nsigned=\r\n\
+                -----BEGIN PGP SIGNATURE-----\r\nsig\r\n-----END PGP SIGNATURE-----"
+                    .to_vec()
+            ))
+        );
+        // Only at the start of a line, and only when it is closed
+        assert_eq!(protection(b"\r\nquote: -----BEGIN PGP MESSAGE----- x -----END PGP MESSAGE-----").unwrap(), None);
+        assert_eq!(protection(b"\r\n-----BEGIN PGP MESSAGE-----\r\ncut off").unwrap(), None);
+        assert_eq!(protection(b"Content-Type: text/html\r\n\r\n-----BEGIN PGP MESSAGE-----\r\n-----END PGP MESSAGE-----").unwrap(), None);
+    }
+
+    #[test]
+    fn test_age() {
+        let alice = age::generate();
+        let bob = age::generate();
+        let recipients = [("bob@example.org".to_string(), bob.recipient.clone())];
+        let keys = Keys { age_identities: std::slice::from_ref(&alice), age_recipients: &recipients, ..Keys::default() };
+        let mut compose = ComposeEmail {
+            account_id: 1,
+            to: vec![crate::db::EmailAddress { name: None, address: "Bob@example.org".to_string() }],
+            cc: None,
+            bcc: None,
+            subject: "Hi".to_string(),
+            body_text: Some("Secret".to_string()),
+            body_html: None,
+            attachments: vec![],
+            in_reply_to: None,
+            references: None,
+            sign: false,
+            encrypt: true,
+            smime: false,
+            age: true,
+            age_passphrase: None,
+        };
+        let sent = protect_compose(&compose, &keys).unwrap();
+        assert!(matches!(protection(&sent).unwrap(), Some(Protection::Age(_))));
+
+        let bob_identities = [bob];
+        let opened = open(&sent, "alice@example.org", &Keys { age_identities: &bob_identities, ..Keys::default() }).unwrap().unwrap();
+        assert!(opened.encrypted && opened.is_mime);
+        assert_eq!(bodies(&opened).0.as_deref(), Some("Secret"));
+        // Sent mail stays readable
+        assert!(open(&sent, "alice@example.org", &keys).is_ok());
+
+        // Attachments go inside the encryption too
+        compose.age_passphrase = Some("hunter2".to_string());
+        compose.attachments = vec![crate::db::Attachment {
+            id: String::new(),
+            filename: "notes.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            size_bytes: 5,
+            content_id: None,
+            is_inline: false,
+            data: Some(b"notes".to_vec()),
+        }];
+        let sent = protect_compose(&compose, &Keys::default()).unwrap();
+        assert!(!String::from_utf8_lossy(&sent).contains("notes.txt"));
+        assert!(open(&sent, "alice@example.org", &Keys::default()).is_err());
+        let opened = open(&sent, "alice@example.org", &Keys { passphrase: Some("hunter2"), ..Keys::default() }).unwrap().unwrap();
+        assert_eq!(bodies(&opened).0.as_deref(), Some("Secret"));
+        assert!(String::from_utf8_lossy(&opened.content).contains("Content-Disposition: attachment; filename=\"notes.txt\"\r\n"));
+        compose.attachments.clear();
+
+        compose.sign = true;
+        assert!(protect_compose(&compose, &keys).is_err());
+
+        // Plain text someone ran through `age -a`
+        let armored = age::encrypt(b"just text", &[&alice.recipient], true).unwrap();
+        let message = [b"Content-Type: text/plain\r\n\r\n".as_slice(), &armored].concat();
+        let opened = open(&message, "alice@example.org", &keys).unwrap().unwrap();
+        assert_eq!((opened.content.as_slice(), opened.is_mime), (b"just text".as_slice(), false));
+    }
+
+    #[test]
+    fn test_open_limits_nesting() {
+        let ca = smime::tests::ca();
+        let alice = [smime::tests::identity("alice@example.org", &ca)];
+        let keys = Keys { identities: &alice, ..Keys::default() };
+        let mut entity = b"Content-Type: text/plain\r\n\r\nHi".to_vec();
+        for _ in 0..MAX_LAYERS {
+            entity = protect_smime(&entity, true, false, &[], &keys).unwrap();
+        }
+        assert_eq!(open(&entity, "alice@example.org", &keys).unwrap().unwrap().signatures.len(), MAX_LAYERS);
+
+        let entity = protect_smime(&entity, true, false, &[], &keys).unwrap();
+        assert!(open(&entity, "alice@example.org", &keys).is_err());
+    }
+
+    #[test]
+    fn test_bodies() {
+        let entity = mime::text_entity(Some("plain café"), Some("<p>html</p>"));
+        let opened = OpenedMessage { content: entity, is_mime: true, encrypted: true, signatures: vec![], certificates: vec![] };
+        assert_eq!(bodies(&opened), (Some("plain café".to_string()), Some("<p>html</p>".to_string())));
+
+        let opened = OpenedMessage { content: mime::text_entity(Some("just text"), None), ..opened };
+        assert_eq!(bodies(&opened), (Some("just text".to_string()), None));
+        let opened = OpenedMessage { content: b"inline".to_vec(), is_mime: false, ..opened };
+        assert_eq!(bodies(&opened), (Some("inline".to_string()), None));
+    }
+}
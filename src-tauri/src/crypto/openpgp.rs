@@ -0,0 +1,471 @@
+//! OpenPGP through Sequoia, using its pure-Rust crypto backend so the
+//! build doesn't need nettle.
+
+use std::io::{self, Write};
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use sequoia_openpgp as openpgp;
+use openpgp::armor;
+use openpgp::cert::prelude::*;
+use openpgp::crypto::{KeyPair, SessionKey};
+use openpgp::packet::{key, Key, Packet, Signature, UserID, PKESK, SKESK};
+use openpgp::parse::stream::{
+    DecryptionHelper, DecryptorBuilder, DetachedVerifierBuilder, GoodChecksum, MessageLayer, MessageStructure,
+    VerificationError, VerificationHelper,
+};
+use openpgp::parse::Parse;
+use openpgp::policy::StandardPolicy;
+use openpgp::serialize::stream::{Armorer, Encryptor2, LiteralWriter, Message, Signer};
+use openpgp::serialize::{Serialize, SerializeInto};
+use openpgp::types::{HashAlgorithm, ReasonForRevocation, RevocationStatus, SymmetricAlgorithm};
+use openpgp::{Fingerprint, KeyHandle};
+
+use super::{CertData, Keys, OpenedMessage};
+use crate::db::{SignatureInfo, SignatureStatus};
+
+/// The hash we sign with, as multipart/signed's micalg names it.
+pub const MICALG: &str = "pgp-sha256";
+
+fn certs(data: &[Vec<u8>]) -> Result<Vec<Cert>> {
+    let mut certs = Vec::new();
+    for keyring in data {
+        for cert in CertParser::from_bytes(keyring)? {
+            certs.push(cert?);
+        }
+    }
+    Ok(certs)
+}
+
+fn unlock(key: &Key<key::SecretParts, key::UnspecifiedRole>, passphrase: Option<&str>) -> Option<KeyPair> {
+    let key = match (key.secret().is_encrypted(), passphrase) {
+        (false, _) => key.clone(),
+        (true, Some(passphrase)) => key.clone().decrypt_secret(&passphrase.into()).ok()?,
+        (true, None) => return None,
+    };
+    key.into_keypair().ok()
+}
+
+fn created_at(signature: &Signature) -> Option<DateTime<Utc>> {
+    signature.signature_creation_time().map(DateTime::<Utc>::from)
+}
+
+fn primary_user_id(cert: &Cert) -> Option<String> {
+    let policy = StandardPolicy::new();
+    let user_id = cert.with_policy(&policy, None).ok()?.primary_userid().ok()?;
+    Some(String::from_utf8_lossy(user_id.userid().value()).into_owned())
+}
+
+fn signature_info(result: Result<GoodChecksum, VerificationError>) -> SignatureInfo {
+    let from_key = |status, sig: &Signature, cert: &Cert| SignatureInfo {
+        status,
+        fingerprint: Some(cert.fingerprint().to_hex()),
+        signer: primary_user_id(cert),
+        created_at: created_at(sig),
+    };
+    let from_issuer = |status, sig: &Signature| SignatureInfo {
+        status,
+        fingerprint: sig.get_issuers().first().map(KeyHandle::to_hex),
+        signer: None,
+        created_at: created_at(sig),
+    };
+    match result {
+        Ok(GoodChecksum { sig, ka, .. }) => from_key(SignatureStatus::Good, sig, ka.cert()),
+        Err(VerificationError::MissingKey { sig, .. }) => from_issuer(SignatureStatus::UnknownKey, sig),
+        Err(VerificationError::BadKey { sig, ka, .. }) => {
+            let expired = ka.alive().is_err() || ka.cert().alive().is_err();
+            let status = if expired { SignatureStatus::Expired } else { SignatureStatus::Bad };
+            from_key(status, sig, ka.cert())
+        }
+        Err(VerificationError::BadSignature { sig, ka, .. }) => {
+            let expired = sig.signature_alive(None, None).is_err();
+            let status = if expired { SignatureStatus::Expired } else { SignatureStatus::Bad };
+            from_key(status, sig, ka.cert())
+        }
+        Err(VerificationError::UnboundKey { sig, cert, .. }) => from_key(SignatureStatus::Bad, sig, cert),
+        Err(VerificationError::MalformedSignature { sig, .. }) => from_issuer(SignatureStatus::Bad, sig),
+    }
+}
+
+struct Helper<'a> {
+    policy: &'a StandardPolicy<'a>,
+    certificates: Vec<Cert>,
+    secret_keys: Vec<Cert>,
+    passphrase: Option<&'a str>,
+    decrypted: bool,
+    signatures: Vec<SignatureInfo>,
+}
+
+impl<'a> Helper<'a> {
+    fn new(policy: &'a StandardPolicy<'a>, keys: &Keys<'a>) -> Result<Self> {
+        Ok(Helper {
+            policy,
+            certificates: certs(keys.certificates)?,
+            secret_keys: certs(keys.secret_keys)?,
+            passphrase: keys.passphrase,
+            decrypted: false,
+            signatures: Vec::new(),
+        })
+    }
+}
+
+impl VerificationHelper for Helper<'_> {
+    fn get_certs(&mut self, ids: &[KeyHandle]) -> openpgp::Result<Vec<Cert>> {
+        Ok(self
+            .certificates
+            .iter()
+            .chain(&self.secret_keys)
+            .filter(|cert| cert.keys().any(|key| ids.iter().any(|id| id.aliases(key.key().key_handle()))))
+            .map(|cert| cert.clone().strip_secret_key_material())
+            .collect())
+    }
+
+    fn check(&mut self, structure: MessageStructure) -> openpgp::Result<()> {
+        for layer in structure.into_iter() {
+            if let MessageLayer::SignatureGroup { results } = layer {
+                self.signatures.extend(results.into_iter().map(signature_info));
+            }
+        }
+        // Bad signatures are reported, not fatal
+        Ok(())
+    }
+}
+
+impl DecryptionHelper for Helper<'_> {
+    fn decrypt<D>(
+        &mut self,
+        pkesks: &[PKESK],
+        _skesks: &[SKESK],
+        sym_algo: Option<SymmetricAlgorithm>,
+        mut decrypt: D,
+    ) -> openpgp::Result<Option<Fingerprint>>
+    where
+        D: FnMut(SymmetricAlgorithm, &SessionKey) -> bool,
+    {
+        for pkesk in pkesks {
+            for cert in &self.secret_keys {
+                let keys = cert
+                    .keys()
+                    .with_policy(self.policy, None)
+                    .supported()
+                    .secret()
+                    .for_transport_encryption()
+                    .for_storage_encryption();
+                for ka in keys {
+                    if !pkesk.recipient().is_wildcard() && *pkesk.recipient() != ka.key().keyid() {
+                        continue;
+                    }
+                    let Some(mut keypair) = unlock(ka.key(), self.passphrase) else {
+                        continue;
+                    };
+                    let decrypted = pkesk
+                        .decrypt(&mut keypair, sym_algo)
+                        .map(|(algo, session_key)| decrypt(algo, &session_key))
+                        .unwrap_or(false);
+                    if decrypted {
+                        self.decrypted = true;
+                        return Ok(Some(cert.fingerprint()));
+                    }
+                }
+            }
+        }
+        Err(anyhow!("No key to decrypt the message with"))
+    }
+}
+
+/// Decrypts and/or verifies an OpenPGP message, armored or binary. Also
+/// takes cleartext-signed messages.
+pub fn decrypt(data: &[u8], keys: &Keys) -> Result<OpenedMessage> {
+    let policy = StandardPolicy::new();
+    let helper = Helper::new(&policy, keys)?;
+    let mut decryptor = DecryptorBuilder::from_bytes(data)?.with_policy(&policy, None, helper)?;
+    let mut content = Vec::new();
+    io::copy(&mut decryptor, &mut content)?;
+    let helper = decryptor.into_helper();
+    Ok(OpenedMessage {
+        content,
+        is_mime: false,
+        encrypted: helper.decrypted,
+        signatures: helper.signatures,
+        certificates: Vec::new(),
+    })
+}
+
+/// Checks a detached signature over `signed`.
+pub fn verify_detached(signed: &[u8], signature: &[u8], keys: &Keys) -> Result<Vec<SignatureInfo>> {
+    let policy = StandardPolicy::new();
+    let helper = Helper::new(&policy, keys)?;
+    let mut verifier = DetachedVerifierBuilder::from_bytes(signature)?.with_policy(&policy, None, helper)?;
+    verifier.verify_bytes(signed)?;
+    Ok(verifier.into_helper().signatures)
+}
+
+/// Our first key that can sign.
+fn signer(policy: &StandardPolicy, keys: &Keys) -> Result<KeyPair> {
+    for cert in certs(keys.secret_keys)? {
+        let signing_keys = cert.keys().with_policy(policy, None).supported().alive().revoked(false).secret().for_signing();
+        for ka in signing_keys {
+            if let Some(keypair) = unlock(ka.key(), keys.passphrase) {
+                return Ok(keypair);
+            }
+        }
+    }
+    bail!("No usable signing key")
+}
+
+fn has_email(user_id: &UserID, address: &str) -> bool {
+    matches!(user_id.email_normalized(), Ok(Some(email)) if email.eq_ignore_ascii_case(address))
+}
+
+fn has_address(cert: &Cert, address: &str) -> bool {
+    cert.userids().any(|user_id| has_email(user_id.userid(), address))
+}
+
+/// Encrypts `data` into an armored OpenPGP message for `recipients` and
+/// ourselves, signing it too when `sign` is set.
+pub fn encrypt(data: &[u8], recipients: &[&str], sign: bool, keys: &Keys) -> Result<Vec<u8>> {
+    let policy = StandardPolicy::new();
+    let certificates = certs(keys.certificates)?;
+    let mut to = Vec::new();
+    for address in recipients {
+        match certificates.iter().find(|cert| has_address(cert, address)) {
+            Some(cert) => to.push(cert.clone()),
+            None => bail!("No OpenPGP key for {}", address),
+        }
+    }
+    to.extend(certs(keys.secret_keys)?);
+
+    let mut encryption_keys = Vec::new();
+    for cert in &to {
+        let usable = cert
+            .keys()
+            .with_policy(&policy, None)
+            .supported()
+            .alive()
+            .revoked(false)
+            .for_transport_encryption();
+        let before = encryption_keys.len();
+        encryption_keys.extend(usable);
+        if encryption_keys.len() == before {
+            bail!("No usable encryption key in {}", cert.fingerprint());
+        }
+    }
+
+    let mut sink = Vec::new();
+    let message = Armorer::new(Message::new(&mut sink)).build()?;
+    let message = Encryptor2::for_recipients(message, encryption_keys).build()?;
+    let message = if sign {
+        Signer::new(message, signer(&policy, keys)?).hash_algo(HashAlgorithm::SHA256)?.build()?
+    } else {
+        message
+    };
+    let mut message = LiteralWriter::new(message).build()?;
+    message.write_all(data)?;
+    message.finalize()?;
+    Ok(sink)
+}
+
+/// An armored detached signature over `data`.
+pub fn sign_detached(data: &[u8], keys: &Keys) -> Result<Vec<u8>> {
+    let policy = StandardPolicy::new();
+    let keypair = signer(&policy, keys)?;
+    let mut sink = Vec::new();
+    let message = Armorer::new(Message::new(&mut sink)).kind(armor::Kind::Signature).build()?;
+    let mut message = Signer::new(message, keypair).detached().hash_algo(HashAlgorithm::SHA256)?.build()?;
+    message.write_all(data)?;
+    message.finalize()?;
+    Ok(sink)
+}
+
+fn cert_data(cert: &Cert) -> Result<CertData> {
+    let policy = StandardPolicy::new();
+    let expires_at = cert
+        .with_policy(&policy, None)
+        .ok()
+        .and_then(|valid| valid.primary_key().key_expiration_time())
+        .map(DateTime::<Utc>::from);
+    let mut data = Vec::new();
+    cert.as_tsk().serialize(&mut data)?;
+    Ok(CertData {
+        fingerprint: cert.fingerprint().to_hex(),
+        user_ids: cert.userids().map(|user_id| String::from_utf8_lossy(user_id.userid().value()).into_owned()).collect(),
+        addresses: cert.userids().filter_map(|user_id| user_id.userid().email_normalized().ok().flatten()).collect(),
+        has_secret: cert.is_tsk(),
+        created_at: cert.primary_key().creation_time().into(),
+        expires_at,
+        revoked: matches!(cert.revocation_status(&policy, None), RevocationStatus::Revoked(_)),
+        data,
+    })
+}
+
+/// Replaces each of `cert`'s secret keys with what `f` makes of it.
+fn map_secrets(
+    cert: Cert,
+    mut f: impl FnMut(Key<key::SecretParts, key::UnspecifiedRole>) -> Result<Key<key::SecretParts, key::UnspecifiedRole>>,
+) -> Result<Cert> {
+    let mut packets: Vec<Packet> = Vec::new();
+    for ka in cert.keys().secret() {
+        let key = f(ka.key().clone())?;
+        packets.push(if ka.primary() {
+            key.role_into_primary().into()
+        } else {
+            key.role_into_subordinate().into()
+        });
+    }
+    cert.insert_packets(packets)
+}
+
+/// Every certificate in `data`, a keyring, with secret keys unlocked by
+/// `passphrase` when they are protected.
+pub fn read_certs(data: &[u8], passphrase: Option<&str>) -> Result<Vec<CertData>> {
+    let mut certs = Vec::new();
+    for cert in CertParser::from_bytes(data)? {
+        let cert = cert?;
+        let fingerprint = cert.fingerprint();
+        let cert = map_secrets(cert, |key| {
+            if !key.secret().is_encrypted() {
+                return Ok(key);
+            }
+            let Some(passphrase) = passphrase else {
+                bail!("The secret key {} needs its passphrase", fingerprint);
+            };
+            key.decrypt_secret(&passphrase.into())
+                .map_err(|_| anyhow!("Wrong passphrase for {}", fingerprint))
+        })?;
+        certs.push(cert_data(&cert)?);
+    }
+    Ok(certs)
+}
+
+/// Merges a newer copy of a certificate, e.g. with fresh signatures or a
+/// revocation, into the one we have.
+pub fn merge(existing: &[u8], new: &[u8]) -> Result<CertData> {
+    let existing = Cert::from_bytes(existing)?;
+    let new = Cert::from_bytes(new)?;
+    if existing.fingerprint() != new.fingerprint() {
+        bail!("Can't merge different certificates");
+    }
+    cert_data(&existing.merge_public_and_secret(new)?)
+}
+
+/// A new key for `user_id` that can sign and encrypt.
+pub fn generate(user_id: &str) -> Result<CertData> {
+    let (cert, _) = CertBuilder::general_purpose(None, Some(user_id)).generate()?;
+    cert_data(&cert)
+}
+
+/// Revokes one of our own certificates.
+pub fn revoke(data: &[u8], reason: &str) -> Result<CertData> {
+    let cert = Cert::from_bytes(data)?;
+    let mut signer = cert
+        .primary_key()
+        .key()
+        .clone()
+        .parts_into_secret()
+        .map_err(|_| anyhow!("Only keys we have the secret for can be revoked"))?
+        .into_keypair()?;
+    let revocation = cert.revoke(&mut signer, ReasonForRevocation::KeyRetired, reason.as_bytes())?;
+    cert_data(&cert.insert_packets(vec![revocation])?)
+}
+
+/// The armored certificate, with its secret keys protected by `passphrase`
+/// when one is given and public only otherwise.
+pub fn export(data: &[u8], passphrase: Option<&str>) -> Result<Vec<u8>> {
+    let cert = Cert::from_bytes(data)?;
+    let mut out = Vec::new();
+    match passphrase {
+        Some(passphrase) if cert.is_tsk() => {
+            let cert = map_secrets(cert, |key| key.encrypt_secret(&passphrase.into()))?;
+            cert.as_tsk().armored().serialize(&mut out)?;
+        }
+        _ => cert.armored().serialize(&mut out)?,
+    }
+    Ok(out)
+}
+
+/// Whether anything can be encrypted to the certificate in `data`.
+pub fn can_encrypt(data: &[u8]) -> bool {
+    let policy = StandardPolicy::new();
+    let Ok(cert) = Cert::from_bytes(data) else {
+        return false;
+    };
+    let mut keys = cert.keys().with_policy(&policy, None).supported().alive().revoked(false).for_transport_encryption();
+    keys.next().is_some()
+}
+
+/// The public certificate cut down to what an Autocrypt header carries: the
+/// primary key, the user ID for `address` and the encryption subkeys.
+pub fn autocrypt_key(data: &[u8], address: &str) -> Result<Vec<u8>> {
+    let policy = StandardPolicy::new();
+    let cert = Cert::from_bytes(data)?.strip_secret_key_material();
+    let cert = cert.retain_userids(|user_id| has_email(user_id.userid(), address));
+    let encryption: Vec<Fingerprint> = cert
+        .keys()
+        .subkeys()
+        .with_policy(&policy, None)
+        .alive()
+        .revoked(false)
+        .for_transport_encryption()
+        .map(|ka| ka.key().fingerprint())
+        .collect();
+    let cert = cert.retain_subkeys(|subkey| encryption.contains(&subkey.key().fingerprint()));
+    cert.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{open, protect};
+
+    fn generate(address: &str) -> (Vec<u8>, Vec<u8>) {
+        let (cert, _) = CertBuilder::general_purpose(None, Some(address))
+            .set_password(Some("secret".into()))
+            .generate()
+            .unwrap();
+        let mut public = Vec::new();
+        cert.armored().serialize(&mut public).unwrap();
+        let mut secret = Vec::new();
+        cert.as_tsk().armored().serialize(&mut secret).unwrap();
+        (public, secret)
+    }
+
+    #[test]
+    fn test_sign_and_encrypt_round_trip() {
+        let (alice_public, alice_secret) = generate("Alice <alice@example.com>");
+        let (bob_public, bob_secret) = generate("Bob <bob@example.org>");
+        let entity = b"Content-Type: text/plain\r\n\r\nHello Bob\r\n";
+
+        let alice_certificates = [bob_public.clone()];
+        let alice_secrets = [alice_secret];
+        let alice = Keys { certificates: &alice_certificates, secret_keys: &alice_secrets, passphrase: Some("secret"), ..Keys::default() };
+        let bob_certificates = [alice_public];
+        let bob_secrets = [bob_secret];
+        let bob = Keys { certificates: &bob_certificates, secret_keys: &bob_secrets, passphrase: Some("secret"), ..Keys::default() };
+
+        let signed = protect(entity, true, false, &[], &alice).unwrap();
+        let opened = open(&signed, "alice@example.com", &bob).unwrap().unwrap();
+        assert_eq!(opened.content, entity);
+        assert!(!opened.encrypted);
+        assert_eq!(opened.signatures.len(), 1);
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Good);
+        assert_eq!(opened.signatures[0].signer.as_deref(), Some("Alice <alice@example.com>"));
+
+        // Tampering breaks the signature; a stranger's key is unknown
+        let tampered = String::from_utf8(signed.clone()).unwrap().replace("Hello Bob", "Hello Eve");
+        let opened = open(tampered.as_bytes(), "alice@example.com", &bob).unwrap().unwrap();
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Bad);
+        let opened = open(&signed, "alice@example.com", &Keys::default()).unwrap().unwrap();
+        assert_eq!(opened.signatures[0].status, SignatureStatus::UnknownKey);
+
+        let encrypted = protect(entity, true, true, &["Bob@example.org"], &alice).unwrap();
+        let opened = open(&encrypted, "alice@example.com", &bob).unwrap().unwrap();
+        assert!(opened.encrypted && opened.is_mime);
+        assert_eq!(opened.content, entity);
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Good);
+        // Sent mail stays readable to the sender
+        assert!(open(&encrypted, "alice@example.com", &alice).unwrap().unwrap().encrypted);
+        let wrong_passphrase = Keys { passphrase: Some("wrong"), ..bob };
+        assert!(open(&encrypted, "alice@example.com", &wrong_passphrase).is_err());
+        assert!(protect(entity, false, true, &["carol@example.net"], &alice).is_err());
+    }
+}
@@ -0,0 +1,53 @@
+//! Stand-in for the Sequoia backend when built without the `openpgp`
+//! feature.
+
+use anyhow::{bail, Result};
+
+use super::{CertData, Keys, OpenedMessage};
+use crate::db::SignatureInfo;
+
+pub const MICALG: &str = "pgp-sha256";
+
+pub fn decrypt(_data: &[u8], _keys: &Keys) -> Result<OpenedMessage> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn verify_detached(_signed: &[u8], _signature: &[u8], _keys: &Keys) -> Result<Vec<SignatureInfo>> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn encrypt(_data: &[u8], _recipients: &[&str], _sign: bool, _keys: &Keys) -> Result<Vec<u8>> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn sign_detached(_data: &[u8], _keys: &Keys) -> Result<Vec<u8>> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn read_certs(_data: &[u8], _passphrase: Option<&str>) -> Result<Vec<CertData>> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn merge(_existing: &[u8], _new: &[u8]) -> Result<CertData> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn generate(_user_id: &str) -> Result<CertData> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn revoke(_data: &[u8], _reason: &str) -> Result<CertData> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn export(_data: &[u8], _passphrase: Option<&str>) -> Result<Vec<u8>> {
+    bail!("Built without OpenPGP support")
+}
+
+pub fn can_encrypt(_data: &[u8]) -> bool {
+    false
+}
+
+pub fn autocrypt_key(_data: &[u8], _address: &str) -> Result<Vec<u8>> {
+    bail!("Built without OpenPGP support")
+}
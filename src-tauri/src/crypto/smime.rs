@@ -0,0 +1,925 @@
+//! S/MIME (RFC 8551): CMS signed and enveloped data, as carried by
+//! multipart/signed and application/pkcs7-mime entities. Signatures are
+//! checked against the trust store and mail is decrypted with identities
+//! imported from PKCS#12 files. Only RSA keys are supported.
+
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use cbc::cipher::block_padding::Pkcs7;
+use cbc::cipher::{BlockCipher, BlockDecryptMut, KeyInit, KeyIvInit};
+use chrono::{DateTime, Utc};
+use cms::builder::{
+    create_signing_time_attribute, ContentEncryptionAlgorithm, EnvelopedDataBuilder, KeyEncryptionInfo,
+    KeyTransRecipientInfoBuilder, SignedDataBuilder, SignerInfoBuilder,
+};
+use cms::cert::{CertificateChoices, IssuerAndSerialNumber};
+use cms::content_info::ContentInfo;
+use cms::encrypted_data::EncryptedData;
+use cms::enveloped_data::{EnvelopedData, RecipientIdentifier, RecipientInfo};
+use cms::signed_data::{EncapsulatedContentInfo, SignedData, SignerIdentifier, SignerInfo};
+use const_oid::db::{rfc3280, rfc5911, rfc5912};
+use const_oid::ObjectIdentifier;
+use der::asn1::{Any, ContextSpecific, OctetString};
+use der::{Decode, Encode};
+use hmac::digest::core_api::BlockSizeUser;
+use hmac::digest::{Digest, FixedOutputReset};
+use hmac::{Mac, SimpleHmac};
+use pkcs12::cert_type::CertBag;
+use pkcs12::kdf::{derive_key_utf8, Pkcs12KeyType};
+use pkcs12::mac_data::MacData;
+use pkcs12::pbe_params::{EncryptedPrivateKeyInfo, Pkcs12PbeParams};
+use pkcs12::pfx::Pfx;
+use pkcs12::safe_bag::SafeContents;
+use rsa::pkcs1v15::{Pkcs1v15Sign, SigningKey};
+use rsa::pkcs8::{DecodePrivateKey, DecodePublicKey, EncodePublicKey};
+use rsa::{Oaep, Pkcs1v15Encrypt, RsaPrivateKey, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Sha256, Sha384, Sha512};
+use x509_cert::ext::pkix::name::GeneralName;
+use x509_cert::ext::pkix::{BasicConstraints, SubjectAltName, SubjectKeyIdentifier};
+use x509_cert::spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned};
+use x509_cert::time::Time;
+use x509_cert::Certificate;
+
+use super::{Keys, OpenedMessage};
+use crate::db::{SignatureInfo, SignatureStatus};
+
+pub const MICALG: &str = "sha-256";
+
+/// How many issuers we follow from a signer's certificate to a trust anchor.
+const MAX_PATH: usize = 8;
+
+/// One of our own certificates with its private key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Identity {
+    pub certificate: Vec<u8>, // DER
+    pub private_key: Vec<u8>, // PKCS#8 DER
+}
+
+/// One X.509 certificate, with what the certificate store lists about it.
+#[derive(Debug, Clone)]
+pub struct X509Data {
+    pub fingerprint: String,
+    pub subject: String,
+    pub issuer: String,
+    pub addresses: Vec<String>, // Lowercased
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    pub is_ca: bool,
+    pub data: Vec<u8>, // DER
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Hash {
+    Sha1,
+    Sha256,
+    Sha384,
+    Sha512,
+}
+
+impl Hash {
+    fn from_digest(algorithm: ObjectIdentifier) -> Result<Self> {
+        match algorithm {
+            rfc5912::ID_SHA_1 => Ok(Hash::Sha1),
+            rfc5912::ID_SHA_256 => Ok(Hash::Sha256),
+            rfc5912::ID_SHA_384 => Ok(Hash::Sha384),
+            rfc5912::ID_SHA_512 => Ok(Hash::Sha512),
+            other => bail!("Unsupported digest algorithm {}", other),
+        }
+    }
+
+    /// The hash of an RSA signature algorithm. CMS may name plain
+    /// rsaEncryption and leave the hash to the digest algorithm.
+    fn from_signature(algorithm: ObjectIdentifier, digest: Option<ObjectIdentifier>) -> Result<Self> {
+        match algorithm {
+            rfc5912::SHA_1_WITH_RSA_ENCRYPTION => Ok(Hash::Sha1),
+            rfc5912::SHA_256_WITH_RSA_ENCRYPTION => Ok(Hash::Sha256),
+            rfc5912::SHA_384_WITH_RSA_ENCRYPTION => Ok(Hash::Sha384),
+            rfc5912::SHA_512_WITH_RSA_ENCRYPTION => Ok(Hash::Sha512),
+            rfc5912::RSA_ENCRYPTION => Self::from_digest(digest.ok_or_else(|| anyhow!("No digest algorithm"))?),
+            other => bail!("Unsupported signature algorithm {}", other),
+        }
+    }
+
+    fn digest(self, data: &[u8]) -> Vec<u8> {
+        match self {
+            Hash::Sha1 => Sha1::digest(data).to_vec(),
+            Hash::Sha256 => Sha256::digest(data).to_vec(),
+            Hash::Sha384 => Sha384::digest(data).to_vec(),
+            Hash::Sha512 => Sha512::digest(data).to_vec(),
+        }
+    }
+
+    fn padding(self) -> Pkcs1v15Sign {
+        match self {
+            Hash::Sha1 => Pkcs1v15Sign::new::<Sha1>(),
+            Hash::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+            Hash::Sha384 => Pkcs1v15Sign::new::<Sha384>(),
+            Hash::Sha512 => Pkcs1v15Sign::new::<Sha512>(),
+        }
+    }
+}
+
+/// `data` re-encoded with definite lengths and unsegmented strings, which is
+/// all the DER parser needs: much S/MIME mail is streamed as BER.
+fn ber_to_der(data: &[u8]) -> Result<Vec<u8>> {
+    let (tag, content, _) = ber_element(data, 0)?;
+    Ok(der_element(&tag, &content))
+}
+
+/// The tag and DER content of the BER element at the start of `data`, and
+/// what follows it.
+fn ber_element(data: &[u8], depth: usize) -> Result<(Vec<u8>, Vec<u8>, &[u8])> {
+    let truncated = || anyhow!("Truncated BER data");
+    if depth > 64 {
+        bail!("BER data is nested too deeply");
+    }
+    let first = *data.first().ok_or_else(truncated)?;
+    let mut at = 1;
+    if first & 0x1F == 0x1F {
+        while *data.get(at).ok_or_else(truncated)? & 0x80 != 0 {
+            at += 1;
+        }
+        at += 1;
+    }
+    let mut tag = data[..at].to_vec();
+    let length = match *data.get(at).ok_or_else(truncated)? {
+        0x80 => None,
+        byte if byte & 0x80 == 0 => Some(byte as usize),
+        byte => {
+            let count = (byte & 0x7F) as usize;
+            if count > 4 {
+                bail!("BER length is too long");
+            }
+            let bytes = data.get(at + 1..at + 1 + count).ok_or_else(truncated)?;
+            at += count;
+            Some(bytes.iter().fold(0, |length, &b| length << 8 | b as usize))
+        }
+    };
+    at += 1;
+
+    if first & 0x20 == 0 {
+        let length = length.ok_or_else(|| anyhow!("Indefinite length on a primitive BER element"))?;
+        let content = data.get(at..at + length).ok_or_else(truncated)?;
+        return Ok((tag, content.to_vec(), &data[at + length..]));
+    }
+
+    // Children as (their original first tag byte, tag, content)
+    let mut children = Vec::new();
+    let (mut inner, rest) = match length {
+        Some(length) => (data.get(at..at + length).ok_or_else(truncated)?, &data[at + length..]),
+        None => (&data[at..], &data[at..]),
+    };
+    let rest = loop {
+        if length.is_none() && inner.starts_with(&[0, 0]) {
+            break &inner[2..];
+        }
+        if length.is_some() && inner.is_empty() {
+            break rest;
+        }
+        let (child_tag, child_content, after) = ber_element(inner, depth + 1)?;
+        children.push((inner[0], child_tag, child_content));
+        inner = after;
+    };
+
+    // An OCTET STRING in segments, or one under an implicit tag, like the
+    // encryptedContent of enveloped data. A lone OCTET STRING under a
+    // context tag of definite length is taken to be explicitly tagged.
+    let segments = !children.is_empty() && children.iter().all(|(first, _, _)| *first == 0x04);
+    let implicit = first & 0xC0 == 0x80 && segments && (length.is_none() || children.len() > 1);
+    if first == 0x24 || implicit {
+        tag[0] &= !0x20;
+        let content = children.into_iter().flat_map(|(_, _, content)| content).collect();
+        return Ok((tag, content, rest));
+    }
+    let content = children.iter().flat_map(|(_, tag, content)| der_element(tag, content)).collect();
+    Ok((tag, content, rest))
+}
+
+fn der_element(tag: &[u8], content: &[u8]) -> Vec<u8> {
+    let mut out = tag.to_vec();
+    if content.len() < 0x80 {
+        out.push(content.len() as u8);
+    } else {
+        let length = content.len().to_be_bytes();
+        let skip = length.iter().take_while(|&&b| b == 0).count();
+        out.push(0x80 | (length.len() - skip) as u8);
+        out.extend_from_slice(&length[skip..]);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+fn time(time: &Time) -> DateTime<Utc> {
+    time.to_system_time().into()
+}
+
+fn is_valid(certificate: &Certificate, now: SystemTime) -> bool {
+    let validity = &certificate.tbs_certificate.validity;
+    validity.not_before.to_system_time() <= now && now <= validity.not_after.to_system_time()
+}
+
+fn is_ca(certificate: &Certificate) -> bool {
+    matches!(certificate.tbs_certificate.get::<BasicConstraints>(), Ok(Some((_, constraints))) if constraints.ca)
+}
+
+fn is_self_signed(certificate: &Certificate) -> bool {
+    certificate.tbs_certificate.subject == certificate.tbs_certificate.issuer
+}
+
+/// Email addresses from the subject and its alternative names.
+fn addresses(certificate: &Certificate) -> Vec<String> {
+    let mut addresses = Vec::new();
+    for rdn in certificate.tbs_certificate.subject.0.iter() {
+        for attribute in rdn.0.iter().filter(|attribute| attribute.oid == rfc3280::EMAIL_ADDRESS) {
+            addresses.push(String::from_utf8_lossy(attribute.value.value()).to_lowercase());
+        }
+    }
+    if let Ok(Some((_, SubjectAltName(names)))) = certificate.tbs_certificate.get::<SubjectAltName>() {
+        for name in names {
+            if let GeneralName::Rfc822Name(address) = name {
+                addresses.push(address.to_string().to_lowercase());
+            }
+        }
+    }
+    let mut unique = Vec::new();
+    for address in addresses {
+        if !unique.contains(&address) {
+            unique.push(address);
+        }
+    }
+    unique
+}
+
+fn key_identifier(certificate: &Certificate) -> Option<SubjectKeyIdentifier> {
+    certificate.tbs_certificate.get::<SubjectKeyIdentifier>().ok().flatten().map(|(_, id)| id)
+}
+
+fn issuer_and_serial(certificate: &Certificate) -> IssuerAndSerialNumber {
+    IssuerAndSerialNumber {
+        issuer: certificate.tbs_certificate.issuer.clone(),
+        serial_number: certificate.tbs_certificate.serial_number.clone(),
+    }
+}
+
+fn is_signer(certificate: &Certificate, sid: &SignerIdentifier) -> bool {
+    match sid {
+        SignerIdentifier::IssuerAndSerialNumber(id) => *id == issuer_and_serial(certificate),
+        SignerIdentifier::SubjectKeyIdentifier(id) => key_identifier(certificate).as_ref() == Some(id),
+    }
+}
+
+fn is_recipient(certificate: &Certificate, rid: &RecipientIdentifier) -> bool {
+    match rid {
+        RecipientIdentifier::IssuerAndSerialNumber(id) => *id == issuer_and_serial(certificate),
+        RecipientIdentifier::SubjectKeyIdentifier(id) => key_identifier(certificate).as_ref() == Some(id),
+    }
+}
+
+fn rsa_public_key(key: &SubjectPublicKeyInfoOwned) -> Result<RsaPublicKey> {
+    RsaPublicKey::from_public_key_der(&key.to_der()?).context("Only RSA keys are supported")
+}
+
+fn verify_rsa(key: &SubjectPublicKeyInfoOwned, hash: Hash, data: &[u8], signature: &[u8]) -> Result<bool> {
+    Ok(rsa_public_key(key)?.verify(hash.padding(), &hash.digest(data), signature).is_ok())
+}
+
+fn issued_by(certificate: &Certificate, issuer: &Certificate) -> bool {
+    let check = || -> Result<bool> {
+        let hash = Hash::from_signature(certificate.signature_algorithm.oid, None)?;
+        let signature = certificate.signature.as_bytes().ok_or_else(|| anyhow!("Malformed signature"))?;
+        let tbs = certificate.tbs_certificate.to_der()?;
+        verify_rsa(&issuer.tbs_certificate.subject_public_key_info, hash, &tbs, signature)
+    };
+    certificate.tbs_certificate.issuer == issuer.tbs_certificate.subject && check().unwrap_or(false)
+}
+
+/// What the store lists about a DER certificate.
+pub fn x509_data(der: &[u8]) -> Result<X509Data> {
+    let certificate = Certificate::from_der(der)?;
+    let tbs = &certificate.tbs_certificate;
+    Ok(X509Data {
+        fingerprint: fingerprint(der),
+        subject: tbs.subject.to_string(),
+        issuer: tbs.issuer.to_string(),
+        addresses: addresses(&certificate),
+        not_before: time(&tbs.validity.not_before),
+        not_after: time(&tbs.validity.not_after),
+        is_ca: is_ca(&certificate),
+        data: der.to_vec(),
+    })
+}
+
+/// The certificates in `data`: PEM, a DER certificate, or a PKCS#7
+/// certificate bundle (.p7b).
+pub fn read_certificates(data: &[u8]) -> Result<Vec<X509Data>> {
+    let is_pem = data.windows(27).any(|window| window == b"-----BEGIN CERTIFICATE-----");
+    let certificates = if is_pem {
+        Certificate::load_pem_chain(data)?
+    } else {
+        let data = ber_to_der(data)?;
+        match Certificate::from_der(&data) {
+            Ok(certificate) => vec![certificate],
+            Err(_) => {
+                let info = ContentInfo::from_der(&data)?;
+                if info.content_type != rfc5911::ID_SIGNED_DATA {
+                    bail!("Not a certificate or certificate bundle");
+                }
+                carried_certificates(&info.content.decode_as::<SignedData>()?)
+            }
+        }
+    };
+    certificates.iter().map(|certificate| x509_data(&certificate.to_der()?)).collect()
+}
+
+fn carried_certificates(signed: &SignedData) -> Vec<Certificate> {
+    let choices = signed.certificates.iter().flat_map(|set| set.0.iter());
+    choices
+        .filter_map(|choice| match choice {
+            CertificateChoices::Certificate(certificate) => Some(certificate.clone()),
+            CertificateChoices::Other(_) => None,
+        })
+        .collect()
+}
+
+/// The certificates a signature is checked with: those the message carries,
+/// those we know, and the trust anchors.
+struct Store {
+    certificates: Vec<Certificate>,
+    anchors: Vec<Certificate>,
+}
+
+impl Store {
+    fn new(keys: &Keys, carried: &[Certificate]) -> Self {
+        let parse = |der: &Vec<u8>| Certificate::from_der(der).ok();
+        let mut certificates = carried.to_vec();
+        certificates.extend(keys.x509_certificates.iter().filter_map(parse));
+        certificates.extend(keys.identities.iter().map(|identity| &identity.certificate).filter_map(parse));
+        let anchors = keys.trust_anchors.iter().filter_map(parse).collect();
+        Store { certificates, anchors }
+    }
+
+    /// Whether `certificate` chains to a trust anchor through at most
+    /// `depth` more issuers, each a CA that is valid now.
+    fn is_trusted(&self, certificate: &Certificate, depth: usize, now: SystemTime) -> bool {
+        if self.anchors.contains(certificate) {
+            return true;
+        }
+        depth > 0
+            && self
+                .anchors
+                .iter()
+                .chain(self.certificates.iter().filter(|issuer| is_ca(issuer) && is_valid(issuer, now)))
+                .filter(|issuer| *issuer != certificate && issued_by(certificate, issuer))
+                .any(|issuer| self.is_trusted(issuer, depth - 1, now))
+    }
+
+    /// The status of `signer`'s signature, which is only good when its
+    /// certificate is for `sender`, the message's From address.
+    fn check(&self, signer: &SignerInfo, content_type: ObjectIdentifier, content: &[u8], sender: &str) -> SignatureInfo {
+        let created_at = signing_time(signer);
+        let certificate = self.certificates.iter().chain(&self.anchors).find(|certificate| is_signer(certificate, &signer.sid));
+        let Some(certificate) = certificate else {
+            return SignatureInfo { status: SignatureStatus::UnknownKey, fingerprint: None, signer: None, created_at };
+        };
+        let now = SystemTime::now();
+        let addresses = addresses(certificate);
+        let status = match check_signature(signer, content_type, content, certificate) {
+            Ok(true) if !is_valid(certificate, now) => SignatureStatus::Expired,
+            Ok(true) if !addresses.contains(&sender.trim().to_lowercase()) => SignatureStatus::SignerMismatch,
+            Ok(true) if !self.is_trusted(certificate, MAX_PATH, now) => SignatureStatus::Untrusted,
+            Ok(true) => SignatureStatus::Good,
+            Ok(false) | Err(_) => SignatureStatus::Bad,
+        };
+        let signer = addresses.into_iter().next().unwrap_or_else(|| certificate.tbs_certificate.subject.to_string());
+        SignatureInfo {
+            status,
+            fingerprint: certificate.to_der().ok().map(|der| fingerprint(&der)),
+            signer: Some(signer),
+            created_at,
+        }
+    }
+}
+
+fn signed_attribute(signer: &SignerInfo, oid: ObjectIdentifier) -> Option<&Any> {
+    let attributes = signer.signed_attrs.as_ref()?;
+    attributes.iter().find(|attribute| attribute.oid == oid)?.values.iter().next()
+}
+
+fn signing_time(signer: &SignerInfo) -> Option<DateTime<Utc>> {
+    let value = signed_attribute(signer, rfc5911::ID_SIGNING_TIME)?;
+    Time::from_der(&value.to_der().ok()?).ok().map(|signed_at| time(&signed_at))
+}
+
+/// Whether `signer`'s signature over `content` was made with
+/// `certificate`'s key.
+fn check_signature(signer: &SignerInfo, content_type: ObjectIdentifier, content: &[u8], certificate: &Certificate) -> Result<bool> {
+    let hash = Hash::from_signature(signer.signature_algorithm.oid, Some(signer.digest_alg.oid))?;
+    let signed = match &signer.signed_attrs {
+        None => content.to_vec(),
+        Some(attributes) => {
+            let digest = Hash::from_digest(signer.digest_alg.oid)?.digest(content);
+            let message_digest = signed_attribute(signer, rfc5911::ID_MESSAGE_DIGEST)
+                .ok_or_else(|| anyhow!("No message digest"))?
+                .decode_as::<OctetString>()?;
+            let signed_type = signed_attribute(signer, rfc5911::ID_CONTENT_TYPE)
+                .ok_or_else(|| anyhow!("No content type"))?
+                .decode_as::<ObjectIdentifier>()?;
+            if message_digest.as_bytes() != digest || signed_type != content_type {
+                return Ok(false);
+            }
+            attributes.to_der()?
+        }
+    };
+    let key = &certificate.tbs_certificate.subject_public_key_info;
+    verify_rsa(key, hash, &signed, signer.signature.as_bytes())
+}
+
+/// Checks every signature in `signed` over `content`, from `sender`.
+fn verify(signed: &SignedData, content: Vec<u8>, sender: &str, keys: &Keys) -> Result<OpenedMessage> {
+    let carried = carried_certificates(signed);
+    let store = Store::new(keys, &carried);
+    let content_type = signed.encap_content_info.econtent_type;
+    let signatures = signed.signer_infos.0.iter().map(|signer| store.check(signer, content_type, &content, sender)).collect();
+    Ok(OpenedMessage {
+        content,
+        is_mime: true,
+        encrypted: false,
+        signatures,
+        certificates: carried.iter().map(|certificate| certificate.to_der()).collect::<der::Result<_>>()?,
+    })
+}
+
+/// Checks a detached signature, the body of an application/pkcs7-signature
+/// part, over `signed` from `sender`.
+pub fn verify_detached(signed: &[u8], signature: &[u8], sender: &str, keys: &Keys) -> Result<OpenedMessage> {
+    let info = ContentInfo::from_der(&ber_to_der(signature)?)?;
+    if info.content_type != rfc5911::ID_SIGNED_DATA {
+        bail!("Not an S/MIME signature");
+    }
+    verify(&info.content.decode_as()?, signed.to_vec(), sender, keys)
+}
+
+/// Opens the body of an application/pkcs7-mime entity: decrypts enveloped
+/// data, or verifies signed data from `sender` and unwraps what it signs.
+pub fn open(data: &[u8], sender: &str, keys: &Keys) -> Result<OpenedMessage> {
+    let info = ContentInfo::from_der(&ber_to_der(data)?)?;
+    match info.content_type {
+        rfc5911::ID_ENVELOPED_DATA => Ok(OpenedMessage {
+            content: decrypt(&info.content.decode_as()?, keys)?,
+            is_mime: true,
+            encrypted: true,
+            signatures: Vec::new(),
+            certificates: Vec::new(),
+        }),
+        rfc5911::ID_SIGNED_DATA => {
+            let signed: SignedData = info.content.decode_as()?;
+            let content = signed.encap_content_info.econtent.as_ref().ok_or_else(|| anyhow!("Signed data has no content"))?;
+            verify(&signed, content.decode_as::<OctetString>()?.into_bytes(), sender, keys)
+        }
+        other => bail!("Unsupported CMS content type {}", other),
+    }
+}
+
+fn cbc_decrypt<C>(key: &[u8], iv: &[u8], data: &[u8]) -> Result<Vec<u8>>
+where
+    C: BlockCipher + BlockDecryptMut + KeyInit,
+{
+    cbc::Decryptor::<C>::new_from_slices(key, iv)
+        .map_err(|_| anyhow!("Wrong key or IV length"))?
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| anyhow!("Failed to decrypt"))
+}
+
+fn decrypt(enveloped: &EnvelopedData, keys: &Keys) -> Result<Vec<u8>> {
+    for identity in keys.identities {
+        let certificate = Certificate::from_der(&identity.certificate)?;
+        let recipient = enveloped.recip_infos.0.iter().find_map(|info| match info {
+            RecipientInfo::Ktri(info) if is_recipient(&certificate, &info.rid) => Some(info),
+            _ => None,
+        });
+        let Some(recipient) = recipient else {
+            continue;
+        };
+
+        let key = RsaPrivateKey::from_pkcs8_der(&identity.private_key)?;
+        let encrypted_key = recipient.enc_key.as_bytes();
+        let default_parameters = recipient.key_enc_alg.parameters.as_ref().map_or(true, |parameters| parameters.value().is_empty());
+        let content_key = match recipient.key_enc_alg.oid {
+            rfc5912::RSA_ENCRYPTION => key.decrypt(Pkcs1v15Encrypt, encrypted_key)?,
+            // SHA-1 throughout, unless the parameters say otherwise
+            rfc5912::ID_RSAES_OAEP if default_parameters => key.decrypt(Oaep::new::<Sha1>(), encrypted_key)?,
+            other => bail!("Unsupported key encryption algorithm {}", other),
+        };
+
+        let content = &enveloped.encrypted_content;
+        let data = content.encrypted_content.as_ref().ok_or_else(|| anyhow!("Enveloped data has no content"))?;
+        let iv = content.content_enc_alg.parameters.as_ref().ok_or_else(|| anyhow!("No IV"))?.decode_as::<OctetString>()?;
+        let (data, iv) = (data.as_bytes(), iv.as_bytes());
+        return match content.content_enc_alg.oid {
+            rfc5911::ID_AES_128_CBC => cbc_decrypt::<aes::Aes128>(&content_key, iv, data),
+            rfc5911::ID_AES_192_CBC => cbc_decrypt::<aes::Aes192>(&content_key, iv, data),
+            rfc5911::ID_AES_256_CBC => cbc_decrypt::<aes::Aes256>(&content_key, iv, data),
+            rfc5911::DES_EDE_3_CBC => cbc_decrypt::<des::TdesEde3>(&content_key, iv, data),
+            other => bail!("Unsupported content encryption algorithm {}", other),
+        };
+    }
+    bail!("The message isn't encrypted to any of our S/MIME identities")
+}
+
+/// `certificate` and the intermediate certificates we know that issued it,
+/// for recipients to build a path with. Roots are left to them.
+fn with_issuers(certificate: Certificate, keys: &Keys) -> Vec<Certificate> {
+    let known: Vec<Certificate> = keys.x509_certificates.iter().filter_map(|der| Certificate::from_der(der).ok()).collect();
+    let mut chain = vec![certificate];
+    while chain.len() < MAX_PATH {
+        let last = chain.last().expect("chain starts with the certificate");
+        match known.iter().find(|issuer| !is_self_signed(issuer) && issued_by(last, issuer) && !chain.contains(issuer)) {
+            Some(issuer) => chain.push(issuer.clone()),
+            None => break,
+        }
+    }
+    chain
+}
+
+/// A detached signature over `entity` with our first identity, carrying
+/// its certificate chain.
+pub fn sign_detached(entity: &[u8], keys: &Keys) -> Result<Vec<u8>> {
+    let identity = keys.identities.first().ok_or_else(|| anyhow!("No S/MIME identity to sign with"))?;
+    let certificate = Certificate::from_der(&identity.certificate)?;
+    let signer = SigningKey::<Sha256>::new(RsaPrivateKey::from_pkcs8_der(&identity.private_key)?);
+    let content = EncapsulatedContentInfo { econtent_type: rfc5911::ID_DATA, econtent: None };
+    let digest = Sha256::digest(entity);
+    let digest_algorithm = AlgorithmIdentifierOwned { oid: rfc5912::ID_SHA_256, parameters: None };
+    let sid = SignerIdentifier::IssuerAndSerialNumber(issuer_and_serial(&certificate));
+
+    let build = || -> std::result::Result<Vec<u8>, cms::builder::Error> {
+        let mut signer_info = SignerInfoBuilder::new(&signer, sid, digest_algorithm.clone(), &content, Some(&digest))?;
+        signer_info.add_signed_attribute(create_signing_time_attribute()?)?;
+        let mut builder = SignedDataBuilder::new(&content);
+        builder.add_digest_algorithm(digest_algorithm.clone())?;
+        for certificate in with_issuers(certificate, keys) {
+            builder.add_certificate(CertificateChoices::Certificate(certificate))?;
+        }
+        builder.add_signer_info::<_, rsa::pkcs1v15::Signature>(signer_info)?;
+        Ok(builder.build()?.to_der()?)
+    };
+    build().map_err(|e| anyhow!("Failed to sign: {}", e))
+}
+
+/// Enveloped data for `entity`, encrypted to the newest valid certificate
+/// we have for each of `recipients` and to our first identity, so sent
+/// mail stays readable.
+pub fn encrypt(entity: &[u8], recipients: &[&str], keys: &Keys) -> Result<Vec<u8>> {
+    let now = SystemTime::now();
+    let known: Vec<Certificate> = keys.x509_certificates.iter().filter_map(|der| Certificate::from_der(der).ok()).collect();
+    let mut certificates = Vec::new();
+    for recipient in recipients {
+        let recipient = recipient.trim().to_lowercase();
+        let certificate = known
+            .iter()
+            .filter(|certificate| is_valid(certificate, now) && addresses(certificate).contains(&recipient))
+            .max_by_key(|certificate| certificate.tbs_certificate.validity.not_after.to_unix_duration())
+            .ok_or_else(|| anyhow!("No S/MIME certificate for {}", recipient))?;
+        certificates.push(certificate.clone());
+    }
+    if let Some(identity) = keys.identities.first() {
+        certificates.push(Certificate::from_der(&identity.certificate)?);
+    }
+    let public_keys = certificates
+        .iter()
+        .map(|certificate| rsa_public_key(&certificate.tbs_certificate.subject_public_key_info))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut rngs: Vec<_> = certificates.iter().map(|_| rand::thread_rng()).collect();
+    let build = || -> std::result::Result<EnvelopedData, cms::builder::Error> {
+        let mut builder = EnvelopedDataBuilder::new(None, entity, ContentEncryptionAlgorithm::Aes256Cbc, None)?;
+        for ((certificate, key), rng) in certificates.iter().zip(public_keys).zip(&mut rngs) {
+            let rid = RecipientIdentifier::IssuerAndSerialNumber(issuer_and_serial(certificate));
+            builder.add_recipient_info(KeyTransRecipientInfoBuilder::new(rid, KeyEncryptionInfo::Rsa(key), rng)?)?;
+        }
+        builder.build_with_rng(&mut rand::thread_rng())
+    };
+    let enveloped = build().map_err(|e| anyhow!("Failed to encrypt: {}", e))?;
+    let info = ContentInfo { content_type: rfc5911::ID_ENVELOPED_DATA, content: Any::encode_from(&enveloped)? };
+    Ok(info.to_der()?)
+}
+
+/// The identity in a PKCS#12 file (.p12 or .pfx), and the other
+/// certificates it holds, usually the issuer chain.
+pub fn read_pkcs12(data: &[u8], password: &str) -> Result<(Identity, Vec<Vec<u8>>)> {
+    let pfx = Pfx::from_der(&ber_to_der(data)?)?;
+    if pfx.auth_safe.content_type != rfc5911::ID_DATA {
+        bail!("Only password-protected PKCS#12 files are supported");
+    }
+    let auth_safe = pfx.auth_safe.content.decode_as::<OctetString>()?;
+    if let Some(mac_data) = &pfx.mac_data {
+        check_mac(mac_data, password, auth_safe.as_bytes())?;
+    }
+
+    let mut private_keys = Vec::new();
+    let mut certificates = Vec::new();
+    for info in Vec::<ContentInfo>::from_der(auth_safe.as_bytes())? {
+        let bags = match info.content_type {
+            rfc5911::ID_DATA => info.content.decode_as::<OctetString>()?.into_bytes(),
+            rfc5911::ID_ENCRYPTED_DATA => {
+                let encrypted: EncryptedData = info.content.decode_as()?;
+                let content = &encrypted.enc_content_info;
+                let data = content.encrypted_content.as_ref().ok_or_else(|| anyhow!("Encrypted data has no content"))?;
+                decrypt_pbe(&content.content_enc_alg, password, data.as_bytes())?
+            }
+            // Contents protected with a public key, which we can't open
+            _ => continue,
+        };
+        for bag in SafeContents::from_der(&bags)? {
+            let value = ContextSpecific::<Any>::from_der(&bag.bag_value)?.value;
+            match bag.bag_id {
+                pkcs12::PKCS_12_KEY_BAG_OID => private_keys.push(value.to_der()?),
+                pkcs12::PKCS_12_PKCS8_KEY_BAG_OID => {
+                    let shrouded: EncryptedPrivateKeyInfo = value.decode_as()?;
+                    private_keys.push(decrypt_pbe(&shrouded.encryption_algorithm, password, shrouded.encrypted_data.as_bytes())?);
+                }
+                pkcs12::PKCS_12_CERT_BAG_OID => {
+                    let bag: CertBag = value.decode_as()?;
+                    if bag.cert_id == pkcs12::PKCS_12_X509_CERT_OID {
+                        certificates.push(bag.cert_value.into_bytes());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let [private_key] = &private_keys[..] else {
+        bail!("Expected one private key in the file, found {}", private_keys.len());
+    };
+    let key = RsaPrivateKey::from_pkcs8_der(private_key).context("Only RSA keys are supported")?;
+    let public_key = RsaPublicKey::from(key).to_public_key_der()?;
+    let index = certificates
+        .iter()
+        .position(|der| {
+            let certificate = Certificate::from_der(der).ok();
+            certificate.and_then(|c| c.tbs_certificate.subject_public_key_info.to_der().ok()).as_deref() == Some(public_key.as_bytes())
+        })
+        .ok_or_else(|| anyhow!("The file has no certificate for its private key"))?;
+    let certificate = certificates.remove(index);
+    Ok((Identity { certificate, private_key: private_key.clone() }, certificates))
+}
+
+fn mac_matches<D>(password: &str, mac_data: &MacData, content: &[u8]) -> Result<bool>
+where
+    D: Digest + FixedOutputReset + BlockSizeUser,
+{
+    let salt = mac_data.mac_salt.as_bytes();
+    let key = derive_key_utf8::<D>(password, salt, Pkcs12KeyType::Mac, mac_data.iterations, <D as Digest>::output_size())?;
+    let mut mac = <SimpleHmac<D> as Mac>::new_from_slice(&key).expect("HMAC takes keys of any length");
+    mac.update(content);
+    Ok(mac.verify_slice(mac_data.mac.digest.as_bytes()).is_ok())
+}
+
+/// Checks the file's MAC, which is keyed with the password.
+fn check_mac(mac_data: &MacData, password: &str, content: &[u8]) -> Result<()> {
+    let matches = match mac_data.mac.algorithm.oid {
+        rfc5912::ID_SHA_1 => mac_matches::<Sha1>(password, mac_data, content)?,
+        rfc5912::ID_SHA_256 => mac_matches::<Sha256>(password, mac_data, content)?,
+        rfc5912::ID_SHA_512 => mac_matches::<Sha512>(password, mac_data, content)?,
+        other => bail!("Unsupported PKCS#12 MAC algorithm {}", other),
+    };
+    if !matches {
+        bail!("Wrong password, or the file is damaged");
+    }
+    Ok(())
+}
+
+/// Decrypts PKCS#12 contents: PBES2, as current tools write, or the SHA-1
+/// schemes with 3DES or RC2 that older ones do.
+fn decrypt_pbe(algorithm: &AlgorithmIdentifierOwned, password: &str, data: &[u8]) -> Result<Vec<u8>> {
+    let legacy = |key_len: usize| -> Result<(Vec<u8>, Vec<u8>)> {
+        let parameters = algorithm.parameters.as_ref().ok_or_else(|| anyhow!("No PBE parameters"))?;
+        let parameters: Pkcs12PbeParams = parameters.decode_as()?;
+        let salt = parameters.salt.as_bytes();
+        let key = derive_key_utf8::<Sha1>(password, salt, Pkcs12KeyType::EncryptionKey, parameters.iterations, key_len)?;
+        let iv = derive_key_utf8::<Sha1>(password, salt, Pkcs12KeyType::Iv, parameters.iterations, 8)?;
+        Ok((key, iv))
+    };
+    let decrypted = match algorithm.oid {
+        pkcs5::pbes2::PBES2_OID => {
+            let der = algorithm.to_der()?;
+            let scheme = pkcs5::EncryptionScheme::try_from(der.as_slice())?;
+            scheme.decrypt(password, data).map_err(|_| anyhow!("Failed to decrypt"))
+        }
+        pkcs12::PKCS_12_PBE_WITH_SHAAND3_KEY_TRIPLE_DES_CBC => {
+            let (key, iv) = legacy(24)?;
+            cbc_decrypt::<des::TdesEde3>(&key, &iv, data)
+        }
+        pkcs12::PKCS_12_PBE_WITH_SHAAND128_BIT_RC2_CBC => {
+            let (key, iv) = legacy(16)?;
+            cbc_decrypt::<rc2::Rc2>(&key, &iv, data)
+        }
+        pkcs12::PKCS_12_PBEWITH_SHAAND40_BIT_RC2_CBC => {
+            let (key, iv) = legacy(5)?;
+            cbc_decrypt::<rc2::Rc2>(&key, &iv, data)
+        }
+        other => bail!("Unsupported PKCS#12 encryption {}", other),
+    };
+    decrypted.context("Wrong password")
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use der::asn1::Ia5String;
+    use der::EncodePem;
+    use pkcs12::digest_info::DigestInfo;
+    use rsa::pkcs8::EncodePrivateKey;
+    use std::str::FromStr;
+    use std::time::Duration;
+    use x509_cert::builder::{Builder, CertificateBuilder, Profile};
+    use x509_cert::name::Name;
+    use x509_cert::serial_number::SerialNumber;
+    use x509_cert::time::Validity;
+
+    fn rsa_key() -> RsaPrivateKey {
+        // Small keys keep the tests quick
+        RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap()
+    }
+
+    fn spki(key: &RsaPrivateKey) -> SubjectPublicKeyInfoOwned {
+        SubjectPublicKeyInfoOwned::from_key(RsaPublicKey::from(key)).unwrap()
+    }
+
+    /// A root CA and its key.
+    pub(crate) fn ca() -> (Certificate, RsaPrivateKey) {
+        let key = rsa_key();
+        let signer = SigningKey::<Sha256>::new(key.clone());
+        let builder = CertificateBuilder::new(
+            Profile::Root,
+            SerialNumber::from(1u32),
+            Validity::from_now(Duration::from_secs(3600)).unwrap(),
+            Name::from_str("CN=Test CA").unwrap(),
+            spki(&key),
+            &signer,
+        )
+        .unwrap();
+        (builder.build::<rsa::pkcs1v15::Signature>().unwrap(), key)
+    }
+
+    /// An identity for `address` issued by `ca`.
+    pub(crate) fn identity(address: &str, ca: &(Certificate, RsaPrivateKey)) -> Identity {
+        let key = rsa_key();
+        let signer = SigningKey::<Sha256>::new(ca.1.clone());
+        let issuer = ca.0.tbs_certificate.subject.clone();
+        let profile = Profile::Leaf { issuer, enable_key_agreement: false, enable_key_encipherment: true };
+        let mut builder = CertificateBuilder::new(
+            profile,
+            SerialNumber::from(rand::random::<u32>()),
+            Validity::from_now(Duration::from_secs(3600)).unwrap(),
+            Name::from_str(&format!("CN={}", address)).unwrap(),
+            spki(&key),
+            &signer,
+        )
+        .unwrap();
+        let names = SubjectAltName(vec![GeneralName::Rfc822Name(Ia5String::new(address).unwrap())]);
+        builder.add_extension(&names).unwrap();
+        let certificate = builder.build::<rsa::pkcs1v15::Signature>().unwrap();
+        Identity { certificate: certificate.to_der().unwrap(), private_key: key.to_pkcs8_der().unwrap().as_bytes().to_vec() }
+    }
+
+    /// `identity` and the CA certificate as a PKCS#12 file, as exported
+    /// from another mail client.
+    pub(crate) fn pkcs12(identity: &Identity, ca: &Certificate, password: &str) -> Vec<u8> {
+        // Encoding wraps the value in its [0] tag, though decoding keeps it
+        let bag = |bag_id, bag_value| pkcs12::safe_bag::SafeBag { bag_id, bag_value, bag_attributes: None };
+        let certificate_bag = |der: &[u8]| {
+            let bag = CertBag { cert_id: pkcs12::PKCS_12_X509_CERT_OID, cert_value: OctetString::new(der).unwrap() };
+            bag.to_der().unwrap()
+        };
+        let bags = vec![
+            bag(pkcs12::PKCS_12_CERT_BAG_OID, certificate_bag(&ca.to_der().unwrap())),
+            bag(pkcs12::PKCS_12_KEY_BAG_OID, identity.private_key.clone()),
+            bag(pkcs12::PKCS_12_CERT_BAG_OID, certificate_bag(&identity.certificate)),
+        ];
+        let data = |bytes: Vec<u8>| ContentInfo {
+            content_type: rfc5911::ID_DATA,
+            content: Any::encode_from(&OctetString::new(bytes).unwrap()).unwrap(),
+        };
+        let auth_safe = vec![data(bags.to_der().unwrap())].to_der().unwrap();
+
+        let salt = [7u8; 8];
+        let key = derive_key_utf8::<Sha256>(password, &salt, Pkcs12KeyType::Mac, 1000, 32).unwrap();
+        let mut mac = <SimpleHmac<Sha256> as Mac>::new_from_slice(&key).unwrap();
+        mac.update(&auth_safe);
+        Pfx {
+            version: pkcs12::pfx::Version::V3,
+            auth_safe: data(auth_safe),
+            mac_data: Some(MacData {
+                mac: DigestInfo {
+                    algorithm: AlgorithmIdentifierOwned { oid: rfc5912::ID_SHA_256, parameters: None },
+                    digest: OctetString::new(mac.finalize().into_bytes().to_vec()).unwrap(),
+                },
+                mac_salt: OctetString::new(salt.to_vec()).unwrap(),
+                iterations: 1000,
+            }),
+        }
+        .to_der()
+        .unwrap()
+    }
+
+    fn keys<'a>(identities: &'a [Identity], certificates: &'a [Vec<u8>], anchors: &'a [Vec<u8>]) -> Keys<'a> {
+        Keys { identities, x509_certificates: certificates, trust_anchors: anchors, ..Keys::default() }
+    }
+
+    #[test]
+    fn test_certificate_data() {
+        let ca = ca();
+        let alice = identity("Alice@Example.org", &ca);
+        let data = x509_data(&alice.certificate).unwrap();
+        assert_eq!(data.addresses, vec!["alice@example.org"]);
+        assert_eq!(data.subject, "CN=Alice@Example.org");
+        assert_eq!(data.issuer, "CN=Test CA");
+        assert!(!data.is_ca);
+        assert_eq!(data.fingerprint.len(), 64);
+        assert!(x509_data(&ca.0.to_der().unwrap()).unwrap().is_ca);
+
+        let pem = ca.0.to_pem(der::pem::LineEnding::LF).unwrap();
+        let read = read_certificates(pem.as_bytes()).unwrap();
+        assert_eq!(read.len(), 1);
+        assert!(read[0].is_ca);
+        assert_eq!(read_certificates(&alice.certificate).unwrap()[0].fingerprint, data.fingerprint);
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let ca = ca();
+        let alice = identity("alice@example.org", &ca);
+        let anchor = vec![ca.0.to_der().unwrap()];
+        let entity = b"Content-Type: text/plain\r\n\r\nHello";
+        let signature = sign_detached(entity, &keys(std::slice::from_ref(&alice), &[], &[])).unwrap();
+
+        // The signature carries Alice's certificate, so only the anchor is needed
+        let opened = verify_detached(entity, &signature, "alice@example.org", &keys(&[], &[], &anchor)).unwrap();
+        assert_eq!(opened.content, entity);
+        assert_eq!(opened.signatures.len(), 1);
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Good);
+        assert_eq!(opened.signatures[0].signer.as_deref(), Some("alice@example.org"));
+        assert!(opened.signatures[0].created_at.is_some());
+        assert_eq!(opened.certificates, vec![alice.certificate.clone()]);
+
+        let opened = verify_detached(entity, &signature, "alice@example.org", &keys(&[], &[], &[])).unwrap();
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Untrusted);
+        // Alice's certificate doesn't vouch for mail claiming to be from Bob
+        let opened = verify_detached(entity, &signature, "bob@example.org", &keys(&[], &[], &anchor)).unwrap();
+        assert_eq!(opened.signatures[0].status, SignatureStatus::SignerMismatch);
+        let opened = verify_detached(entity, &signature, " Alice@Example.org", &keys(&[], &[], &anchor)).unwrap();
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Good);
+        let opened = verify_detached(b"Content-Type: text/plain\r\n\r\nHellO", &signature, "alice@example.org", &keys(&[], &[], &anchor)).unwrap();
+        assert_eq!(opened.signatures[0].status, SignatureStatus::Bad);
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt() {
+        let ca = ca();
+        let alice = identity("alice@example.org", &ca);
+        let bob = identity("bob@example.org", &ca);
+        let entity = b"Content-Type: text/plain\r\n\r\nSecret";
+
+        let known = [bob.certificate.clone()];
+        let encrypted = encrypt(entity, &["Bob@example.org"], &keys(std::slice::from_ref(&alice), &known, &[])).unwrap();
+        let opened = open(&encrypted, "alice@example.org", &keys(std::slice::from_ref(&bob), &[], &[])).unwrap();
+        assert!(opened.encrypted);
+        assert_eq!(opened.content, entity);
+        // Encrypted to ourselves too
+        assert_eq!(open(&encrypted, "alice@example.org", &keys(std::slice::from_ref(&alice), &[], &[])).unwrap().content, entity);
+
+        let carol = identity("carol@example.org", &ca);
+        assert!(open(&encrypted, "alice@example.org", &keys(&[carol], &[], &[])).is_err());
+        assert!(encrypt(entity, &["dave@example.org"], &keys(&[alice], &known, &[])).is_err());
+    }
+
+    #[test]
+    fn test_ber_to_der() {
+        // An indefinite-length SEQUENCE around an OCTET STRING in two segments
+        let ber = [0x30, 0x80, 0x24, 0x80, 0x04, 0x01, b'a', 0x04, 0x02, b'b', b'c', 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(ber_to_der(&ber).unwrap(), vec![0x30, 0x05, 0x04, 0x03, b'a', b'b', b'c']);
+        let der = [0x30, 0x03, 0x02, 0x01, 0x05];
+        assert_eq!(ber_to_der(&der).unwrap(), der);
+        assert!(ber_to_der(&[0x30, 0x80, 0x02, 0x01]).is_err());
+    }
+
+    #[test]
+    fn test_read_pkcs12() {
+        let ca = ca();
+        let alice = identity("alice@example.org", &ca);
+        let pfx = pkcs12(&alice, &ca.0, "secret");
+
+        let (identity, others) = read_pkcs12(&pfx, "secret").unwrap();
+        assert_eq!(identity, alice);
+        assert_eq!(others, vec![ca.0.to_der().unwrap()]);
+        assert!(read_pkcs12(&pfx, "wrong").unwrap_err().to_string().contains("Wrong password"));
+    }
+}
@@ -0,0 +1,142 @@
+//! The master password. Secrets at rest are sealed with AES-256-GCM under a
+//! key Argon2id derives from it, and that key is only held in memory while
+//! the vault is unlocked.
+
+use std::sync::Mutex;
+
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{anyhow, bail, Result};
+use argon2::Argon2;
+use rand::RngCore;
+use sqlx::SqliteConnection;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const VERIFIER: &[u8] = b"slopmail vault";
+
+/// The vault needs the master password before it can seal or open.
+#[derive(Debug, thiserror::Error)]
+#[error("The vault is locked")]
+pub struct Locked;
+
+#[derive(Default)]
+pub struct Vault {
+    cipher: Mutex<Option<Aes256Gcm>>,
+}
+
+fn derive(password: &str, salt: &[u8]) -> Result<Aes256Gcm> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive key: {}", e))?;
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+fn seal_with(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), plaintext)
+        .map_err(|_| anyhow!("Failed to seal secret"))?;
+    Ok([&nonce[..], &ciphertext].concat())
+}
+
+fn open_with(cipher: &Aes256Gcm, sealed: &[u8]) -> Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN {
+        bail!("Sealed secret is truncated");
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    cipher
+        .decrypt(Nonce::from_slice(nonce), ciphertext)
+        .map_err(|_| anyhow!("Failed to open secret"))
+}
+
+impl Vault {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Unlocks with `password`, setting it as the master password if there
+    /// isn't one yet.
+    pub async fn unlock(&self, conn: &mut SqliteConnection, password: &str) -> Result<()> {
+        let row: Option<(Vec<u8>, Vec<u8>)> = sqlx::query_as("SELECT salt, verifier FROM vault WHERE id = 1")
+            .fetch_optional(&mut *conn)
+            .await?;
+        let cipher = match row {
+            Some((salt, verifier)) => {
+                let cipher = derive(password, &salt)?;
+                if open_with(&cipher, &verifier).ok().as_deref() != Some(VERIFIER) {
+                    bail!("Wrong master password");
+                }
+                cipher
+            }
+            None => {
+                let mut salt = [0u8; SALT_LEN];
+                rand::thread_rng().fill_bytes(&mut salt);
+                let cipher = derive(password, &salt)?;
+                sqlx::query("INSERT INTO vault (id, salt, verifier) VALUES (1, ?, ?)")
+                    .bind(&salt[..])
+                    .bind(seal_with(&cipher, VERIFIER)?)
+                    .execute(&mut *conn)
+                    .await?;
+                cipher
+            }
+        };
+        *self.cipher.lock().expect("vault lock poisoned") = Some(cipher);
+        Ok(())
+    }
+
+    pub fn lock(&self) {
+        *self.cipher.lock().expect("vault lock poisoned") = None;
+    }
+
+    pub fn is_unlocked(&self) -> bool {
+        self.cipher.lock().expect("vault lock poisoned").is_some()
+    }
+
+    fn with_cipher<T>(&self, f: impl FnOnce(&Aes256Gcm) -> Result<T>) -> Result<T> {
+        match self.cipher.lock().expect("vault lock poisoned").as_ref() {
+            Some(cipher) => f(cipher),
+            None => Err(Locked.into()),
+        }
+    }
+
+    pub fn seal(&self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        self.with_cipher(|cipher| seal_with(cipher, plaintext))
+    }
+
+    pub fn open(&self, sealed: &[u8]) -> Result<Vec<u8>> {
+        self.with_cipher(|cipher| open_with(cipher, sealed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_unlock_seal_open() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+
+        let vault = Vault::new();
+        assert!(vault.seal(b"secret").is_err());
+        vault.unlock(&mut conn, "correct horse").await.unwrap();
+        let sealed = vault.seal(b"secret").unwrap();
+        assert_ne!(sealed, vault.seal(b"secret").unwrap());
+        assert_eq!(vault.open(&sealed).unwrap(), b"secret");
+
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(vault.open(&tampered).is_err());
+
+        vault.lock();
+        assert!(!vault.is_unlocked());
+        assert!(vault.open(&sealed).is_err());
+        assert!(vault.unlock(&mut conn, "wrong").await.is_err());
+        assert!(!vault.is_unlocked());
+        vault.unlock(&mut conn, "correct horse").await.unwrap();
+        assert_eq!(vault.open(&sealed).unwrap(), b"secret");
+    }
+}
@@ -0,0 +1,237 @@
+use anyhow::Result;
+use sqlx::SqliteConnection;
+
+use super::{Label, LabelSource};
+
+/// Keywords that carry state rather than a user's tag, so they never become
+/// labels.
+const STATE_KEYWORDS: &[&str] = &[
+    "$Forwarded", "$MDNSent", "$Junk", "$NotJunk", "$Phishing", "$Submitted",
+    "$SubmitPending", "Junk", "NonJunk", "NotJunk",
+];
+
+/// Thunderbird's default tags, stored as `$Label1` to `$Label5`.
+const THUNDERBIRD_TAGS: &[(&str, &str, &str)] = &[
+    ("$Label1", "Important", "#ff0000"),
+    ("$Label2", "Work", "#ff9900"),
+    ("$Label3", "Personal", "#009900"),
+    ("$Label4", "To Do", "#3333ff"),
+    ("$Label5", "Later", "#993399"),
+];
+
+/// Display name and default color for the label mirroring `keyword`, or
+/// `None` when the keyword is not a tag.
+pub fn keyword_label(keyword: &str) -> Option<(String, Option<&'static str>)> {
+    if STATE_KEYWORDS.iter().any(|k| k.eq_ignore_ascii_case(keyword)) {
+        return None;
+    }
+    match THUNDERBIRD_TAGS.iter().find(|(k, _, _)| k.eq_ignore_ascii_case(keyword)) {
+        Some((_, name, color)) => Some((name.to_string(), Some(color))),
+        None => Some((keyword.to_string(), None)),
+    }
+}
+
+/// Display name for a Gmail label. System labels arrive as `\Inbox` etc.
+pub fn gmail_label_name(label: &str) -> String {
+    match label.strip_prefix('\\') {
+        Some("Draft") => "Drafts".to_string(),
+        Some(system) => system.to_string(),
+        None => label.to_string(),
+    }
+}
+
+/// Validates a `#rrggbb` color.
+pub fn is_valid_color(color: &str) -> bool {
+    color.len() == 7 && color.starts_with('#') && color[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Labels of an email: LOCAL and GMAIL labels through `email_labels`,
+/// KEYWORD labels through `email_keywords`.
+pub async fn labels_for_email(conn: &mut SqliteConnection, email_id: i64) -> Result<Vec<Label>> {
+    let labels = sqlx::query_as(
+        "SELECT l.* FROM labels l JOIN email_labels el ON el.label_id = l.id WHERE el.email_id = ?1 \
+         UNION \
+         SELECT l.* FROM labels l JOIN emails e ON e.account_id = l.account_id \
+         JOIN email_keywords k ON k.email_id = e.id AND k.keyword = l.remote_name COLLATE NOCASE \
+         WHERE e.id = ?1 AND l.source = 'KEYWORD' \
+         ORDER BY name",
+    )
+    .bind(email_id)
+    .fetch_all(&mut *conn)
+    .await?;
+    Ok(labels)
+}
+
+/// Makes sure a KEYWORD label exists for a keyword set on `email_id`.
+pub async fn ensure_keyword_label(conn: &mut SqliteConnection, email_id: i64, keyword: &str) -> Result<()> {
+    let Some((name, color)) = keyword_label(keyword) else {
+        return Ok(());
+    };
+    sqlx::query(
+        "INSERT INTO labels (account_id, name, color, source, remote_name) \
+         SELECT account_id, ?, ?, 'KEYWORD', ? FROM emails WHERE id = ? \
+         ON CONFLICT (account_id, source, name) DO NOTHING",
+    )
+    .bind(name)
+    .bind(color)
+    .bind(keyword)
+    .bind(email_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Returns the GMAIL label for `remote_name`, creating it if needed.
+async fn ensure_gmail_label(conn: &mut SqliteConnection, account_id: i64, remote_name: &str) -> Result<Label> {
+    let existing: Option<Label> = sqlx::query_as("SELECT * FROM labels WHERE account_id = ? AND source = 'GMAIL' AND remote_name = ?")
+        .bind(account_id)
+        .bind(remote_name)
+        .fetch_optional(&mut *conn)
+        .await?;
+    if let Some(label) = existing {
+        return Ok(label);
+    }
+
+    let label = sqlx::query_as("INSERT INTO labels (account_id, name, source, remote_name) VALUES (?, ?, ?, ?) RETURNING *")
+        .bind(account_id)
+        .bind(gmail_label_name(remote_name))
+        .bind(LabelSource::Gmail)
+        .bind(remote_name)
+        .fetch_one(&mut *conn)
+        .await?;
+    Ok(label)
+}
+
+/// Replaces the GMAIL labels of the messages in `folder_id` with the
+/// X-GM-LABELS the server reported. Returns how many messages changed.
+pub async fn apply_gmail_labels(conn: &mut SqliteConnection, folder_id: i64, server: &[(i64, Vec<String>)]) -> Result<usize> {
+    let mut updated = 0;
+    for (uid, remote_names) in server {
+        let email: Option<(i64, i64)> = sqlx::query_as("SELECT id, account_id FROM emails WHERE folder_id = ? AND uid = ?")
+            .bind(folder_id)
+            .bind(uid)
+            .fetch_optional(&mut *conn)
+            .await?;
+        let Some((email_id, account_id)) = email else {
+            continue;
+        };
+
+        let mut wanted = Vec::new();
+        for remote_name in remote_names {
+            wanted.push(ensure_gmail_label(conn, account_id, remote_name).await?.id);
+        }
+        let mut current: Vec<i64> = sqlx::query_scalar(
+            "SELECT el.label_id FROM email_labels el JOIN labels l ON l.id = el.label_id \
+             WHERE el.email_id = ? AND l.source = 'GMAIL'",
+        )
+        .bind(email_id)
+        .fetch_all(&mut *conn)
+        .await?;
+        wanted.sort_unstable();
+        wanted.dedup();
+        current.sort_unstable();
+        if wanted == current {
+            continue;
+        }
+
+        for label_id in current.iter().filter(|id| !wanted.contains(id)) {
+            remove(conn, email_id, *label_id).await?;
+        }
+        for label_id in wanted.iter().filter(|id| !current.contains(id)) {
+            assign(conn, email_id, *label_id).await?;
+        }
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+/// Adds a LOCAL or GMAIL label to an email.
+pub async fn assign(conn: &mut SqliteConnection, email_id: i64, label_id: i64) -> Result<()> {
+    sqlx::query("INSERT OR IGNORE INTO email_labels (email_id, label_id) VALUES (?, ?)")
+        .bind(email_id)
+        .bind(label_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Removes a LOCAL or GMAIL label from an email.
+pub async fn remove(conn: &mut SqliteConnection, email_id: i64, label_id: i64) -> Result<()> {
+    sqlx::query("DELETE FROM email_labels WHERE email_id = ? AND label_id = ?")
+        .bind(email_id)
+        .bind(label_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_conn() -> sqlx::pool::PoolConnection<sqlx::Sqlite> {
+        let pool = crate::db::test_pool_with_account().await;
+        for sql in [
+            "INSERT INTO folders (id, account_id, name, display_name, folder_type) \
+             VALUES (1, 1, '[Gmail]/All Mail', 'All Mail', 'CUSTOM')",
+            "INSERT INTO emails (id, account_id, folder_id, message_id, subject, from_address, to_addresses, size_bytes, internal_date, uid) \
+             VALUES (1, 1, 1, 'msg-1@example.com', 'Hello', 'sender@example.com', '[]', 100, CURRENT_TIMESTAMP, 42)",
+        ] {
+            sqlx::query(sql).execute(pool.as_ref()).await.unwrap();
+        }
+        pool.acquire().await.unwrap()
+    }
+
+    fn names(labels: &[Label]) -> Vec<&str> {
+        labels.iter().map(|label| label.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_keyword_labels() {
+        assert_eq!(keyword_label("$label1"), Some(("Important".to_string(), Some("#ff0000"))));
+        assert_eq!(keyword_label("Invoices"), Some(("Invoices".to_string(), None)));
+        assert_eq!(keyword_label("$Forwarded"), None);
+        assert_eq!(gmail_label_name("\\Inbox"), "Inbox");
+        assert_eq!(gmail_label_name("Work/Projects"), "Work/Projects");
+        assert!(is_valid_color("#3366cc"));
+        assert!(!is_valid_color("blue"));
+    }
+
+    #[tokio::test]
+    async fn test_gmail_labels_replace_previous_sync() {
+        let mut conn = test_conn().await;
+
+        let server = vec![(42, vec!["\\Inbox".to_string(), "Receipts".to_string()])];
+        assert_eq!(apply_gmail_labels(&mut conn, 1, &server).await.unwrap(), 1);
+        assert_eq!(names(&labels_for_email(&mut conn, 1).await.unwrap()), vec!["Inbox", "Receipts"]);
+
+        // Archived in Gmail: \Inbox is gone. Unknown UIDs are ignored.
+        let server = vec![(42, vec!["Receipts".to_string()]), (43, vec!["Other".to_string()])];
+        assert_eq!(apply_gmail_labels(&mut conn, 1, &server).await.unwrap(), 1);
+        assert_eq!(names(&labels_for_email(&mut conn, 1).await.unwrap()), vec!["Receipts"]);
+        assert_eq!(apply_gmail_labels(&mut conn, 1, &server).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_keyword_and_local_labels() {
+        let mut conn = test_conn().await;
+
+        sqlx::query("INSERT INTO email_keywords (email_id, keyword) VALUES (1, '$Label2'), (1, '$Forwarded')")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        ensure_keyword_label(&mut conn, 1, "$Label2").await.unwrap();
+        ensure_keyword_label(&mut conn, 1, "$Forwarded").await.unwrap();
+
+        let local: Label = sqlx::query_as("INSERT INTO labels (account_id, name, color, source) VALUES (1, 'Taxes', '#00aa00', 'LOCAL') RETURNING *")
+            .fetch_one(&mut *conn)
+            .await
+            .unwrap();
+        assign(&mut conn, 1, local.id).await.unwrap();
+
+        let labels = labels_for_email(&mut conn, 1).await.unwrap();
+        assert_eq!(names(&labels), vec!["Taxes", "Work"]);
+        assert_eq!(labels[1].source, LabelSource::Keyword);
+        assert_eq!(labels[1].remote_name.as_deref(), Some("$Label2"));
+    }
+}
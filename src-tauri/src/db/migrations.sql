@@ -11,7 +11,12 @@ CREATE TABLE IF NOT EXISTS accounts (
     jmap_url TEXT,
     username TEXT NOT NULL,
     password_encrypted TEXT NOT NULL,
+    auth_method TEXT NOT NULL DEFAULT 'PASSWORD' CHECK (auth_method IN ('PASSWORD', 'OAUTH2')),
     use_ssl BOOLEAN NOT NULL DEFAULT 1,
+    tls_ca_bundle TEXT, -- PEM CAs trusted for this account's servers
+    proxy_url TEXT, -- socks5://, tor:// or http:// proxy; the global one when NULL
+    authserv_id TEXT, -- Trusted Authentication-Results issuer
+    autocrypt_prefer_encrypt BOOLEAN NOT NULL DEFAULT 0,
     created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
 );
@@ -22,17 +27,30 @@ CREATE TABLE IF NOT EXISTS folders (
     account_id INTEGER NOT NULL,
     name TEXT NOT NULL,
     display_name TEXT NOT NULL,
-    folder_type TEXT NOT NULL CHECK (folder_type IN ('INBOX', 'SENT', 'DRAFTS', 'TRASH', 'SPAM', 'CUSTOM')),
+    folder_type TEXT NOT NULL CHECK (folder_type IN ('INBOX', 'SENT', 'DRAFTS', 'TRASH', 'SPAM', 'ARCHIVE', 'ALL', 'CUSTOM')),
     message_count INTEGER NOT NULL DEFAULT 0,
     unread_count INTEGER NOT NULL DEFAULT 0,
     uid_validity INTEGER,
     uid_next INTEGER,
+    delimiter TEXT,
+    parent_name TEXT, -- Enclosing folder, personal namespace prefix stripped
+    is_subscribed BOOLEAN NOT NULL DEFAULT 1,
+    is_selectable BOOLEAN NOT NULL DEFAULT 1,
     created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE,
     UNIQUE(account_id, name)
 );
 
+-- Folder types chosen by the user, applied over detection on every sync
+CREATE TABLE IF NOT EXISTS folder_type_overrides (
+    account_id INTEGER NOT NULL,
+    folder_name TEXT NOT NULL,
+    folder_type TEXT NOT NULL CHECK (folder_type IN ('SENT', 'DRAFTS', 'TRASH', 'SPAM', 'ARCHIVE', 'ALL', 'CUSTOM')),
+    PRIMARY KEY (account_id, folder_name),
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
 -- Emails table
 CREATE TABLE IF NOT EXISTS emails (
     id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -59,6 +77,9 @@ CREATE TABLE IF NOT EXISTS emails (
     is_deleted BOOLEAN NOT NULL DEFAULT 0,
     uid INTEGER, -- IMAP specific
     mod_seq INTEGER, -- IMAP specific
+    trackers_blocked INTEGER, -- Counted when the HTML body is first rendered
+    auth_verdict TEXT CHECK (auth_verdict IN ('PASS', 'FAIL', 'NONE')),
+    auth_results TEXT, -- JSON array
     created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
     FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE,
@@ -79,10 +100,205 @@ CREATE TABLE IF NOT EXISTS sync_state (
     FOREIGN KEY (folder_id) REFERENCES folders(id) ON DELETE CASCADE
 );
 
+-- IMAP keywords ($Forwarded, $Junk, $Label1, ...) set on each message
+CREATE TABLE IF NOT EXISTS email_keywords (
+    email_id INTEGER NOT NULL,
+    keyword TEXT NOT NULL,
+    PRIMARY KEY (email_id, keyword),
+    FOREIGN KEY (email_id) REFERENCES emails(id) ON DELETE CASCADE
+);
+
+-- Labels: local tags, Gmail labels and IMAP keywords. Gmail accounts keep
+-- one row per message (from [Gmail]/All Mail) and express the other labels
+-- through email_labels rather than duplicate rows per label folder.
+CREATE TABLE IF NOT EXISTS labels (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    color TEXT, -- "#rrggbb"
+    source TEXT NOT NULL CHECK (source IN ('LOCAL', 'GMAIL', 'KEYWORD')),
+    remote_name TEXT, -- Gmail label or keyword as the server knows it
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE,
+    UNIQUE(account_id, source, name)
+);
+
+-- Membership of LOCAL and GMAIL labels. KEYWORD labels follow email_keywords.
+CREATE TABLE IF NOT EXISTS email_labels (
+    email_id INTEGER NOT NULL,
+    label_id INTEGER NOT NULL,
+    PRIMARY KEY (email_id, label_id),
+    FOREIGN KEY (email_id) REFERENCES emails(id) ON DELETE CASCADE,
+    FOREIGN KEY (label_id) REFERENCES labels(id) ON DELETE CASCADE
+);
+
+-- Decoded MIME leaf parts kept for offline display: inline images and
+-- attachments. The data stays NULL until the part is downloaded.
+CREATE TABLE IF NOT EXISTS email_parts (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    email_id INTEGER NOT NULL,
+    section TEXT NOT NULL, -- IMAP part number, e.g. "1.2"
+    content_id TEXT, -- Without angle brackets
+    content_type TEXT NOT NULL,
+    filename TEXT,
+    is_inline BOOLEAN NOT NULL DEFAULT 0,
+    size_bytes INTEGER NOT NULL,
+    data BLOB,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (email_id) REFERENCES emails(id) ON DELETE CASCADE,
+    UNIQUE(email_id, section)
+);
+
+-- Senders and domains whose remote images load without asking
+CREATE TABLE IF NOT EXISTS remote_content_allowlist (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    scope TEXT NOT NULL CHECK (scope IN ('SENDER', 'DOMAIN')),
+    value TEXT NOT NULL, -- Lowercased address or domain
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE(scope, value)
+);
+
+-- Offline operation journal, replayed to the server in id order
+CREATE TABLE IF NOT EXISTS pending_ops (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    account_id INTEGER NOT NULL,
+    email_id INTEGER NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('ADD_FLAG', 'REMOVE_FLAG', 'MOVE', 'DELETE')),
+    flag TEXT, -- ADD_FLAG / REMOVE_FLAG only, in IMAP form
+    folder_id INTEGER NOT NULL, -- Folder the message was in when the op was recorded
+    uid INTEGER, -- IMAP UID in that folder
+    target_folder_id INTEGER, -- MOVE only
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+-- Master password check. Secrets are sealed with a key derived from the
+-- password and this salt; the verifier is a sealed known value.
+CREATE TABLE IF NOT EXISTS vault (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    salt BLOB NOT NULL,
+    verifier BLOB NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- OpenPGP keyring. Key material is sealed by the vault; what's needed to
+-- list and look up keys is kept in the clear.
+CREATE TABLE IF NOT EXISTS pgp_keys (
+    fingerprint TEXT PRIMARY KEY, -- Uppercase hex
+    user_ids TEXT NOT NULL, -- JSON array
+    has_secret BOOLEAN NOT NULL DEFAULT 0,
+    trust TEXT NOT NULL DEFAULT 'UNKNOWN' CHECK (trust IN ('UNKNOWN', 'NEVER', 'MARGINAL', 'FULL', 'ULTIMATE')),
+    key_created_at DATETIME NOT NULL,
+    expires_at DATETIME,
+    revoked BOOLEAN NOT NULL DEFAULT 0,
+    key_data BLOB NOT NULL, -- Sealed binary certificate, with secret keys when we have them
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS pgp_key_addresses (
+    fingerprint TEXT NOT NULL,
+    address TEXT NOT NULL, -- Lowercased, from the key's user IDs
+    PRIMARY KEY (fingerprint, address),
+    FOREIGN KEY (fingerprint) REFERENCES pgp_keys(fingerprint) ON DELETE CASCADE
+);
+
+-- S/MIME certificates: our identities, whose private keys are sealed by the
+-- vault, trust anchors, and certificates collected from signed mail
+CREATE TABLE IF NOT EXISTS smime_certificates (
+    fingerprint TEXT PRIMARY KEY, -- Uppercase hex SHA-256 of the certificate
+    subject TEXT NOT NULL,
+    issuer TEXT NOT NULL,
+    not_before DATETIME NOT NULL,
+    not_after DATETIME NOT NULL,
+    is_ca BOOLEAN NOT NULL DEFAULT 0,
+    trusted BOOLEAN NOT NULL DEFAULT 0, -- A trust anchor for signatures
+    certificate BLOB NOT NULL, -- DER
+    private_key BLOB, -- Sealed PKCS#8
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS smime_certificate_addresses (
+    fingerprint TEXT NOT NULL,
+    address TEXT NOT NULL, -- Lowercased, from the subject and its alternative names
+    PRIMARY KEY (fingerprint, address),
+    FOREIGN KEY (fingerprint) REFERENCES smime_certificates(fingerprint) ON DELETE CASCADE
+);
+
+-- age keys: our identities, whose secrets are sealed by the vault, and the
+-- recipients of people we encrypt to
+CREATE TABLE IF NOT EXISTS age_keys (
+    recipient TEXT PRIMARY KEY, -- age1... or an SSH public key without its comment
+    name TEXT NOT NULL DEFAULT '',
+    address TEXT, -- Lowercased; whose key it is
+    identity BLOB, -- Sealed secret key, ours only
+    passphrase BLOB, -- Sealed, when the secret key is a protected SSH key
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Autocrypt Level 1 peer state, per account and lowercased peer address
+CREATE TABLE IF NOT EXISTS autocrypt_peers (
+    account_id INTEGER NOT NULL,
+    address TEXT NOT NULL,
+    last_seen DATETIME, -- Date of the newest message from the peer
+    autocrypt_timestamp DATETIME, -- Date of the newest message with an Autocrypt header
+    public_key BLOB, -- Binary OpenPGP certificate from that header
+    prefer_encrypt TEXT NOT NULL DEFAULT 'NO_PREFERENCE' CHECK (prefer_encrypt IN ('MUTUAL', 'NO_PREFERENCE')),
+    gossip_timestamp DATETIME,
+    gossip_key BLOB, -- From Autocrypt-Gossip in an encrypted message
+    PRIMARY KEY (account_id, address),
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+-- OAuth2 grants for accounts that sign in with OAUTH2. Tokens are sealed by
+-- the vault; the endpoint and client are kept to refresh with
+CREATE TABLE IF NOT EXISTS oauth_tokens (
+    account_id INTEGER PRIMARY KEY,
+    provider TEXT NOT NULL,
+    token_url TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    client_secret TEXT,
+    refresh_token BLOB NOT NULL,
+    access_token BLOB,
+    expires_at DATETIME,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+-- Server certificates trusted on first use, by SHA-256 fingerprint. A pin
+-- replaces CA verification for its host
+CREATE TABLE IF NOT EXISTS certificate_pins (
+    account_id INTEGER NOT NULL,
+    host TEXT NOT NULL COLLATE NOCASE,
+    fingerprint TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (account_id, host),
+    FOREIGN KEY (account_id) REFERENCES accounts(id) ON DELETE CASCADE
+);
+
+-- App-wide settings, such as the global proxy
+CREATE TABLE IF NOT EXISTS settings (
+    key TEXT PRIMARY KEY,
+    value TEXT NOT NULL,
+    updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
 -- Indexes for performance
 CREATE INDEX IF NOT EXISTS idx_emails_account_folder ON emails(account_id, folder_id);
 CREATE INDEX IF NOT EXISTS idx_emails_from_address ON emails(from_address);
 CREATE INDEX IF NOT EXISTS idx_emails_internal_date ON emails(internal_date);
 CREATE INDEX IF NOT EXISTS idx_emails_is_read ON emails(is_read);
 CREATE INDEX IF NOT EXISTS idx_emails_thread_id ON emails(thread_id);
-CREATE INDEX IF NOT EXISTS idx_folders_account_id ON folders(account_id);
\ No newline at end of file
+CREATE INDEX IF NOT EXISTS idx_folders_account_id ON folders(account_id);
+CREATE INDEX IF NOT EXISTS idx_pending_ops_account_id ON pending_ops(account_id, id);
+CREATE INDEX IF NOT EXISTS idx_email_labels_label_id ON email_labels(label_id);
+CREATE INDEX IF NOT EXISTS idx_email_parts_content_id ON email_parts(email_id, content_id);
+CREATE INDEX IF NOT EXISTS idx_pgp_key_addresses_address ON pgp_key_addresses(address);
+CREATE INDEX IF NOT EXISTS idx_smime_certificate_addresses_address ON smime_certificate_addresses(address);
+CREATE INDEX IF NOT EXISTS idx_age_keys_address ON age_keys(address);
@@ -1,11 +1,15 @@
+pub mod labels;
 pub mod models;
+pub mod parts;
 
 pub use models::*;
 
-use sqlx::{migrate::MigrateDatabase, Sqlite, SqlitePool};
+use sqlx::{migrate::MigrateDatabase, Sqlite, SqliteConnection, SqlitePool};
 use std::sync::Arc;
 use anyhow::Result;
 
+use crate::email::warnings;
+
 pub type DbPool = Arc<SqlitePool>;
 
 pub async fn init_database(database_url: &str) -> Result<DbPool> {
@@ -24,8 +28,84 @@ pub async fn init_database(database_url: &str) -> Result<DbPool> {
     Ok(pool)
 }
 
-async fn run_migrations(pool: &DbPool) -> Result<()> {
+pub(crate) async fn run_migrations(pool: &DbPool) -> Result<()> {
     let migration_sql = include_str!("migrations.sql");
     sqlx::query(migration_sql).execute(pool.as_ref()).await?;
     Ok(())
-}
\ No newline at end of file
+}
+
+/// An in-memory database holding account 1, test@example.com.
+#[cfg(test)]
+pub(crate) async fn test_pool_with_account() -> DbPool {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let pool = Arc::new(pool);
+    run_migrations(&pool).await.unwrap();
+    sqlx::query(
+        "INSERT INTO accounts (id, name, email, protocol, username, password_encrypted) \
+         VALUES (1, 'Test', 'test@example.com', 'IMAP', 'test@example.com', 'password')",
+    )
+    .execute(pool.as_ref())
+    .await
+    .unwrap();
+    pool
+}
+
+/// Loads an email together with its keywords and labels.
+pub async fn load_email(conn: &mut SqliteConnection, email_id: i64) -> Result<Option<Email>> {
+    let email: Option<Email> = sqlx::query_as("SELECT * FROM emails WHERE id = ?")
+        .bind(email_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    let Some(mut email) = email else {
+        return Ok(None);
+    };
+
+    load_details(conn, &mut email).await?;
+    Ok(Some(email))
+}
+
+/// Fills in the keywords, labels and security warnings, which live outside
+/// the `emails` row.
+pub async fn load_details(conn: &mut SqliteConnection, email: &mut Email) -> Result<()> {
+    email.keywords = sqlx::query_scalar("SELECT keyword FROM email_keywords WHERE email_id = ? ORDER BY keyword")
+        .bind(email.id)
+        .fetch_all(&mut *conn)
+        .await?;
+    email.labels = labels::labels_for_email(conn, email.id).await?;
+
+    let attachments: Vec<Attachment> = email
+        .attachments
+        .as_deref()
+        .and_then(|json| serde_json::from_str(json).ok())
+        .unwrap_or_default();
+    // Archives can only be looked into once they have been downloaded
+    let mut archives = Vec::new();
+    for attachment in attachments.iter().filter(|a| warnings::is_archive(&a.filename, &a.content_type)) {
+        if let Some(EmailPart { data: Some(data), .. }) = parts::find_by_section(conn, email.id, &attachment.id).await? {
+            archives.extend(warnings::archive_entries(&data).map(|entries| (attachment.id.clone(), entries)));
+        }
+    }
+    email.warnings = warnings::analyze(email, &attachments, &archives);
+    Ok(())
+}
+
+/// Whether the allow-list lets remote content load in mail from `sender`,
+/// by address or by the sender's domain or a parent of it.
+pub async fn remote_content_allowed(conn: &mut SqliteConnection, sender: &str) -> Result<bool> {
+    let sender = sender.trim().to_ascii_lowercase();
+    let domain = sender.rsplit_once('@').map(|(_, domain)| domain.to_string()).unwrap_or_default();
+    let allowed = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM remote_content_allowlist WHERE \
+           (scope = 'SENDER' AND value = ?1) OR \
+           (scope = 'DOMAIN' AND (value = ?2 OR substr(?2, -length(value) - 1) = '.' || value)))",
+    )
+    .bind(&sender)
+    .bind(&domain)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(allowed)
+}
@@ -14,9 +14,43 @@ pub struct Account {
     pub jmap_url: Option<String>,
     pub username: String,
     pub password_encrypted: String, // Encrypted with master password
+    pub auth_method: AuthMethod,
     pub use_ssl: bool,
+    pub tls_ca_bundle: Option<String>, // PEM, CAs trusted besides the public ones
+    pub proxy_url: Option<String>, // See email::net; email::load_account fills in the global one
+    pub authserv_id: Option<String>, // Whose Authentication-Results headers to trust
+    pub autocrypt_prefer_encrypt: bool, // Autocrypt prefer-encrypt=mutual
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
+    /// Bearer token for OAUTH2 accounts, see `email::oauth::prepare`.
+    /// Never stored or sent to the UI.
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub access_token: Option<String>,
+    /// Loaded by `email::load_account`, see `email::pins`.
+    #[sqlx(skip)]
+    #[serde(skip)]
+    pub certificate_pins: Vec<CertificatePin>,
+}
+
+/// How an account signs in to its IMAP and SMTP servers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AuthMethod {
+    #[default]
+    Password,
+    Oauth2, // XOAUTH2 or OAUTHBEARER with a token from `email::oauth`
+}
+
+/// A server certificate the user chose to trust for an account, by its
+/// SHA-256 fingerprint.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, sqlx::FromRow)]
+pub struct CertificatePin {
+    pub account_id: i64,
+    pub host: String,
+    pub fingerprint: String, // Uppercase hex, no separators
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -25,15 +59,35 @@ pub struct Folder {
     pub account_id: i64,
     pub name: String,
     pub display_name: String,
-    pub folder_type: String, // "INBOX", "SENT", "DRAFTS", "TRASH", "SPAM", "CUSTOM"
+    pub folder_type: FolderType,
     pub message_count: i32,
     pub unread_count: i32,
     pub uid_validity: Option<i64>, // IMAP specific
     pub uid_next: Option<i64>,     // IMAP specific
+    pub delimiter: Option<String>, // Hierarchy delimiter, None for flat servers
+    pub parent_name: Option<String>, // Enclosing folder, None at the top of the tree
+    pub is_subscribed: bool,
+    pub is_selectable: bool, // false for \Noselect hierarchy placeholders
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+/// Role of a folder. Serialized as "INBOX", "SENT", "DRAFTS", ... like the
+/// `folder_type` column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum FolderType {
+    Inbox,
+    Sent,
+    Drafts,
+    Trash,
+    Spam,
+    Archive,
+    All, // Gmail's All Mail
+    Custom,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct Email {
     pub id: i64,
@@ -60,24 +114,131 @@ pub struct Email {
     pub is_deleted: bool,
     pub uid: Option<i64>, // IMAP specific
     pub mod_seq: Option<i64>, // IMAP specific
+    pub trackers_blocked: Option<i64>, // Counted when the HTML body is first rendered
+    pub auth_verdict: Option<AuthVerdict>, // Unset until the sender has been checked
+    pub auth_results: Option<String>, // JSON array of AuthResult
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub keywords: Vec<String>, // From email_keywords, e.g. "$Forwarded"
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub labels: Vec<Label>,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub warnings: Vec<SecurityWarning>, // Worked out from the message on load
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum LabelSource {
+    Local,   // User tag, never leaves this device
+    Gmail,   // X-GM-LABELS
+    Keyword, // IMAP keyword such as $Label1
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct Label {
+    pub id: i64,
+    pub account_id: i64,
+    pub name: String,
+    pub color: Option<String>, // "#rrggbb"
+    pub source: LabelSource,
+    pub remote_name: Option<String>, // Gmail label or keyword as the server knows it
     pub created_at: DateTime<Utc>,
     pub updated_at: DateTime<Utc>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AllowScope {
+    Sender, // One address
+    Domain, // A domain and its subdomains
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct RemoteContentRule {
+    pub id: i64,
+    pub scope: AllowScope,
+    pub value: String, // Lowercased address or domain
+    pub created_at: DateTime<Utc>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct EmailAddress {
     pub name: Option<String>,
     pub address: String,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum AuthVerdict {
+    Pass, // DMARC passed, or DKIM or SPF passed for the From domain
+    Fail,
+    None, // Nothing conclusive either way
+}
+
+/// One DKIM, SPF or DMARC result, as RFC 8601 reports it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuthResult {
+    pub method: String, // "dkim", "spf", "dmarc"
+    pub result: String, // "pass", "fail", "none", "temperror"...
+    pub domain: Option<String>, // The domain the result is for, e.g. header.d
+    pub reason: Option<String>,
+    pub local: bool, // Verified here rather than reported by the server
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum WarningKind {
+    ExecutableAttachment,
+    DoubleExtension,   // invoice.pdf.exe
+    MacroDocument,     // .docm, .xlsm and the like
+    ArchiveExecutable, // An executable inside an attached archive
+    DisplayNameSpoof,  // "PayPal <x@evil.tld>"
+    LookalikeDomain,   // IDN homograph of an ASCII domain
+    LinkMismatch,      // Link text shows one domain, the href goes to another
+}
+
+/// Something about a message that suggests it is malicious. `subject` is
+/// what the warning is about (a file name, a domain, a link) and `detail`
+/// explains it for the user.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SecurityWarning {
+    pub kind: WarningKind,
+    pub subject: String,
+    pub detail: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Attachment {
-    pub id: String,
+    pub id: String, // IMAP section for received mail, e.g. "2"
     pub filename: String,
     pub content_type: String,
     pub size_bytes: i64,
     pub content_id: Option<String>,
     pub is_inline: bool,
+    #[serde(default, skip_serializing)]
+    pub data: Option<Vec<u8>>, // Contents of an outgoing attachment
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct EmailPart {
+    pub id: i64,
+    pub email_id: i64,
+    pub section: String, // IMAP part number, e.g. "1.2"
+    pub content_id: Option<String>, // Without angle brackets
+    pub content_type: String,
+    pub filename: Option<String>,
+    pub is_inline: bool,
+    pub size_bytes: i64,
+    #[serde(skip)]
+    pub data: Option<Vec<u8>>, // NULL until downloaded
+    pub created_at: DateTime<Utc>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -92,6 +253,130 @@ pub struct ComposeEmail {
     pub attachments: Vec<Attachment>,
     pub in_reply_to: Option<String>,
     pub references: Option<String>,
+    #[serde(default)]
+    pub sign: bool, // Sign the body
+    #[serde(default)]
+    pub encrypt: bool, // Encrypt to the recipients' keys
+    #[serde(default)]
+    pub smime: bool, // With S/MIME rather than OpenPGP
+    #[serde(default)]
+    pub age: bool, // Encrypt with age rather than OpenPGP; age can't sign
+    #[serde(default)]
+    pub age_passphrase: Option<String>, // With age, to this passphrase instead of keys
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum KeyTrust {
+    Unknown,
+    Never, // Don't encrypt to or believe signatures from this key
+    Marginal,
+    Full,
+    Ultimate, // Our own keys
+}
+
+/// A key in the OpenPGP keyring, without its key material.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PgpKey {
+    pub fingerprint: String,
+    pub user_ids: String, // JSON array
+    pub has_secret: bool,
+    pub trust: KeyTrust,
+    pub key_created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub revoked: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PreferEncrypt {
+    Mutual,
+    #[default]
+    NoPreference,
+}
+
+/// What Autocrypt Level 1 suggests for encrypting to one or all recipients,
+/// weakest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EncryptRecommendation {
+    Disable,    // No usable key
+    Discourage, // Only a gossiped or stale key
+    Available,
+    Encrypt, // Both sides prefer encryption
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipientRecommendation {
+    pub address: String,
+    pub recommendation: EncryptRecommendation,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AutocryptRecommendation {
+    pub recommendation: EncryptRecommendation,
+    pub recipients: Vec<RecipientRecommendation>,
+}
+
+/// An age key: one of our identities when it has a secret, otherwise
+/// someone's recipient to encrypt to.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct AgeKey {
+    pub recipient: String,
+    pub name: String,
+    pub address: Option<String>,
+    pub has_identity: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// An S/MIME certificate: one of our identities when it has a private key,
+/// a trust anchor when trusted, or one collected from signed mail.
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct SmimeCertificate {
+    pub fingerprint: String,
+    pub subject: String,
+    pub issuer: String,
+    pub addresses: String, // JSON array
+    pub not_before: DateTime<Utc>,
+    pub not_after: DateTime<Utc>,
+    pub is_ca: bool,
+    pub trusted: bool,
+    pub has_private_key: bool,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum SignatureStatus {
+    Good,
+    Bad,
+    UnknownKey,     // We don't have the signer's certificate
+    Expired,        // The signature or the key that made it has expired
+    Untrusted,      // S/MIME: the certificate doesn't chain to a trust anchor
+    SignerMismatch, // S/MIME: the certificate isn't for the From address
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignatureInfo {
+    pub status: SignatureStatus,
+    pub fingerprint: Option<String>, // Of the signing key, or the issuer it names
+    pub signer: Option<String>,      // The certificate's primary user ID
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// An encrypted or signed email, opened for display.
+#[derive(Debug, Clone, Serialize)]
+pub struct SecureContent {
+    pub encrypted: bool,
+    pub signatures: Vec<SignatureInfo>,
+    pub body_text: Option<String>,
+    pub body_html: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -102,4 +387,28 @@ pub struct SyncState {
     pub last_mod_seq: Option<i64>,
     pub last_sync: DateTime<Utc>,
     pub sync_token: Option<String>, // JMAP specific
-}
\ No newline at end of file
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+#[sqlx(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum PendingOpKind {
+    AddFlag,
+    RemoveFlag,
+    Move,
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct PendingOp {
+    pub id: i64,
+    pub account_id: i64,
+    pub email_id: i64,
+    pub kind: PendingOpKind,
+    pub flag: Option<String>,          // ADD_FLAG / REMOVE_FLAG only, in IMAP form
+    pub folder_id: i64,                // Folder the message was in when the op was recorded
+    pub uid: Option<i64>,              // IMAP UID in that folder
+    pub target_folder_id: Option<i64>, // MOVE only
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
@@ -0,0 +1,59 @@
+use anyhow::Result;
+use sqlx::SqliteConnection;
+
+use super::{Attachment, EmailPart};
+
+/// The part an inline `cid:` reference points at. Content-IDs are matched
+/// without their angle brackets.
+pub async fn find_by_content_id(conn: &mut SqliteConnection, email_id: i64, content_id: &str) -> Result<Option<EmailPart>> {
+    let content_id = content_id.trim().trim_start_matches('<').trim_end_matches('>');
+    let part = sqlx::query_as(
+        "SELECT * FROM email_parts WHERE email_id = ? AND content_id = ? COLLATE NOCASE \
+         ORDER BY section LIMIT 1",
+    )
+    .bind(email_id)
+    .bind(content_id)
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(part)
+}
+
+pub async fn find(conn: &mut SqliteConnection, email_id: i64, part_id: i64) -> Result<Option<EmailPart>> {
+    let part = sqlx::query_as("SELECT * FROM email_parts WHERE email_id = ? AND id = ?")
+        .bind(email_id)
+        .bind(part_id)
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(part)
+}
+
+pub async fn find_by_section(conn: &mut SqliteConnection, email_id: i64, section: &str) -> Result<Option<EmailPart>> {
+    let part = sqlx::query_as("SELECT * FROM email_parts WHERE email_id = ? AND section = ?")
+        .bind(email_id)
+        .bind(section)
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(part)
+}
+
+/// Stores the downloaded bytes of an attachment, whose id is its section.
+pub async fn save(conn: &mut SqliteConnection, email_id: i64, attachment: &Attachment, data: &[u8]) -> Result<EmailPart> {
+    let content_id = attachment.content_id.as_deref().map(|id| id.trim().trim_start_matches('<').trim_end_matches('>'));
+    let part = sqlx::query_as(
+        "INSERT INTO email_parts (email_id, section, content_id, content_type, filename, is_inline, size_bytes, data) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT (email_id, section) DO UPDATE SET data = excluded.data, size_bytes = excluded.size_bytes \
+         RETURNING *",
+    )
+    .bind(email_id)
+    .bind(&attachment.id)
+    .bind(content_id)
+    .bind(&attachment.content_type)
+    .bind(&attachment.filename)
+    .bind(attachment.is_inline)
+    .bind(data.len() as i64)
+    .bind(data)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(part)
+}
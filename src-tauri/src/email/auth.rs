@@ -0,0 +1,279 @@
+//! Whether a message really comes from its From domain: the receiving
+//! server's `Authentication-Results` (RFC 8601), optionally backed by our
+//! own DKIM verification, boiled down to one verdict.
+
+use super::dkim;
+use super::dns::DnsResolver;
+use super::mime;
+use super::warnings::registrable_domain;
+use crate::db::{Account, AuthResult, AuthVerdict};
+
+/// The authserv-ids of providers that don't need one configured, by the
+/// domain of their IMAP server.
+const KNOWN_AUTHSERV_IDS: [(&str, &str); 4] = [
+    ("gmail.com", "mx.google.com"),
+    ("googlemail.com", "mx.google.com"),
+    ("outlook.com", "outlook.com"),
+    ("office365.com", "outlook.com"),
+];
+
+/// The authserv-id whose results we believe. Anyone can add an
+/// `Authentication-Results` header before the message reaches the server,
+/// so only the account's own server counts: the configured id, or a known
+/// provider's. Other servers' results are ignored until one is configured.
+fn trusted_id(account: &Account) -> Option<&str> {
+    if let Some(trusted) = &account.authserv_id {
+        return Some(trusted);
+    }
+    let server = account.imap_server.as_deref()?.to_ascii_lowercase();
+    let domain = registrable_domain(&server);
+    KNOWN_AUTHSERV_IDS.iter().find(|(known, _)| *known == domain).map(|(_, id)| *id)
+}
+
+/// Results from the `Authentication-Results` header `account`'s server
+/// added. Servers add theirs on top, so a header further down with the same
+/// id was there before the message arrived.
+pub fn authentication_results(headers: &str, account: &Account) -> Vec<AuthResult> {
+    let Some(trusted) = trusted_id(account) else {
+        return Vec::new();
+    };
+    for header in mime::header_values(headers, "Authentication-Results") {
+        let header = strip_comments(&header);
+        let mut statements = split_outside_quotes(&header, ';').into_iter();
+        let authserv_id = statements.next().unwrap_or_default();
+        let authserv_id = authserv_id.split_whitespace().next().unwrap_or_default();
+        if authserv_id.eq_ignore_ascii_case(trusted) {
+            return statements.filter_map(|statement| parse_result(&statement)).collect();
+        }
+    }
+    Vec::new()
+}
+
+/// One `method=result ptype.property=value ...` statement.
+fn parse_result(statement: &str) -> Option<AuthResult> {
+    let mut words = split_outside_quotes(statement, ' ').into_iter().filter(|word| !word.is_empty());
+    let (method, result) = words.next()?.split_once('=').map(|(m, r)| (m.to_string(), r.to_string()))?;
+    // A version may follow the method, as in "dkim/1"
+    let method = method.split('/').next().unwrap_or_default().trim().to_ascii_lowercase();
+
+    let mut properties = Vec::new();
+    for word in words {
+        if let Some((name, value)) = word.split_once('=') {
+            properties.push((name.to_ascii_lowercase(), value.trim_matches('"').to_string()));
+        }
+    }
+    let property = |name: &str| properties.iter().find(|(n, _)| n == name).map(|(_, value)| value.clone());
+    let domain = property("header.d")
+        .or_else(|| property("header.from"))
+        .or_else(|| property("smtp.mailfrom"))
+        .or_else(|| property("header.i"))
+        .map(|value| value.rsplit('@').next().unwrap_or_default().to_ascii_lowercase());
+
+    Some(AuthResult {
+        method,
+        result: result.trim().to_ascii_lowercase(),
+        domain,
+        reason: property("reason"),
+        local: false,
+    })
+}
+
+fn strip_comments(value: &str) -> String {
+    let mut out = String::with_capacity(value.len());
+    let (mut depth, mut quoted, mut escaped) = (0usize, false, false);
+    for c in value.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '"' if depth == 0 => quoted = !quoted,
+            '(' if !quoted => {
+                depth += 1;
+                continue;
+            }
+            ')' if !quoted && depth > 0 => {
+                depth -= 1;
+                continue;
+            }
+            _ => {}
+        }
+        if depth == 0 {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn split_outside_quotes(value: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut quoted = false;
+    for c in value.chars() {
+        if c == '"' {
+            quoted = !quoted;
+        }
+        if c == separator && !quoted {
+            parts.push(String::new());
+        } else {
+            parts.last_mut().expect("never empty").push(c);
+        }
+    }
+    parts.into_iter().map(|part| part.trim().to_string()).collect()
+}
+
+/// DMARC decides when the server ran it. Otherwise a DKIM signature or SPF
+/// pass for the From domain is a pass, and a failure with nothing passing
+/// is a fail.
+pub fn verdict(results: &[AuthResult], from_address: &str) -> AuthVerdict {
+    let from_domain = from_address.rsplit('@').next().unwrap_or_default().to_ascii_lowercase();
+    let from_domain = registrable_domain(&from_domain);
+    let aligned = |result: &AuthResult| result.domain.as_deref().map(registrable_domain) == Some(from_domain);
+
+    match results.iter().find(|result| result.method == "dmarc").map(|result| result.result.as_str()) {
+        Some("pass") => return AuthVerdict::Pass,
+        Some("fail") => return AuthVerdict::Fail,
+        _ => {}
+    }
+    let passed = results
+        .iter()
+        .any(|result| matches!(result.method.as_str(), "dkim" | "spf") && result.result == "pass" && aligned(result));
+    let failed = results
+        .iter()
+        .any(|result| matches!(result.method.as_str(), "dkim" | "spf") && result.result == "fail");
+    match (passed, failed) {
+        (true, _) => AuthVerdict::Pass,
+        (false, true) => AuthVerdict::Fail,
+        (false, false) => AuthVerdict::None,
+    }
+}
+
+/// Checks `message`, a whole RFC 5322 message, from `from_address`. DKIM is
+/// verified locally as well when a resolver is given.
+pub async fn check(
+    message: &[u8],
+    account: &Account,
+    from_address: &str,
+    resolver: Option<&dyn DnsResolver>,
+) -> (AuthVerdict, Vec<AuthResult>) {
+    let (headers, _) = mime::split_message(message);
+    let mut results = authentication_results(&String::from_utf8_lossy(headers), account);
+    if let Some(resolver) = resolver {
+        results.extend(dkim::verify(message, resolver).await);
+    }
+    (verdict(&results, from_address), results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::AuthMethod;
+    use crate::email::dkim::tests::{keys, BODY, HEADERS, RSA_SIGNATURE};
+
+    fn account(authserv_id: Option<&str>) -> Account {
+        Account {
+            id: 1,
+            name: "Test".to_string(),
+            email: "bob@example.org".to_string(),
+            protocol: "IMAP".to_string(),
+            imap_server: Some("imap.example.org".to_string()),
+            imap_port: Some(993),
+            smtp_server: None,
+            smtp_port: None,
+            jmap_url: None,
+            username: "bob".to_string(),
+            password_encrypted: "password".to_string(),
+            auth_method: AuthMethod::Password,
+            use_ssl: true,
+            tls_ca_bundle: None,
+            proxy_url: None,
+            authserv_id: authserv_id.map(str::to_string),
+            autocrypt_prefer_encrypt: false,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+            access_token: None,
+            certificate_pins: Vec::new(),
+        }
+    }
+
+    const RESULTS: &str = "Authentication-Results: mx.example.org;\r\n \
+        dkim=pass (2048-bit key; secure) header.d=example.com header.i=@example.com header.b=\"abc;def\";\r\n \
+        spf=softfail (domain of transitioning alice@example.com) smtp.mailfrom=alice@example.com;\r\n \
+        dmarc=pass (p=REJECT) header.from=example.com\r\n\
+        Authentication-Results: mx.evil.tld; dmarc=fail header.from=example.com\r\n";
+
+    #[test]
+    fn test_parse_authentication_results() {
+        let results = authentication_results(RESULTS, &account(Some("mx.example.org")));
+        let summary: Vec<(&str, &str, Option<&str>)> = results
+            .iter()
+            .map(|r| (r.method.as_str(), r.result.as_str(), r.domain.as_deref()))
+            .collect();
+        assert_eq!(summary, vec![
+            ("dkim", "pass", Some("example.com")),
+            ("spf", "softfail", Some("example.com")),
+            ("dmarc", "pass", Some("example.com")),
+        ]);
+        assert!(results.iter().all(|r| !r.local));
+
+        // Only the configured id counts
+        let results = authentication_results(RESULTS, &account(Some("MX.EVIL.TLD")));
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].result, "fail");
+        assert!(authentication_results(RESULTS, &account(Some("mx.other.org"))).is_empty());
+
+        let results = authentication_results("Authentication-Results: mx.example.org 1; none\r\n", &account(Some("mx.example.org")));
+        assert!(results.is_empty());
+        let results = authentication_results(
+            "Authentication-Results: mx.example.org; dkim/1=fail reason=\"bad (really)\"\r\n",
+            &account(Some("mx.example.org")),
+        );
+        assert_eq!((results[0].method.as_str(), results[0].reason.as_deref()), ("dkim", Some("bad (really)")));
+    }
+
+    #[test]
+    fn test_trusts_only_the_receiving_server() {
+        // The sender can add a header with our server's id; ours is on top
+        let forged = "Authentication-Results: mx.example.org; dmarc=fail header.from=example.com\r\n\
+            Authentication-Results: mx.example.org; dmarc=pass header.from=example.com\r\n";
+        let results = authentication_results(forged, &account(Some("mx.example.org")));
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].result, "fail");
+
+        // Without a configured id, only known providers' results count
+        assert!(authentication_results(RESULTS, &account(None)).is_empty());
+        let gmail = Account { imap_server: Some("imap.gmail.com".to_string()), ..account(None) };
+        let results = authentication_results("Authentication-Results: mx.google.com; dkim=pass header.i=@example.com\r\n", &gmail);
+        assert_eq!((results[0].method.as_str(), results[0].result.as_str()), ("dkim", "pass"));
+        assert!(authentication_results(RESULTS, &gmail).is_empty());
+    }
+
+    #[test]
+    fn test_verdict() {
+        let result = |method: &str, result: &str, domain: &str| AuthResult {
+            method: method.to_string(),
+            result: result.to_string(),
+            domain: Some(domain.to_string()),
+            reason: None,
+            local: false,
+        };
+        let from = "alice@news.example.com";
+        assert_eq!(verdict(&[result("dmarc", "fail", "example.com"), result("dkim", "pass", "example.com")], from), AuthVerdict::Fail);
+        assert_eq!(verdict(&[result("dkim", "pass", "mail.example.com")], from), AuthVerdict::Pass);
+        // A valid signature from some other domain says nothing about the sender
+        assert_eq!(verdict(&[result("dkim", "pass", "mailer.net")], from), AuthVerdict::None);
+        assert_eq!(verdict(&[result("dkim", "pass", "mailer.net"), result("spf", "fail", "example.com")], from), AuthVerdict::Fail);
+        assert_eq!(verdict(&[result("spf", "softfail", "example.com")], from), AuthVerdict::None);
+        assert_eq!(verdict(&[], from), AuthVerdict::None);
+    }
+
+    #[tokio::test]
+    async fn test_check_with_local_dkim() {
+        let message = format!("Authentication-Results: mx.example.org; spf=fail smtp.mailfrom=example.com\r\n{}{}\r\n{}", RSA_SIGNATURE, HEADERS, BODY);
+        let resolver = keys();
+        let (verdict, results) = check(message.as_bytes(), &account(Some("mx.example.org")), "alice@example.com", Some(&resolver)).await;
+        assert_eq!(verdict, AuthVerdict::Pass);
+        assert_eq!(results.len(), 2);
+        assert!(results[1].local);
+
+        let (verdict, _) = check(message.as_bytes(), &account(Some("mx.example.org")), "alice@example.com", None).await;
+        assert_eq!(verdict, AuthVerdict::Fail);
+    }
+}
@@ -0,0 +1,462 @@
+//! Finding an account's servers from its address: a bundled copy of
+//! Mozilla's ISPDB, the domain's autoconfig file, RFC 6186 SRV records and
+//! guesses at the usual host names, in that order of trust.
+
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use serde::Serialize;
+
+use super::dns::{DnsResolver, Srv};
+use super::net::Connector;
+use super::oauth;
+use crate::db::AuthMethod;
+
+/// ISPDB entries for the big providers, from autoconfig.thunderbird.net.
+const ISPDB: &[&str] = &[
+    include_str!("ispdb/aol.com.xml"),
+    include_str!("ispdb/fastmail.com.xml"),
+    include_str!("ispdb/gmx.net.xml"),
+    include_str!("ispdb/googlemail.com.xml"),
+    include_str!("ispdb/hotmail.com.xml"),
+    include_str!("ispdb/icloud.com.xml"),
+    include_str!("ispdb/mailbox.org.xml"),
+    include_str!("ispdb/posteo.de.xml"),
+    include_str!("ispdb/web.de.xml"),
+    include_str!("ispdb/yahoo.com.xml"),
+    include_str!("ispdb/zoho.com.xml"),
+];
+
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where a candidate came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Source {
+    Ispdb,
+    Autoconfig,
+    Srv,
+    Guess,
+}
+
+impl Source {
+    /// How far to trust what this source says, out of 100.
+    pub fn confidence(self) -> u8 {
+        match self {
+            Source::Ispdb => 90,
+            Source::Autoconfig => 80,
+            Source::Srv => 70,
+            Source::Guess => 30,
+        }
+    }
+}
+
+/// Settings that may work for an address. Both servers use TLS.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Candidate {
+    pub source: Source,
+    pub confidence: u8,
+    pub imap_server: String,
+    pub imap_port: u16,
+    pub smtp_server: Option<String>,
+    pub smtp_port: Option<u16>,
+    pub username: String,
+    pub auth_method: AuthMethod,
+}
+
+#[async_trait]
+pub trait HttpFetcher: Send + Sync {
+    /// The body at `url`, or None when the server has nothing there.
+    async fn get(&self, url: &str) -> Result<Option<String>>;
+}
+
+/// Fetches over HTTPS only, redirects included, since the answer decides
+/// where the password goes.
+pub struct SystemFetcher(reqwest::Client);
+
+impl SystemFetcher {
+    pub fn new(connector: &Connector) -> Result<Self> {
+        let redirect = reqwest::redirect::Policy::custom(|attempt| {
+            if attempt.url().scheme() == "https" && attempt.previous().len() < 5 {
+                attempt.follow()
+            } else {
+                attempt.stop()
+            }
+        });
+        Ok(SystemFetcher(connector.http_client()?.timeout(HTTP_TIMEOUT).redirect(redirect).build()?))
+    }
+}
+
+#[async_trait]
+impl HttpFetcher for SystemFetcher {
+    async fn get(&self, url: &str) -> Result<Option<String>> {
+        let response = self.0.get(url).send().await?;
+        if !response.status().is_success() {
+            return Ok(None);
+        }
+        Ok(Some(response.text().await?))
+    }
+}
+
+/// Candidate settings for `address`, the likeliest first. A hit in the
+/// bundled ISPDB is trusted alone; otherwise every other source is asked,
+/// and one that fails is skipped. Without `dns`, as behind a proxy, SRV
+/// records and host name guesses are left out.
+pub async fn discover(address: &str, dns: Option<&dyn DnsResolver>, http: &dyn HttpFetcher) -> Result<Vec<Candidate>> {
+    let address = address.trim();
+    let domain = match address.rsplit_once('@') {
+        Some((local, domain)) if !local.is_empty() && domain.contains('.') => domain.to_ascii_lowercase(),
+        _ => bail!("{} isn't an email address", address),
+    };
+    if let Some(candidate) = ispdb(address, &domain) {
+        return Ok(vec![candidate]);
+    }
+
+    let mut found = vec![(Source::Autoconfig, autoconfig(address, &domain, http).await)];
+    if let Some(dns) = dns {
+        found.push((Source::Srv, srv(address, &domain, dns).await));
+        found.push((Source::Guess, guess(address, &domain, dns).await));
+    }
+    let mut candidates = Vec::new();
+    for (source, found) in found {
+        match found {
+            Ok(candidate) => candidates.extend(candidate),
+            Err(e) => tracing::warn!("{:?} lookup for {} failed: {}", source, domain, e),
+        }
+    }
+
+    // The same servers from a less trusted source add nothing
+    candidates.sort_by_key(|candidate| std::cmp::Reverse(candidate.confidence));
+    let mut ranked: Vec<Candidate> = Vec::new();
+    for candidate in candidates {
+        let servers = |c: &Candidate| (c.imap_server.to_ascii_lowercase(), c.imap_port, c.smtp_server.clone(), c.smtp_port);
+        if !ranked.iter().any(|kept| servers(kept) == servers(&candidate)) {
+            ranked.push(candidate);
+        }
+    }
+    Ok(ranked)
+}
+
+fn ispdb(address: &str, domain: &str) -> Option<Candidate> {
+    let xml = ISPDB
+        .iter()
+        .find(|xml| elements(xml, "domain").iter().any(|(_, name)| unescape(name.trim()).eq_ignore_ascii_case(domain)))?;
+    from_config(xml, address, Source::Ispdb)
+}
+
+/// The domain's own autoconfig file, at either place Thunderbird looks.
+async fn autoconfig(address: &str, domain: &str, http: &dyn HttpFetcher) -> Result<Option<Candidate>> {
+    let mut url = url::Url::parse(&format!("https://autoconfig.{}/mail/config-v1.1.xml", domain))?;
+    url.query_pairs_mut().append_pair("emailaddress", address);
+    let well_known = format!("https://{}/.well-known/autoconfig/mail/config-v1.1.xml", domain);
+
+    let mut error = None;
+    for url in [url.as_str(), &well_known] {
+        match http.get(url).await {
+            Ok(Some(xml)) => {
+                if let Some(candidate) = from_config(&xml, address, Source::Autoconfig) {
+                    return Ok(Some(candidate));
+                }
+            }
+            Ok(None) => {}
+            Err(e) => error = Some(e),
+        }
+    }
+    error.map_or(Ok(None), Err)
+}
+
+/// IMAP over TLS and submission (RFC 6186), with the address as username.
+async fn srv(address: &str, domain: &str, dns: &dyn DnsResolver) -> Result<Option<Candidate>> {
+    // A lone record with target "." says the service isn't offered
+    let offered = |records: Vec<Srv>| records.into_iter().next().filter(|srv| !srv.target.is_empty());
+    let Some(imap) = offered(dns.srv(&format!("_imaps._tcp.{}", domain)).await?) else {
+        return Ok(None);
+    };
+    // Our SMTP client only speaks TLS from the start on port 465
+    let smtp = match offered(dns.srv(&format!("_submissions._tcp.{}", domain)).await?) {
+        Some(smtp) if smtp.port == 465 => Some(smtp),
+        _ => offered(dns.srv(&format!("_submission._tcp.{}", domain)).await?).filter(|smtp| smtp.port != 465),
+    };
+    Ok(Some(candidate(
+        Source::Srv,
+        address,
+        (imap.target, imap.port),
+        smtp.map(|smtp| (smtp.target, smtp.port)),
+        address.to_string(),
+        true,
+    )))
+}
+
+/// imap. or mail., and smtp. or mail., under the domain, when they resolve.
+async fn guess(address: &str, domain: &str, dns: &dyn DnsResolver) -> Result<Option<Candidate>> {
+    let mut found = [None, None];
+    for (host, prefixes) in found.iter_mut().zip([["imap", "mail"], ["smtp", "mail"]]) {
+        for prefix in prefixes {
+            let name = format!("{}.{}", prefix, domain);
+            if dns.resolves(&name).await? {
+                *host = Some(name);
+                break;
+            }
+        }
+    }
+    let [Some(imap), smtp] = found else {
+        return Ok(None);
+    };
+    Ok(Some(candidate(Source::Guess, address, (imap, 993), smtp.map(|smtp| (smtp, 587)), address.to_string(), true)))
+}
+
+/// Settings from a clientConfig document, ISPDB's or the domain's: its
+/// first IMAP server over TLS, and an SMTP server our client can reach.
+fn from_config(xml: &str, address: &str, source: Source) -> Option<Candidate> {
+    let imap = servers(xml, "incomingServer", "imap").into_iter().find(|server| server.socket_type == "SSL")?;
+    let outgoing = servers(xml, "outgoingServer", "smtp");
+    let smtp = outgoing
+        .iter()
+        .find(|server| server.socket_type == "SSL" && server.port == 465)
+        .or_else(|| outgoing.iter().find(|server| server.socket_type == "STARTTLS" && server.port != 465));
+
+    let username = match expand(&imap.username, address) {
+        username if username.is_empty() => address.to_string(),
+        username => username,
+    };
+    let offers_oauth = imap.authentication.iter().any(|method| method == "OAuth2");
+    Some(candidate(
+        source,
+        address,
+        (expand(&imap.hostname, address), imap.port),
+        smtp.map(|smtp| (expand(&smtp.hostname, address), smtp.port)),
+        username,
+        offers_oauth,
+    ))
+}
+
+fn candidate(
+    source: Source,
+    address: &str,
+    imap: (String, u16),
+    smtp: Option<(String, u16)>,
+    username: String,
+    offers_oauth: bool,
+) -> Candidate {
+    // OAuth2 only where we have a client registered with the provider
+    let auth_method = match oauth::detect_address(address, &[&imap.0]) {
+        Some(provider) if offers_oauth && oauth::provider(provider).is_ok() => AuthMethod::Oauth2,
+        _ => AuthMethod::Password,
+    };
+    let (smtp_server, smtp_port) = smtp.map_or((None, None), |(server, port)| (Some(server), Some(port)));
+    Candidate {
+        source,
+        confidence: source.confidence(),
+        imap_server: imap.0,
+        imap_port: imap.1,
+        smtp_server,
+        smtp_port,
+        username,
+        auth_method,
+    }
+}
+
+struct Server {
+    hostname: String,
+    port: u16,
+    socket_type: String,
+    username: String,
+    authentication: Vec<String>,
+}
+
+fn servers(xml: &str, element: &str, kind: &str) -> Vec<Server> {
+    elements(xml, element)
+        .into_iter()
+        .filter(|(attributes, _)| attribute(attributes, "type") == Some(kind))
+        .filter_map(|(_, body)| {
+            Some(Server {
+                hostname: text(body, "hostname")?,
+                port: text(body, "port")?.parse().ok()?,
+                socket_type: text(body, "socketType").unwrap_or_default(),
+                username: text(body, "username").unwrap_or_default(),
+                authentication: elements(body, "authentication").iter().map(|(_, method)| unescape(method.trim())).collect(),
+            })
+        })
+        .collect()
+}
+
+/// Fills in the placeholders clientConfig files use.
+fn expand(template: &str, address: &str) -> String {
+    let (local, domain) = address.rsplit_once('@').unwrap_or((address, ""));
+    template
+        .replace("%EMAILADDRESS%", address)
+        .replace("%EMAILLOCALPART%", local)
+        .replace("%EMAILDOMAIN%", domain)
+}
+
+/// The elements named `name` in `xml`, as (attributes, contents). Just
+/// enough XML for clientConfig files, where no element nests in one of the
+/// same name.
+fn elements<'a>(xml: &'a str, name: &str) -> Vec<(&'a str, &'a str)> {
+    let (open, close) = (format!("<{}", name), format!("</{}>", name));
+    let mut found = Vec::new();
+    let mut rest = xml;
+    while let Some(start) = rest.find(&open) {
+        let after = &rest[start + open.len()..];
+        let Some(end) = after.find('>') else { break };
+        // <port> isn't the start of <portal>
+        if !after.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace()) {
+            rest = after;
+            continue;
+        }
+        if let Some(attributes) = after[..end].strip_suffix('/') {
+            found.push((attributes, ""));
+            rest = &after[end + 1..];
+            continue;
+        }
+        let body = &after[end + 1..];
+        let Some(stop) = body.find(&close) else { break };
+        found.push((&after[..end], &body[..stop]));
+        rest = &body[stop + close.len()..];
+    }
+    found
+}
+
+fn attribute<'a>(attributes: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!("{}=", name);
+    attributes.match_indices(&key).find_map(|(at, _)| {
+        if !attributes[..at].ends_with(char::is_whitespace) {
+            return None;
+        }
+        let value = &attributes[at + key.len()..];
+        let quote = value.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        value[1..].split(quote).next()
+    })
+}
+
+/// The trimmed text of the first `name` element, if it has any.
+fn text(xml: &str, name: &str) -> Option<String> {
+    let (_, body) = elements(xml, name).into_iter().next()?;
+    Some(unescape(body.trim())).filter(|text| !text.is_empty())
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::dns::tests::FixtureResolver;
+    use std::collections::HashMap;
+
+    /// Serves fixed bodies by URL. URLs on hosts under "fail." error.
+    struct FixtureFetcher(HashMap<&'static str, &'static str>);
+
+    #[async_trait]
+    impl HttpFetcher for FixtureFetcher {
+        async fn get(&self, url: &str) -> Result<Option<String>> {
+            if url.starts_with("https://fail.") || url.starts_with("https://autoconfig.fail.") {
+                bail!("connection refused");
+            }
+            Ok(self.0.get(url).map(|body| body.to_string()))
+        }
+    }
+
+    const AUTOCONFIG: &str = r#"<?xml version="1.0"?>
+<clientConfig version="1.1">
+  <emailProvider id="example.org">
+    <domain>example.org</domain>
+    <incomingServer type="pop3"><hostname>pop.example.org</hostname><port>995</port><socketType>SSL</socketType></incomingServer>
+    <incomingServer type="imap">
+      <hostname>imap.%EMAILDOMAIN%</hostname>
+      <port>143</port>
+      <socketType>STARTTLS</socketType>
+    </incomingServer>
+    <incomingServer
+        type='imap'>
+      <hostname>imap.%EMAILDOMAIN%</hostname>
+      <port>993</port>
+      <socketType>SSL</socketType>
+      <username>%EMAILLOCALPART%</username>
+      <authentication>password-cleartext</authentication>
+    </incomingServer>
+    <outgoingServer type="smtp">
+      <hostname>smtp.example.org</hostname>
+      <port>25</port>
+      <socketType>plain</socketType>
+    </outgoingServer>
+    <outgoingServer type="smtp">
+      <hostname>smtp.example.org</hostname>
+      <port>587</port>
+      <socketType>STARTTLS</socketType>
+    </outgoingServer>
+  </emailProvider>
+</clientConfig>"#;
+
+    #[tokio::test]
+    async fn test_ispdb() {
+        let (dns, http) = (FixtureResolver(Vec::new()), FixtureFetcher(HashMap::new()));
+        let found = discover(" Someone@GoogleMail.com", Some(&dns), &http).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].source, found[0].confidence), (Source::Ispdb, 90));
+        assert_eq!((found[0].imap_server.as_str(), found[0].imap_port), ("imap.gmail.com", 993));
+        assert_eq!((found[0].smtp_server.as_deref(), found[0].smtp_port), (Some("smtp.gmail.com"), Some(465)));
+        // Tests are built without OAuth2 client IDs
+        assert_eq!(found[0].auth_method, AuthMethod::Password);
+
+        let found = discover("someone@web.de", Some(&dns), &http).await.unwrap();
+        assert_eq!(found[0].username, "someone");
+        assert_eq!((found[0].smtp_server.as_deref(), found[0].smtp_port), (Some("smtp.web.de"), Some(587)));
+        assert!(discover("someone", Some(&dns), &http).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_network_sources() {
+        let http = FixtureFetcher(HashMap::from([
+            ("https://autoconfig.example.org/mail/config-v1.1.xml?emailaddress=someone%40example.org", AUTOCONFIG),
+            ("https://example.net/.well-known/autoconfig/mail/config-v1.1.xml", AUTOCONFIG),
+        ]));
+        let dns = FixtureResolver(vec![
+            ("_imaps._tcp.example.org", "0 1 993 imap.example.org."),
+            ("_submission._tcp.example.org", "0 1 587 smtp.example.org."),
+            ("_imaps._tcp.example.com", "0 1 993 mx.example.com."),
+            ("_submissions._tcp.example.com", "0 0 0 ."),
+            ("_submission._tcp.example.com", "0 1 587 mx.example.com."),
+            ("mail.example.org", "192.0.2.1"),
+        ]);
+
+        // Autoconfig and SRV agree, so only the more trusted is kept
+        let found = discover("someone@example.org", Some(&dns), &http).await.unwrap();
+        assert_eq!(found.iter().map(|c| c.source).collect::<Vec<_>>(), vec![Source::Autoconfig, Source::Guess]);
+        assert_eq!((found[0].imap_server.as_str(), found[0].imap_port, found[0].username.as_str()), ("imap.example.org", 993, "someone"));
+        assert_eq!(found[0].smtp_port, Some(587));
+        assert_eq!((found[1].imap_server.as_str(), found[1].smtp_server.as_deref()), ("mail.example.org", Some("mail.example.org")));
+
+        let found = discover("someone@example.net", Some(&dns), &http).await.unwrap();
+        assert_eq!(found.iter().map(|c| c.source).collect::<Vec<_>>(), vec![Source::Autoconfig]);
+        assert_eq!(found[0].imap_server, "imap.example.net");
+
+        let found = discover("someone@example.com", Some(&dns), &http).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].source, found[0].username.as_str()), (Source::Srv, "someone@example.com"));
+        assert_eq!((found[0].smtp_server.as_deref(), found[0].smtp_port), (Some("mx.example.com"), Some(587)));
+
+        // Failing lookups are skipped
+        assert!(discover("someone@fail.example", Some(&dns), &http).await.unwrap().is_empty());
+        assert!(discover("someone@timeout.example", Some(&dns), &http).await.unwrap().is_empty());
+
+        // Behind a proxy only autoconfig is asked, over the proxy
+        let found = discover("someone@example.org", None, &http).await.unwrap();
+        assert_eq!(found.iter().map(|c| c.source).collect::<Vec<_>>(), vec![Source::Autoconfig]);
+        assert!(discover("someone@example.com", None, &http).await.unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_elements() {
+        let xml = r#"<a x="1"/><ab>no</ab><a  y='2' x="3">three</a>"#;
+        assert_eq!(elements(xml, "a"), vec![(r#" x="1""#, ""), (r#"  y='2' x="3""#, "three")]);
+        assert_eq!(attribute(r#" y='2' xx="1" x="3""#, "x"), Some("3"));
+        assert_eq!(text("<b> &lt;&amp;lt; </b>", "b").as_deref(), Some("<&lt;"));
+    }
+}
@@ -0,0 +1,454 @@
+//! DKIM signature verification (RFC 6376, and RFC 8463 for Ed25519), for
+//! checking a message ourselves instead of taking the server's word.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::{digest, signature};
+
+use super::dns::DnsResolver;
+use super::mime;
+use crate::db::AuthResult;
+
+/// At most this many signatures are checked per message.
+const MAX_SIGNATURES: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Canonicalization {
+    Simple,
+    Relaxed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    RsaSha1,
+    RsaSha256,
+    Ed25519Sha256,
+}
+
+/// Why a signature did not verify, as an RFC 8601 result and a reason.
+struct Failure(&'static str, String);
+
+fn permerror(reason: impl Into<String>) -> Failure {
+    Failure("permerror", reason.into())
+}
+
+fn fail(reason: impl Into<String>) -> Failure {
+    Failure("fail", reason.into())
+}
+
+/// Checks every DKIM signature on `message`. No signatures gives a single
+/// "none" result.
+pub async fn verify(message: &[u8], resolver: &dyn DnsResolver) -> Vec<AuthResult> {
+    let message = mime::to_crlf(message);
+    let (headers, body) = mime::split_message(&message);
+    let fields = header_fields(headers);
+
+    let signatures: Vec<&[u8]> = fields
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("DKIM-Signature"))
+        .map(|(_, raw)| *raw)
+        .take(MAX_SIGNATURES)
+        .collect();
+    if signatures.is_empty() {
+        return vec![AuthResult {
+            method: "dkim".to_string(),
+            result: "none".to_string(),
+            domain: None,
+            reason: None,
+            local: true,
+        }];
+    }
+
+    let mut results = Vec::new();
+    for signature in signatures {
+        let tags = parse_tags(&String::from_utf8_lossy(field_value(signature)));
+        let outcome = verify_signature(signature, &tags, &fields, body, resolver).await;
+        let (result, reason) = match outcome {
+            Ok(()) => ("pass", None),
+            Err(Failure(result, reason)) => (result, Some(reason)),
+        };
+        results.push(AuthResult {
+            method: "dkim".to_string(),
+            result: result.to_string(),
+            domain: tags.get("d").map(|d| d.to_ascii_lowercase()),
+            reason,
+            local: true,
+        });
+    }
+    results
+}
+
+async fn verify_signature(
+    signature: &[u8],
+    tags: &HashMap<String, String>,
+    fields: &[(String, &[u8])],
+    body: &[u8],
+    resolver: &dyn DnsResolver,
+) -> Result<(), Failure> {
+    let tag = |name: &str| tags.get(name).map(String::as_str).ok_or_else(|| permerror(format!("missing {}= tag", name)));
+    if tag("v")? != "1" {
+        return Err(permerror("unsupported version"));
+    }
+    let algorithm = match tag("a")?.to_ascii_lowercase().as_str() {
+        "rsa-sha1" => Algorithm::RsaSha1,
+        "rsa-sha256" => Algorithm::RsaSha256,
+        "ed25519-sha256" => Algorithm::Ed25519Sha256,
+        other => return Err(permerror(format!("unsupported algorithm {}", other))),
+    };
+    let (header_canon, body_canon) = canonicalizations(tags.get("c").map(String::as_str).unwrap_or("simple/simple"))?;
+    let domain = tag("d")?.to_ascii_lowercase();
+    let selector = tag("s")?;
+    let signed: Vec<String> = tag("h")?.split(':').map(|name| name.trim().to_ascii_lowercase()).collect();
+    if !signed.iter().any(|name| name == "from") {
+        return Err(permerror("From is not signed"));
+    }
+    if let Some(identity) = tags.get("i") {
+        let identity_domain = identity.rsplit('@').next().unwrap_or_default().to_ascii_lowercase();
+        if identity_domain != domain && !identity_domain.ends_with(&format!(".{}", domain)) {
+            return Err(permerror("i= is outside d="));
+        }
+    }
+    if let Some(expires) = tags.get("x").and_then(|x| x.parse::<i64>().ok()) {
+        if expires < chrono::Utc::now().timestamp() {
+            return Err(fail("signature expired"));
+        }
+    }
+    let body_hash = STANDARD.decode(tag("bh")?).map_err(|_| permerror("bad bh= tag"))?;
+    let signature_bytes = STANDARD.decode(tag("b")?).map_err(|_| permerror("bad b= tag"))?;
+
+    // Body hash
+    let mut canonical_body = canonicalize_body(body, body_canon);
+    if let Some(length) = tags.get("l") {
+        let length = length.parse::<usize>().map_err(|_| permerror("bad l= tag"))?;
+        canonical_body.truncate(length);
+    }
+    let hash_algorithm = match algorithm {
+        Algorithm::RsaSha1 => &digest::SHA1_FOR_LEGACY_USE_ONLY,
+        Algorithm::RsaSha256 | Algorithm::Ed25519Sha256 => &digest::SHA256,
+    };
+    if digest::digest(hash_algorithm, &canonical_body).as_ref() != body_hash.as_slice() {
+        return Err(fail("body hash did not verify"));
+    }
+
+    // Signed headers, each name taking the next instance from the bottom
+    let mut data = Vec::new();
+    let mut used: HashMap<&str, usize> = HashMap::new();
+    for name in &signed {
+        let skip = used.entry(name.as_str()).or_default();
+        let instance = fields.iter().rev().filter(|(field, _)| field.eq_ignore_ascii_case(name)).nth(*skip);
+        *skip += 1;
+        if let Some((_, raw)) = instance {
+            data.extend(canonicalize_header(raw, header_canon));
+        }
+    }
+    let mut own = canonicalize_header(&without_signature(signature), header_canon);
+    if own.ends_with(b"\r\n") {
+        own.truncate(own.len() - 2);
+    }
+    data.extend(own);
+
+    // Key record
+    let name = format!("{}._domainkey.{}", selector, domain);
+    let records = resolver.txt(&name).await.map_err(|e| Failure("temperror", format!("key lookup failed: {}", e)))?;
+    let record = records.first().ok_or_else(|| permerror(format!("no key at {}", name)))?;
+    let key_tags = parse_tags(record);
+    let key = key_tags.get("p").ok_or_else(|| permerror("key record has no p= tag"))?;
+    if key.is_empty() {
+        return Err(fail("key revoked"));
+    }
+    let key = STANDARD.decode(key).map_err(|_| permerror("bad key"))?;
+    let key_type = key_tags.get("k").map(|k| k.to_ascii_lowercase()).unwrap_or_else(|| "rsa".to_string());
+
+    let verified = match (algorithm, key_type.as_str()) {
+        (Algorithm::RsaSha1, "rsa") | (Algorithm::RsaSha256, "rsa") => {
+            let scheme = if algorithm == Algorithm::RsaSha1 {
+                &signature::RSA_PKCS1_1024_8192_SHA1_FOR_LEGACY_USE_ONLY
+            } else {
+                &signature::RSA_PKCS1_1024_8192_SHA256_FOR_LEGACY_USE_ONLY
+            };
+            let key = rsa_public_key(&key).ok_or_else(|| permerror("bad RSA key"))?;
+            signature::UnparsedPublicKey::new(scheme, key).verify(&data, &signature_bytes).is_ok()
+        }
+        // Ed25519 signs the hash rather than the data itself
+        (Algorithm::Ed25519Sha256, "ed25519") => signature::UnparsedPublicKey::new(&signature::ED25519, &key)
+            .verify(digest::digest(&digest::SHA256, &data).as_ref(), &signature_bytes)
+            .is_ok(),
+        _ => return Err(permerror("key type does not match the algorithm")),
+    };
+    if verified {
+        Ok(())
+    } else {
+        Err(fail("signature did not verify"))
+    }
+}
+
+fn canonicalizations(c: &str) -> Result<(Canonicalization, Canonicalization), Failure> {
+    let parse = |name: &str| match name.trim().to_ascii_lowercase().as_str() {
+        "simple" => Ok(Canonicalization::Simple),
+        "relaxed" => Ok(Canonicalization::Relaxed),
+        other => Err(permerror(format!("unknown canonicalization {}", other))),
+    };
+    match c.split_once('/') {
+        Some((header, body)) => Ok((parse(header)?, parse(body)?)),
+        None => Ok((parse(c)?, Canonicalization::Simple)),
+    }
+}
+
+/// Each header field's name and raw bytes, folded lines and the final
+/// CRLF included.
+fn header_fields(headers: &[u8]) -> Vec<(String, &[u8])> {
+    let mut fields: Vec<(String, &[u8])> = Vec::new();
+    let mut start = 0;
+    while start < headers.len() {
+        let mut end = start;
+        loop {
+            end += headers[end..].windows(2).position(|window| window == b"\r\n").map_or(headers.len() - end, |at| at + 2);
+            if end >= headers.len() || !matches!(headers[end], b' ' | b'\t') {
+                break;
+            }
+        }
+        let raw = &headers[start..end];
+        if let Some(colon) = raw.iter().position(|&b| b == b':') {
+            fields.push((String::from_utf8_lossy(&raw[..colon]).trim().to_string(), raw));
+        }
+        start = end;
+    }
+    fields
+}
+
+fn field_value(raw: &[u8]) -> &[u8] {
+    raw.iter().position(|&b| b == b':').map_or(raw, |colon| &raw[colon + 1..])
+}
+
+/// `tag=value` pairs, with whitespace taken out of the values.
+fn parse_tags(value: &str) -> HashMap<String, String> {
+    value
+        .split(';')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(tag, value)| {
+            let value: String = value.chars().filter(|c| !c.is_whitespace()).collect();
+            (tag.trim().to_string(), value)
+        })
+        .collect()
+}
+
+/// The signature header with its b= value emptied, as it was when signed.
+fn without_signature(raw: &[u8]) -> Vec<u8> {
+    let colon = raw.iter().position(|&b| b == b':').unwrap_or(0);
+    let mut out = raw[..=colon].to_vec();
+    let segments: Vec<&[u8]> = raw[colon + 1..].split(|&b| b == b';').collect();
+    for (i, segment) in segments.iter().enumerate() {
+        if i > 0 {
+            out.push(b';');
+        }
+        let equals = segment.iter().position(|&b| b == b'=');
+        match equals {
+            Some(at) if trim(&segment[..at]) == b"b" => {
+                out.extend_from_slice(&segment[..=at]);
+                // Keep the line break after the value, if it is the last tag
+                if segment.ends_with(b"\r\n") {
+                    out.extend_from_slice(b"\r\n");
+                }
+            }
+            _ => out.extend_from_slice(segment),
+        }
+    }
+    out
+}
+
+fn is_wsp(b: u8) -> bool {
+    b == b' ' || b == b'\t'
+}
+
+fn trim(data: &[u8]) -> &[u8] {
+    let start = data.iter().position(|b| !b.is_ascii_whitespace()).unwrap_or(data.len());
+    let end = data.iter().rposition(|b| !b.is_ascii_whitespace()).map_or(start, |at| at + 1);
+    &data[start..end]
+}
+
+/// Runs of spaces and tabs squeezed to one space.
+fn squeeze(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    for &b in data {
+        if is_wsp(b) {
+            if out.last() != Some(&b' ') {
+                out.push(b' ');
+            }
+        } else {
+            out.push(b);
+        }
+    }
+    out
+}
+
+fn canonicalize_header(raw: &[u8], canon: Canonicalization) -> Vec<u8> {
+    match canon {
+        Canonicalization::Simple => raw.to_vec(),
+        Canonicalization::Relaxed => {
+            let colon = raw.iter().position(|&b| b == b':').unwrap_or(raw.len());
+            let mut out = trim(&raw[..colon]).to_ascii_lowercase();
+            out.push(b':');
+            let unfolded: Vec<u8> = raw[(colon + 1).min(raw.len())..].iter().copied().filter(|&b| b != b'\r' && b != b'\n').collect();
+            out.extend_from_slice(trim(&squeeze(&unfolded)));
+            out.extend_from_slice(b"\r\n");
+            out
+        }
+    }
+}
+
+fn canonicalize_body(body: &[u8], canon: Canonicalization) -> Vec<u8> {
+    let mut lines: Vec<Vec<u8>> = body
+        .split(|&b| b == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line).to_vec())
+        .collect();
+    if canon == Canonicalization::Relaxed {
+        for line in &mut lines {
+            let mut squeezed = squeeze(line);
+            while squeezed.last() == Some(&b' ') {
+                squeezed.pop();
+            }
+            *line = squeezed;
+        }
+    }
+    while lines.last().is_some_and(|line| line.is_empty()) {
+        lines.pop();
+    }
+    // An empty body is a single CRLF in simple, and nothing in relaxed
+    if lines.is_empty() && canon == Canonicalization::Simple {
+        return b"\r\n".to_vec();
+    }
+    lines.into_iter().flat_map(|mut line| {
+        line.extend_from_slice(b"\r\n");
+        line
+    }).collect()
+}
+
+/// The PKCS#1 RSAPublicKey inside a DER SubjectPublicKeyInfo, which is how
+/// DKIM publishes keys. Some publish the bare PKCS#1 key, which is passed
+/// through.
+fn rsa_public_key(der: &[u8]) -> Option<&[u8]> {
+    let (tag, spki, _) = der_element(der)?;
+    if tag != 0x30 {
+        return None;
+    }
+    let (tag, _, rest) = der_element(spki)?;
+    match tag {
+        // RSAPublicKey starts with the modulus
+        0x02 => Some(der),
+        // AlgorithmIdentifier, then the key as a BIT STRING
+        0x30 => {
+            let (tag, bits, _) = der_element(rest)?;
+            (tag == 0x03 && bits.first() == Some(&0)).then(|| &bits[1..])
+        }
+        _ => None,
+    }
+}
+
+/// The tag, contents and remainder of the DER element at the start of
+/// `data`.
+fn der_element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let tag = *data.first()?;
+    let first = *data.get(1)? as usize;
+    let (len, header) = if first < 0x80 {
+        (first, 2)
+    } else {
+        let count = first & 0x7f;
+        if count == 0 || count > 4 {
+            return None;
+        }
+        let len = data.get(2..2 + count)?.iter().fold(0usize, |len, &b| len << 8 | b as usize);
+        (len, 2 + count)
+    };
+    let end = header.checked_add(len)?;
+    Some((tag, data.get(header..end)?, &data[end..]))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::email::dns::tests::FixtureResolver;
+
+    pub const RSA_SIGNATURE: &str = "DKIM-Signature: v=1; a=rsa-sha256; c=relaxed/relaxed; d=example.com;\r\n s=test; h=from:to:subject; bh=Wni3pTXutyq2SKYuw/BrylmBoj/7ipDzJkZd0PyfeLs=;\r\n b=WOm7dufIdIqUtHZTDI0He3HMig14xRsxskehCAfv/Kwxd6sjZcn5bznJ6wvrDG7Vfu+ogsKTAbssNNkRxNd66BF/cp82QzKgwVVmsuI1gvXg8NDahEMpzHUn6vYLnfC7yFP2Kq/xEb2mgqVrya5UQvQUEE65tcaMiYeN63hz9sU=\r\n";
+    pub const ED25519_SIGNATURE: &str = "DKIM-Signature: v=1; a=ed25519-sha256; c=relaxed/simple; d=mail.example.com; i=@mail.example.com;\r\n s=ed; h=from:subject:from; bh=QOvDb5tdIDtUxRw8bCNmAEJZ9Rnr0UUE7BAY7Vw00kU=; b=rkhQnuy54Ysq7oyOnfj6K/8KFtblm3UU7u2Pz0IEsKupAhKYRzrexklX3a+WoXDpGF9VPTM27gp3Z40dNadqDg==\r\n";
+    pub const HEADERS: &str = "From: Alice <alice@example.com>\r\nTo: bob@example.org\r\nSubject:  Hello \r\n\tthere  \r\n";
+    pub const BODY: &str = "Hi Bob,  \r\n\r\nSee  you\tsoon.\r\n\r\n\r\n";
+
+    pub fn keys() -> FixtureResolver {
+        FixtureResolver(vec![
+            ("test._domainkey.example.com", "v=DKIM1; k=rsa; p=MIGfMA0GCSqGSIb3DQEBAQUAA4GNADCBiQKBgQCvRmpfhvfujWsg9hfGDJTV0E8TGvybWGh4N281mGNrcY7RWKCjmH34SCVNTMYS3vyiGgwJoj69o+lA3nCVLfUr1pygaEc7GameslrRrsipc3x6gEedggELcA7lZVKk0JI7Z3F55JVqTnwD6SAH/7zHHIuIBSsV35qWn7X7t37lBwIDAQAB"),
+            ("ed._domainkey.mail.example.com", "v=DKIM1; k=ed25519; p=ppYJeBwI/pZ4kCRLbU8RU7erdDtZJ58xi6dlvEkpGqM="),
+        ])
+    }
+
+    fn results(results: &[AuthResult]) -> Vec<(&str, Option<&str>)> {
+        results.iter().map(|r| (r.result.as_str(), r.domain.as_deref())).collect()
+    }
+
+    #[tokio::test]
+    async fn test_verify_signatures() {
+        let message = format!("{}{}{}\r\n{}", RSA_SIGNATURE, ED25519_SIGNATURE, HEADERS, BODY);
+        let verified = verify(message.as_bytes(), &keys()).await;
+        assert_eq!(results(&verified), vec![("pass", Some("example.com")), ("pass", Some("mail.example.com"))]);
+
+        // Still passes with LF line endings, which some stores keep
+        let verified = verify(message.replace("\r\n", "\n").as_bytes(), &keys()).await;
+        assert_eq!(results(&verified), vec![("pass", Some("example.com")), ("pass", Some("mail.example.com"))]);
+
+        // Relaxed body canonicalization ignores trailing whitespace, simple does not
+        let message = format!("{}{}{}\r\n{}", RSA_SIGNATURE, ED25519_SIGNATURE, HEADERS, BODY.replace("soon.", "soon. "));
+        let verified = verify(message.as_bytes(), &keys()).await;
+        assert_eq!(results(&verified), vec![("pass", Some("example.com")), ("fail", Some("mail.example.com"))]);
+        assert_eq!(verified[1].reason.as_deref(), Some("body hash did not verify"));
+
+        // An added From fills the unused "from" slot in the Ed25519 signature
+        let message = format!("{}{}From: mallory@example.net\r\n{}\r\n{}", RSA_SIGNATURE, ED25519_SIGNATURE, HEADERS, BODY);
+        let verified = verify(message.as_bytes(), &keys()).await;
+        assert_eq!(results(&verified), vec![("pass", Some("example.com")), ("fail", Some("mail.example.com"))]);
+        assert_eq!(verified[1].reason.as_deref(), Some("signature did not verify"));
+
+        let message = format!("{}{}\r\n{}", RSA_SIGNATURE, HEADERS.replace("Hello", "Goodbye"), BODY);
+        assert_eq!(results(&verify(message.as_bytes(), &keys()).await), vec![("fail", Some("example.com"))]);
+    }
+
+    #[tokio::test]
+    async fn test_verify_failures() {
+        let message = format!("{}\r\n{}", HEADERS, BODY);
+        assert_eq!(results(&verify(message.as_bytes(), &keys()).await), vec![("none", None)]);
+
+        let message = format!("{}{}\r\n{}", RSA_SIGNATURE.replace("s=test", "s=gone"), HEADERS, BODY);
+        let verified = verify(message.as_bytes(), &keys()).await;
+        assert_eq!(results(&verified), vec![("permerror", Some("example.com"))]);
+        assert_eq!(verified[0].reason.as_deref(), Some("no key at gone._domainkey.example.com"));
+
+        let message = format!("{}{}\r\n{}", RSA_SIGNATURE.replace("s=test", "s=timeout"), HEADERS, BODY);
+        assert_eq!(results(&verify(message.as_bytes(), &keys()).await), vec![("temperror", Some("example.com"))]);
+
+        let revoked = FixtureResolver(vec![("test._domainkey.example.com", "v=DKIM1; p=")]);
+        let message = format!("{}{}\r\n{}", RSA_SIGNATURE, HEADERS, BODY);
+        let verified = verify(message.as_bytes(), &revoked).await;
+        assert_eq!(verified[0].reason.as_deref(), Some("key revoked"));
+
+        let message = format!("{}{}\r\n{}", RSA_SIGNATURE.replace("h=from:to:subject", "h=to:subject"), HEADERS, BODY);
+        let verified = verify(message.as_bytes(), &keys()).await;
+        assert_eq!(verified[0].reason.as_deref(), Some("From is not signed"));
+    }
+
+    #[test]
+    fn test_canonicalization() {
+        let header = b"SubJect :  Hello \r\n\tthere  \r\n";
+        assert_eq!(canonicalize_header(header, Canonicalization::Relaxed), b"subject:Hello there\r\n");
+        assert_eq!(canonicalize_header(header, Canonicalization::Simple), header);
+
+        assert_eq!(canonicalize_body(b"a  b \r\n\r\n\r\n", Canonicalization::Relaxed), b"a b\r\n");
+        assert_eq!(canonicalize_body(b"a  b \r\n\r\n\r\n", Canonicalization::Simple), b"a  b \r\n");
+        assert_eq!(canonicalize_body(b"", Canonicalization::Simple), b"\r\n");
+        assert_eq!(canonicalize_body(b"", Canonicalization::Relaxed), b"");
+        assert_eq!(canonicalize_body(b"no newline", Canonicalization::Simple), b"no newline\r\n");
+
+        let signature = b"DKIM-Signature: a=rsa-sha256; bh=abc;\r\n b=AAAA\r\n BBBB\r\n";
+        assert_eq!(without_signature(signature), b"DKIM-Signature: a=rsa-sha256; bh=abc;\r\n b=\r\n");
+    }
+}
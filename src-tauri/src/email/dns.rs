@@ -0,0 +1,125 @@
+//! DNS lookups, for DKIM keys and for finding an account's servers. A trait
+//! so tests can answer from fixtures.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use hickory_resolver::error::{ResolveError, ResolveErrorKind};
+
+/// An SRV record (RFC 2782).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Srv {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    /// Without the trailing dot, so empty when the service isn't offered.
+    pub target: String,
+}
+
+#[async_trait]
+pub trait DnsResolver: Send + Sync {
+    /// The TXT records at `name`, each with its strings joined. Empty when
+    /// there are none.
+    async fn txt(&self, name: &str) -> Result<Vec<String>>;
+    /// The SRV records at `name`, the preferred first. Empty when there are
+    /// none.
+    async fn srv(&self, name: &str) -> Result<Vec<Srv>>;
+    /// Whether `host` has an address.
+    async fn resolves(&self, host: &str) -> Result<bool>;
+}
+
+/// Resolves through the system's configured name servers.
+pub struct SystemResolver(hickory_resolver::TokioAsyncResolver);
+
+impl SystemResolver {
+    pub fn new() -> Result<Self> {
+        Ok(SystemResolver(hickory_resolver::TokioAsyncResolver::tokio_from_system_conf()?))
+    }
+}
+
+/// Lookups that found nothing become empty answers.
+fn or_none<T>(result: Result<T, ResolveError>) -> Result<Option<T>> {
+    match result {
+        Ok(lookup) => Ok(Some(lookup)),
+        Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[async_trait]
+impl DnsResolver for SystemResolver {
+    async fn txt(&self, name: &str) -> Result<Vec<String>> {
+        let Some(lookup) = or_none(self.0.txt_lookup(name).await)? else {
+            return Ok(Vec::new());
+        };
+        Ok(lookup
+            .iter()
+            .map(|txt| txt.txt_data().iter().map(|part| String::from_utf8_lossy(part)).collect())
+            .collect())
+    }
+
+    async fn srv(&self, name: &str) -> Result<Vec<Srv>> {
+        let Some(lookup) = or_none(self.0.srv_lookup(name).await)? else {
+            return Ok(Vec::new());
+        };
+        let mut records: Vec<Srv> = lookup
+            .iter()
+            .map(|srv| Srv {
+                priority: srv.priority(),
+                weight: srv.weight(),
+                port: srv.port(),
+                target: srv.target().to_utf8().trim_end_matches('.').to_string(),
+            })
+            .collect();
+        // Lowest priority first, then the heaviest, instead of a weighted draw
+        records.sort_by_key(|srv| (srv.priority, std::cmp::Reverse(srv.weight)));
+        Ok(records)
+    }
+
+    async fn resolves(&self, host: &str) -> Result<bool> {
+        Ok(or_none(self.0.lookup_ip(host).await)?.is_some_and(|lookup| lookup.iter().next().is_some()))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Answers from a fixed table of (name, record) pairs. SRV records are
+    /// written "priority weight port target", and any record makes a name
+    /// resolve. Names with a "timeout" label fail.
+    pub struct FixtureResolver(pub Vec<(&'static str, &'static str)>);
+
+    impl FixtureResolver {
+        fn records(&self, name: &str) -> Result<Vec<&'static str>> {
+            if name.split('.').any(|label| label == "timeout") {
+                return Err(anyhow::anyhow!("timed out"));
+            }
+            Ok(self.0.iter().filter(|(at, _)| *at == name).map(|(_, record)| *record).collect())
+        }
+    }
+
+    #[async_trait]
+    impl DnsResolver for FixtureResolver {
+        async fn txt(&self, name: &str) -> Result<Vec<String>> {
+            Ok(self.records(name)?.into_iter().map(str::to_string).collect())
+        }
+
+        async fn srv(&self, name: &str) -> Result<Vec<Srv>> {
+            let mut records = Vec::new();
+            for record in self.records(name)? {
+                let fields: Vec<&str> = record.split_whitespace().collect();
+                records.push(Srv {
+                    priority: fields[0].parse()?,
+                    weight: fields[1].parse()?,
+                    port: fields[2].parse()?,
+                    target: fields[3].trim_end_matches('.').to_string(),
+                });
+            }
+            Ok(records)
+        }
+
+        async fn resolves(&self, host: &str) -> Result<bool> {
+            Ok(!self.records(host)?.is_empty())
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::fmt;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+/// A message flag the user can change: one of the IMAP system flags or a
+/// keyword such as `$Forwarded`, `$Junk` or `$Label1`. Serialized in its IMAP
+/// form (`\Seen`, `$Junk`).
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Flag {
+    Seen,
+    Answered,
+    Flagged,
+    Draft,
+    Keyword(String),
+}
+
+impl Flag {
+    pub const JUNK: &'static str = "$Junk";
+    pub const NOT_JUNK: &'static str = "$NotJunk";
+
+    /// Parses a flag as it appears in a FLAGS list. `\Deleted` and `\Recent`
+    /// are rejected: deletion has its own path and `\Recent` is server-owned.
+    pub fn parse(value: &str) -> Result<Self> {
+        if let Some(system) = value.strip_prefix('\\') {
+            return match system.to_ascii_lowercase().as_str() {
+                "seen" => Ok(Flag::Seen),
+                "answered" => Ok(Flag::Answered),
+                "flagged" => Ok(Flag::Flagged),
+                "draft" => Ok(Flag::Draft),
+                _ => Err(anyhow!("Unsupported system flag {}", value)),
+            };
+        }
+
+        let is_atom_char = |c: char| c.is_ascii_graphic() && !"(){%*\"\\]".contains(c);
+        if value.is_empty() || !value.chars().all(is_atom_char) {
+            return Err(anyhow!("Invalid keyword {:?}", value));
+        }
+        Ok(Flag::Keyword(value.to_string()))
+    }
+
+    pub fn as_imap(&self) -> &str {
+        match self {
+            Flag::Seen => "\\Seen",
+            Flag::Answered => "\\Answered",
+            Flag::Flagged => "\\Flagged",
+            Flag::Draft => "\\Draft",
+            Flag::Keyword(keyword) => keyword,
+        }
+    }
+
+    /// The `emails` column mirroring a system flag. Keywords live in
+    /// `email_keywords` instead.
+    pub fn column(&self) -> Option<&'static str> {
+        match self {
+            Flag::Seen => Some("is_read"),
+            Flag::Answered => Some("is_answered"),
+            Flag::Flagged => Some("is_flagged"),
+            Flag::Draft => Some("is_draft"),
+            Flag::Keyword(_) => None,
+        }
+    }
+
+    /// The keyword that cannot be set together with this one.
+    pub fn opposite(&self) -> Option<Flag> {
+        match self {
+            Flag::Keyword(k) if k.eq_ignore_ascii_case(Self::JUNK) => Some(Flag::Keyword(Self::NOT_JUNK.to_string())),
+            Flag::Keyword(k) if k.eq_ignore_ascii_case(Self::NOT_JUNK) => Some(Flag::Keyword(Self::JUNK.to_string())),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_imap())
+    }
+}
+
+impl TryFrom<String> for Flag {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        Flag::parse(&value)
+    }
+}
+
+impl From<Flag> for String {
+    fn from(flag: Flag) -> Self {
+        flag.as_imap().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_flags() {
+        assert_eq!(Flag::parse("\\SEEN").unwrap(), Flag::Seen);
+        assert_eq!(Flag::parse("$Forwarded").unwrap(), Flag::Keyword("$Forwarded".to_string()));
+        assert!(Flag::parse("\\Deleted").is_err());
+        assert!(Flag::parse("two words").is_err());
+        assert!(Flag::parse("").is_err());
+    }
+
+    #[test]
+    fn test_serde_uses_imap_form() {
+        let flags: Vec<Flag> = serde_json::from_str(r#"["\\Flagged", "$Label1"]"#).unwrap();
+        assert_eq!(flags, vec![Flag::Flagged, Flag::Keyword("$Label1".to_string())]);
+        assert_eq!(serde_json::to_string(&flags).unwrap(), r#"["\\Flagged","$Label1"]"#);
+    }
+
+    #[test]
+    fn test_junk_opposites() {
+        assert_eq!(Flag::parse("$Junk").unwrap().opposite(), Some(Flag::Keyword("$NotJunk".to_string())));
+        assert_eq!(Flag::Seen.opposite(), None);
+    }
+}
@@ -0,0 +1,191 @@
+use crate::db::{Folder, FolderType};
+
+/// Leaf names, lowercased, that mail clients and servers commonly use for
+/// each role. Only consulted when the server does not advertise SPECIAL-USE.
+const KNOWN_NAMES: &[(FolderType, &[&str])] = &[
+    (FolderType::Sent, &[
+        "sent", "sent items", "sent mail", "sent messages", "gesendet", "gesendete objekte",
+        "gesendete elemente", "envoyés", "éléments envoyés", "enviados", "elementos enviados",
+        "posta inviata", "inviata", "verzonden", "verzonden items", "skickat", "wysłane",
+        "отправленные",
+    ]),
+    (FolderType::Drafts, &[
+        "drafts", "draft", "entwürfe", "brouillons", "borradores", "bozze", "concepten",
+        "utkast", "kopie robocze", "черновики",
+    ]),
+    (FolderType::Trash, &[
+        "trash", "bin", "deleted items", "deleted messages", "papierkorb", "gelöschte objekte",
+        "gelöschte elemente", "corbeille", "éléments supprimés", "papelera", "elementos eliminados",
+        "cestino", "prullenbak", "verwijderde items", "papperskorgen", "kosz", "корзина",
+    ]),
+    (FolderType::Spam, &[
+        "spam", "junk", "junk e-mail", "junk email", "bulk mail", "spamverdacht",
+        "courrier indésirable", "correo no deseado", "posta indesiderata", "ongewenste e-mail",
+        "skräppost", "спам",
+    ]),
+    (FolderType::Archive, &[
+        "archive", "archives", "archiv", "archivo", "archivio", "archief", "arkiv", "archiwum",
+        "архив",
+    ]),
+    (FolderType::All, &["all mail", "alle nachrichten", "tous les messages"]),
+];
+
+/// The role named by an RFC 6154 special-use attribute such as `\Sent`.
+pub fn from_special_use(attributes: &[String]) -> Option<FolderType> {
+    attributes.iter().find_map(|attribute| match attribute.to_ascii_lowercase().as_str() {
+        "\\sent" => Some(FolderType::Sent),
+        "\\drafts" => Some(FolderType::Drafts),
+        "\\trash" => Some(FolderType::Trash),
+        "\\junk" => Some(FolderType::Spam),
+        "\\archive" => Some(FolderType::Archive),
+        "\\all" => Some(FolderType::All),
+        _ => None,
+    })
+}
+
+/// The role suggested by a folder's name, e.g. "Gesendet" or
+/// "[Gmail]/Sent Mail".
+pub fn from_name(name: &str, delimiter: Option<char>) -> Option<FolderType> {
+    let leaf = leaf_name(name, delimiter).to_lowercase();
+    KNOWN_NAMES
+        .iter()
+        .find(|(_, names)| names.contains(&leaf.as_str()))
+        .map(|(folder_type, _)| *folder_type)
+}
+
+/// Picks a type for every listed folder, given its name, delimiter and
+/// attributes. Special-use attributes win over names, and each role other
+/// than CUSTOM goes to at most one folder.
+pub fn detect_types(folders: &[(&str, Option<char>, &[String])]) -> Vec<FolderType> {
+    let mut types: Vec<FolderType> = folders
+        .iter()
+        .map(|(name, _, attributes)| {
+            if name.eq_ignore_ascii_case("INBOX") {
+                FolderType::Inbox
+            } else {
+                from_special_use(attributes).unwrap_or(FolderType::Custom)
+            }
+        })
+        .collect();
+
+    for (i, (name, delimiter, _)) in folders.iter().enumerate() {
+        if types[i] != FolderType::Custom {
+            continue;
+        }
+        if let Some(guess) = from_name(name, *delimiter) {
+            if !types.contains(&guess) {
+                types[i] = guess;
+            }
+        }
+    }
+    types
+}
+
+/// Last component of a hierarchical name, e.g. "Sent Mail" for
+/// "[Gmail]/Sent Mail".
+pub fn leaf_name(name: &str, delimiter: Option<char>) -> &str {
+    delimiter.and_then(|d| name.rsplit(d).next()).unwrap_or(name)
+}
+
+/// The folder enclosing `name`, or `None` at the top of the tree. The
+/// personal namespace prefix (e.g. "INBOX." on Courier) is not a level of
+/// its own, so its children are top-level.
+pub fn parent_name(name: &str, delimiter: Option<char>, personal_prefix: &str) -> Option<String> {
+    let (parent, _) = name.rsplit_once(delimiter?)?;
+    let is_prefix = !personal_prefix.is_empty() && personal_prefix.strip_suffix(delimiter?) == Some(parent);
+    (!parent.is_empty() && !is_prefix).then(|| parent.to_string())
+}
+
+/// The name `name` has after its folder or one of its ancestors was renamed
+/// from `old` to `new`, or `None` if the rename does not touch it.
+pub fn renamed(name: &str, old: &str, new: &str, delimiter: Option<char>) -> Option<String> {
+    if name == old {
+        return Some(new.to_string());
+    }
+    let rest = name.strip_prefix(old)?.strip_prefix(delimiter?)?;
+    Some(format!("{}{}{}", new, delimiter?, rest))
+}
+
+/// Applies the user's per-account choices. A folder given a role by the
+/// user takes it away from whichever folder was detected for it.
+pub fn apply_overrides(folders: &mut [Folder], overrides: &[(String, FolderType)]) {
+    for (name, folder_type) in overrides {
+        if !folders.iter().any(|folder| &folder.name == name) {
+            continue;
+        }
+        for folder in folders.iter_mut() {
+            if &folder.name == name {
+                folder.folder_type = *folder_type;
+            } else if folder.folder_type == *folder_type && *folder_type != FolderType::Custom {
+                folder.folder_type = FolderType::Custom;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folder(name: &str, folder_type: FolderType) -> Folder {
+        Folder {
+            id: 0,
+            account_id: 1,
+            name: name.to_string(),
+            display_name: name.to_string(),
+            folder_type,
+            message_count: 0,
+            unread_count: 0,
+            uid_validity: None,
+            uid_next: None,
+            delimiter: None,
+            parent_name: None,
+            is_subscribed: true,
+            is_selectable: true,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_from_name() {
+        assert_eq!(from_name("Gesendet", None), Some(FolderType::Sent));
+        assert_eq!(from_name("[Gmail]/Sent Mail", Some('/')), Some(FolderType::Sent));
+        assert_eq!(from_name("INBOX.Papierkorb", Some('.')), Some(FolderType::Trash));
+        assert_eq!(from_name("Projects", Some('/')), None);
+    }
+
+    #[test]
+    fn test_special_use_beats_names() {
+        let sent_attr = vec!["\\HasNoChildren".to_string(), "\\Sent".to_string()];
+        let none: Vec<String> = vec![];
+        let types = detect_types(&[
+            ("inbox", Some('/'), &none),
+            ("Sent", Some('/'), &none),
+            ("[Gmail]/Sent Mail", Some('/'), &sent_attr),
+            ("Entwürfe", Some('/'), &none),
+        ]);
+        assert_eq!(types, vec![FolderType::Inbox, FolderType::Custom, FolderType::Sent, FolderType::Drafts]);
+    }
+
+    #[test]
+    fn test_hierarchy() {
+        assert_eq!(leaf_name("[Gmail]/Sent Mail", Some('/')), "Sent Mail");
+        assert_eq!(parent_name("Work/Clients/Acme", Some('/'), ""), Some("Work/Clients".to_string()));
+        assert_eq!(parent_name("INBOX.Work", Some('.'), "INBOX."), None);
+        assert_eq!(parent_name("INBOX.Work.Acme", Some('.'), "INBOX."), Some("INBOX.Work".to_string()));
+        assert_eq!(parent_name("Work", None, ""), None);
+
+        assert_eq!(renamed("Work", "Work", "Jobs", Some('/')), Some("Jobs".to_string()));
+        assert_eq!(renamed("Work/Acme", "Work", "Jobs", Some('/')), Some("Jobs/Acme".to_string()));
+        assert_eq!(renamed("Workshop", "Work", "Jobs", Some('/')), None);
+    }
+
+    #[test]
+    fn test_overrides_move_roles() {
+        let mut folders = vec![folder("INBOX", FolderType::Inbox), folder("Archive", FolderType::Archive), folder("Old", FolderType::Custom)];
+        apply_overrides(&mut folders, &[("Old".to_string(), FolderType::Archive), ("Gone".to_string(), FolderType::Sent)]);
+        let types: Vec<FolderType> = folders.iter().map(|f| f.folder_type).collect();
+        assert_eq!(types, vec![FolderType::Inbox, FolderType::Custom, FolderType::Archive]);
+    }
+}
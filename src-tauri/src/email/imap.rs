@@ -1,359 +0,0 @@
-use async_trait::async_trait;
-use anyhow::{Result, anyhow};
-
-use crate::db::{Account, Email, Folder, EmailAddress, ComposeEmail};
-use super::EmailProtocol;
-
-pub struct ImapHandler;
-
-impl ImapHandler {
-    pub fn new() -> Self {
-        Self
-    }
-}
-
-#[async_trait]
-impl EmailProtocol for ImapHandler {
-    async fn test_connection(&self, _account: &Account) -> Result<bool> {
-        // TODO: Implement real IMAP connection test
-        // For now, simulate connection test
-        tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-        Ok(true)
-    }
-
-    async fn fetch_folders(&self, account: &Account) -> Result<Vec<Folder>> {
-        // TODO: Implement real IMAP folder listing
-        // For now, return common folders
-        Ok(vec![
-            Folder {
-                id: 0, // Will be set by database
-                account_id: account.id,
-                name: "INBOX".to_string(),
-                display_name: "Inbox".to_string(),
-                folder_type: "INBOX".to_string(),
-                message_count: 0,
-                unread_count: 0,
-                uid_validity: None,
-                uid_next: None,
-                created_at: chrono::Utc::now(),
-                updated_at: chrono::Utc::now(),
-            },
-            Folder {
-                id: 0, // Will be set by database
-                account_id: account.id,
-                name: "SENT".to_string(),
-                display_name: "Sent".to_string(),
-                folder_type: "SENT".to_string(),
-                message_count: 0,
-                unread_count: 0,
-                uid_validity: None,
-                uid_next: None,
-                created_at: chrono::Utc::now(),
-                updated_at: chrono::Utc::now(),
-            },
-            Folder {
-                id: 0, // Will be set by database
-                account_id: account.id,
-                name: "DRAFTS".to_string(),
-                display_name: "Drafts".to_string(),
-                folder_type: "DRAFT".to_string(),
-                message_count: 0,
-                unread_count: 0,
-                uid_validity: None,
-                uid_next: None,
-                created_at: chrono::Utc::now(),
-                updated_at: chrono::Utc::now(),
-            },
-            Folder {
-                id: 0, // Will be set by database
-                account_id: account.id,
-                name: "TRASH".to_string(),
-                display_name: "Trash".to_string(),
-                folder_type: "TRASH".to_string(),
-                message_count: 0,
-                unread_count: 0,
-                uid_validity: None,
-                uid_next: None,
-                created_at: chrono::Utc::now(),
-                updated_at: chrono::Utc::now(),
-            }
-        ])
-    }
-
-    async fn fetch_emails(&self, account: &Account, folder: &Folder, limit: u32, offset: u32) -> Result<Vec<Email>> {
-        // TODO: Implement real IMAP email fetching
-        // For now, generate mock emails based on folder type
-        let mut emails = Vec::new();
-        let start_id = offset + 1;
-        let end_id = start_id + limit;
-
-        for i in start_id..end_id {
-            let email = match folder.folder_type.as_str() {
-                "INBOX" => Email {
-                    id: i as i64,
-                    account_id: account.id,
-                    folder_id: folder.id,
-                    message_id: format!("msg-{}@example.com", i),
-                    thread_id: None,
-                    subject: format!("Test Email #{}", i),
-                    from_address: "sender@example.com".to_string(),
-                    from_name: Some("Test Sender".to_string()),
-                    to_addresses: serde_json::to_string(&vec![EmailAddress {
-                        name: None,
-                        address: account.email.clone(),
-                    }]).unwrap(),
-                    cc_addresses: None,
-                    bcc_addresses: None,
-                    body_text: Some(format!("This is test email #{} in your inbox.", i)),
-                    body_html: Some(format!("<p>This is test email #{} in your inbox.</p>", i)),
-                    attachments: None,
-                    size_bytes: 150,
-                    internal_date: chrono::Utc::now(),
-                    received_date: chrono::Utc::now(),
-                    is_read: i % 3 == 0, // Every 3rd email is read
-                    is_flagged: i % 5 == 0, // Every 5th email is flagged
-                    is_answered: false,
-                    is_draft: false,
-                    is_deleted: false,
-                    uid: Some(i as i64),
-                    mod_seq: None,
-                    created_at: chrono::Utc::now(),
-                    updated_at: chrono::Utc::now(),
-                },
-                "SENT" => Email {
-                    id: i as i64,
-                    account_id: account.id,
-                    folder_id: folder.id,
-                    message_id: format!("sent-{}@example.com", i),
-                    thread_id: None,
-                    subject: format!("Sent Email #{}", i),
-                    from_address: account.email.clone(),
-                    from_name: Some("Me".to_string()),
-                    to_addresses: serde_json::to_string(&vec![EmailAddress {
-                        name: None,
-                        address: "recipient@example.com".to_string(),
-                    }]).unwrap(),
-                    cc_addresses: None,
-                    bcc_addresses: None,
-                    body_text: Some(format!("This is sent email #{}.", i)),
-                    body_html: Some(format!("<p>This is sent email #{}.</p>", i)),
-                    attachments: None,
-                    size_bytes: 150,
-                    internal_date: chrono::Utc::now(),
-                    received_date: chrono::Utc::now(),
-                    is_read: true,
-                    is_flagged: false,
-                    is_answered: false,
-                    is_draft: false,
-                    is_deleted: false,
-                    uid: Some(i as i64),
-                    mod_seq: None,
-                    created_at: chrono::Utc::now(),
-                    updated_at: chrono::Utc::now(),
-                },
-                _ => Email {
-                    id: i as i64,
-                    account_id: account.id,
-                    folder_id: folder.id,
-                    message_id: format!("other-{}@example.com", i),
-                    thread_id: None,
-                    subject: format!("Email #{}", i),
-                    from_address: "unknown@example.com".to_string(),
-                    from_name: None,
-                    to_addresses: serde_json::to_string(&vec![EmailAddress {
-                        name: None,
-                        address: account.email.clone(),
-                    }]).unwrap(),
-                    cc_addresses: None,
-                    bcc_addresses: None,
-                    body_text: Some(format!("This is email #{}.", i)),
-                    body_html: Some(format!("<p>This is email #{}.</p>", i)),
-                    attachments: None,
-                    size_bytes: 150,
-                    internal_date: chrono::Utc::now(),
-                    received_date: chrono::Utc::now(),
-                    is_read: true,
-                    is_flagged: false,
-                    is_answered: false,
-                    is_draft: folder.folder_type == "DRAFT",
-                    is_deleted: folder.folder_type == "TRASH",
-                    uid: Some(i as i64),
-                    mod_seq: None,
-                    created_at: chrono::Utc::now(),
-                    updated_at: chrono::Utc::now(),
-                }
-            };
-            emails.push(email);
-        }
-
-        Ok(emails)
-    }
-
-    async fn send_email(&self, _account: &Account, _email: &ComposeEmail) -> Result<String> {
-        Err(anyhow!("IMAP handler cannot send emails - use SMTP"))
-    }
-
-    async fn mark_read(&self, _account: &Account, _email_id: &str) -> Result<()> {
-        // TODO: Implement real IMAP STORE command
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        Ok(())
-    }
-
-    async fn delete_email(&self, _account: &Account, _email_id: &str) -> Result<()> {
-        // TODO: Implement real IMAP STORE + EXPUNGE commands
-        tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
-        Ok(())
-    }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::db::{Account, Folder, Email};
-
-    #[tokio::test]
-    async fn test_imap_connection() {
-        let handler = ImapHandler::new();
-        let account = Account {
-            id: 1,
-            name: "Test Account".to_string(),
-            email: "test@example.com".to_string(),
-            protocol: "IMAP".to_string(),
-            imap_server: Some("imap.example.com".to_string()),
-            imap_port: Some(993),
-            smtp_server: Some("smtp.example.com".to_string()),
-            smtp_port: Some(587),
-            jmap_url: None,
-            username: "test@example.com".to_string(),
-            password_encrypted: "password".to_string(),
-            use_ssl: true,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        };
-
-        let result = handler.test_connection(&account).await;
-        assert!(result.is_ok());
-        assert!(result.unwrap());
-    }
-
-    #[tokio::test]
-    async fn test_fetch_folders() {
-        let handler = ImapHandler::new();
-        let account = Account {
-            id: 1,
-            name: "Test Account".to_string(),
-            email: "test@example.com".to_string(),
-            protocol: "IMAP".to_string(),
-            imap_server: Some("imap.example.com".to_string()),
-            imap_port: Some(993),
-            smtp_server: Some("smtp.example.com".to_string()),
-            smtp_port: Some(587),
-            jmap_url: None,
-            username: "test@example.com".to_string(),
-            password_encrypted: "password".to_string(),
-            use_ssl: true,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        };
-
-        let folders = handler.fetch_folders(&account).await.unwrap();
-        assert!(!folders.is_empty());
-        assert_eq!(folders.len(), 4); // INBOX, SENT, DRAFTS, TRASH
-        
-        // Check that INBOX is present
-        let inbox = folders.iter().find(|f| f.name == "INBOX");
-        assert!(inbox.is_some());
-    }
-
-    #[tokio::test]
-    async fn test_fetch_emails() {
-        let handler = ImapHandler::new();
-        let account = Account {
-            id: 1,
-            name: "Test Account".to_string(),
-            email: "test@example.com".to_string(),
-            protocol: "IMAP".to_string(),
-            imap_server: Some("imap.example.com".to_string()),
-            imap_port: Some(993),
-            smtp_server: Some("smtp.example.com".to_string()),
-            smtp_port: Some(587),
-            jmap_url: None,
-            username: "test@example.com".to_string(),
-            password_encrypted: "password".to_string(),
-            use_ssl: true,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        };
-
-        let folder = Folder {
-            id: 1,
-            account_id: 1,
-            name: "INBOX".to_string(),
-            display_name: "Inbox".to_string(),
-            folder_type: "INBOX".to_string(),
-            message_count: 0,
-            unread_count: 0,
-            uid_validity: None,
-            uid_next: None,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        };
-
-        let emails = handler.fetch_emails(&account, &folder, 10, 0).await.unwrap();
-        assert_eq!(emails.len(), 10);
-        
-        // Check email properties
-        let first_email = &emails[0];
-        assert!(!first_email.subject.is_empty());
-        assert!(!first_email.from_address.is_empty());
-        assert!(first_email.body_text.is_some());
-    }
-
-    #[tokio::test]
-    async fn test_mark_read() {
-        let handler = ImapHandler::new();
-        let account = Account {
-            id: 1,
-            name: "Test Account".to_string(),
-            email: "test@example.com".to_string(),
-            protocol: "IMAP".to_string(),
-            imap_server: Some("imap.example.com".to_string()),
-            imap_port: Some(993),
-            smtp_server: Some("smtp.example.com".to_string()),
-            smtp_port: Some(587),
-            jmap_url: None,
-            username: "test@example.com".to_string(),
-            password_encrypted: "password".to_string(),
-            use_ssl: true,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        };
-
-        let result = handler.mark_read(&account, "123").await;
-        assert!(result.is_ok());
-    }
-
-    #[tokio::test]
-    async fn test_delete_email() {
-        let handler = ImapHandler::new();
-        let account = Account {
-            id: 1,
-            name: "Test Account".to_string(),
-            email: "test@example.com".to_string(),
-            protocol: "IMAP".to_string(),
-            imap_server: Some("imap.example.com".to_string()),
-            imap_port: Some(993),
-            smtp_server: Some("smtp.example.com".to_string()),
-            smtp_port: Some(587),
-            jmap_url: None,
-            username: "test@example.com".to_string(),
-            password_encrypted: "password".to_string(),
-            use_ssl: true,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
-        };
-
-        let result = handler.delete_email(&account, "123").await;
-        assert!(result.is_ok());
-    }
-}
\ No newline at end of file
@@ -0,0 +1,668 @@
+use std::io;
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+use crate::db::{Account, AuthMethod};
+use crate::email::{net, sasl, tls, ProtocolError};
+
+use super::utf7;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImapError {
+    #[error("IMAP server rejected the command: {text}")]
+    No { code: Option<String>, text: String },
+    #[error("IMAP server reported a protocol error: {0}")]
+    Bad(String),
+}
+
+/// One server response line, with any literals it carried. The literal
+/// markers (`{n}`) are kept in `text`.
+#[derive(Debug, Clone)]
+pub struct Line {
+    pub text: String,
+    pub literals: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub untagged: Vec<Line>,
+    /// Text of the tagged OK, including its response code.
+    pub text: String,
+}
+
+impl Response {
+    /// Response codes from the tagged completion and untagged OK lines.
+    pub fn codes(&self) -> impl Iterator<Item = &str> {
+        self.untagged
+            .iter()
+            .filter_map(|line| line.text.strip_prefix("* OK "))
+            .chain(std::iter::once(self.text.as_str()))
+            .filter_map(response_code)
+    }
+
+    /// Flags the selected mailbox stores permanently, from the SELECT
+    /// response. `None` means the server did not say, so all are allowed.
+    pub fn permanent_flags(&self) -> Option<Vec<String>> {
+        self.codes().find_map(|code| {
+            let list = code.strip_prefix("PERMANENTFLAGS (")?.strip_suffix(')')?;
+            Some(list.split_whitespace().map(str::to_string).collect())
+        })
+    }
+
+    /// Source to destination UID pairs from a UIDPLUS COPYUID response code.
+    pub fn copy_uid(&self) -> Option<Vec<(i64, i64)>> {
+        self.codes().find_map(|code| {
+            let mut parts = code.split_whitespace();
+            if !parts.next()?.eq_ignore_ascii_case("COPYUID") {
+                return None;
+            }
+            let _uid_validity = parts.next()?;
+            let source = parse_uid_set(parts.next()?)?;
+            let target = parse_uid_set(parts.next()?)?;
+            (source.len() == target.len()).then(|| source.into_iter().zip(target).collect())
+        })
+    }
+}
+
+/// A single authenticated IMAP connection.
+pub struct ImapClient {
+    stream: BufReader<Box<dyn tls::Stream>>,
+    next_tag: u32,
+    capabilities: Vec<String>,
+    /// RFC 6855 is enabled: mailbox names travel as UTF-8, not modified UTF-7.
+    utf8_accept: bool,
+    /// The selected mailbox and the SELECT response that opened it.
+    selected: Option<(String, Response)>,
+}
+
+impl ImapClient {
+    pub async fn connect(account: &Account) -> Result<Self> {
+        let host = account.imap_server.as_deref().ok_or_else(|| anyhow!("Account has no IMAP server configured"))?;
+        let port = account.imap_port.unwrap_or(if account.use_ssl { 993 } else { 143 }) as u16;
+
+        let tcp = net::Connector::for_account(account)?.connect(host, port).await?;
+        let stream: Box<dyn tls::Stream> = if account.use_ssl {
+            tls::connect(host, Box::new(tcp), &tls::Trust::for_host(account, host)).await?
+        } else {
+            Box::new(tcp)
+        };
+
+        let (mut client, greeting) = Self::greet(stream).await?;
+        if !greeting.starts_with("* PREAUTH") {
+            client.login(account, host, port).await?;
+        }
+        client.refresh_capabilities().await?;
+
+        if client.has_capability("UTF8=ACCEPT") {
+            let response = client.run("ENABLE UTF8=ACCEPT").await?;
+            client.utf8_accept = response.untagged.iter().any(|line| {
+                let text = line.text.to_ascii_uppercase();
+                text.starts_with("* ENABLED") && text.split_whitespace().any(|c| c == "UTF8=ACCEPT")
+            });
+        }
+        Ok(client)
+    }
+
+    /// Reads the server's greeting over a fresh connection. It must be OK
+    /// or PREAUTH.
+    pub async fn greet(stream: Box<dyn tls::Stream>) -> Result<(Self, String)> {
+        let mut client = Self {
+            stream: BufReader::new(stream),
+            next_tag: 0,
+            capabilities: Vec::new(),
+            utf8_accept: false,
+            selected: None,
+        };
+        let greeting = client.read_line().await?;
+        if !greeting.text.starts_with("* OK") && !greeting.text.starts_with("* PREAUTH") {
+            return Err(anyhow!("Unexpected IMAP greeting: {}", greeting.text));
+        }
+        Ok((client, greeting.text))
+    }
+
+    /// Signs in with the account's password, or with its OAuth2 access
+    /// token over OAUTHBEARER or XOAUTH2.
+    pub async fn login(&mut self, account: &Account, host: &str, port: u16) -> Result<()> {
+        if account.auth_method == AuthMethod::Password {
+            let login = self.run(&format!("LOGIN {} {}", quote(&account.username), quote(&account.password_encrypted))).await;
+            return login.map(drop).map_err(auth_error);
+        }
+        let token = account.access_token.as_deref().ok_or_else(|| anyhow!("No OAuth2 access token for {}", account.email))?;
+        self.refresh_capabilities().await?;
+        let offered: Vec<String> = self
+            .capabilities
+            .iter()
+            .filter(|c| c.len() > 5 && c[..5].eq_ignore_ascii_case("AUTH="))
+            .map(|c| c[5..].to_string())
+            .collect();
+        let (mechanism, response) = match sasl::choose(&offered, true) {
+            Some("OAUTHBEARER") => ("OAUTHBEARER", sasl::oauthbearer(&account.username, host, port, token)),
+            Some(mechanism) => (mechanism, sasl::xoauth2(&account.username, token)),
+            None => return Err(anyhow!("The IMAP server doesn't offer OAuth2 sign-in")),
+        };
+        self.authenticate(mechanism, &response).await.map(drop).map_err(auth_error)
+    }
+
+    /// Runs AUTHENTICATE, sending the initial response inline when the
+    /// server has SASL-IR. A challenge after the response means the server
+    /// turned it down; the reason it gives goes into the NO.
+    async fn authenticate(&mut self, mechanism: &str, response: &[u8]) -> Result<Response> {
+        let encoded = STANDARD.encode(response);
+        let inline = self.has_capability("SASL-IR");
+        self.next_tag += 1;
+        let tag = format!("A{:04}", self.next_tag);
+        match inline {
+            true => self.write_line(&format!("{} AUTHENTICATE {} {}", tag, mechanism, encoded)).await?,
+            false => self.write_line(&format!("{} AUTHENTICATE {}", tag, mechanism)).await?,
+        }
+
+        let (mut sent, mut reason) = (inline, None);
+        let mut untagged = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            if let Some(challenge) = line.text.strip_prefix('+') {
+                if sent {
+                    reason = sasl::oauth_error(challenge);
+                    // RFC 7628 ends a failed exchange with %x01, XOAUTH2 with nothing
+                    self.write_line(if mechanism == "OAUTHBEARER" { "AQ==" } else { "" }).await?;
+                } else {
+                    self.write_line(&encoded).await?;
+                    sent = true;
+                }
+                continue;
+            }
+            match completion(&tag, &line) {
+                Some(Ok(text)) => return Ok(Response { untagged, text }),
+                Some(Err(ImapError::No { code, text })) => {
+                    let text = match &reason {
+                        Some(reason) => format!("{} ({})", text, reason),
+                        None => text,
+                    };
+                    return Err(classify(ImapError::No { code, text }));
+                }
+                Some(Err(e)) => return Err(classify(e)),
+                None => untagged.push(line),
+            }
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c.eq_ignore_ascii_case(capability))
+    }
+
+    pub fn capabilities(&self) -> &[String] {
+        &self.capabilities
+    }
+
+    pub async fn refresh_capabilities(&mut self) -> Result<()> {
+        let response = self.run("CAPABILITY").await?;
+        self.capabilities = response
+            .untagged
+            .iter()
+            .filter_map(|line| line.text.strip_prefix("* CAPABILITY "))
+            .flat_map(|caps| caps.split_whitespace().map(str::to_string))
+            .collect();
+        Ok(())
+    }
+
+    /// A mailbox name as a command argument, in the encoding the session uses.
+    pub fn mailbox(&self, name: &str) -> String {
+        if self.utf8_accept {
+            quote(name)
+        } else {
+            quote(&utf7::encode(name))
+        }
+    }
+
+    /// A mailbox name from a server response. Names that are not valid
+    /// modified UTF-7 are kept as sent.
+    pub fn decode_mailbox(&self, name: &str) -> String {
+        match self.utf8_accept {
+            true => name.to_string(),
+            false => utf7::decode(name).unwrap_or_else(|| name.to_string()),
+        }
+    }
+
+    /// Selects `mailbox`, or returns the response that selected it when it
+    /// already is. Updates since then arrive with later commands.
+    pub async fn select(&mut self, mailbox: &str) -> Result<Response> {
+        if let Some((_, response)) = self.selected.as_ref().filter(|(name, _)| name == mailbox) {
+            return Ok(response.clone());
+        }
+        let response = self.run(&format!("SELECT {}", self.mailbox(mailbox))).await?;
+        self.selected = Some((mailbox.to_string(), response.clone()));
+        Ok(response)
+    }
+
+    /// Prefix and delimiter of the personal namespace. Servers without
+    /// NAMESPACE keep folders at the root and report the delimiter for
+    /// `LIST "" ""`.
+    pub async fn personal_namespace(&mut self) -> Result<(String, Option<char>)> {
+        if self.has_capability("NAMESPACE") {
+            let response = self.run("NAMESPACE").await?;
+            if let Some((prefix, delimiter)) = response.untagged.iter().find_map(|line| parse_namespace(&line.text)) {
+                return Ok((self.decode_mailbox(&prefix), delimiter));
+            }
+        }
+        let response = self.run("LIST \"\" \"\"").await?;
+        let delimiter = response.untagged.iter().find_map(parse_list_entry).and_then(|entry| entry.delimiter);
+        Ok((String::new(), delimiter))
+    }
+
+    pub async fn logout(mut self) -> Result<()> {
+        self.run("LOGOUT").await?;
+        Ok(())
+    }
+
+    /// Sends a command and collects the untagged responses until its tagged
+    /// completion. NO and BAD completions become `ImapError`s.
+    pub async fn run(&mut self, command: &str) -> Result<Response> {
+        // These leave no mailbox or another one selected, even if they fail
+        let verb = command.split(' ').next().unwrap_or_default();
+        if ["SELECT", "EXAMINE", "CLOSE", "UNSELECT", "DELETE", "RENAME"].iter().any(|v| verb.eq_ignore_ascii_case(v)) {
+            self.selected = None;
+        }
+        self.next_tag += 1;
+        let tag = format!("A{:04}", self.next_tag);
+        self.write_line(&format!("{} {}", tag, command)).await?;
+
+        let mut untagged = Vec::new();
+        loop {
+            let line = self.read_line().await?;
+            match completion(&tag, &line) {
+                Some(result) => return Ok(Response { untagged, text: result.map_err(classify)? }),
+                None => untagged.push(line),
+            }
+        }
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    async fn read_line(&mut self) -> Result<Line> {
+        let mut text = String::new();
+        let mut literals = Vec::new();
+        loop {
+            let mut buf = Vec::new();
+            if self.stream.read_until(b'\n', &mut buf).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "IMAP server closed the connection").into());
+            }
+            let chunk = String::from_utf8_lossy(&buf);
+            let chunk = chunk.trim_end_matches(['\r', '\n']);
+            text.push_str(chunk);
+
+            match literal_len(chunk) {
+                Some(len) => {
+                    let mut literal = vec![0; len];
+                    self.stream.read_exact(&mut literal).await?;
+                    literals.push(literal);
+                }
+                None => return Ok(Line { text, literals }),
+            }
+        }
+    }
+}
+
+/// The outcome of the command tagged `tag` if `line` is its completion:
+/// the text of an OK, or the NO or BAD as an `ImapError`.
+fn completion(tag: &str, line: &Line) -> Option<Result<String, ImapError>> {
+    let completion = line.text.strip_prefix(tag)?.strip_prefix(' ')?;
+    let (status, text) = completion.split_once(' ').unwrap_or((completion, ""));
+    Some(match status.to_ascii_uppercase().as_str() {
+        "OK" => Ok(text.to_string()),
+        "NO" => Err(ImapError::No {
+            code: response_code(text).map(|code| code.split_whitespace().next().unwrap_or(code).to_ascii_uppercase()),
+            text: text.to_string(),
+        }),
+        _ => Err(ImapError::Bad(text.to_string())),
+    })
+}
+
+/// Adds the `ProtocolError` a NO's response code (RFC 5530) stands for, as
+/// context on the `ImapError`.
+fn classify(error: ImapError) -> anyhow::Error {
+    let kind = match &error {
+        ImapError::No { code: Some(code), .. } => match code.as_str() {
+            "AUTHENTICATIONFAILED" | "AUTHORIZATIONFAILED" | "EXPIRED" => Some(ProtocolError::AuthFailed),
+            "OVERQUOTA" | "LIMIT" => Some(ProtocolError::QuotaExceeded),
+            "ALREADYEXISTS" => Some(ProtocolError::AlreadyExists),
+            "UNAVAILABLE" | "INUSE" => Some(ProtocolError::Unavailable),
+            _ => None,
+        },
+        _ => None,
+    };
+    match kind {
+        Some(kind) => anyhow::Error::new(error).context(kind),
+        None => error.into(),
+    }
+}
+
+/// A NO to signing in is a refusal of the credentials, whatever the code.
+fn auth_error(error: anyhow::Error) -> anyhow::Error {
+    match error.downcast_ref::<ImapError>() {
+        Some(ImapError::No { .. }) if error.downcast_ref::<ProtocolError>().is_none() => error.context(ProtocolError::AuthFailed),
+        _ => error,
+    }
+}
+
+/// Length of the literal announced at the end of `line` (`{n}` or `{n+}`).
+fn literal_len(line: &str) -> Option<usize> {
+    let rest = line.strip_suffix('}')?;
+    let start = rest.rfind('{')?;
+    rest[start + 1..].trim_end_matches('+').parse().ok()
+}
+
+/// The bracketed response code at the start of a status text, without brackets.
+fn response_code(text: &str) -> Option<&str> {
+    let rest = text.strip_prefix('[')?;
+    rest.find(']').map(|end| &rest[..end])
+}
+
+/// Extracts the UID and flags from an untagged FETCH response such as
+/// `* 12 FETCH (UID 42 FLAGS (\Seen $Forwarded))`.
+pub fn parse_fetch_flags(line: &str) -> Option<(i64, Vec<String>)> {
+    parse_fetch_list(line, "FLAGS")
+}
+
+/// Extracts the UID and a parenthesized list item, such as Gmail's
+/// `X-GM-LABELS (\Inbox "Work/Q3 plans")`, from an untagged FETCH response.
+pub fn parse_fetch_list(line: &str, item: &str) -> Option<(i64, Vec<String>)> {
+    let upper = line.to_ascii_uppercase();
+    let items = upper.find(" FETCH (")? + " FETCH (".len() - 1;
+
+    let uid_start = items + [" UID ", "(UID "].iter().filter_map(|n| upper[items..].find(n)).min()? + " UID ".len();
+    let uid_len = line[uid_start..].find(|c: char| !c.is_ascii_digit()).unwrap_or(line.len() - uid_start);
+    let uid = line[uid_start..uid_start + uid_len].parse().ok()?;
+
+    let needle = format!("{} (", item.to_ascii_uppercase());
+    let list_start = upper[items..]
+        .match_indices(&needle)
+        .map(|(i, _)| items + i)
+        .find(|&i| matches!(upper.as_bytes()[i - 1], b' ' | b'('))?
+        + needle.len();
+    Some((uid, parse_list(&line[list_start..])?))
+}
+
+/// The content of a body item such as `BODY[2]` or `BINARY[2]` in an
+/// untagged FETCH response, sent as a literal or a quoted string. `None`
+/// when the item is missing or NIL.
+pub fn parse_fetch_body(line: &Line, item: &str) -> Option<Vec<u8>> {
+    let upper = line.text.to_ascii_uppercase();
+    let needle = format!("{} ", item.to_ascii_uppercase());
+    let start = upper
+        .match_indices(&needle)
+        .map(|(i, _)| i)
+        .find(|&i| i > 0 && matches!(upper.as_bytes()[i - 1], b' ' | b'('))?
+        + needle.len();
+
+    let rest = &line.text[start..];
+    if let Some(quoted) = rest.strip_prefix('"') {
+        return parse_quoted(&mut quoted.chars()).map(String::into_bytes);
+    }
+    // BINARY content may come as a literal8, ~{n}
+    if !rest.trim_start_matches('~').starts_with('{') {
+        return None;
+    }
+    // One literal per marker before the item
+    let index = line.text[..start]
+        .match_indices('}')
+        .filter(|(i, _)| literal_len(&line.text[..=*i]).is_some())
+        .count();
+    line.literals.get(index).cloned()
+}
+
+/// Parses the atoms and quoted strings of a list up to its closing `)`.
+fn parse_list(text: &str) -> Option<Vec<String>> {
+    let mut values = Vec::new();
+    let mut chars = text.chars().peekable();
+    loop {
+        match chars.next()? {
+            ')' => return Some(values),
+            ' ' => {}
+            '"' => values.push(parse_quoted(&mut chars)?),
+            c => {
+                let mut value = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c == ' ' || c == ')' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                values.push(value);
+            }
+        }
+    }
+}
+
+/// Reads a quoted string whose opening quote has been consumed.
+fn parse_quoted(chars: &mut impl Iterator<Item = char>) -> Option<String> {
+    let mut value = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(value),
+            '\\' => value.push(chars.next()?),
+            c => value.push(c),
+        }
+    }
+}
+
+/// One mailbox from a LIST response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ListEntry {
+    pub attributes: Vec<String>,
+    pub delimiter: Option<char>,
+    pub name: String,
+}
+
+impl ListEntry {
+    pub fn has_attribute(&self, attribute: &str) -> bool {
+        self.attributes.iter().any(|a| a.eq_ignore_ascii_case(attribute))
+    }
+}
+
+/// Parses `* LIST (\HasNoChildren \Sent) "/" "Sent Items"` and the same
+/// shape of LSUB line. The mailbox name may also be an atom or a literal.
+pub fn parse_list_entry(line: &Line) -> Option<ListEntry> {
+    let start = line.text.get(..8)?;
+    if !start.eq_ignore_ascii_case("* LIST (") && !start.eq_ignore_ascii_case("* LSUB (") {
+        return None;
+    }
+    let rest = &line.text[8..];
+    let close = rest.find(')')?;
+    let attributes = rest[..close].split_whitespace().map(str::to_string).collect();
+
+    let rest = rest[close + 1..].trim_start();
+    let (delimiter, rest) = match rest.strip_prefix('"') {
+        Some(quoted) => {
+            let mut chars = quoted.chars();
+            let delimiter = parse_quoted(&mut chars)?;
+            (delimiter.chars().next(), chars.as_str())
+        }
+        None => (None, rest.get(3..)?), // NIL
+    };
+
+    let rest = rest.trim_start();
+    let name = if literal_len(rest).is_some() {
+        String::from_utf8_lossy(line.literals.first()?).into_owned()
+    } else if let Some(quoted) = rest.strip_prefix('"') {
+        parse_quoted(&mut quoted.chars())?
+    } else {
+        rest.split_whitespace().next()?.to_string()
+    };
+    Some(ListEntry { attributes, delimiter, name })
+}
+
+/// The first personal namespace from `* NAMESPACE (("INBOX." ".")) NIL NIL`,
+/// as prefix and delimiter.
+pub fn parse_namespace(line: &str) -> Option<(String, Option<char>)> {
+    let rest = line.strip_prefix("* NAMESPACE ")?.strip_prefix("((\"")?;
+    let mut chars = rest.chars();
+    let prefix = parse_quoted(&mut chars)?;
+    let rest = chars.as_str().trim_start();
+    let delimiter = match rest.strip_prefix('"') {
+        Some(quoted) => parse_quoted(&mut quoted.chars())?.chars().next(),
+        None => None, // NIL
+    };
+    Some((prefix, delimiter))
+}
+
+/// Quotes a string for use as an IMAP astring.
+pub fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Formats UIDs as a compact sequence set, e.g. `1:3,7`.
+pub fn format_uid_set(uids: &[i64]) -> String {
+    let mut sorted = uids.to_vec();
+    sorted.sort_unstable();
+    sorted.dedup();
+
+    let mut ranges: Vec<(i64, i64)> = Vec::new();
+    for uid in sorted {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == uid => *end = uid,
+            _ => ranges.push((uid, uid)),
+        }
+    }
+    ranges
+        .iter()
+        .map(|(start, end)| if start == end { start.to_string() } else { format!("{}:{}", start, end) })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Expands a sequence set such as `304,319:320` in the order given.
+pub fn parse_uid_set(set: &str) -> Option<Vec<i64>> {
+    let mut uids = Vec::new();
+    for part in set.split(',') {
+        match part.split_once(':') {
+            Some((start, end)) => {
+                let (start, end): (i64, i64) = (start.parse().ok()?, end.parse().ok()?);
+                if start <= end {
+                    uids.extend(start..=end);
+                } else {
+                    uids.extend((end..=start).rev());
+                }
+            }
+            None => uids.push(part.parse().ok()?),
+        }
+    }
+    Some(uids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_uid_sets() {
+        assert_eq!(format_uid_set(&[7, 1, 2, 3, 3]), "1:3,7");
+        assert_eq!(parse_uid_set("304,319:320"), Some(vec![304, 319, 320]));
+        assert_eq!(parse_uid_set("5:3"), Some(vec![5, 4, 3]));
+        assert_eq!(parse_uid_set("1:*"), None);
+    }
+
+    #[test]
+    fn test_copy_uid() {
+        let response = Response {
+            untagged: vec![Line {
+                text: "* OK [COPYUID 38505 304,319:320 3956:3958] Done".to_string(),
+                literals: vec![],
+            }],
+            text: "Move completed".to_string(),
+        };
+        assert_eq!(response.copy_uid(), Some(vec![(304, 3956), (319, 3957), (320, 3958)]));
+    }
+
+    #[test]
+    fn test_parse_fetch_flags() {
+        assert_eq!(
+            parse_fetch_flags("* 12 FETCH (FLAGS (\\Seen $Forwarded) UID 42)"),
+            Some((42, vec!["\\Seen".to_string(), "$Forwarded".to_string()]))
+        );
+        assert_eq!(parse_fetch_flags("* 3 FETCH (UID 7 FLAGS ())"), Some((7, vec![])));
+        assert_eq!(parse_fetch_flags("* 3 EXISTS"), None);
+    }
+
+    #[test]
+    fn test_parse_fetch_list() {
+        let line = r#"* 1 FETCH (X-GM-LABELS (\Inbox "Work/Q3 plans" "say \"hi\"" Receipts) UID 9)"#;
+        assert_eq!(
+            parse_fetch_list(line, "X-GM-LABELS"),
+            Some((9, vec!["\\Inbox".to_string(), "Work/Q3 plans".to_string(), "say \"hi\"".to_string(), "Receipts".to_string()]))
+        );
+        // FLAGS must not match the tail of another item name
+        assert_eq!(parse_fetch_list("* 1 FETCH (UID 9 X-FLAGS (a) FLAGS (b))", "FLAGS"), Some((9, vec!["b".to_string()])));
+    }
+
+    #[test]
+    fn test_parse_list_entry() {
+        let line = |text: &str, literals: Vec<Vec<u8>>| Line { text: text.to_string(), literals };
+
+        assert_eq!(
+            parse_list_entry(&line(r#"* LIST (\HasNoChildren \Sent) "/" "[Gmail]/Sent Mail""#, vec![])),
+            Some(ListEntry {
+                attributes: vec!["\\HasNoChildren".to_string(), "\\Sent".to_string()],
+                delimiter: Some('/'),
+                name: "[Gmail]/Sent Mail".to_string(),
+            })
+        );
+        let inbox = parse_list_entry(&line(r#"* LIST () "\\" INBOX"#, vec![])).unwrap();
+        assert_eq!((inbox.delimiter, inbox.name.as_str()), (Some('\\'), "INBOX"));
+        let flat = parse_list_entry(&line("* LIST (\\Noselect) NIL {8}", vec![b"Projects".to_vec()])).unwrap();
+        assert_eq!((flat.delimiter, flat.name.as_str()), (None, "Projects"));
+        assert!(flat.has_attribute("\\NoSelect"));
+        assert_eq!(parse_list_entry(&line("* LSUB () \"/\" INBOX", vec![])).unwrap().name, "INBOX");
+        assert_eq!(parse_list_entry(&line("* STATUS INBOX (MESSAGES 3)", vec![])), None);
+    }
+
+    #[test]
+    fn test_parse_namespace() {
+        assert_eq!(parse_namespace(r##"* NAMESPACE (("INBOX." ".")) NIL (("#shared." "."))"##), Some(("INBOX.".to_string(), Some('.'))));
+        assert_eq!(parse_namespace(r#"* NAMESPACE (("" "/")) NIL NIL"#), Some((String::new(), Some('/'))));
+        assert_eq!(parse_namespace("* NAMESPACE NIL NIL NIL"), None);
+    }
+
+    #[test]
+    fn test_permanent_flags() {
+        let response = Response {
+            untagged: vec![Line {
+                text: "* OK [PERMANENTFLAGS (\\Seen \\Flagged \\*)] Limited".to_string(),
+                literals: vec![],
+            }],
+            text: "[READ-WRITE] SELECT completed".to_string(),
+        };
+        assert_eq!(response.permanent_flags(), Some(vec!["\\Seen".to_string(), "\\Flagged".to_string(), "\\*".to_string()]));
+    }
+
+    #[test]
+    fn test_fetch_body() {
+        let line = Line {
+            text: "* 3 FETCH (UID 42 BODY[2.MIME] {37} BODY[2] {8} BODY[3] \"a \\\"b\\\"\" BODY[4] NIL)".to_string(),
+            literals: vec![b"Content-Transfer-Encoding: base64\r\n\r\n".to_vec(), b"aGVsbG8=".to_vec()],
+        };
+        assert_eq!(parse_fetch_body(&line, "BODY[2.MIME]").unwrap().len(), 37);
+        assert_eq!(parse_fetch_body(&line, "body[2]").unwrap(), b"aGVsbG8=");
+        assert_eq!(parse_fetch_body(&line, "BODY[3]").unwrap(), b"a \"b\"");
+        assert_eq!(parse_fetch_body(&line, "BODY[4]"), None);
+        assert_eq!(parse_fetch_body(&line, "BODY[5]"), None);
+
+        let line = Line { text: "* 3 FETCH (BINARY[2] ~{3} UID 42)".to_string(), literals: vec![vec![0, 1, 2]] };
+        assert_eq!(parse_fetch_body(&line, "BINARY[2]").unwrap(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_literal_len() {
+        assert_eq!(literal_len("* 1 FETCH (BODY[] {342}"), Some(342));
+        assert_eq!(literal_len("* LIST () \"/\" {5+}"), Some(5));
+        assert_eq!(literal_len("* OK done"), None);
+    }
+}
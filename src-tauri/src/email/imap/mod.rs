@@ -0,0 +1,1099 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+
+use crate::db::{Account, Email, Folder, FolderType, EmailAddress, ComposeEmail};
+use super::{folders, mime, EmailProtocol, Flag, ProtocolError, UidMapping};
+
+pub mod client;
+pub mod pool;
+#[cfg(test)]
+pub(crate) mod test_server;
+pub mod utf7;
+
+pub use client::{ImapClient, ImapError};
+pub use pool::SessionPool;
+use client::{format_uid_set, parse_fetch_body, parse_fetch_flags, parse_fetch_list, parse_list_entry, ListEntry, Response};
+
+/// Capability of servers with Gmail's X-GM-LABELS extension.
+const GMAIL_EXTENSION: &str = "X-GM-EXT-1";
+
+pub struct ImapHandler {
+    sessions: Arc<SessionPool>,
+}
+
+impl ImapHandler {
+    pub fn new(sessions: Arc<SessionPool>) -> Self {
+        Self { sessions }
+    }
+
+    async fn select(&self, client: &mut ImapClient, folder: &Folder) -> Result<Response> {
+        client.select(&folder.name).await.map_err(|e| match e.downcast_ref::<ImapError>() {
+            Some(ImapError::No { .. }) => ProtocolError::FolderNotFound(folder.name.clone()).into(),
+            _ => e,
+        })
+    }
+
+    /// Moves or copies `uids` from `folder` to `target` in one session.
+    async fn transfer(&self, account: &Account, folder: &Folder, uids: &[i64], target: &Folder, remove: bool) -> Result<Vec<UidMapping>> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        self.select(client, folder).await?;
+
+        let set = format_uid_set(uids);
+        let mut expunged = true;
+        let response = if remove && client.has_capability("MOVE") {
+            client.run(&format!("UID MOVE {} {}", set, client.mailbox(&target.name))).await
+        } else {
+            let copied = client.run(&format!("UID COPY {} {}", set, client.mailbox(&target.name))).await;
+            if remove && copied.is_ok() {
+                // RFC 6851 fallback. The copies exist now, so failing here
+                // would have the move retried and copy them again.
+                if let Err(e) = expunge(client, &set).await {
+                    tracing::warn!("Failed to remove moved messages {} from {}: {:#}", set, folder.name, e);
+                    expunged = false;
+                }
+            }
+            copied
+        };
+        let response = response.map_err(|e| folder_error(e, target))?;
+        let has_uidplus = client.has_capability("UIDPLUS");
+        if expunged {
+            session.release().await?;
+        }
+
+        match response.copy_uid() {
+            Some(pairs) => {
+                if let Some(missing) = uids.iter().find(|uid| !pairs.iter().any(|(source, _)| source == *uid)) {
+                    return Err(ProtocolError::MessageNotFound { folder: folder.name.clone(), uid: *missing }.into());
+                }
+                Ok(pairs.into_iter().map(|(source_uid, target_uid)| UidMapping { source_uid, target_uid }).collect())
+            }
+            // UIDPLUS servers omit COPYUID only when nothing was copied
+            None if has_uidplus => Err(ProtocolError::MessageNotFound { folder: folder.name.clone(), uid: uids[0] }.into()),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// Marks `set` \Deleted and expunges it. Without UIDPLUS a plain EXPUNGE
+/// would also purge unrelated messages marked \Deleted, so the messages stay
+/// flagged until the next expunge instead.
+async fn expunge(client: &mut ImapClient, set: &str) -> Result<()> {
+    client.run(&format!("UID STORE {} +FLAGS.SILENT (\\Deleted)", set)).await?;
+    if client.has_capability("UIDPLUS") {
+        client.run(&format!("UID EXPUNGE {}", set)).await?;
+    }
+    Ok(())
+}
+
+/// The account's Trash folder, if the server has one other than `folder`.
+async fn find_trash(client: &mut ImapClient, folder: &Folder) -> Result<Option<Folder>> {
+    let listed = client.run("LIST \"\" \"*\"").await?;
+    let entries: Vec<ListEntry> = listed
+        .untagged
+        .iter()
+        .filter_map(parse_list_entry)
+        .map(|mut entry| {
+            entry.name = client.decode_mailbox(&entry.name);
+            entry
+        })
+        .collect();
+    let listed: Vec<(&str, Option<char>, &[String])> = entries
+        .iter()
+        .map(|entry| (entry.name.as_str(), entry.delimiter, entry.attributes.as_slice()))
+        .collect();
+    let trash = entries
+        .iter()
+        .zip(folders::detect_types(&listed))
+        .find(|(entry, folder_type)| *folder_type == FolderType::Trash && entry.name != folder.name)
+        .map(|(entry, _)| Folder { name: entry.name.clone(), folder_type: FolderType::Trash, ..folder.clone() });
+    Ok(trash)
+}
+
+/// Maps a NO for a mailbox the server does not have onto `FolderNotFound`.
+fn folder_error(error: anyhow::Error, folder: &Folder) -> anyhow::Error {
+    match error.downcast_ref::<ImapError>() {
+        Some(ImapError::No { code: Some(code), .. }) if code == "TRYCREATE" || code == "NONEXISTENT" => {
+            ProtocolError::FolderNotFound(folder.name.clone()).into()
+        }
+        _ => error,
+    }
+}
+
+/// Rejects names that would silently create extra hierarchy levels.
+fn check_folder_name(name: &str, delimiter: Option<char>) -> Result<()> {
+    match delimiter {
+        _ if name.trim().is_empty() => Err(anyhow!("Folder name cannot be empty")),
+        Some(delimiter) if name.contains(delimiter) => Err(anyhow!("Folder names cannot contain {:?}", delimiter)),
+        _ => Ok(()),
+    }
+}
+
+#[async_trait]
+impl EmailProtocol for ImapHandler {
+    async fn test_connection(&self, account: &Account) -> Result<bool> {
+        ImapClient::connect(account).await?.logout().await?;
+        Ok(true)
+    }
+
+    async fn fetch_folders(&self, account: &Account) -> Result<Vec<Folder>> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        let (personal_prefix, _) = client.personal_namespace().await?;
+
+        let extended = client.has_capability("LIST-EXTENDED");
+        let command = match (extended, client.has_capability("SPECIAL-USE")) {
+            (true, true) => "LIST \"\" \"*\" RETURN (SUBSCRIBED SPECIAL-USE)",
+            (true, false) => "LIST \"\" \"*\" RETURN (SUBSCRIBED)",
+            _ => "LIST \"\" \"*\"",
+        };
+        let decode = |client: &ImapClient, mut entry: ListEntry| {
+            entry.name = client.decode_mailbox(&entry.name);
+            entry
+        };
+        let listed = client.run(command).await?;
+        let entries: Vec<ListEntry> = listed.untagged.iter().filter_map(parse_list_entry).map(|e| decode(client, e)).collect();
+        let subscribed: Vec<String> = if extended {
+            entries.iter().filter(|e| e.has_attribute("\\Subscribed")).map(|e| e.name.clone()).collect()
+        } else {
+            let lsub = client.run("LSUB \"\" \"*\"").await?;
+            lsub.untagged.iter().filter_map(parse_list_entry).map(|e| decode(client, e).name).collect()
+        };
+        session.release().await?;
+
+        let entries: Vec<ListEntry> = entries.into_iter().filter(|entry| !entry.has_attribute("\\NonExistent")).collect();
+        let listed: Vec<(&str, Option<char>, &[String])> = entries
+            .iter()
+            .map(|entry| (entry.name.as_str(), entry.delimiter, entry.attributes.as_slice()))
+            .collect();
+        let types = folders::detect_types(&listed);
+
+        Ok(entries
+            .iter()
+            .zip(types)
+            .map(|(entry, folder_type)| Folder {
+                id: 0, // Will be set by database
+                account_id: account.id,
+                name: entry.name.clone(),
+                display_name: if folder_type == FolderType::Inbox {
+                    "Inbox".to_string()
+                } else {
+                    folders::leaf_name(&entry.name, entry.delimiter).to_string()
+                },
+                folder_type,
+                message_count: 0,
+                unread_count: 0,
+                uid_validity: None,
+                uid_next: None,
+                delimiter: entry.delimiter.map(String::from),
+                parent_name: folders::parent_name(&entry.name, entry.delimiter, &personal_prefix),
+                is_subscribed: folder_type == FolderType::Inbox || subscribed.contains(&entry.name),
+                is_selectable: !entry.has_attribute("\\Noselect"),
+                created_at: chrono::Utc::now(),
+                updated_at: chrono::Utc::now(),
+            })
+            .collect())
+    }
+
+    async fn fetch_emails(&self, account: &Account, folder: &Folder, limit: u32, offset: u32) -> Result<Vec<Email>> {
+        // TODO: Implement real IMAP email fetching
+        // For now, generate mock emails based on folder type
+        let mut emails = Vec::new();
+        let start_id = offset + 1;
+        let end_id = start_id + limit;
+
+        for i in start_id..end_id {
+            let email = match folder.folder_type {
+                FolderType::Inbox => Email {
+                    id: i as i64,
+                    account_id: account.id,
+                    folder_id: folder.id,
+                    message_id: format!("msg-{}@example.com", i),
+                    thread_id: None,
+                    subject: format!("Test Email #{}", i),
+                    from_address: "sender@example.com".to_string(),
+                    from_name: Some("Test Sender".to_string()),
+                    to_addresses: serde_json::to_string(&vec![EmailAddress {
+                        name: None,
+                        address: account.email.clone(),
+                    }]).unwrap(),
+                    cc_addresses: None,
+                    bcc_addresses: None,
+                    body_text: Some(format!("This is test email #{} in your inbox.", i)),
+                    body_html: Some(format!("<p>This is test email #{} in your inbox.</p>", i)),
+                    attachments: None,
+                    size_bytes: 150,
+                    internal_date: chrono::Utc::now(),
+                    received_date: chrono::Utc::now(),
+                    is_read: i % 3 == 0, // Every 3rd email is read
+                    is_flagged: i % 5 == 0, // Every 5th email is flagged
+                    is_answered: false,
+                    is_draft: false,
+                    is_deleted: false,
+                    uid: Some(i as i64),
+                    mod_seq: None,
+                    trackers_blocked: None,
+                    auth_verdict: None,
+                    auth_results: None,
+                    created_at: chrono::Utc::now(),
+                    updated_at: chrono::Utc::now(),
+                    keywords: vec![],
+                    labels: vec![],
+                    warnings: vec![],
+                },
+                FolderType::Sent => Email {
+                    id: i as i64,
+                    account_id: account.id,
+                    folder_id: folder.id,
+                    message_id: format!("sent-{}@example.com", i),
+                    thread_id: None,
+                    subject: format!("Sent Email #{}", i),
+                    from_address: account.email.clone(),
+                    from_name: Some("Me".to_string()),
+                    to_addresses: serde_json::to_string(&vec![EmailAddress {
+                        name: None,
+                        address: "recipient@example.com".to_string(),
+                    }]).unwrap(),
+                    cc_addresses: None,
+                    bcc_addresses: None,
+                    body_text: Some(format!("This is sent email #{}.", i)),
+                    body_html: Some(format!("<p>This is sent email #{}.</p>", i)),
+                    attachments: None,
+                    size_bytes: 150,
+                    internal_date: chrono::Utc::now(),
+                    received_date: chrono::Utc::now(),
+                    is_read: true,
+                    is_flagged: false,
+                    is_answered: false,
+                    is_draft: false,
+                    is_deleted: false,
+                    uid: Some(i as i64),
+                    mod_seq: None,
+                    trackers_blocked: None,
+                    auth_verdict: None,
+                    auth_results: None,
+                    created_at: chrono::Utc::now(),
+                    updated_at: chrono::Utc::now(),
+                    keywords: vec![],
+                    labels: vec![],
+                    warnings: vec![],
+                },
+                _ => Email {
+                    id: i as i64,
+                    account_id: account.id,
+                    folder_id: folder.id,
+                    message_id: format!("other-{}@example.com", i),
+                    thread_id: None,
+                    subject: format!("Email #{}", i),
+                    from_address: "unknown@example.com".to_string(),
+                    from_name: None,
+                    to_addresses: serde_json::to_string(&vec![EmailAddress {
+                        name: None,
+                        address: account.email.clone(),
+                    }]).unwrap(),
+                    cc_addresses: None,
+                    bcc_addresses: None,
+                    body_text: Some(format!("This is email #{}.", i)),
+                    body_html: Some(format!("<p>This is email #{}.</p>", i)),
+                    attachments: None,
+                    size_bytes: 150,
+                    internal_date: chrono::Utc::now(),
+                    received_date: chrono::Utc::now(),
+                    is_read: true,
+                    is_flagged: false,
+                    is_answered: false,
+                    is_draft: folder.folder_type == FolderType::Drafts,
+                    is_deleted: folder.folder_type == FolderType::Trash,
+                    uid: Some(i as i64),
+                    mod_seq: None,
+                    trackers_blocked: None,
+                    auth_verdict: None,
+                    auth_results: None,
+                    created_at: chrono::Utc::now(),
+                    updated_at: chrono::Utc::now(),
+                    keywords: vec![],
+                    labels: vec![],
+                    warnings: vec![],
+                }
+            };
+            emails.push(email);
+        }
+
+        Ok(emails)
+    }
+
+    async fn send_email(&self, _account: &Account, _email: &ComposeEmail) -> Result<String> {
+        Err(anyhow!("IMAP handler cannot send emails - use SMTP"))
+    }
+
+    async fn set_flags(&self, account: &Account, folder: &Folder, uids: &[i64], add: &[Flag], remove: &[Flag]) -> Result<()> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        let selected = self.select(client, folder).await?;
+
+        // Keywords the mailbox cannot store permanently stay local-only.
+        let permanent = selected.permanent_flags();
+        let storable = |flag: &&Flag| match (flag, &permanent) {
+            (Flag::Keyword(keyword), Some(allowed)) => allowed.iter().any(|a| a == "\\*" || a.eq_ignore_ascii_case(keyword)),
+            _ => true,
+        };
+
+        let set = format_uid_set(uids);
+        let mut stored = false;
+        for (sign, flags) in [('+', add), ('-', remove)] {
+            let flags: Vec<&str> = flags.iter().filter(storable).map(Flag::as_imap).collect();
+            if flags.is_empty() {
+                continue;
+            }
+            client.run(&format!("UID STORE {} {}FLAGS.SILENT ({})", set, sign, flags.join(" "))).await?;
+            stored = true;
+        }
+        // STORE is silent about expunged UIDs, and servers may not answer a
+        // change that was already in place, so ask which UIDs still exist
+        let existing: Vec<i64> = match stored {
+            true => {
+                let response = client.run(&format!("UID FETCH {} (UID FLAGS)", set)).await?;
+                response.untagged.iter().filter_map(|line| parse_fetch_flags(&line.text)).map(|(uid, _)| uid).collect()
+            }
+            false => uids.to_vec(),
+        };
+        session.release().await?;
+
+        match uids.iter().find(|uid| !existing.contains(uid)) {
+            Some(missing) => Err(ProtocolError::MessageNotFound { folder: folder.name.clone(), uid: *missing }.into()),
+            None => Ok(()),
+        }
+    }
+
+    async fn fetch_flags(&self, account: &Account, folder: &Folder) -> Result<Vec<(i64, Vec<Flag>)>> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        self.select(client, folder).await?;
+        let response = client.run("UID FETCH 1:* (UID FLAGS)").await?;
+        session.release().await?;
+
+        Ok(response
+            .untagged
+            .iter()
+            .filter_map(|line| parse_fetch_flags(&line.text))
+            .map(|(uid, flags)| (uid, flags.iter().filter_map(|flag| Flag::parse(flag).ok()).collect()))
+            .collect())
+    }
+
+    async fn move_emails(&self, account: &Account, folder: &Folder, uids: &[i64], target: &Folder) -> Result<Vec<UidMapping>> {
+        self.transfer(account, folder, uids, target, true).await
+    }
+
+    async fn copy_emails(&self, account: &Account, folder: &Folder, uids: &[i64], target: &Folder) -> Result<Vec<UidMapping>> {
+        self.transfer(account, folder, uids, target, false).await
+    }
+
+    async fn delete_email(&self, account: &Account, folder: &Folder, uid: i64) -> Result<()> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        // Without UIDPLUS the message can't be expunged on its own, so it
+        // goes to Trash instead, unless that's where it already is
+        let trash = match client.has_capability("UIDPLUS") {
+            true => None,
+            false => find_trash(client, folder).await?,
+        };
+        if let Some(trash) = trash {
+            session.release().await?;
+            return self.transfer(account, folder, &[uid], &trash, true).await.map(drop);
+        }
+        self.select(client, folder).await?;
+        expunge(client, &uid.to_string()).await?;
+        session.release().await
+    }
+
+    async fn create_folder(&self, account: &Account, parent: Option<&Folder>, name: &str) -> Result<Folder> {
+        let parent = match parent {
+            Some(parent) => {
+                let delimiter = parent.delimiter.as_deref().and_then(|d| d.chars().next());
+                let delimiter = delimiter.ok_or_else(|| anyhow!("{} cannot contain folders", parent.display_name))?;
+                check_folder_name(name, Some(delimiter))?;
+                Some((parent, delimiter))
+            }
+            None => None,
+        };
+
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        let (prefix, delimiter) = match parent {
+            Some((parent, delimiter)) => (format!("{}{}", parent.name, delimiter), Some(delimiter)),
+            None => client.personal_namespace().await?,
+        };
+        check_folder_name(name, delimiter)?;
+
+        let full_name = format!("{}{}", prefix, name);
+        client.run(&format!("CREATE {}", client.mailbox(&full_name))).await?;
+        client.run(&format!("SUBSCRIBE {}", client.mailbox(&full_name))).await?;
+        session.release().await?;
+
+        Ok(Folder {
+            id: 0, // Will be set by database
+            account_id: account.id,
+            name: full_name.clone(),
+            display_name: name.to_string(),
+            folder_type: FolderType::Custom,
+            message_count: 0,
+            unread_count: 0,
+            uid_validity: None,
+            uid_next: None,
+            delimiter: delimiter.map(String::from),
+            parent_name: parent.map(|(parent, _)| parent.name.clone()),
+            is_subscribed: true,
+            is_selectable: true,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        })
+    }
+
+    async fn rename_folder(&self, account: &Account, folder: &Folder, name: &str) -> Result<String> {
+        let delimiter = folder.delimiter.as_deref().and_then(|d| d.chars().next());
+        check_folder_name(name, delimiter)?;
+        let new_name = match delimiter.and_then(|d| folder.name.rsplit_once(d)) {
+            Some((parent, _)) => format!("{}{}{}", parent, delimiter.unwrap(), name),
+            None => name.to_string(),
+        };
+
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        client
+            .run(&format!("RENAME {} {}", client.mailbox(&folder.name), client.mailbox(&new_name)))
+            .await
+            .map_err(|e| folder_error(e, folder))?;
+        // Subscriptions are not renamed with the folder
+        if folder.is_subscribed {
+            client.run(&format!("UNSUBSCRIBE {}", client.mailbox(&folder.name))).await.ok();
+            client.run(&format!("SUBSCRIBE {}", client.mailbox(&new_name))).await?;
+        }
+        session.release().await?;
+        Ok(new_name)
+    }
+
+    async fn delete_folder(&self, account: &Account, folder: &Folder) -> Result<()> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        client.run(&format!("DELETE {}", client.mailbox(&folder.name))).await.map_err(|e| folder_error(e, folder))?;
+        client.run(&format!("UNSUBSCRIBE {}", client.mailbox(&folder.name))).await.ok();
+        session.release().await
+    }
+
+    async fn subscribe_folder(&self, account: &Account, folder: &Folder, subscribed: bool) -> Result<()> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        let command = if subscribed { "SUBSCRIBE" } else { "UNSUBSCRIBE" };
+        client.run(&format!("{} {}", command, client.mailbox(&folder.name))).await?;
+        session.release().await
+    }
+
+    async fn fetch_labels(&self, account: &Account, folder: &Folder) -> Result<Vec<(i64, Vec<String>)>> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        if !client.has_capability(GMAIL_EXTENSION) {
+            session.release().await?;
+            return Ok(Vec::new());
+        }
+        self.select(client, folder).await?;
+        let response = client.run("UID FETCH 1:* (UID X-GM-LABELS)").await?;
+
+        // User labels are encoded like mailbox names
+        let labels = response
+            .untagged
+            .iter()
+            .filter_map(|line| parse_fetch_list(&line.text, "X-GM-LABELS"))
+            .map(|(uid, labels)| {
+                let labels = labels
+                    .iter()
+                    .map(|label| if label.starts_with('\\') { label.clone() } else { client.decode_mailbox(label) })
+                    .collect();
+                (uid, labels)
+            })
+            .collect();
+        session.release().await?;
+        Ok(labels)
+    }
+
+    async fn set_labels(&self, account: &Account, folder: &Folder, uids: &[i64], add: &[String], remove: &[String]) -> Result<()> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        if !client.has_capability(GMAIL_EXTENSION) {
+            session.release().await?;
+            return Err(anyhow!("Server does not support labels"));
+        }
+        self.select(client, folder).await?;
+
+        let set = format_uid_set(uids);
+        for (sign, labels) in [('+', add), ('-', remove)] {
+            if labels.is_empty() {
+                continue;
+            }
+            // System labels (\Inbox, \Starred) are sent as atoms, user labels quoted
+            let labels: Vec<String> = labels
+                .iter()
+                .map(|label| if label.starts_with('\\') { label.clone() } else { client.mailbox(label) })
+                .collect();
+            client.run(&format!("UID STORE {} {}X-GM-LABELS.SILENT ({})", set, sign, labels.join(" "))).await?;
+        }
+        session.release().await
+    }
+
+    async fn fetch_part(&self, account: &Account, folder: &Folder, uid: i64, section: &str) -> Result<Vec<u8>> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        self.select(client, folder).await?;
+
+        // BINARY (RFC 3516) has the server decode the part for us
+        let data = if client.has_capability("BINARY") {
+            let response = client.run(&format!("UID FETCH {} (BINARY.PEEK[{}])", uid, section)).await?;
+            fetched_body(&response, &format!("BINARY[{}]", section))
+        } else {
+            let response = client.run(&format!("UID FETCH {} (BODY.PEEK[{}.MIME] BODY.PEEK[{}])", uid, section, section)).await?;
+            let headers = fetched_body(&response, &format!("BODY[{}.MIME]", section));
+            match fetched_body(&response, &format!("BODY[{}]", section)) {
+                Some(body) => {
+                    let headers = String::from_utf8_lossy(headers.as_deref().unwrap_or_default()).into_owned();
+                    let encoding = mime::header_value(&headers, "Content-Transfer-Encoding");
+                    Some(mime::decode_transfer(encoding.as_deref(), &body)?)
+                }
+                None => None,
+            }
+        };
+        session.release().await?;
+        data.ok_or_else(|| ProtocolError::MessageNotFound { folder: folder.name.clone(), uid }.into())
+    }
+
+    async fn fetch_message(&self, account: &Account, folder: &Folder, uid: i64) -> Result<Vec<u8>> {
+        let mut session = self.sessions.checkout(account).await?;
+        let client = &mut *session;
+        self.select(client, folder).await?;
+        let response = client.run(&format!("UID FETCH {} (BODY.PEEK[])", uid)).await?;
+        session.release().await?;
+        fetched_body(&response, "BODY[]").ok_or_else(|| ProtocolError::MessageNotFound { folder: folder.name.clone(), uid }.into())
+    }
+}
+
+/// The first FETCH line in `response` carrying the body item `item`.
+fn fetched_body(response: &Response, item: &str) -> Option<Vec<u8>> {
+    response.untagged.iter().find_map(|line| parse_fetch_body(line, item))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Account, AuthMethod, Folder, Email};
+
+    #[tokio::test]
+    async fn test_imap_connection() {
+        let handler = ImapHandler::new(Default::default());
+        let port = test_server::serve("IMAP4rev1", vec![]).await;
+        assert!(handler.test_connection(&test_server::account(port)).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_oauth_login() {
+        let port = test_server::serve("IMAP4rev1 SASL-IR AUTH=XOAUTH2", vec![
+            ("AUTHENTICATE XOAUTH2 dXNlcj10ZXN0QGV4YW1wbGUuY29tAWF1dGg9QmVhcmVyIHlhMjkudG9rZW4BAQ==", vec!["{tag} OK Success"]),
+        ]).await;
+        let mut account = test_server::account(port);
+        account.auth_method = AuthMethod::Oauth2;
+        account.access_token = Some("ya29.token".to_string());
+        ImapClient::connect(&account).await.unwrap().logout().await.unwrap();
+
+        // A rejected token gets a JSON challenge, which the client answers
+        // with %x01 before the NO
+        let port = test_server::serve("IMAP4rev1 SASL-IR AUTH=OAUTHBEARER", vec![
+            ("AUTHENTICATE OAUTHBEARER ", vec!["+ eyJzdGF0dXMiOiI0MDEiLCJzY2hlbWVzIjoiYmVhcmVyIn0="]),
+            ("", vec!["A0002 NO [AUTHENTICATIONFAILED] Invalid credentials"]),
+        ]).await;
+        account.imap_port = Some(port as i32);
+        let error = ImapClient::connect(&account).await.err().unwrap();
+        match error.downcast_ref::<ImapError>() {
+            Some(ImapError::No { code, text }) => {
+                assert_eq!(code.as_deref(), Some("AUTHENTICATIONFAILED"));
+                assert!(text.ends_with("(the token is invalid or has expired)"));
+            }
+            _ => panic!("unexpected error {}", error),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_folders() {
+        let port = test_server::serve("IMAP4rev1 NAMESPACE SPECIAL-USE LIST-EXTENDED", vec![
+            ("NAMESPACE", vec!["* NAMESPACE ((\"\" \"/\")) NIL NIL", "{tag} OK NAMESPACE completed"]),
+            ("LIST \"\" \"*\" RETURN (SUBSCRIBED SPECIAL-USE)", vec![
+                "* LIST (\\HasChildren) \"/\" INBOX",
+                "* LIST (\\HasNoChildren \\Drafts \\Subscribed) \"/\" \"Drafts\"",
+                "* LIST (\\Noselect \\HasChildren) \"/\" \"[Gmail]\"",
+                "* LIST (\\HasNoChildren \\Sent \\Subscribed) \"/\" \"[Gmail]/Sent Mail\"",
+                "* LIST (\\HasNoChildren \\Subscribed) \"/\" \"Papierkorb\"",
+                "* LIST (\\HasNoChildren) \"/\" \"Projects\"",
+                "{tag} OK LIST completed",
+            ]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let folders = ImapHandler::new(Default::default()).fetch_folders(&account).await.unwrap();
+        let summary: Vec<(&str, &str, FolderType)> = folders
+            .iter()
+            .map(|f| (f.name.as_str(), f.display_name.as_str(), f.folder_type))
+            .collect();
+        assert_eq!(summary, vec![
+            ("INBOX", "Inbox", FolderType::Inbox),
+            ("Drafts", "Drafts", FolderType::Drafts),
+            ("[Gmail]", "[Gmail]", FolderType::Custom),
+            ("[Gmail]/Sent Mail", "Sent Mail", FolderType::Sent),
+            ("Papierkorb", "Papierkorb", FolderType::Trash),
+            ("Projects", "Projects", FolderType::Custom),
+        ]);
+        assert!(!folders[2].is_selectable);
+        assert_eq!(folders[3].parent_name.as_deref(), Some("[Gmail]"));
+        assert_eq!(folders.iter().filter(|f| !f.is_subscribed).map(|f| f.name.as_str()).collect::<Vec<_>>(), vec!["[Gmail]", "Projects"]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_folders_with_lsub() {
+        let port = test_server::serve("IMAP4rev1 NAMESPACE", vec![
+            ("NAMESPACE", vec!["* NAMESPACE ((\"INBOX.\" \".\")) NIL NIL", "{tag} OK NAMESPACE completed"]),
+            ("LIST \"\" \"*\"", vec![
+                "* LIST (\\HasChildren) \".\" INBOX",
+                "* LIST (\\HasChildren) \".\" INBOX.Work",
+                "* LIST (\\HasNoChildren) \".\" INBOX.Work.Acme",
+                "{tag} OK LIST completed",
+            ]),
+            ("LSUB \"\" \"*\"", vec!["* LSUB () \".\" INBOX.Work.Acme", "{tag} OK LSUB completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let folders = ImapHandler::new(Default::default()).fetch_folders(&account).await.unwrap();
+        let tree: Vec<(&str, Option<&str>, bool)> = folders
+            .iter()
+            .map(|f| (f.display_name.as_str(), f.parent_name.as_deref(), f.is_subscribed))
+            .collect();
+        assert_eq!(tree, vec![
+            ("Inbox", None, true),
+            ("Work", None, false),
+            ("Acme", Some("INBOX.Work"), true),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_folders_decodes_names() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("LIST \"\" \"\"", vec!["* LIST (\\Noselect) \"/\" \"\"", "{tag} OK LIST completed"]),
+            ("LIST \"\" \"*\"", vec![
+                "* LIST () \"/\" INBOX",
+                "* LIST () \"/\" \"&BB4EQgQ,BEAEMAQyBDsENQQ9BD0ESwQ1-\"",
+                "{tag} OK LIST completed",
+            ]),
+            ("LSUB \"\" \"*\"", vec!["* LSUB () \"/\" \"&BB4EQgQ,BEAEMAQyBDsENQQ9BD0ESwQ1-\"", "{tag} OK LSUB completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let folders = ImapHandler::new(Default::default()).fetch_folders(&account).await.unwrap();
+        let sent = &folders[1];
+        assert_eq!((sent.name.as_str(), sent.display_name.as_str()), ("Отправленные", "Отправленные"));
+        assert_eq!(sent.folder_type, FolderType::Sent);
+        assert!(sent.is_subscribed);
+    }
+
+    #[tokio::test]
+    async fn test_create_folder_encodes_name() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("LIST \"\" \"\"", vec!["* LIST (\\Noselect) \"/\" \"\"", "{tag} OK LIST completed"]),
+            ("CREATE \"Entw&APw-rfe\"", vec!["{tag} OK CREATE completed"]),
+            ("SUBSCRIBE \"Entw&APw-rfe\"", vec!["{tag} OK SUBSCRIBE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let folder = ImapHandler::new(Default::default()).create_folder(&account, None, "Entwürfe").await.unwrap();
+        assert_eq!(folder.name, "Entwürfe");
+    }
+
+    #[tokio::test]
+    async fn test_create_folder_with_utf8_accept() {
+        let port = test_server::serve("IMAP4rev1 UTF8=ACCEPT", vec![
+            ("ENABLE UTF8=ACCEPT", vec!["* ENABLED UTF8=ACCEPT", "{tag} OK ENABLE completed"]),
+            ("LIST \"\" \"\"", vec!["* LIST (\\Noselect) \"/\" \"\"", "{tag} OK LIST completed"]),
+            ("CREATE \"Entwürfe\"", vec!["{tag} OK CREATE completed"]),
+            ("SUBSCRIBE \"Entwürfe\"", vec!["{tag} OK SUBSCRIBE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let folder = ImapHandler::new(Default::default()).create_folder(&account, None, "Entwürfe").await.unwrap();
+        assert_eq!(folder.name, "Entwürfe");
+    }
+
+    #[tokio::test]
+    async fn test_create_folder_in_namespace() {
+        let port = test_server::serve("IMAP4rev1 NAMESPACE", vec![
+            ("NAMESPACE", vec!["* NAMESPACE ((\"INBOX.\" \".\")) NIL NIL", "{tag} OK NAMESPACE completed"]),
+            ("CREATE \"INBOX.Receipts\"", vec!["{tag} OK CREATE completed"]),
+            ("SUBSCRIBE \"INBOX.Receipts\"", vec!["{tag} OK SUBSCRIBE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let folder = ImapHandler::new(Default::default()).create_folder(&account, None, "Receipts").await.unwrap();
+        assert_eq!((folder.name.as_str(), folder.parent_name.as_deref()), ("INBOX.Receipts", None));
+
+        let error = ImapHandler::new(Default::default()).create_folder(&account, Some(&folder), "a.b").await.unwrap_err();
+        assert_eq!(error.to_string(), "Folder names cannot contain '.'");
+    }
+
+    #[tokio::test]
+    async fn test_rename_folder_keeps_parent() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("RENAME \"Work/Acme\" \"Work/Acme Corp\"", vec!["{tag} OK RENAME completed"]),
+            ("UNSUBSCRIBE \"Work/Acme\"", vec!["{tag} OK UNSUBSCRIBE completed"]),
+            ("SUBSCRIBE \"Work/Acme Corp\"", vec!["{tag} OK SUBSCRIBE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+        let mut folder = test_folder();
+        folder.name = "Work/Acme".to_string();
+        folder.delimiter = Some("/".to_string());
+
+        let new_name = ImapHandler::new(Default::default()).rename_folder(&account, &folder, "Acme Corp").await.unwrap();
+        assert_eq!(new_name, "Work/Acme Corp");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_emails() {
+        let handler = ImapHandler::new(Default::default());
+        let account = Account {
+            id: 1,
+            name: "Test Account".to_string(),
+            email: "test@example.com".to_string(),
+            protocol: "IMAP".to_string(),
+            imap_server: Some("imap.example.com".to_string()),
+            imap_port: Some(993),
+            smtp_server: Some("smtp.example.com".to_string()),
+            smtp_port: Some(587),
+            jmap_url: None,
+            username: "test@example.com".to_string(),
+            password_encrypted: "password".to_string(),
+            auth_method: AuthMethod::Password,
+            use_ssl: true,
+            tls_ca_bundle: None,
+            proxy_url: None,
+            authserv_id: None,
+            autocrypt_prefer_encrypt: false,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+            access_token: None,
+            certificate_pins: Vec::new(),
+        };
+
+        let folder = Folder {
+            id: 1,
+            account_id: 1,
+            name: "INBOX".to_string(),
+            display_name: "Inbox".to_string(),
+            folder_type: FolderType::Inbox,
+            message_count: 0,
+            unread_count: 0,
+            uid_validity: None,
+            uid_next: None,
+            delimiter: None,
+            parent_name: None,
+            is_subscribed: true,
+            is_selectable: true,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        };
+
+        let emails = handler.fetch_emails(&account, &folder, 10, 0).await.unwrap();
+        assert_eq!(emails.len(), 10);
+        
+        // Check email properties
+        let first_email = &emails[0];
+        assert!(!first_email.subject.is_empty());
+        assert!(!first_email.from_address.is_empty());
+        assert!(first_email.body_text.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_mark_read() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["* OK [PERMANENTFLAGS (\\Seen \\Flagged)] Limited", "{tag} OK [READ-WRITE] SELECT completed"]),
+            // Already read, so the server has nothing to report
+            ("UID STORE 123 +FLAGS.SILENT (\\Seen)", vec!["{tag} OK STORE completed"]),
+            ("UID FETCH 123 (UID FLAGS)", vec!["* 1 FETCH (UID 123 FLAGS (\\Seen))", "{tag} OK FETCH completed"]),
+        ]).await;
+        let handler = ImapHandler::new(Default::default());
+        let account = test_server::account(port);
+
+        // $Label1 is not in PERMANENTFLAGS, so it is not sent
+        let label = Flag::Keyword("$Label1".to_string());
+        let result = handler.set_flags(&account, &test_folder(), &[123], &[Flag::Seen, label], &[]).await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_set_flags_on_expunged_message() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID STORE 123 -FLAGS.SILENT (\\Flagged)", vec!["{tag} OK STORE completed"]),
+            ("UID FETCH 123 (UID FLAGS)", vec!["{tag} OK FETCH completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let error = ImapHandler::new(Default::default()).set_flags(&account, &test_folder(), &[123], &[], &[Flag::Flagged]).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ProtocolError>(), Some(ProtocolError::MessageNotFound { uid: 123, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_flags() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID FETCH 1:* (UID FLAGS)", vec![
+                "* 1 FETCH (UID 5 FLAGS (\\Seen \\Recent $Junk))",
+                "* 2 FETCH (UID 6 FLAGS ())",
+                "{tag} OK FETCH completed",
+            ]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let flags = ImapHandler::new(Default::default()).fetch_flags(&account, &test_folder()).await.unwrap();
+        assert_eq!(flags, vec![
+            (5, vec![Flag::Seen, Flag::Keyword("$Junk".to_string())]),
+            (6, vec![]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_delete_email() {
+        let (port, commands) = test_server::serve_logged("IMAP4rev1 UIDPLUS", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID STORE 123 +FLAGS.SILENT (\\Deleted)", vec!["{tag} OK STORE completed"]),
+            ("UID EXPUNGE 123", vec!["* 1 EXPUNGE", "{tag} OK EXPUNGE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        ImapHandler::new(Default::default()).delete_email(&account, &test_folder(), 123).await.unwrap();
+        assert!(commands.lock().unwrap().iter().any(|command| command == "UID EXPUNGE 123"));
+    }
+
+    #[tokio::test]
+    async fn test_delete_email_moves_to_trash() {
+        let (port, commands) = test_server::serve_logged("IMAP4rev1", vec![
+            ("LIST \"\" \"*\"", vec!["* LIST () \"/\" \"INBOX\"", "* LIST (\\Trash) \"/\" \"Bin\"", "{tag} OK LIST completed"]),
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID COPY 123 \"Bin\"", vec!["{tag} OK COPY completed"]),
+            ("UID STORE 123 +FLAGS.SILENT (\\Deleted)", vec!["{tag} OK STORE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        ImapHandler::new(Default::default()).delete_email(&account, &test_folder(), 123).await.unwrap();
+        let commands = commands.lock().unwrap();
+        assert!(commands.iter().any(|command| command == "UID COPY 123 \"Bin\""));
+        assert!(commands.iter().any(|command| command == "UID STORE 123 +FLAGS.SILENT (\\Deleted)"));
+        assert!(!commands.iter().any(|command| command.contains("EXPUNGE")));
+    }
+
+    #[tokio::test]
+    async fn test_move_emails_uses_move() {
+        let port = test_server::serve("IMAP4rev1 MOVE UIDPLUS", vec![
+            ("SELECT \"INBOX\"", vec!["* 3 EXISTS", "{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID MOVE 42 \"Archive\"", vec!["* OK [COPYUID 1 42 7] Moved", "* 1 EXPUNGE", "{tag} OK MOVE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+        let inbox = Folder { id: 1, name: "INBOX".to_string(), ..test_folder() };
+        let archive = Folder { id: 2, name: "Archive".to_string(), ..test_folder() };
+
+        let mapping = ImapHandler::new(Default::default()).move_emails(&account, &inbox, &[42], &archive).await.unwrap();
+        assert_eq!(mapping, vec![UidMapping { source_uid: 42, target_uid: 7 }]);
+    }
+
+    #[tokio::test]
+    async fn test_move_emails_falls_back_to_copy() {
+        let port = test_server::serve("IMAP4rev1 UIDPLUS", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID COPY 42:43 \"Archive\"", vec!["{tag} OK [COPYUID 1 42:43 7:8] COPY completed"]),
+            ("UID STORE 42:43 +FLAGS.SILENT (\\Deleted)", vec!["{tag} OK STORE completed"]),
+            ("UID EXPUNGE 42:43", vec!["* 1 EXPUNGE", "* 1 EXPUNGE", "{tag} OK EXPUNGE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+        let inbox = Folder { id: 1, name: "INBOX".to_string(), ..test_folder() };
+        let archive = Folder { id: 2, name: "Archive".to_string(), ..test_folder() };
+
+        let mapping = ImapHandler::new(Default::default()).move_emails(&account, &inbox, &[43, 42], &archive).await.unwrap();
+        assert_eq!(mapping.len(), 2);
+        assert_eq!(mapping[1], UidMapping { source_uid: 43, target_uid: 8 });
+    }
+
+    #[tokio::test]
+    async fn test_move_emails_keeps_copies_when_expunge_fails() {
+        let port = test_server::serve("IMAP4rev1 UIDPLUS", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID COPY 42 \"Archive\"", vec!["{tag} OK [COPYUID 1 42 7] COPY completed"]),
+            ("UID STORE 42 +FLAGS.SILENT (\\Deleted)", vec!["{tag} NO Mailbox is read-only"]),
+        ]).await;
+        let account = test_server::account(port);
+        let inbox = Folder { id: 1, name: "INBOX".to_string(), ..test_folder() };
+        let archive = Folder { id: 2, name: "Archive".to_string(), ..test_folder() };
+
+        // Failing would have the move retried, copying the message again
+        let mapping = ImapHandler::new(Default::default()).move_emails(&account, &inbox, &[42], &archive).await.unwrap();
+        assert_eq!(mapping, vec![UidMapping { source_uid: 42, target_uid: 7 }]);
+    }
+
+    #[tokio::test]
+    async fn test_move_emails_to_missing_folder() {
+        let port = test_server::serve("IMAP4rev1 MOVE", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID MOVE 42 \"Gone\"", vec!["{tag} NO [TRYCREATE] No such mailbox"]),
+        ]).await;
+        let account = test_server::account(port);
+        let inbox = Folder { id: 1, name: "INBOX".to_string(), ..test_folder() };
+        let gone = Folder { id: 2, name: "Gone".to_string(), ..test_folder() };
+
+        let error = ImapHandler::new(Default::default()).move_emails(&account, &inbox, &[42], &gone).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ProtocolError>(), Some(ProtocolError::FolderNotFound(name)) if name == "Gone"));
+    }
+
+    #[tokio::test]
+    async fn test_copy_emails_over_quota() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID COPY 42 \"Archive\"", vec!["{tag} NO [OVERQUOTA] Mailbox is full"]),
+        ]).await;
+        let account = test_server::account(port);
+        let inbox = Folder { id: 1, name: "INBOX".to_string(), ..test_folder() };
+        let archive = Folder { id: 2, name: "Archive".to_string(), ..test_folder() };
+
+        let error = ImapHandler::new(Default::default()).copy_emails(&account, &inbox, &[42], &archive).await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ProtocolError>(), Some(ProtocolError::QuotaExceeded)));
+        assert!(matches!(error.downcast_ref::<ImapError>(), Some(ImapError::No { code: Some(code), .. }) if code == "OVERQUOTA"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_labels() {
+        let port = test_server::serve("IMAP4rev1 X-GM-EXT-1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID FETCH 1:* (UID X-GM-LABELS)", vec![
+                "* 1 FETCH (X-GM-LABELS (\\Inbox \"Work/Q3 plans\") UID 5)",
+                "* 2 FETCH (X-GM-LABELS () UID 6)",
+                "{tag} OK FETCH completed",
+            ]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let labels = ImapHandler::new(Default::default()).fetch_labels(&account, &test_folder()).await.unwrap();
+        assert_eq!(labels, vec![
+            (5, vec!["\\Inbox".to_string(), "Work/Q3 plans".to_string()]),
+            (6, vec![]),
+        ]);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_labels_without_gmail_extension() {
+        let port = test_server::serve("IMAP4rev1", vec![]).await;
+        let account = test_server::account(port);
+
+        let labels = ImapHandler::new(Default::default()).fetch_labels(&account, &test_folder()).await.unwrap();
+        assert!(labels.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_set_labels() {
+        let port = test_server::serve("IMAP4rev1 X-GM-EXT-1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID STORE 5:6 +X-GM-LABELS.SILENT (\"Receipts\")", vec!["{tag} OK STORE completed"]),
+            ("UID STORE 5:6 -X-GM-LABELS.SILENT (\\Inbox)", vec!["{tag} OK STORE completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let result = ImapHandler::new(Default::default())
+            .set_labels(&account, &test_folder(), &[5, 6], &["Receipts".to_string()], &["\\Inbox".to_string()])
+            .await;
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_set_labels_without_gmail_extension() {
+        let (port, log) = test_server::serve_logged("IMAP4rev1", vec![]).await;
+        let account = test_server::account(port);
+        let handler = ImapHandler::new(Default::default());
+
+        let labels = ["Receipts".to_string()];
+        assert!(handler.set_labels(&account, &test_folder(), &[5], &labels, &[]).await.is_err());
+        assert!(handler.set_labels(&account, &test_folder(), &[5], &labels, &[]).await.is_err());
+        // The healthy session went back to the pool rather than being dropped
+        let logins = log.lock().unwrap().iter().filter(|command| command.starts_with("LOGIN ")).count();
+        assert_eq!(logins, 1);
+    }
+
+    #[tokio::test]
+    async fn test_fetch_part_decodes_body() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID FETCH 42 (BODY.PEEK[2.MIME] BODY.PEEK[2])", vec![
+                "* 3 FETCH (UID 42 BODY[2.MIME] {68}\r\nContent-Type: application/pdf\r\nContent-Transfer-Encoding: base64\r\n\r\n BODY[2] {14}\r\nJVBERi0x\r\nLjQ=)",
+                "{tag} OK FETCH completed",
+            ]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let data = ImapHandler::new(Default::default()).fetch_part(&account, &test_folder(), 42, "2").await.unwrap();
+        assert_eq!(data, b"%PDF-1.4");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_part_with_binary() {
+        let port = test_server::serve("IMAP4rev1 BINARY", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID FETCH 42 (BINARY.PEEK[1.2])", vec!["* 3 FETCH (UID 42 BINARY[1.2] ~{3}\r\nabc)", "{tag} OK FETCH completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let data = ImapHandler::new(Default::default()).fetch_part(&account, &test_folder(), 42, "1.2").await.unwrap();
+        assert_eq!(data, b"abc");
+    }
+
+    #[tokio::test]
+    async fn test_fetch_part_of_expunged_message() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID FETCH 42 ", vec!["{tag} OK FETCH completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let error = ImapHandler::new(Default::default()).fetch_part(&account, &test_folder(), 42, "2").await.unwrap_err();
+        assert!(matches!(error.downcast_ref::<ProtocolError>(), Some(ProtocolError::MessageNotFound { uid: 42, .. })));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_message() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID FETCH 42 (BODY.PEEK[])", vec!["* 3 FETCH (UID 42 BODY[] {22}\r\nSubject: Hi\r\n\r\nHello\r\n)", "{tag} OK FETCH completed"]),
+        ]).await;
+        let account = test_server::account(port);
+
+        let data = ImapHandler::new(Default::default()).fetch_message(&account, &test_folder(), 42).await.unwrap();
+        assert_eq!(data, b"Subject: Hi\r\n\r\nHello\r\n");
+    }
+
+    fn test_folder() -> Folder {
+        Folder {
+            id: 1,
+            account_id: 1,
+            name: "INBOX".to_string(),
+            display_name: "Inbox".to_string(),
+            folder_type: FolderType::Inbox,
+            message_count: 0,
+            unread_count: 0,
+            uid_validity: None,
+            uid_next: None,
+            delimiter: None,
+            parent_name: None,
+            is_subscribed: true,
+            is_selectable: true,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+}
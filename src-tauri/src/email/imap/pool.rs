@@ -0,0 +1,326 @@
+//! Signed-in IMAP sessions kept open between calls, so a command doesn't
+//! pay for a TCP, TLS and login handshake every time.
+
+use std::collections::HashMap;
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+
+use crate::db::Account;
+
+use super::ImapClient;
+
+/// Connections per account to servers we know nothing about.
+const DEFAULT_LIMIT: usize = 4;
+/// Gmail allows 15 connections per account across all clients. The rest
+/// are left for the user's other devices.
+const GMAIL_LIMIT: usize = 10;
+/// Sessions kept open per account between calls. Others are logged out
+/// when they come back.
+const MAX_IDLE: usize = 2;
+/// Servers may log out clients idle for 30 minutes (RFC 3501), so older
+/// sessions are dropped rather than checked.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// How long an idle session has to answer NOOP before it counts as dropped.
+const NOOP_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long shutting down waits for each LOGOUT.
+const LOGOUT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// The sessions of every account, shared by all IMAP handlers.
+#[derive(Default)]
+pub struct SessionPool {
+    accounts: Mutex<HashMap<i64, Arc<Sessions>>>,
+}
+
+/// The settings a session signed in with. Sessions of an account whose
+/// settings changed are not reused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Settings {
+    host: Option<String>,
+    port: Option<i32>,
+    username: String,
+    use_ssl: bool,
+    tls_ca_bundle: Option<String>,
+    proxy_url: Option<String>,
+}
+
+impl Settings {
+    fn of(account: &Account) -> Self {
+        Settings {
+            host: account.imap_server.clone(),
+            port: account.imap_port,
+            username: account.username.clone(),
+            use_ssl: account.use_ssl,
+            tls_ca_bundle: account.tls_ca_bundle.clone(),
+            proxy_url: account.proxy_url.clone(),
+        }
+    }
+}
+
+/// One account's connections. Idle sessions keep their permits, so the
+/// limit counts every open connection.
+struct Sessions {
+    settings: Settings,
+    permits: Arc<Semaphore>,
+    idle: Mutex<Idle>,
+    /// Woken when a session comes back to the idle list.
+    returned: Notify,
+}
+
+#[derive(Default)]
+struct Idle {
+    clients: Vec<(ImapClient, OwnedSemaphorePermit, Instant)>,
+    closed: bool,
+}
+
+impl Sessions {
+    fn new(settings: Settings) -> Self {
+        let host = settings.host.as_deref().unwrap_or_default();
+        Sessions {
+            permits: Arc::new(Semaphore::new(connection_limit(host))),
+            settings,
+            idle: Mutex::new(Idle::default()),
+            returned: Notify::new(),
+        }
+    }
+
+    fn take_idle(&self) -> Option<(ImapClient, OwnedSemaphorePermit, Instant)> {
+        self.idle.lock().unwrap().clients.pop()
+    }
+
+    /// Stops handing out sessions and returns the idle ones to log out.
+    /// Sessions in use are logged out when they come back.
+    fn close(&self) -> Vec<ImapClient> {
+        self.permits.close();
+        let mut idle = self.idle.lock().unwrap();
+        idle.closed = true;
+        idle.clients.drain(..).map(|(client, _, _)| client).collect()
+    }
+}
+
+/// How many connections an account may have open to `host` at once.
+fn connection_limit(host: &str) -> usize {
+    let host = host.to_ascii_lowercase();
+    let within = |domain: &str| host == domain || host.ends_with(&format!(".{}", domain));
+    match within("gmail.com") || within("googlemail.com") {
+        true => GMAIL_LIMIT,
+        false => DEFAULT_LIMIT,
+    }
+}
+
+impl SessionPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A signed-in session for `account`: an idle one that still answers
+    /// NOOP, or a new connection once the account is under its limit.
+    pub async fn checkout(&self, account: &Account) -> Result<Session> {
+        let sessions = self.sessions(account).await;
+        loop {
+            while let Some((mut client, permit, since)) = sessions.take_idle() {
+                if since.elapsed() < IDLE_TIMEOUT {
+                    if let Ok(Ok(_)) = tokio::time::timeout(NOOP_TIMEOUT, client.run("NOOP")).await {
+                        return Ok(Session { client, permit, sessions });
+                    }
+                }
+                tracing::debug!("Reconnecting stale IMAP session of account {}", account.id);
+            }
+
+            let returned = sessions.returned.notified();
+            tokio::select! {
+                permit = sessions.permits.clone().acquire_owned() => {
+                    let permit = permit.map_err(|_| anyhow!("The IMAP sessions of {} were closed", account.email))?;
+                    let client = ImapClient::connect(account).await?;
+                    return Ok(Session { client, permit, sessions });
+                }
+                // Another caller finished first; its session is quicker than a new one
+                _ = returned => {}
+            }
+        }
+    }
+
+    /// Logs out of every session of the account, such as when it is removed.
+    pub async fn close_account(&self, account_id: i64) {
+        let sessions = self.accounts.lock().unwrap().remove(&account_id);
+        if let Some(sessions) = sessions {
+            logout_all(sessions.close()).await;
+        }
+    }
+
+    /// Logs out of every session, for when the app exits.
+    pub async fn close_all(&self) {
+        let accounts: Vec<Arc<Sessions>> = self.accounts.lock().unwrap().drain().map(|(_, sessions)| sessions).collect();
+        logout_all(accounts.iter().flat_map(|sessions| sessions.close()).collect()).await;
+    }
+
+    /// The account's sessions, replacing them if its settings changed.
+    async fn sessions(&self, account: &Account) -> Arc<Sessions> {
+        let settings = Settings::of(account);
+        let (sessions, stale) = {
+            let mut accounts = self.accounts.lock().unwrap();
+            match accounts.get(&account.id) {
+                Some(sessions) if sessions.settings == settings => return sessions.clone(),
+                _ => {
+                    let sessions = Arc::new(Sessions::new(settings));
+                    (sessions.clone(), accounts.insert(account.id, sessions))
+                }
+            }
+        };
+        if let Some(stale) = stale {
+            logout_all(stale.close()).await;
+        }
+        sessions
+    }
+}
+
+async fn logout_all(clients: Vec<ImapClient>) {
+    let mut logouts = tokio::task::JoinSet::new();
+    for client in clients {
+        logouts.spawn(tokio::time::timeout(LOGOUT_TIMEOUT, client.logout()));
+    }
+    while let Some(result) = logouts.join_next().await {
+        if let Ok(Ok(Err(e))) = result {
+            tracing::debug!("IMAP logout failed: {}", e);
+        }
+    }
+}
+
+/// A session checked out of the pool. `release` hands it back; dropping it
+/// instead closes the connection, which is what a failed command needs
+/// since the server may still be mid-response.
+pub struct Session {
+    client: ImapClient,
+    permit: OwnedSemaphorePermit,
+    sessions: Arc<Sessions>,
+}
+
+impl Session {
+    /// Returns the session for the next caller, or logs out when enough
+    /// are idle already or the account's sessions were closed.
+    pub async fn release(self) -> Result<()> {
+        let Session { client, permit, sessions } = self;
+        let surplus = {
+            let mut idle = sessions.idle.lock().unwrap();
+            if idle.closed || idle.clients.len() >= MAX_IDLE {
+                Some(client)
+            } else {
+                idle.clients.push((client, permit, Instant::now()));
+                None
+            }
+        };
+        match surplus {
+            Some(client) => client.logout().await,
+            None => {
+                sessions.returned.notify_one();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Deref for Session {
+    type Target = ImapClient;
+
+    fn deref(&self) -> &ImapClient {
+        &self.client
+    }
+}
+
+impl DerefMut for Session {
+    fn deref_mut(&mut self) -> &mut ImapClient {
+        &mut self.client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::imap::test_server;
+
+    #[tokio::test]
+    async fn test_reuses_sessions() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["* 3 EXISTS", "{tag} OK [READ-WRITE] SELECT completed"]),
+        ]).await;
+        let account = test_server::account(port);
+        let pool = SessionPool::new();
+
+        let mut session = pool.checkout(&account).await.unwrap();
+        session.select("INBOX").await.unwrap();
+        session.release().await.unwrap();
+
+        // Same connection: the script has no second SELECT, and none is sent
+        let mut session = pool.checkout(&account).await.unwrap();
+        let selected = session.select("INBOX").await.unwrap();
+        assert_eq!(selected.untagged[0].text, "* 3 EXISTS");
+        session.release().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reconnects_after_drop() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK SELECT completed"]),
+            ("UID FETCH", vec!["* BYE Server shutting down", "{tag} OK FETCH completed"]),
+        ]).await;
+        let account = test_server::account(port);
+        let pool = SessionPool::new();
+
+        let mut session = pool.checkout(&account).await.unwrap();
+        session.select("INBOX").await.unwrap();
+        session.run("UID FETCH 1 FLAGS").await.unwrap();
+        session.release().await.unwrap();
+
+        // The server hung up on the idle session, so NOOP fails and a new
+        // one signs in and selects again
+        let mut session = pool.checkout(&account).await.unwrap();
+        session.select("INBOX").await.unwrap();
+        session.run("UID FETCH 1 FLAGS").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_limits_connections() {
+        let port = test_server::serve("IMAP4rev1", vec![]).await;
+        let account = test_server::account(port);
+        let pool = Arc::new(SessionPool::new());
+
+        let mut sessions = Vec::new();
+        for _ in 0..DEFAULT_LIMIT {
+            sessions.push(pool.checkout(&account).await.unwrap());
+        }
+        let waiting = tokio::spawn({
+            let (pool, account) = (pool.clone(), account.clone());
+            async move { pool.checkout(&account).await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!waiting.is_finished());
+
+        sessions.pop().unwrap().release().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), waiting).await.unwrap().unwrap().unwrap();
+        assert_eq!(connection_limit("imap.gmail.com"), GMAIL_LIMIT);
+        assert_eq!(connection_limit("imap.notgmail.com"), DEFAULT_LIMIT);
+        assert_eq!(connection_limit("evilgooglemail.com"), DEFAULT_LIMIT);
+    }
+
+    #[tokio::test]
+    async fn test_close_account() {
+        let port = test_server::serve("IMAP4rev1", vec![]).await;
+        let account = test_server::account(port);
+        let pool = SessionPool::new();
+
+        let idle = pool.checkout(&account).await.unwrap();
+        let busy = pool.checkout(&account).await.unwrap();
+        let sessions = busy.sessions.clone();
+        idle.release().await.unwrap();
+        pool.close_account(account.id).await;
+        assert!(sessions.idle.lock().unwrap().clients.is_empty());
+
+        // Sessions in use are logged out when they come back
+        busy.release().await.unwrap();
+        assert!(sessions.idle.lock().unwrap().clients.is_empty());
+        assert!(pool.checkout(&account).await.is_ok());
+    }
+}
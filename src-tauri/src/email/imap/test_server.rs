@@ -0,0 +1,90 @@
+//! A scripted IMAP server for exercising `ImapHandler` over a real socket.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::db::{Account, AuthMethod};
+
+/// Serves each connection the script from the start. LOGIN, CAPABILITY,
+/// NOOP and LOGOUT are answered automatically; every other command must
+/// start with the next expected prefix in `script` and is answered with its
+/// lines, with `{tag}` replaced by the client's tag. Anything unexpected
+/// gets a BAD. A reply that starts with `* BYE` closes the connection.
+pub async fn serve(capabilities: &'static str, script: Vec<(&'static str, Vec<&'static str>)>) -> u16 {
+    serve_logged(capabilities, script).await.0
+}
+
+/// Like `serve`, also recording every command received, without its tag.
+pub async fn serve_logged(capabilities: &'static str, script: Vec<(&'static str, Vec<&'static str>)>) -> (u16, Arc<Mutex<Vec<String>>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let commands = log.clone();
+    tokio::spawn(async move {
+        while let Ok((socket, _)) = listener.accept().await {
+            tokio::spawn(converse(socket, capabilities, script.clone(), commands.clone()));
+        }
+    });
+
+    (port, log)
+}
+
+async fn converse(socket: TcpStream, capabilities: &'static str, script: Vec<(&'static str, Vec<&'static str>)>, log: Arc<Mutex<Vec<String>>>) {
+    let (read, mut write) = socket.into_split();
+    let mut lines = BufReader::new(read).lines();
+    let mut script = script.into_iter();
+
+    write.write_all(b"* OK fake IMAP ready\r\n").await.unwrap();
+    while let Ok(Some(line)) = lines.next_line().await {
+        let (tag, command) = line.split_once(' ').unwrap_or((line.as_str(), ""));
+        log.lock().unwrap().push(command.to_string());
+        let reply = if command.starts_with("LOGIN ") {
+            format!("{} OK LOGIN completed\r\n", tag)
+        } else if command == "CAPABILITY" {
+            format!("* CAPABILITY {}\r\n{} OK CAPABILITY completed\r\n", capabilities, tag)
+        } else if command == "NOOP" {
+            format!("{} OK NOOP completed\r\n", tag)
+        } else if command == "LOGOUT" {
+            format!("* BYE\r\n{} OK LOGOUT completed\r\n", tag)
+        } else {
+            match script.next() {
+                Some((expected, replies)) if command.starts_with(expected) => {
+                    replies.iter().map(|reply| format!("{}\r\n", reply.replace("{tag}", tag))).collect()
+                }
+                _ => format!("{} BAD unexpected command {}\r\n", tag, command),
+            }
+        };
+        if write.write_all(reply.as_bytes()).await.is_err() || reply.starts_with("* BYE") {
+            break;
+        }
+    }
+}
+
+pub fn account(port: u16) -> Account {
+    Account {
+        id: 1,
+        name: "Test Account".to_string(),
+        email: "test@example.com".to_string(),
+        protocol: "IMAP".to_string(),
+        imap_server: Some("127.0.0.1".to_string()),
+        imap_port: Some(port as i32),
+        smtp_server: None,
+        smtp_port: None,
+        jmap_url: None,
+        username: "test@example.com".to_string(),
+        password_encrypted: "password".to_string(),
+        auth_method: AuthMethod::Password,
+        use_ssl: false,
+        tls_ca_bundle: None,
+        proxy_url: None,
+        authserv_id: None,
+        autocrypt_prefer_encrypt: false,
+        created_at: chrono::Utc::now(),
+        updated_at: chrono::Utc::now(),
+        access_token: None,
+        certificate_pins: Vec::new(),
+    }
+}
@@ -0,0 +1,122 @@
+//! Modified UTF-7 (RFC 3501 section 5.1.3), the encoding of mailbox names on
+//! servers without UTF8=ACCEPT.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+pub fn encode(name: &str) -> String {
+    let mut encoded = String::new();
+    let mut pending: Vec<u16> = Vec::new();
+    for c in name.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut encoded, &mut pending);
+            if c == '&' {
+                encoded.push_str("&-");
+            } else {
+                encoded.push(c);
+            }
+        } else {
+            let mut units = [0; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut units));
+        }
+    }
+    flush(&mut encoded, &mut pending);
+    encoded
+}
+
+/// Writes the pending UTF-16 units as a `&...-` run.
+fn flush(encoded: &mut String, pending: &mut Vec<u16>) {
+    if pending.is_empty() {
+        return;
+    }
+    encoded.push('&');
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for byte in pending.drain(..).flat_map(u16::to_be_bytes) {
+        bits = (bits << 8) | byte as u32;
+        bit_count += 8;
+        while bit_count >= 6 {
+            bit_count -= 6;
+            encoded.push(ALPHABET[((bits >> bit_count) & 0x3f) as usize] as char);
+        }
+    }
+    if bit_count > 0 {
+        encoded.push(ALPHABET[((bits << (6 - bit_count)) & 0x3f) as usize] as char);
+    }
+    encoded.push('-');
+}
+
+/// Decodes a mailbox name, or returns `None` if it is not valid modified
+/// UTF-7.
+pub fn decode(name: &str) -> Option<String> {
+    let mut decoded = String::new();
+    let mut rest = name;
+    while let Some(start) = rest.find('&') {
+        decoded.push_str(&rest[..start]);
+        let run = &rest[start + 1..];
+        let end = run.find('-')?;
+        if end == 0 {
+            decoded.push('&');
+        } else {
+            decoded.push_str(&decode_run(&run[..end])?);
+        }
+        rest = &run[end + 1..];
+    }
+    decoded.push_str(rest);
+    Some(decoded)
+}
+
+fn decode_run(run: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut bits: u32 = 0;
+    let mut bit_count = 0;
+    for c in run.bytes() {
+        let value = ALPHABET.iter().position(|&a| a == c)? as u32;
+        bits = (bits << 6) | value;
+        bit_count += 6;
+        if bit_count >= 8 {
+            bit_count -= 8;
+            bytes.push((bits >> bit_count) as u8);
+        }
+    }
+    // Leftover bits are padding and must be zero
+    if bit_count >= 6 || bits & ((1 << bit_count) - 1) != 0 || bytes.len() % 2 != 0 {
+        return None;
+    }
+    let units: Vec<u16> = bytes.chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect();
+    String::from_utf16(&units).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_names() {
+        let cases = [
+            ("Отправленные", "&BB4EQgQ,BEAEMAQyBDsENQQ9BD0ESwQ1-"),
+            ("Entwürfe", "Entw&APw-rfe"),
+            ("~peter/mail/台北/日本語", "~peter/mail/&U,BTFw-/&ZeVnLIqe-"),
+            ("Tom & Jerry", "Tom &- Jerry"),
+            ("INBOX", "INBOX"),
+        ];
+        for (name, encoded) in cases {
+            assert_eq!(encode(name), encoded);
+            assert_eq!(decode(encoded).as_deref(), Some(name));
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for name in ["Mail 📬 archive", "Ünïcödé/子フォルダ", "a&b&-c", "", "日本&語"] {
+            assert_eq!(decode(&encode(name)).as_deref(), Some(name));
+        }
+    }
+
+    #[test]
+    fn test_rejects_malformed() {
+        assert_eq!(decode("&U,BTFw"), None); // unterminated
+        assert_eq!(decode("&AGE!-"), None); // not in the alphabet
+        assert_eq!(decode("&2D0-"), None); // unpaired surrogate
+        assert_eq!(decode("&AP1-"), None); // non-zero padding bits
+    }
+}
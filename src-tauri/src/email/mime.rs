@@ -0,0 +1,520 @@
+//! The bits of MIME needed to take fetched messages and body parts apart.
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::db::{Attachment, ComposeEmail};
+
+/// Value of the header `name` in a MIME header block, with folded lines
+/// joined.
+pub fn header_value(headers: &str, name: &str) -> Option<String> {
+    header_values(headers, name).into_iter().next()
+}
+
+/// Values of every `name` header, top to bottom.
+pub fn header_values(headers: &str, name: &str) -> Vec<String> {
+    let mut values = Vec::new();
+    let mut current: Option<String> = None;
+    for line in headers.split("\r\n").flat_map(|line| line.split('\n')) {
+        if line.starts_with([' ', '\t']) {
+            if let Some(value) = current.as_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        values.extend(current.take());
+        if line.is_empty() {
+            break;
+        }
+        if let Some((field, rest)) = line.split_once(':') {
+            if field.trim().eq_ignore_ascii_case(name) {
+                current = Some(rest.trim().to_string());
+            }
+        }
+    }
+    values.extend(current);
+    values
+}
+
+/// Header text, as RFC 2047 encoded words when it isn't plain ASCII.
+pub fn encode_text(text: &str) -> String {
+    if text.is_ascii() {
+        return text.to_string();
+    }
+    // Keep each encoded word within 75 characters
+    let mut words = Vec::new();
+    let mut chunk = String::new();
+    for c in text.chars() {
+        if chunk.len() + c.len_utf8() > 45 {
+            words.push(format!("=?utf-8?B?{}?=", STANDARD.encode(&chunk)));
+            chunk.clear();
+        }
+        chunk.push(c);
+    }
+    words.push(format!("=?utf-8?B?{}?=", STANDARD.encode(&chunk)));
+    words.join("\r\n ")
+}
+
+/// A display name, quoted or encoded as it needs.
+pub fn encode_phrase(name: &str) -> String {
+    if !name.is_ascii() {
+        encode_text(name)
+    } else if name.contains(|c: char| "()<>[]:;@\\,.\"".contains(c)) {
+        format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        name.to_string()
+    }
+}
+
+/// The bare addresses in an address-list header value such as To, e.g.
+/// `"Doe, Jane" <jane@example.com>, bob@example.org`.
+pub fn addresses(value: &str) -> Vec<String> {
+    let mut mailboxes = vec![String::new()];
+    let (mut quoted, mut angle) = (false, false);
+    for c in value.chars() {
+        match c {
+            '"' if !angle => quoted = !quoted,
+            '<' if !quoted => angle = true,
+            '>' if !quoted => angle = false,
+            ',' if !quoted && !angle => {
+                mailboxes.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        mailboxes.last_mut().expect("never empty").push(c);
+    }
+    mailboxes
+        .iter()
+        .filter_map(|mailbox| {
+            let address = match (mailbox.rfind('<'), mailbox.rfind('>')) {
+                (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+                _ => mailbox.rsplit(':').next().unwrap_or_default().trim_end_matches(';'),
+            };
+            let address = address.trim();
+            address.contains('@').then(|| address.to_string())
+        })
+        .collect()
+}
+
+/// Splits a message into its header block, ending with the last header's
+/// line break, and its body.
+pub fn split_message(message: &[u8]) -> (&[u8], &[u8]) {
+    let crlf = message.windows(4).position(|window| window == b"\r\n\r\n").map(|at| (at + 2, at + 4));
+    let lf = message.windows(2).position(|window| window == b"\n\n").map(|at| (at + 1, at + 2));
+    match crlf.into_iter().chain(lf).min() {
+        Some((headers_end, body_start)) => (&message[..headers_end], &message[body_start..]),
+        None => (message, &[]),
+    }
+}
+
+/// A parameter of a structured header value such as Content-Type, e.g.
+/// `boundary`. Quoted values come back unquoted.
+pub fn parameter(value: &str, name: &str) -> Option<String> {
+    let mut rest = value.split_once(';')?.1;
+    loop {
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let (param, next) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut param = String::new();
+                let mut escaped = false;
+                let mut end = None;
+                for (at, c) in quoted.char_indices() {
+                    match c {
+                        _ if escaped => {
+                            param.push(c);
+                            escaped = false;
+                        }
+                        '\\' => escaped = true,
+                        '"' => {
+                            end = Some(at);
+                            break;
+                        }
+                        _ => param.push(c),
+                    }
+                }
+                let next = quoted[end? + 1..].split_once(';').map_or("", |(_, next)| next);
+                (param, next)
+            }
+            None => {
+                let (param, next) = after.split_once(';').unwrap_or((after, ""));
+                (param.trim().to_string(), next)
+            }
+        };
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(param);
+        }
+        rest = next;
+    }
+}
+
+/// The type/subtype of a Content-Type value, lowercased.
+pub fn essence(content_type: &str) -> String {
+    content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase()
+}
+
+/// The body parts of a multipart body, each with its own headers and
+/// without the line break that belongs to the next delimiter.
+pub fn multipart_parts<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary);
+    let delimiter = delimiter.as_bytes();
+
+    let mut starts = Vec::new();
+    let mut at = 0;
+    while let Some(found) = body[at..].windows(delimiter.len()).position(|window| window == delimiter) {
+        let start = at + found;
+        let end = start + delimiter.len();
+        let at_line_start = start == 0 || body[start - 1] == b'\n';
+        if at_line_start && matches!(body.get(end), None | Some(b'\r' | b'\n' | b' ' | b'\t' | b'-')) {
+            starts.push(start);
+        }
+        at = end;
+    }
+
+    let mut parts = Vec::new();
+    for (i, &start) in starts.iter().enumerate() {
+        let after = start + delimiter.len();
+        let Some(&next) = starts.get(i + 1) else {
+            break;
+        };
+        if body[after..].starts_with(b"--") {
+            break;
+        }
+        let content_start = body[after..next].iter().position(|&b| b == b'\n').map_or(next, |newline| after + newline + 1);
+        let mut end = next;
+        if end > content_start && body[end - 1] == b'\n' {
+            end -= 1;
+            if end > content_start && body[end - 1] == b'\r' {
+                end -= 1;
+            }
+        }
+        parts.push(&body[content_start..end]);
+    }
+    parts
+}
+
+/// `data` with bare LFs turned into CRLF, as on the wire.
+pub fn to_crlf(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for (i, &byte) in data.iter().enumerate() {
+        if byte == b'\n' && (i == 0 || data[i - 1] != b'\r') {
+            out.push(b'\r');
+        }
+        out.push(byte);
+    }
+    out
+}
+
+/// A multipart entity. `content_type` is everything but the boundary, e.g.
+/// `multipart/signed; protocol="application/pgp-signature"`.
+pub fn multipart(content_type: &str, parts: &[&[u8]]) -> Vec<u8> {
+    let boundary = format!("=_{}", uuid::Uuid::new_v4().simple());
+    let mut out = format!("Content-Type: {}; boundary=\"{}\"\r\n\r\n", content_type, boundary).into_bytes();
+    for part in parts {
+        out.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        out.extend_from_slice(part);
+        out.extend_from_slice(b"\r\n");
+    }
+    out.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    out
+}
+
+/// The body of an outgoing message as a MIME entity: text/plain, text/html,
+/// or multipart/alternative with both.
+pub fn text_entity(body_text: Option<&str>, body_html: Option<&str>) -> Vec<u8> {
+    let part = |subtype: &str, text: &str| {
+        format!(
+            "Content-Type: text/{}; charset=utf-8\r\nContent-Transfer-Encoding: quoted-printable\r\n\r\n{}",
+            subtype,
+            encode_quoted_printable(text.as_bytes())
+        )
+        .into_bytes()
+    };
+    match (body_text, body_html) {
+        (Some(text), Some(html)) => multipart("multipart/alternative", &[&part("plain", text), &part("html", html)]),
+        (None, Some(html)) => part("html", html),
+        (text, None) => part("plain", text.unwrap_or_default()),
+    }
+}
+
+/// The whole body of an outgoing message: `text_entity`, followed by the
+/// attachments in a multipart/mixed when there are any.
+pub fn compose_entity(email: &ComposeEmail) -> Result<Vec<u8>> {
+    let text = text_entity(email.body_text.as_deref(), email.body_html.as_deref());
+    if email.attachments.is_empty() {
+        return Ok(text);
+    }
+    let mut parts = vec![text];
+    for attachment in &email.attachments {
+        let data = attachment.data.as_deref().ok_or_else(|| anyhow!("No contents for attachment {}", attachment.filename))?;
+        parts.push(attachment_entity(attachment, data));
+    }
+    let parts: Vec<&[u8]> = parts.iter().map(Vec::as_slice).collect();
+    Ok(multipart("multipart/mixed", &parts))
+}
+
+fn attachment_entity(attachment: &Attachment, data: &[u8]) -> Vec<u8> {
+    let content_type = match essence(&attachment.content_type) {
+        essence if essence.contains('/') && !essence.contains(|c: char| c.is_whitespace() || c.is_control()) => essence,
+        _ => "application/octet-stream".to_string(),
+    };
+    let disposition = if attachment.is_inline { "inline" } else { "attachment" };
+    let mut out = format!(
+        "Content-Type: {}; {}\r\nContent-Transfer-Encoding: base64\r\nContent-Disposition: {}; {}\r\n",
+        content_type,
+        encode_parameter("name", &attachment.filename),
+        disposition,
+        encode_parameter("filename", &attachment.filename)
+    );
+    if let Some(content_id) = &attachment.content_id {
+        out.push_str(&format!("Content-ID: <{}>\r\n", content_id.trim().trim_start_matches('<').trim_end_matches('>')));
+    }
+    out.push_str("\r\n");
+    out.push_str(&encode_base64(data));
+    out.into_bytes()
+}
+
+/// `name="value"`, or RFC 2231's `name*=utf-8''value` when `value` isn't
+/// printable ASCII.
+fn encode_parameter(name: &str, value: &str) -> String {
+    if value.bytes().all(|b| (0x20..0x7f).contains(&b)) {
+        format!("{}=\"{}\"", name, value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        format!("{}*=utf-8''{}", name, utf8_percent_encode(value, NON_ALPHANUMERIC))
+    }
+}
+
+/// Quoted-printable with CRLF line breaks and lines of at most 76
+/// characters.
+pub fn encode_quoted_printable(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len() + data.len() / 8);
+    let lines: Vec<&[u8]> = data.split(|&b| b == b'\n').collect();
+    for (index, line) in lines.iter().enumerate() {
+        if index > 0 {
+            out.push_str("\r\n");
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut length = 0;
+        for (at, &byte) in line.iter().enumerate() {
+            let last = at + 1 == line.len();
+            let token = match byte {
+                b' ' | b'\t' if !last => (byte as char).to_string(),
+                33..=126 if byte != b'=' => (byte as char).to_string(),
+                _ => format!("={:02X}", byte),
+            };
+            // Leave room for the soft break's "="
+            if length + token.len() > 75 {
+                out.push_str("=\r\n");
+                length = 0;
+            }
+            length += token.len();
+            out.push_str(&token);
+        }
+    }
+    out
+}
+
+/// Base64 in CRLF-terminated lines of 76 characters.
+pub fn encode_base64(data: &[u8]) -> String {
+    let encoded = STANDARD.encode(data);
+    let mut out = String::with_capacity(encoded.len() + encoded.len() / 38);
+    for line in encoded.as_bytes().chunks(76) {
+        out.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Undoes a Content-Transfer-Encoding. No encoding means 7bit.
+pub fn decode_transfer(encoding: Option<&str>, data: &[u8]) -> Result<Vec<u8>> {
+    match encoding.map(|e| e.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("7bit") | Some("8bit") | Some("binary") => Ok(data.to_vec()),
+        Some("base64") => {
+            let cleaned: Vec<u8> = data.iter().copied().filter(|b| !b.is_ascii_whitespace()).collect();
+            // Some senders drop the padding
+            let padded_len = (cleaned.len() + 3) / 4 * 4;
+            let mut padded = cleaned;
+            padded.resize(padded_len, b'=');
+            STANDARD.decode(&padded).map_err(|e| anyhow!("Invalid base64 body: {}", e))
+        }
+        Some("quoted-printable") => Ok(decode_quoted_printable(data)),
+        Some(other) => Err(anyhow!("Unsupported transfer encoding: {}", other)),
+    }
+}
+
+/// Quoted-printable (RFC 2045 section 6.7). Malformed escapes are kept as
+/// they are, as most readers do.
+pub fn decode_quoted_printable(data: &[u8]) -> Vec<u8> {
+    let hex = |b: u8| (b as char).to_digit(16).map(|d| d as u8);
+    let mut decoded = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i] != b'=' {
+            decoded.push(data[i]);
+            i += 1;
+            continue;
+        }
+        match data.get(i + 1..i + 3) {
+            // Soft line break
+            Some([b'\r', b'\n']) => i += 3,
+            Some([b'\n', _]) => i += 2,
+            Some(&[high, low]) if hex(high).is_some() && hex(low).is_some() => {
+                decoded.push(hex(high).unwrap() << 4 | hex(low).unwrap());
+                i += 3;
+            }
+            _ if data.get(i + 1) == Some(&b'\n') => i += 2,
+            _ => {
+                decoded.push(b'=');
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_header_value() {
+        let headers = "Content-Type: application/pdf;\r\n\tname=\"report.pdf\"\r\nContent-Transfer-Encoding: BASE64\r\n\r\n";
+        assert_eq!(header_value(headers, "content-type").as_deref(), Some("application/pdf; name=\"report.pdf\""));
+        assert_eq!(header_value(headers, "Content-Transfer-Encoding").as_deref(), Some("BASE64"));
+        assert_eq!(header_value(headers, "Content-ID"), None);
+
+        let headers = "Received: from a\r\n  by b\r\nSubject: x\r\nreceived: from c\r\n\r\nReceived: in the body";
+        assert_eq!(header_values(headers, "Received"), vec!["from a by b", "from c"]);
+    }
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message(b"A: 1\r\nB: 2\r\n\r\nbody\r\n\r\nmore"), (&b"A: 1\r\nB: 2\r\n"[..], &b"body\r\n\r\nmore"[..]));
+        assert_eq!(split_message(b"A: 1\n\nbody"), (&b"A: 1\n"[..], &b"body"[..]));
+        assert_eq!(split_message(b"A: 1\r\n"), (&b"A: 1\r\n"[..], &b""[..]));
+    }
+
+    #[test]
+    fn test_encode_header_text() {
+        assert_eq!(encode_text("Hello"), "Hello");
+        assert_eq!(encode_text("Café"), "=?utf-8?B?Q2Fmw6k=?=");
+        let long = encode_text(&"é".repeat(40));
+        assert!(long.split("\r\n ").all(|word| word.len() <= 75 && word.starts_with("=?utf-8?B?")));
+        assert_eq!(encode_phrase("Jane Doe"), "Jane Doe");
+        assert_eq!(encode_phrase("Doe, \"J\""), "\"Doe, \\\"J\\\"\"");
+    }
+
+    #[test]
+    fn test_addresses() {
+        let value = "\"Doe, Jane\" <jane@example.com>, bob@example.org,\r\n Team: carol@example.net;, undisclosed-recipients:;";
+        assert_eq!(addresses(value), vec!["jane@example.com", "bob@example.org", "carol@example.net"]);
+        assert!(addresses("").is_empty());
+    }
+
+    #[test]
+    fn test_parameter() {
+        let value = "multipart/signed; micalg=pgp-sha256;\r\n protocol=\"application/pgp-signature\"; Boundary=\"a;b \\\"c\\\"\"";
+        assert_eq!(parameter(value, "micalg").as_deref(), Some("pgp-sha256"));
+        assert_eq!(parameter(value, "protocol").as_deref(), Some("application/pgp-signature"));
+        assert_eq!(parameter(value, "boundary").as_deref(), Some("a;b \"c\""));
+        assert_eq!(parameter(value, "charset"), None);
+        assert_eq!(parameter("text/plain", "charset"), None);
+        assert_eq!(essence(" Text/Plain ; charset=utf-8"), "text/plain");
+    }
+
+    #[test]
+    fn test_multipart_round_trip() {
+        let entity = multipart("multipart/mixed", &[b"Content-Type: text/plain\r\n\r\none\r\n", b"two"]);
+        let (headers, body) = split_message(&entity);
+        let content_type = header_value(&String::from_utf8_lossy(headers), "Content-Type").unwrap();
+        assert_eq!(essence(&content_type), "multipart/mixed");
+        let boundary = parameter(&content_type, "boundary").unwrap();
+        assert_eq!(multipart_parts(body, &boundary), vec![&b"Content-Type: text/plain\r\n\r\none\r\n"[..], &b"two"[..]]);
+
+        // Preamble, LF endings, and a line that only starts like the boundary
+        let body = b"preamble\n--xyz\nA: 1\n\n--xyzw is not a delimiter\n--xyz \n\nsecond\n--xyz--\nepilogue";
+        assert_eq!(multipart_parts(body, "xyz"), vec![&b"A: 1\n\n--xyzw is not a delimiter"[..], &b"\nsecond"[..]]);
+        assert!(multipart_parts(b"no parts here", "xyz").is_empty());
+    }
+
+    #[test]
+    fn test_compose_entity() {
+        let attachment = |filename: &str, content_type: &str, data: Option<&[u8]>| Attachment {
+            id: String::new(),
+            filename: filename.to_string(),
+            content_type: content_type.to_string(),
+            size_bytes: 0,
+            content_id: None,
+            is_inline: false,
+            data: data.map(<[u8]>::to_vec),
+        };
+        let mut email = ComposeEmail {
+            account_id: 1,
+            to: vec![],
+            cc: None,
+            bcc: None,
+            subject: "Hi".to_string(),
+            body_text: Some("Hi".to_string()),
+            body_html: None,
+            attachments: vec![],
+            in_reply_to: None,
+            references: None,
+            sign: false,
+            encrypt: false,
+            smime: false,
+            age: false,
+            age_passphrase: None,
+        };
+        assert_eq!(compose_entity(&email).unwrap(), text_entity(Some("Hi"), None));
+
+        email.attachments = vec![
+            attachment("Résumé \"v2\".pdf", "Application/PDF; x=1", Some(b"%PDF")),
+            attachment("notes.txt", "text/plain\r\nBcc: x@example.com", Some(b"notes")),
+        ];
+        let entity = compose_entity(&email).unwrap();
+        let (headers, body) = split_message(&entity);
+        let boundary = parameter(&header_value(&String::from_utf8_lossy(headers), "Content-Type").unwrap(), "boundary").unwrap();
+        let parts = multipart_parts(body, &boundary);
+        assert_eq!(parts.len(), 3);
+        let (headers, body) = split_message(parts[1]);
+        let headers = String::from_utf8_lossy(headers);
+        assert_eq!(header_value(&headers, "Content-Type").as_deref(), Some("application/pdf; name*=utf-8''R%C3%A9sum%C3%A9%20%22v2%22%2Epdf"));
+        assert_eq!(decode_transfer(header_value(&headers, "Content-Transfer-Encoding").as_deref(), body).unwrap(), b"%PDF");
+        let headers = String::from_utf8_lossy(split_message(parts[2]).0).into_owned();
+        assert_eq!(header_value(&headers, "Content-Type").as_deref(), Some("application/octet-stream; name=\"notes.txt\""));
+        assert_eq!(header_value(&headers, "Bcc"), None);
+
+        email.attachments.push(attachment("missing.txt", "text/plain", None));
+        assert!(compose_entity(&email).is_err());
+    }
+
+    #[test]
+    fn test_encode_quoted_printable() {
+        let encoded = encode_quoted_printable("café = 1 \nend\t".as_bytes());
+        assert_eq!(encoded, "caf=C3=A9 =3D 1=20\r\nend=09");
+        assert_eq!(decode_quoted_printable(encoded.as_bytes()), "café = 1 \r\nend\t".as_bytes());
+
+        let long = "x".repeat(200);
+        let encoded = encode_quoted_printable(long.as_bytes());
+        assert!(encoded.split("\r\n").all(|line| line.len() <= 76));
+        assert_eq!(decode_quoted_printable(encoded.as_bytes()), long.as_bytes());
+        assert_eq!(to_crlf(b"a\nb\r\nc"), b"a\r\nb\r\nc");
+    }
+
+    #[test]
+    fn test_decode_transfer() {
+        assert_eq!(decode_transfer(Some("Base64"), b"aGVs\r\nbG8").unwrap(), b"hello");
+        let data = vec![0xA5; 100];
+        let encoded = encode_base64(&data);
+        assert_eq!(encoded.lines().map(str::len).collect::<Vec<_>>(), [76, 60]);
+        assert_eq!(decode_transfer(Some("base64"), encoded.as_bytes()).unwrap(), data);
+        assert_eq!(decode_transfer(None, b"plain").unwrap(), b"plain");
+        assert_eq!(decode_transfer(Some("quoted-printable"), b"caf=C3=A9 =\r\nau lait=3D=\nok =ZZ").unwrap(), "café au lait=ok =ZZ".as_bytes());
+        assert!(decode_transfer(Some("x-uuencode"), b"begin").is_err());
+        assert!(decode_transfer(Some("base64"), b"not*base64").is_err());
+    }
+}
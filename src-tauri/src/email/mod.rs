@@ -1,5 +1,9 @@
+use std::sync::Arc;
+
 use async_trait::async_trait;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use sqlx::SqliteConnection;
+use crate::crypto::vault::Vault;
 use crate::db::{Account, Email, Folder};
 
 #[async_trait]
@@ -8,12 +12,98 @@ pub trait EmailProtocol: Send + Sync {
     async fn fetch_folders(&self, account: &Account) -> Result<Vec<Folder>>;
     async fn fetch_emails(&self, account: &Account, folder: &Folder, limit: u32, offset: u32) -> Result<Vec<Email>>;
     async fn send_email(&self, account: &Account, email: &crate::db::ComposeEmail) -> Result<String>;
-    async fn mark_read(&self, account: &Account, email_id: &str) -> Result<()>;
-    async fn delete_email(&self, account: &Account, email_id: &str) -> Result<()>;
+    async fn set_flags(&self, account: &Account, folder: &Folder, uids: &[i64], add: &[Flag], remove: &[Flag]) -> Result<()>;
+    async fn fetch_flags(&self, account: &Account, folder: &Folder) -> Result<Vec<(i64, Vec<Flag>)>>;
+    async fn move_emails(&self, account: &Account, folder: &Folder, uids: &[i64], target: &Folder) -> Result<Vec<UidMapping>>;
+    async fn copy_emails(&self, account: &Account, folder: &Folder, uids: &[i64], target: &Folder) -> Result<Vec<UidMapping>>;
+    async fn delete_email(&self, account: &Account, folder: &Folder, uid: i64) -> Result<()>;
+    /// Creates `name` under `parent`, or at the top of the personal
+    /// namespace, and subscribes to it.
+    async fn create_folder(&self, account: &Account, parent: Option<&Folder>, name: &str) -> Result<Folder>;
+    /// Gives `folder` a new leaf name and returns its new full name. The
+    /// server renames its subfolders along with it.
+    async fn rename_folder(&self, account: &Account, folder: &Folder, name: &str) -> Result<String>;
+    async fn delete_folder(&self, account: &Account, folder: &Folder) -> Result<()>;
+    async fn subscribe_folder(&self, account: &Account, folder: &Folder, subscribed: bool) -> Result<()>;
+    /// Server-side labels per UID (Gmail's X-GM-LABELS). Empty when the
+    /// server has no labels.
+    async fn fetch_labels(&self, account: &Account, folder: &Folder) -> Result<Vec<(i64, Vec<String>)>>;
+    async fn set_labels(&self, account: &Account, folder: &Folder, uids: &[i64], add: &[String], remove: &[String]) -> Result<()>;
+    /// Downloads one MIME part of a message, such as "2" or "1.3", with its
+    /// transfer encoding undone.
+    async fn fetch_part(&self, account: &Account, folder: &Folder, uid: i64, section: &str) -> Result<Vec<u8>>;
+    /// Downloads a whole message as it was delivered, headers included.
+    async fn fetch_message(&self, account: &Account, folder: &Folder, uid: i64) -> Result<Vec<u8>>;
+}
+
+/// Where a message ended up after a copy or move. Handlers return no
+/// mappings when the server does not report them (no UIDPLUS).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UidMapping {
+    pub source_uid: i64,
+    pub target_uid: i64,
+}
+
+/// Server-side failures that callers need to tell apart from connectivity
+/// problems. Handlers return these wrapped in `anyhow::Error`; the unit
+/// variants are context on the server's own error, which stays reachable
+/// by downcast.
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("message {uid} no longer exists in {folder}")]
+    MessageNotFound { folder: String, uid: i64 },
+    #[error("folder {0} does not exist on the server")]
+    FolderNotFound(String),
+    #[error("the server refused the credentials")]
+    AuthFailed,
+    #[error("the mailbox is over its quota")]
+    QuotaExceeded,
+    #[error("it already exists on the server")]
+    AlreadyExists,
+    #[error("the server is unavailable for now")]
+    Unavailable,
+}
+
+/// Returns the handler that talks to the account's mail store, signing in
+/// with the sessions in `sessions`.
+pub fn handler_for(account: &Account, sessions: &Arc<SessionPool>) -> Option<Box<dyn EmailProtocol>> {
+    match account.protocol.as_str() {
+        "IMAP" => Some(Box::new(ImapHandler::new(sessions.clone()))),
+        _ => None,
+    }
+}
+
+/// Loads an account ready to sign in with, see `oauth::prepare`, with the
+/// global proxy when it has none of its own.
+pub async fn load_account(conn: &mut SqliteConnection, vault: &Vault, account_id: i64) -> Result<Account> {
+    let mut account: Account = sqlx::query_as("SELECT * FROM accounts WHERE id = ?")
+        .bind(account_id)
+        .fetch_optional(&mut *conn)
+        .await?
+        .ok_or_else(|| anyhow!("Account not found"))?;
+    net::apply_global(conn, &mut account).await?;
+    oauth::prepare(conn, vault, &mut account).await?;
+    account.certificate_pins = pins::list(conn, account_id).await?;
+    Ok(account)
 }
 
+pub mod flags;
+pub mod folders;
 pub mod imap;
+pub mod auth;
+pub mod diagnostics;
+pub mod discover;
+pub mod dkim;
+pub mod dns;
+pub mod mime;
+pub mod net;
+pub mod oauth;
+pub mod pins;
+pub mod sasl;
+pub mod warnings;
 pub mod smtp;
+pub mod tls;
 
-pub use imap::ImapHandler;
-pub use smtp::SmtpHandler;
\ No newline at end of file
+pub use flags::Flag;
+pub use imap::{ImapHandler, SessionPool};
+pub use smtp::SmtpHandler;
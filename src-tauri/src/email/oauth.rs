@@ -0,0 +1,446 @@
+//! OAuth2 sign-in for IMAP and SMTP: the authorization code flow with PKCE
+//! (RFC 7636) through the system browser and a loopback redirect (RFC 8252),
+//! and refresh tokens sealed by the vault to get access tokens with.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Utc};
+use oauth2::basic::{BasicClient, BasicErrorResponse, BasicTokenResponse};
+use oauth2::{
+    AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, HttpRequest, HttpResponse, PkceCodeChallenge,
+    RedirectUrl, RefreshToken, RequestTokenError, Scope, TokenResponse, TokenUrl,
+};
+use sqlx::SqliteConnection;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+
+use super::net::Connector;
+use crate::crypto::vault::Vault;
+use crate::db::{Account, AuthMethod};
+
+/// How long the user has to finish signing in in the browser.
+const SIGN_IN_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Access tokens this close to expiring are refreshed before use.
+const EXPIRY_MARGIN: chrono::Duration = chrono::Duration::seconds(60);
+
+/// An authorization server and our client registration with it.
+#[derive(Debug, Clone)]
+pub struct Provider {
+    pub name: String,
+    pub auth_url: String,
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub scopes: Vec<String>,
+}
+
+/// Google or Microsoft. Our client IDs for them are set at build time, in
+/// SLOPMAIL_GOOGLE_CLIENT_ID and SLOPMAIL_MICROSOFT_CLIENT_ID.
+pub fn provider(name: &str) -> Result<Provider> {
+    let (auth_url, token_url, client_id, client_secret, scopes): (_, _, _, _, &[&str]) = match name {
+        "google" => (
+            "https://accounts.google.com/o/oauth2/v2/auth",
+            "https://oauth2.googleapis.com/token",
+            option_env!("SLOPMAIL_GOOGLE_CLIENT_ID"),
+            // Google issues installed apps a "secret" that isn't one
+            option_env!("SLOPMAIL_GOOGLE_CLIENT_SECRET"),
+            &["https://mail.google.com/"],
+        ),
+        "microsoft" => (
+            "https://login.microsoftonline.com/common/oauth2/v2.0/authorize",
+            "https://login.microsoftonline.com/common/oauth2/v2.0/token",
+            option_env!("SLOPMAIL_MICROSOFT_CLIENT_ID"),
+            None,
+            &["https://outlook.office.com/IMAP.AccessAsUser.All", "https://outlook.office.com/SMTP.Send", "offline_access"],
+        ),
+        _ => bail!("Unknown OAuth2 provider {}", name),
+    };
+    Ok(Provider {
+        name: name.to_string(),
+        auth_url: auth_url.to_string(),
+        token_url: token_url.to_string(),
+        client_id: client_id.ok_or_else(|| anyhow!("This build has no OAuth2 client ID for {}", name))?.to_string(),
+        client_secret: client_secret.map(str::to_string),
+        scopes: scopes.iter().map(|scope| scope.to_string()).collect(),
+    })
+}
+
+/// The provider for an account at, or with servers of, Gmail or Outlook.
+pub fn detect(account: &Account) -> Option<&'static str> {
+    let servers = [&account.imap_server, &account.smtp_server].map(|server| server.as_deref().unwrap_or_default());
+    detect_address(&account.email, &servers)
+}
+
+/// The provider for `address`, or for a mailbox on `servers`.
+pub fn detect_address(address: &str, servers: &[&str]) -> Option<&'static str> {
+    let domain = address.rsplit('@').next().unwrap_or_default().to_ascii_lowercase();
+    let servers: Vec<String> = servers.iter().map(|server| server.to_ascii_lowercase()).collect();
+    if matches!(domain.as_str(), "gmail.com" | "googlemail.com") || servers.iter().any(|server| server.ends_with(".gmail.com")) {
+        return Some("google");
+    }
+    // Outlook.com has addresses under many country domains, like hotmail.co.uk
+    if matches!(domain.split('.').next(), Some("outlook" | "hotmail" | "live" | "msn"))
+        || servers.iter().any(|server| server.ends_with(".office365.com") || server.ends_with(".outlook.com"))
+    {
+        return Some("microsoft");
+    }
+    None
+}
+
+/// Tokens from the token endpoint.
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub access_token: String,
+    pub refresh_token: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+fn client(auth_url: &str, token_url: &str, client_id: &str, client_secret: Option<&str>) -> Result<BasicClient> {
+    let client = BasicClient::new(
+        ClientId::new(client_id.to_string()),
+        client_secret.map(|secret| ClientSecret::new(secret.to_string())),
+        AuthUrl::new(auth_url.to_string())?,
+        Some(TokenUrl::new(token_url.to_string())?),
+    );
+    // Public clients have no secret to put in a Basic header
+    Ok(client.set_auth_type(AuthType::RequestBody))
+}
+
+/// Signs in through the browser, which `open` shows the provider's consent
+/// page in. The provider sends the code back to a listener on 127.0.0.1.
+pub async fn authorize(
+    provider: &Provider,
+    login_hint: &str,
+    connector: &Connector,
+    open: impl FnOnce(&str) -> Result<()>,
+) -> Result<Token> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let redirect_url = format!("http://127.0.0.1:{}/", listener.local_addr()?.port());
+    let client = client(&provider.auth_url, &provider.token_url, &provider.client_id, provider.client_secret.as_deref())?
+        .set_redirect_uri(RedirectUrl::new(redirect_url)?);
+
+    let (challenge, verifier) = PkceCodeChallenge::new_random_sha256();
+    let (url, state) = client
+        .authorize_url(CsrfToken::new_random)
+        .add_scopes(provider.scopes.iter().map(|scope| Scope::new(scope.clone())))
+        .add_extra_param("login_hint", login_hint)
+        .set_pkce_challenge(challenge)
+        .url();
+    open(url.as_str())?;
+
+    let code = tokio::time::timeout(SIGN_IN_TIMEOUT, redirected(&listener, state.secret()))
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the sign-in in the browser"))??;
+    let response = client
+        .exchange_code(AuthorizationCode::new(code))
+        .set_pkce_verifier(verifier)
+        .request_async(|request| http_client(connector, request))
+        .await
+        .map_err(token_error)?;
+    Ok(token(&response))
+}
+
+/// Waits for the browser to come back with the authorization code, and
+/// tells the user they can go back to the app.
+async fn redirected(listener: &TcpListener, state: &str) -> Result<String> {
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let mut reader = BufReader::new(&mut socket);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).await?;
+        let mut header = String::new();
+        while reader.read_line(&mut header).await? > 2 {
+            header.clear();
+        }
+
+        let target = request_line.split_whitespace().nth(1).unwrap_or("/");
+        let url = url::Url::parse("http://127.0.0.1/")?.join(target)?;
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let result = match (query.get("code"), query.get("error")) {
+            (_, Some(error)) => Err(anyhow!("Sign-in failed: {}", query.get("error_description").unwrap_or(error))),
+            (Some(code), None) if query.get("state").map(String::as_str) == Some(state) => Ok(code.clone()),
+            (Some(_), None) => Err(anyhow!("The sign-in response doesn't belong to this sign-in")),
+            // The browser asking for a favicon
+            (None, None) => {
+                socket.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n").await?;
+                continue;
+            }
+        };
+
+        let page = match result {
+            Ok(_) => "Signed in. You can close this window and return to SlopMail.",
+            Err(_) => "Sign-in failed. You can close this window and return to SlopMail.",
+        };
+        let page = format!("<!DOCTYPE html><html><body><p>{}</p></body></html>", page);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            page.len(),
+            page
+        );
+        socket.write_all(response.as_bytes()).await?;
+        return result;
+    }
+}
+
+/// Talks to the token endpoint with our own reqwest, rather than the older
+/// one the oauth2 crate bundles, through the account's proxy.
+async fn http_client(connector: &Connector, request: HttpRequest) -> Result<HttpResponse, reqwest::Error> {
+    let client = connector.http_client()?.redirect(reqwest::redirect::Policy::none()).build()?;
+    let method = reqwest::Method::from_bytes(request.method.as_str().as_bytes()).unwrap_or(reqwest::Method::POST);
+    let mut builder = client.request(method, request.url.as_str()).body(request.body);
+    for (name, value) in &request.headers {
+        builder = builder.header(name.as_str(), value.as_bytes());
+    }
+    let response = builder.send().await?;
+
+    let status_code = oauth2::http::StatusCode::from_u16(response.status().as_u16()).unwrap_or(oauth2::http::StatusCode::BAD_GATEWAY);
+    let mut headers = oauth2::http::HeaderMap::new();
+    for (name, value) in response.headers() {
+        let name = oauth2::http::HeaderName::from_bytes(name.as_str().as_bytes());
+        let value = oauth2::http::HeaderValue::from_bytes(value.as_bytes());
+        if let (Ok(name), Ok(value)) = (name, value) {
+            headers.append(name, value);
+        }
+    }
+    let body = response.bytes().await?.to_vec();
+    Ok(HttpResponse { status_code, headers, body })
+}
+
+fn token_error(error: RequestTokenError<reqwest::Error, BasicErrorResponse>) -> anyhow::Error {
+    match error {
+        RequestTokenError::ServerResponse(response) => match response.error_description() {
+            Some(description) => anyhow!("The provider refused: {} ({})", response.error(), description),
+            None => anyhow!("The provider refused: {}", response.error()),
+        },
+        RequestTokenError::Request(e) => anyhow!("Failed to reach the provider: {}", e),
+        e => anyhow!("Unexpected response from the provider: {}", e),
+    }
+}
+
+fn token(response: &BasicTokenResponse) -> Token {
+    Token {
+        access_token: response.access_token().secret().clone(),
+        refresh_token: response.refresh_token().map(|token| token.secret().clone()),
+        expires_at: response
+            .expires_in()
+            .and_then(|expires_in| chrono::Duration::from_std(expires_in).ok())
+            .map(|expires_in| Utc::now() + expires_in),
+    }
+}
+
+/// Keeps `account_id`'s tokens from signing in with `provider`, and switches
+/// the account to OAuth2.
+pub async fn save(conn: &mut SqliteConnection, vault: &Vault, account_id: i64, provider: &Provider, token: &Token) -> Result<()> {
+    let refresh_token = token.refresh_token.as_deref().ok_or_else(|| anyhow!("The provider didn't grant offline access"))?;
+    sqlx::query(
+        "INSERT INTO oauth_tokens (account_id, provider, token_url, client_id, client_secret, refresh_token, access_token, expires_at) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?) \
+         ON CONFLICT(account_id) DO UPDATE SET provider = excluded.provider, token_url = excluded.token_url, \
+         client_id = excluded.client_id, client_secret = excluded.client_secret, refresh_token = excluded.refresh_token, \
+         access_token = excluded.access_token, expires_at = excluded.expires_at, updated_at = CURRENT_TIMESTAMP",
+    )
+    .bind(account_id)
+    .bind(&provider.name)
+    .bind(&provider.token_url)
+    .bind(&provider.client_id)
+    .bind(&provider.client_secret)
+    .bind(vault.seal(refresh_token.as_bytes())?)
+    .bind(vault.seal(token.access_token.as_bytes())?)
+    .bind(token.expires_at)
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query("UPDATE accounts SET auth_method = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+        .bind(AuthMethod::Oauth2)
+        .bind(account_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// An access token for `account_id`, refreshed first if it is about to
+/// expire. Providers that rotate refresh tokens get the new one kept.
+pub async fn access_token(conn: &mut SqliteConnection, vault: &Vault, account_id: i64, connector: &Connector) -> Result<String> {
+    #[derive(sqlx::FromRow)]
+    struct Grant {
+        token_url: String,
+        client_id: String,
+        client_secret: Option<String>,
+        refresh_token: Vec<u8>,
+        access_token: Option<Vec<u8>>,
+        expires_at: Option<DateTime<Utc>>,
+    }
+    let grant: Grant = sqlx::query_as(
+        "SELECT token_url, client_id, client_secret, refresh_token, access_token, expires_at FROM oauth_tokens WHERE account_id = ?",
+    )
+    .bind(account_id)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| anyhow!("The account hasn't been signed in with OAuth2"))?;
+
+    let unseal = |sealed: &[u8]| -> Result<String> { Ok(String::from_utf8(vault.open(sealed)?)?) };
+    if let (Some(access_token), Some(expires_at)) = (&grant.access_token, grant.expires_at) {
+        if expires_at - EXPIRY_MARGIN > Utc::now() {
+            return unseal(access_token);
+        }
+    }
+
+    // Only the token endpoint is needed to refresh
+    let client = client(&grant.token_url, &grant.token_url, &grant.client_id, grant.client_secret.as_deref())?;
+    let refresh_token = RefreshToken::new(unseal(&grant.refresh_token)?);
+    let response = client.exchange_refresh_token(&refresh_token).request_async(|request| http_client(connector, request)).await.map_err(token_error)?;
+    let token = token(&response);
+
+    let refresh_token = token.refresh_token.as_deref().unwrap_or(refresh_token.secret());
+    sqlx::query(
+        "UPDATE oauth_tokens SET refresh_token = ?, access_token = ?, expires_at = ?, updated_at = CURRENT_TIMESTAMP WHERE account_id = ?",
+    )
+    .bind(vault.seal(refresh_token.as_bytes())?)
+    .bind(vault.seal(token.access_token.as_bytes())?)
+    .bind(token.expires_at)
+    .bind(account_id)
+    .execute(&mut *conn)
+    .await?;
+    Ok(token.access_token)
+}
+
+/// Gets `account` ready to sign in: an OAuth2 account gets a current access
+/// token. Password accounts need nothing.
+pub async fn prepare(conn: &mut SqliteConnection, vault: &Vault, account: &mut Account) -> Result<()> {
+    if account.auth_method == AuthMethod::Oauth2 {
+        let connector = Connector::for_account(account)?;
+        account.access_token = Some(access_token(conn, vault, account.id, &connector).await?);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+    use sha2::{Digest, Sha256};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::AsyncReadExt;
+
+    /// A token endpoint. It gives out "access-1" for the code "code-1" with
+    /// the verifier for the PKCE challenge in `challenge`, and "access-2"
+    /// for the refresh token "refresh-1".
+    async fn token_server(challenge: Arc<Mutex<Option<String>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut reader = BufReader::new(socket);
+                let mut line = String::new();
+                let mut length = 0;
+                while reader.read_line(&mut line).await.unwrap() > 2 {
+                    if let Some(value) = line.to_ascii_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    line.clear();
+                }
+                let mut body = vec![0; length];
+                reader.read_exact(&mut body).await.unwrap();
+                let form: HashMap<String, String> = url::form_urlencoded::parse(&body).into_owned().collect();
+                let field = |name: &str| form.get(name).map(String::as_str);
+
+                let verified = field("code_verifier").map(|verifier| URL_SAFE_NO_PAD.encode(Sha256::digest(verifier)));
+                let (status, json) = match field("grant_type") {
+                    Some("authorization_code") if field("code") == Some("code-1") && verified == *challenge.lock().unwrap() => {
+                        ("200 OK", r#"{"access_token":"access-1","token_type":"Bearer","expires_in":3600,"refresh_token":"refresh-1"}"#)
+                    }
+                    Some("refresh_token") if field("refresh_token") == Some("refresh-1") && field("client_id") == Some("slopmail") => {
+                        ("200 OK", r#"{"access_token":"access-2","token_type":"Bearer","expires_in":3600}"#)
+                    }
+                    _ => ("400 Bad Request", r#"{"error":"invalid_grant","error_description":"Bad code"}"#),
+                };
+                let response = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    json.len(),
+                    json
+                );
+                reader.get_mut().write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        format!("http://127.0.0.1:{}", port)
+    }
+
+    /// Plays the browser: the user consents, or denies with `error`, and the
+    /// provider redirects back with the code.
+    fn browser(challenge: Arc<Mutex<Option<String>>>, error: Option<&'static str>) -> impl FnOnce(&str) -> Result<()> {
+        move |url| {
+            let url = url::Url::parse(url)?;
+            let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            assert_eq!(query["login_hint"], "jane@example.com");
+            assert_eq!(query["code_challenge_method"], "S256");
+            *challenge.lock().unwrap() = Some(query["code_challenge"].clone());
+
+            let mut redirect = url::Url::parse(&query["redirect_uri"])?;
+            match error {
+                Some(error) => redirect.query_pairs_mut().append_pair("error", error),
+                None => redirect.query_pairs_mut().append_pair("code", "code-1").append_pair("state", &query["state"]),
+            };
+            tokio::spawn(async move {
+                reqwest::get(redirect.join("/favicon.ico").unwrap()).await.unwrap();
+                let page = reqwest::get(redirect).await.unwrap().text().await.unwrap();
+                assert!(page.contains(if error.is_some() { "Sign-in failed" } else { "Signed in" }));
+            });
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oauth() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let vault = Vault::new();
+        vault.unlock(&mut conn, "master").await.unwrap();
+
+        let challenge = Arc::new(Mutex::new(None));
+        let base = token_server(challenge.clone()).await;
+        let provider = Provider {
+            name: "test".to_string(),
+            auth_url: format!("{}/authorize", base),
+            token_url: format!("{}/token", base),
+            client_id: "slopmail".to_string(),
+            client_secret: None,
+            scopes: vec!["mail".to_string()],
+        };
+
+        let token = authorize(&provider, "jane@example.com", &Connector::default(), browser(challenge.clone(), None)).await.unwrap();
+        assert_eq!((token.access_token.as_str(), token.refresh_token.as_deref()), ("access-1", Some("refresh-1")));
+        save(&mut conn, &vault, 1, &provider, &token).await.unwrap();
+
+        let mut account: Account = sqlx::query_as("SELECT * FROM accounts WHERE id = 1").fetch_one(&mut *conn).await.unwrap();
+        assert_eq!(account.auth_method, AuthMethod::Oauth2);
+        prepare(&mut conn, &vault, &mut account).await.unwrap();
+        assert_eq!(account.access_token.as_deref(), Some("access-1"));
+
+        // Once it is about to expire, the refresh token gets a new one
+        sqlx::query("UPDATE oauth_tokens SET expires_at = ?").bind(Utc::now() + chrono::Duration::seconds(30)).execute(&mut *conn).await.unwrap();
+        assert_eq!(access_token(&mut conn, &vault, 1, &Connector::default()).await.unwrap(), "access-2");
+        assert_eq!(access_token(&mut conn, &vault, 1, &Connector::default()).await.unwrap(), "access-2");
+        let stored: Vec<u8> = sqlx::query_scalar("SELECT refresh_token FROM oauth_tokens").fetch_one(&mut *conn).await.unwrap();
+        assert_eq!(vault.open(&stored).unwrap(), b"refresh-1");
+
+        let error = authorize(&provider, "jane@example.com", &Connector::default(), browser(challenge, Some("access_denied"))).await.unwrap_err();
+        assert!(error.to_string().contains("access_denied"));
+        assert!(access_token(&mut conn, &vault, 2, &Connector::default()).await.is_err());
+    }
+
+    #[test]
+    fn test_detect() {
+        let mut account = crate::email::imap::test_server::account(0);
+        assert_eq!(detect(&account), None);
+        account.email = "jane@hotmail.co.uk".to_string();
+        assert_eq!(detect(&account), Some("microsoft"));
+        account.email = "jane@example.com".to_string();
+        account.imap_server = Some("imap.gmail.com".to_string());
+        assert_eq!(detect(&account), Some("google"));
+    }
+}
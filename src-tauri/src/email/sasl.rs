@@ -0,0 +1,66 @@
+//! SASL initial responses for IMAP AUTHENTICATE and SMTP AUTH: PLAIN
+//! (RFC 4616) for passwords, and XOAUTH2 and OAUTHBEARER (RFC 7628) for
+//! OAuth2 bearer tokens.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+pub fn plain(username: &str, password: &str) -> Vec<u8> {
+    format!("\0{}\0{}", username, password).into_bytes()
+}
+
+/// Google's and Microsoft's mechanism, which predates OAUTHBEARER.
+pub fn xoauth2(username: &str, token: &str) -> Vec<u8> {
+    format!("user={}\x01auth=Bearer {}\x01\x01", username, token).into_bytes()
+}
+
+pub fn oauthbearer(username: &str, host: &str, port: u16, token: &str) -> Vec<u8> {
+    // The GS2 header escapes ',' and '=' in the authorization identity
+    let username = username.replace('=', "=3D").replace(',', "=2C");
+    format!("n,a={},\x01host={}\x01port={}\x01auth=Bearer {}\x01\x01", username, host, port, token).into_bytes()
+}
+
+/// The mechanism to use from those the server offers: OAUTHBEARER over
+/// XOAUTH2 for tokens, PLAIN over LOGIN for passwords.
+pub fn choose(offered: &[String], oauth: bool) -> Option<&'static str> {
+    let preference: &[&str] = if oauth { &["OAUTHBEARER", "XOAUTH2"] } else { &["PLAIN", "LOGIN"] };
+    preference.iter().copied().find(|mechanism| offered.iter().any(|offered| offered.eq_ignore_ascii_case(mechanism)))
+}
+
+/// The reason in a base64 JSON challenge sent for a rejected token, such as
+/// `{"status":"401","schemes":"bearer"}`.
+pub fn oauth_error(challenge: &str) -> Option<String> {
+    let json = STANDARD.decode(challenge.trim()).ok()?;
+    let value: serde_json::Value = serde_json::from_slice(&json).ok()?;
+    let status = value.get("status")?.as_str()?;
+    Some(match status {
+        "400" => "the token was malformed".to_string(),
+        "401" => "the token is invalid or has expired".to_string(),
+        "403" => "the token doesn't grant access to mail".to_string(),
+        status => format!("status {}", status),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_responses() {
+        assert_eq!(plain("jane", "secret"), b"\0jane\0secret");
+        assert_eq!(xoauth2("jane@example.com", "ya29"), b"user=jane@example.com\x01auth=Bearer ya29\x01\x01");
+        assert_eq!(
+            oauthbearer("a,b=c@example.com", "imap.example.com", 993, "ya29"),
+            b"n,a=a=2Cb=3Dc@example.com,\x01host=imap.example.com\x01port=993\x01auth=Bearer ya29\x01\x01"
+        );
+
+        let offered = vec!["plain".to_string(), "XOAUTH2".to_string(), "OAUTHBEARER".to_string()];
+        assert_eq!(choose(&offered, true), Some("OAUTHBEARER"));
+        assert_eq!(choose(&offered, false), Some("PLAIN"));
+        assert_eq!(choose(&offered[..1], true), None);
+
+        let challenge = STANDARD.encode(r#"{"status":"401","schemes":"bearer","scope":"https://mail.google.com/"}"#);
+        assert_eq!(oauth_error(&challenge).as_deref(), Some("the token is invalid or has expired"));
+        assert_eq!(oauth_error("not base64"), None);
+    }
+}
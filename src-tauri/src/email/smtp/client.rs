@@ -0,0 +1,346 @@
+//! A minimal SMTP submission client (RFC 5321, RFC 6409): TLS from the
+//! start on port 465 or through STARTTLS, AUTH (RFC 4954), and delivery of
+//! one message at a time.
+
+use std::io;
+
+use anyhow::{anyhow, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+use crate::db::{Account, AuthMethod};
+use crate::email::{net, sasl, tls, ProtocolError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SmtpError {
+    #[error("SMTP server replied {code}: {text}")]
+    Rejected { code: u16, text: String },
+}
+
+/// A reply, with the text of all its lines.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub code: u16,
+    pub lines: Vec<String>,
+}
+
+impl Reply {
+    pub fn text(&self) -> String {
+        self.lines.join(" ")
+    }
+}
+
+/// A single authenticated SMTP connection.
+pub struct SmtpClient {
+    stream: BufReader<Box<dyn tls::Stream>>,
+    /// EHLO keywords with their parameters, such as "SIZE 35882577".
+    extensions: Vec<String>,
+}
+
+impl SmtpClient {
+    pub async fn connect(account: &Account) -> Result<Self> {
+        let host = account.smtp_server.as_deref().ok_or_else(|| anyhow!("Account has no SMTP server configured"))?;
+        let port = account.smtp_port.unwrap_or(if account.use_ssl { 465 } else { 587 }) as u16;
+
+        let tcp = net::Connector::for_account(account)?.connect(host, port).await?;
+        // Port 465 speaks TLS from the start, others upgrade with STARTTLS
+        let implicit_tls = account.use_ssl && port == 465;
+        let stream: Box<dyn tls::Stream> = if implicit_tls {
+            tls::connect(host, Box::new(tcp), &tls::Trust::for_host(account, host)).await?
+        } else {
+            Box::new(tcp)
+        };
+
+        let (mut client, _) = Self::greet(stream).await?;
+        client.ehlo().await?;
+        if account.use_ssl && !implicit_tls {
+            let trust = tls::Trust::for_host(account, host);
+            client = Self::new(tls::connect(host, client.starttls().await?, &trust).await?);
+            client.ehlo().await?;
+        }
+        client.login(account, host, port).await?;
+        Ok(client)
+    }
+
+    /// Wraps a connection that's ready for EHLO: after STARTTLS, say.
+    pub fn new(stream: Box<dyn tls::Stream>) -> Self {
+        Self { stream: BufReader::new(stream), extensions: Vec::new() }
+    }
+
+    /// Reads the server's 220 greeting over a fresh connection.
+    pub async fn greet(stream: Box<dyn tls::Stream>) -> Result<(Self, Reply)> {
+        let mut client = Self::new(stream);
+        let greeting = client.expect(220).await?;
+        Ok((client, greeting))
+    }
+
+    /// Sends STARTTLS, and hands back the connection to start TLS on.
+    pub async fn starttls(mut self) -> Result<Box<dyn tls::Stream>> {
+        if !self.has_extension("STARTTLS") {
+            return Err(anyhow!("The SMTP server doesn't offer STARTTLS"));
+        }
+        self.command("STARTTLS", 220).await?;
+        Ok(self.stream.into_inner())
+    }
+
+    pub async fn ehlo(&mut self) -> Result<()> {
+        // An address literal rather than our host name, which is nobody's business
+        let reply = self.command("EHLO [127.0.0.1]", 250).await?;
+        self.extensions = reply.lines.into_iter().skip(1).collect();
+        Ok(())
+    }
+
+    pub fn extensions(&self) -> &[String] {
+        &self.extensions
+    }
+
+    pub fn has_extension(&self, keyword: &str) -> bool {
+        self.extension(keyword).is_some()
+    }
+
+    /// The parameters of an EHLO extension the server announced.
+    pub fn extension(&self, keyword: &str) -> Option<&str> {
+        self.extensions.iter().find_map(|extension| {
+            let (name, parameters) = extension.split_once(' ').unwrap_or((extension, ""));
+            name.eq_ignore_ascii_case(keyword).then_some(parameters)
+        })
+    }
+
+    /// Signs in with the account's password, or with its OAuth2 access
+    /// token. A server that offers no AUTH takes mail without it.
+    pub async fn login(&mut self, account: &Account, host: &str, port: u16) -> Result<()> {
+        let offered: Vec<String> = self.extension("AUTH").unwrap_or_default().split_whitespace().map(str::to_string).collect();
+        if account.auth_method == AuthMethod::Password {
+            return match sasl::choose(&offered, false) {
+                Some("PLAIN") => self.authenticate("PLAIN", &sasl::plain(&account.username, &account.password_encrypted)).await,
+                Some(_) => {
+                    self.command("AUTH LOGIN", 334).await?;
+                    self.command(&STANDARD.encode(&account.username), 334).await?;
+                    self.command(&STANDARD.encode(&account.password_encrypted), 235).await?;
+                    Ok(())
+                }
+                None if offered.is_empty() => Ok(()),
+                None => Err(anyhow!("The SMTP server offers no password sign-in")),
+            };
+        }
+
+        let token = account.access_token.as_deref().ok_or_else(|| anyhow!("No OAuth2 access token for {}", account.email))?;
+        match sasl::choose(&offered, true) {
+            Some("OAUTHBEARER") => self.authenticate("OAUTHBEARER", &sasl::oauthbearer(&account.username, host, port, token)).await,
+            Some(mechanism) => self.authenticate(mechanism, &sasl::xoauth2(&account.username, token)).await,
+            None => Err(anyhow!("The SMTP server doesn't offer OAuth2 sign-in")),
+        }
+    }
+
+    /// AUTH with an initial response. A 334 challenge after it means the
+    /// server turned the token down; the reason it gives goes into the error.
+    async fn authenticate(&mut self, mechanism: &str, response: &[u8]) -> Result<()> {
+        self.write_line(&format!("AUTH {} {}", mechanism, STANDARD.encode(response))).await?;
+        let mut reply = self.read_reply().await?;
+        let mut reason = None;
+        if reply.code == 334 {
+            reason = sasl::oauth_error(&reply.text());
+            // RFC 7628 ends a failed exchange with %x01, XOAUTH2 with nothing
+            self.write_line(if mechanism == "OAUTHBEARER" { "AQ==" } else { "" }).await?;
+            reply = self.read_reply().await?;
+        }
+        if reply.code == 235 {
+            return Ok(());
+        }
+        let text = match reason {
+            Some(reason) => format!("{} ({})", reply.text(), reason),
+            None => reply.text(),
+        };
+        Err(classify(SmtpError::Rejected { code: reply.code, text }))
+    }
+
+    /// Hands `message` to the server for `recipients`. The message must
+    /// have CRLF line endings.
+    pub async fn send(&mut self, from: &str, recipients: &[&str], message: &[u8]) -> Result<()> {
+        if recipients.is_empty() {
+            return Err(anyhow!("The message has no recipients"));
+        }
+        self.command(&format!("MAIL FROM:<{}>", from), 250).await?;
+        for recipient in recipients {
+            self.command(&format!("RCPT TO:<{}>", recipient), 250).await?;
+        }
+        self.command("DATA", 354).await?;
+
+        let mut data = Vec::with_capacity(message.len() + 5);
+        for line in message.split_inclusive(|&b| b == b'\n') {
+            // Lines starting with a dot get another, so none ends the data early
+            if line.starts_with(b".") {
+                data.push(b'.');
+            }
+            data.extend_from_slice(line);
+        }
+        if !data.ends_with(b"\r\n") {
+            data.extend_from_slice(b"\r\n");
+        }
+        data.extend_from_slice(b".\r\n");
+        let stream = self.stream.get_mut();
+        stream.write_all(&data).await?;
+        stream.flush().await?;
+        self.expect(250).await?;
+        Ok(())
+    }
+
+    pub async fn quit(mut self) -> Result<()> {
+        self.command("QUIT", 221).await?;
+        Ok(())
+    }
+
+    /// Sends a command and reads its reply, which must be in the same class
+    /// (2xx, 3xx) as `expected`.
+    pub async fn command(&mut self, command: &str, expected: u16) -> Result<Reply> {
+        self.write_line(command).await?;
+        self.expect(expected).await
+    }
+
+    async fn expect(&mut self, expected: u16) -> Result<Reply> {
+        let reply = self.read_reply().await?;
+        if reply.code / 100 != expected / 100 {
+            return Err(classify(SmtpError::Rejected { code: reply.code, text: reply.text() }));
+        }
+        Ok(reply)
+    }
+
+    async fn write_line(&mut self, line: &str) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Reads a reply, following "250-" continuation lines to the last.
+    async fn read_reply(&mut self) -> Result<Reply> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "SMTP server closed the connection").into());
+            }
+            let line = line.trim_end_matches(['\r', '\n']);
+            let code = line.get(..3).and_then(|code| code.parse().ok()).ok_or_else(|| anyhow!("Malformed SMTP reply: {}", line))?;
+            lines.push(line.get(4..).unwrap_or_default().to_string());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok(Reply { code, lines });
+            }
+        }
+    }
+}
+
+/// Adds the `ProtocolError` a reply code stands for, as context on the
+/// `SmtpError`.
+fn classify(error: SmtpError) -> anyhow::Error {
+    let SmtpError::Rejected { code, text } = &error;
+    let kind = match code {
+        535 => Some(ProtocolError::AuthFailed),
+        // 552 is also "message too big"; the enhanced code tells them apart
+        452 | 552 if text.starts_with("4.2.2") || text.starts_with("5.2.2") => Some(ProtocolError::QuotaExceeded),
+        421 | 450 | 451 => Some(ProtocolError::Unavailable),
+        _ => None,
+    };
+    match kind {
+        Some(kind) => anyhow::Error::new(error).context(kind),
+        None => error.into(),
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Serves one connection. Each line must start with the next expected
+    /// prefix in `script` and gets its reply; the lines of a message after
+    /// DATA are only recorded. Returns everything the client sent.
+    pub(crate) async fn serve(script: Vec<(&'static str, &'static str)>) -> (u16, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let transcript = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let (read, mut write) = socket.into_split();
+            let mut read = BufReader::new(read);
+            let mut script = script.into_iter();
+            let mut transcript = String::new();
+            let mut in_data = false;
+
+            write.write_all(b"220 fake.example.com ESMTP\r\n").await.unwrap();
+            let mut line = String::new();
+            while read.read_line(&mut line).await.unwrap() > 0 {
+                transcript.push_str(&line);
+                let command = line.trim_end().to_string();
+                line.clear();
+                if in_data && command != "." {
+                    continue;
+                }
+                in_data = false;
+                let reply = match script.next() {
+                    Some((expected, reply)) if command.starts_with(expected) => {
+                        in_data = expected == "DATA";
+                        reply.to_string()
+                    }
+                    _ => format!("500 unexpected command {}", command),
+                };
+                write.write_all(format!("{}\r\n", reply).as_bytes()).await.unwrap();
+            }
+            transcript
+        });
+        (port, transcript)
+    }
+
+    pub(crate) fn account(port: u16, auth_method: AuthMethod) -> Account {
+        let mut account = crate::email::imap::test_server::account(0);
+        account.smtp_server = Some("127.0.0.1".to_string());
+        account.smtp_port = Some(port as i32);
+        account.auth_method = auth_method;
+        account.access_token = Some("ya29.token".to_string());
+        account
+    }
+
+    #[tokio::test]
+    async fn test_send() {
+        let (port, transcript) = serve(vec![
+            ("EHLO", "250-fake.example.com\r\n250-SIZE 1000\r\n250 AUTH PLAIN LOGIN XOAUTH2 OAUTHBEARER"),
+            ("AUTH OAUTHBEARER ", "235 2.7.0 Accepted"),
+            ("MAIL FROM:<test@example.com>", "250 OK"),
+            ("RCPT TO:<jane@example.com>", "250 OK"),
+            ("RCPT TO:<john@example.com>", "250 OK"),
+            ("DATA", "354 Go ahead"),
+            (".", "250 Queued"),
+            ("QUIT", "221 Bye"),
+        ])
+        .await;
+        let mut client = SmtpClient::connect(&account(port, AuthMethod::Oauth2)).await.unwrap();
+        assert_eq!(client.extension("size"), Some("1000"));
+        let message = b"Subject: Hi\r\n\r\n.dotted\r\nBye";
+        client.send("test@example.com", &["jane@example.com", "john@example.com"], message).await.unwrap();
+        client.quit().await.unwrap();
+
+        let transcript = transcript.await.unwrap();
+        let response = sasl::oauthbearer("test@example.com", "127.0.0.1", port, "ya29.token");
+        assert!(transcript.contains(&format!("AUTH OAUTHBEARER {}\r\n", STANDARD.encode(response))));
+        assert!(transcript.contains("\r\n\r\n..dotted\r\nBye\r\n.\r\nQUIT"));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_login() {
+        let challenge = "334 eyJzdGF0dXMiOiI0MDEiLCJzY2hlbWVzIjoiYmVhcmVyIn0=";
+        let (port, _) = serve(vec![
+            ("EHLO", "250-fake.example.com\r\n250 AUTH XOAUTH2"),
+            ("AUTH XOAUTH2 ", challenge),
+            ("", "535 5.7.8 Authentication failed"),
+        ])
+        .await;
+        let error = SmtpClient::connect(&account(port, AuthMethod::Oauth2)).await.err().unwrap();
+        assert!(matches!(error.downcast_ref::<SmtpError>(), Some(SmtpError::Rejected { code: 535, .. })));
+        assert!(matches!(error.downcast_ref::<ProtocolError>(), Some(ProtocolError::AuthFailed)));
+        assert!(format!("{:#}", error).contains("invalid or has expired"));
+
+        let (port, _) = serve(vec![("EHLO", "250-fake.example.com\r\n250 AUTH XOAUTH2")]).await;
+        let error = SmtpClient::connect(&account(port, AuthMethod::Password)).await.err().unwrap();
+        assert!(error.to_string().contains("no password sign-in"));
+    }
+}
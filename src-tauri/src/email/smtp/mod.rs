@@ -0,0 +1,343 @@
+use async_trait::async_trait;
+use anyhow::{Result, anyhow};
+
+use crate::db::{Account, ComposeEmail, EmailAddress, Folder};
+use super::{mime, EmailProtocol, Flag, UidMapping};
+
+pub mod client;
+
+pub use client::{SmtpClient, SmtpError};
+
+#[derive(Default)]
+pub struct SmtpHandler {
+    autocrypt: Option<String>,
+}
+
+impl SmtpHandler {
+    pub fn new() -> Self {
+        Self { autocrypt: None }
+    }
+
+    /// Sends `value`, see `crypto::autocrypt::outgoing_header`, as the
+    /// Autocrypt header on every message.
+    pub fn with_autocrypt(mut self, value: String) -> Self {
+        self.autocrypt = Some(value);
+        self
+    }
+
+    /// The whole message for `email` around `body`, a MIME entity such as
+    /// `crypto::protect_compose` makes, and its Message-ID.
+    pub fn message(&self, account: &Account, email: &ComposeEmail, body: &[u8]) -> (String, Vec<u8>) {
+        let mailbox = |address: &EmailAddress| match &address.name {
+            Some(name) => format!("{} <{}>", mime::encode_phrase(name), address.address),
+            None => address.address.clone(),
+        };
+        let list = |addresses: &[EmailAddress]| addresses.iter().map(mailbox).collect::<Vec<_>>().join(", ");
+        let domain = account.email.rsplit('@').next().unwrap_or("localhost");
+        let message_id = format!("<{}@{}>", uuid::Uuid::new_v4(), domain);
+
+        let mut headers = vec![
+            ("From", mailbox(&EmailAddress { name: Some(account.name.clone()), address: account.email.clone() })),
+            ("To", list(&email.to)),
+        ];
+        if let Some(cc) = email.cc.as_deref().filter(|cc| !cc.is_empty()) {
+            headers.push(("Cc", list(cc)));
+        }
+        headers.push(("Subject", mime::encode_text(&email.subject)));
+        headers.push(("Date", chrono::Utc::now().to_rfc2822()));
+        headers.push(("Message-ID", message_id.clone()));
+        if let Some(in_reply_to) = &email.in_reply_to {
+            headers.push(("In-Reply-To", in_reply_to.clone()));
+        }
+        if let Some(references) = &email.references {
+            headers.push(("References", references.clone()));
+        }
+        if let Some(autocrypt) = &self.autocrypt {
+            headers.push(("Autocrypt", autocrypt.clone()));
+        }
+        headers.push(("MIME-Version", "1.0".to_string()));
+
+        // The body's own Content-* headers finish the header block
+        let mut message = Vec::new();
+        for (name, value) in headers {
+            message.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        message.extend_from_slice(&mime::to_crlf(body));
+        (message_id, message)
+    }
+
+    /// Sends `email` with `body` as its content to every recipient,
+    /// Bcc included.
+    pub async fn send_message(&self, account: &Account, email: &ComposeEmail, body: &[u8]) -> Result<String> {
+        let (message_id, message) = self.message(account, email, body);
+        let recipients: Vec<&str> = email
+            .to
+            .iter()
+            .chain(email.cc.iter().flatten())
+            .chain(email.bcc.iter().flatten())
+            .map(|address| address.address.as_str())
+            .collect();
+
+        let mut client = SmtpClient::connect(account).await?;
+        client.send(&account.email, &recipients, &message).await?;
+        client.quit().await?;
+        Ok(message_id)
+    }
+}
+
+#[async_trait]
+impl EmailProtocol for SmtpHandler {
+    async fn test_connection(&self, account: &Account) -> Result<bool> {
+        SmtpClient::connect(account).await?.quit().await?;
+        Ok(true)
+    }
+
+    async fn fetch_folders(&self, _account: &Account) -> Result<Vec<Folder>> {
+        Err(anyhow!("SMTP cannot fetch folders"))
+    }
+
+    async fn fetch_emails(&self, _account: &Account, _folder: &Folder, _limit: u32, _offset: u32) -> Result<Vec<crate::db::Email>> {
+        Err(anyhow!("SMTP cannot fetch emails"))
+    }
+
+    async fn send_email(&self, account: &Account, email: &ComposeEmail) -> Result<String> {
+        let body = mime::compose_entity(email)?;
+        self.send_message(account, email, &body).await
+    }
+
+    async fn set_flags(&self, _account: &Account, _folder: &Folder, _uids: &[i64], _add: &[Flag], _remove: &[Flag]) -> Result<()> {
+        Err(anyhow!("SMTP cannot change email flags"))
+    }
+
+    async fn fetch_flags(&self, _account: &Account, _folder: &Folder) -> Result<Vec<(i64, Vec<Flag>)>> {
+        Err(anyhow!("SMTP cannot fetch email flags"))
+    }
+
+    async fn move_emails(&self, _account: &Account, _folder: &Folder, _uids: &[i64], _target: &Folder) -> Result<Vec<UidMapping>> {
+        Err(anyhow!("SMTP cannot move emails"))
+    }
+
+    async fn copy_emails(&self, _account: &Account, _folder: &Folder, _uids: &[i64], _target: &Folder) -> Result<Vec<UidMapping>> {
+        Err(anyhow!("SMTP cannot copy emails"))
+    }
+
+    async fn delete_email(&self, _account: &Account, _folder: &Folder, _uid: i64) -> Result<()> {
+        Err(anyhow!("SMTP cannot delete emails"))
+    }
+
+    async fn create_folder(&self, _account: &Account, _parent: Option<&Folder>, _name: &str) -> Result<Folder> {
+        Err(anyhow!("SMTP cannot manage folders"))
+    }
+
+    async fn rename_folder(&self, _account: &Account, _folder: &Folder, _name: &str) -> Result<String> {
+        Err(anyhow!("SMTP cannot manage folders"))
+    }
+
+    async fn delete_folder(&self, _account: &Account, _folder: &Folder) -> Result<()> {
+        Err(anyhow!("SMTP cannot manage folders"))
+    }
+
+    async fn subscribe_folder(&self, _account: &Account, _folder: &Folder, _subscribed: bool) -> Result<()> {
+        Err(anyhow!("SMTP cannot manage folders"))
+    }
+
+    async fn fetch_labels(&self, _account: &Account, _folder: &Folder) -> Result<Vec<(i64, Vec<String>)>> {
+        Err(anyhow!("SMTP cannot fetch labels"))
+    }
+
+    async fn set_labels(&self, _account: &Account, _folder: &Folder, _uids: &[i64], _add: &[String], _remove: &[String]) -> Result<()> {
+        Err(anyhow!("SMTP cannot change labels"))
+    }
+
+    async fn fetch_part(&self, _account: &Account, _folder: &Folder, _uid: i64, _section: &str) -> Result<Vec<u8>> {
+        Err(anyhow!("SMTP cannot fetch messages"))
+    }
+
+    async fn fetch_message(&self, _account: &Account, _folder: &Folder, _uid: i64) -> Result<Vec<u8>> {
+        Err(anyhow!("SMTP cannot fetch messages"))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, vault::Vault};
+    use crate::db::AuthMethod;
+
+    fn compose(to: &str) -> ComposeEmail {
+        ComposeEmail {
+            account_id: 1,
+            to: vec![EmailAddress { name: None, address: to.to_string() }],
+            cc: None,
+            bcc: None,
+            subject: "Hi".to_string(),
+            body_text: Some("Hi".to_string()),
+            body_html: None,
+            attachments: vec![],
+            in_reply_to: None,
+            references: None,
+            sign: false,
+            encrypt: false,
+            smime: false,
+            age: false,
+            age_passphrase: None,
+        }
+    }
+
+    /// Sends `email` protected with the keys in `vault` and returns what
+    /// the server got after DATA.
+    async fn send_protected(conn: &mut sqlx::SqliteConnection, vault: &Vault, email: &ComposeEmail) -> String {
+        let (port, transcript) = client::tests::serve(vec![
+            ("EHLO", "250-fake.example.com\r\n250 AUTH PLAIN"),
+            ("AUTH PLAIN ", "235 2.7.0 Accepted"),
+            ("MAIL FROM:<test@example.com>", "250 OK"),
+            ("RCPT TO:", "250 OK"),
+            ("DATA", "354 Go ahead"),
+            (".", "250 Queued"),
+            ("QUIT", "221 Bye"),
+        ])
+        .await;
+        let account = client::tests::account(port, AuthMethod::Password);
+        let body = crypto::protect_outgoing(conn, vault, email, &account.email, None).await.unwrap();
+        SmtpHandler::new().send_message(&account, email, &body).await.unwrap();
+        let transcript = transcript.await.unwrap();
+        transcript[transcript.find("DATA\r\n").unwrap()..].to_string()
+    }
+
+    #[tokio::test]
+    async fn test_send_plain_while_locked() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let mut email = compose("bob@example.org");
+        let sent = send_protected(&mut conn, &Vault::new(), &email).await;
+        assert!(sent.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+
+        // age to a passphrase needs no keys either
+        email.encrypt = true;
+        email.age = true;
+        email.age_passphrase = Some("hunter2".to_string());
+        let sent = send_protected(&mut conn, &Vault::new(), &email).await;
+        assert!(sent.contains("-----BEGIN AGE ENCRYPTED FILE-----"));
+
+        email.age = false;
+        assert!(crypto::protect_outgoing(&mut conn, &Vault::new(), &email, "test@example.com", None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_send_smime() {
+        use crate::crypto::smime::tests::{ca, identity, pkcs12};
+
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let vault = Vault::new();
+        vault.unlock(&mut conn, "master").await.unwrap();
+        let ca = ca();
+        let own = identity("test@example.com", &ca);
+        crypto::certstore::import_identity(&mut conn, &vault, &pkcs12(&own, &ca.0, "secret"), "secret").await.unwrap();
+        crypto::certstore::collect(&mut conn, &[identity("bob@example.org", &ca).certificate]).await.unwrap();
+
+        let mut email = compose("bob@example.org");
+        email.smime = true;
+        email.sign = true;
+        let sent = send_protected(&mut conn, &vault, &email).await;
+        assert!(sent.contains("Content-Type: multipart/signed; micalg=sha-256; protocol=\"application/pkcs7-signature\""));
+
+        email.encrypt = true;
+        let sent = send_protected(&mut conn, &vault, &email).await;
+        assert!(sent.contains("Content-Type: application/pkcs7-mime; smime-type=enveloped-data"));
+    }
+
+    #[cfg(feature = "openpgp")]
+    #[tokio::test]
+    async fn test_send_openpgp() {
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let vault = Vault::new();
+        vault.unlock(&mut conn, "master").await.unwrap();
+        crypto::keyring::generate(&mut conn, &vault, "Test <test@example.com>").await.unwrap();
+        // Only Bob's public key, as if it came from his mail
+        let bob = crypto::keyring::generate(&mut conn, &vault, "Bob <bob@example.org>").await.unwrap();
+        let bob_public = crypto::keyring::export(&mut conn, &vault, std::slice::from_ref(&bob.fingerprint), None).await.unwrap();
+        crypto::keyring::delete(&mut conn, &bob.fingerprint).await.unwrap();
+        crypto::keyring::import(&mut conn, &vault, bob_public.as_bytes(), None).await.unwrap();
+
+        let mut email = compose("bob@example.org");
+        email.sign = true;
+        let sent = send_protected(&mut conn, &vault, &email).await;
+        assert!(sent.contains("Content-Type: multipart/signed; micalg=pgp-sha256; protocol=\"application/pgp-signature\""));
+
+        email.encrypt = true;
+        let sent = send_protected(&mut conn, &vault, &email).await;
+        assert!(sent.contains("Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\""));
+        assert!(!sent.contains("\r\n\r\nHi\r\n"));
+    }
+
+    #[cfg(feature = "openpgp")]
+    #[tokio::test]
+    async fn test_send_to_autocrypt_peer() {
+        use crate::crypto::autocrypt;
+        use crate::db::EncryptRecommendation;
+
+        let pool = crate::db::test_pool_with_account().await;
+        let mut conn = pool.acquire().await.unwrap();
+        let vault = Vault::new();
+        vault.unlock(&mut conn, "master").await.unwrap();
+        crypto::keyring::generate(&mut conn, &vault, "Test <test@example.com>").await.unwrap();
+
+        // Bob's key is only known from the Autocrypt header on his mail
+        let bob = crypto::keyring::generate(&mut conn, &vault, "Bob <bob@example.org>").await.unwrap();
+        let mut bob_account = client::tests::account(0, AuthMethod::Password);
+        bob_account.email = "bob@example.org".to_string();
+        bob_account.autocrypt_prefer_encrypt = true;
+        let header = autocrypt::outgoing_header(&mut conn, &vault, &bob_account).await.unwrap().unwrap();
+        crypto::keyring::delete(&mut conn, &bob.fingerprint).await.unwrap();
+        let message = format!("From: bob@example.org\r\nDate: {}\r\nAutocrypt: {}\r\n\r\nHi", chrono::Utc::now().to_rfc2822(), header);
+        autocrypt::process(&mut conn, 1, message.as_bytes()).await.unwrap();
+
+        let account = crate::email::load_account(&mut conn, &vault, 1).await.unwrap();
+        let recipients = ["bob@example.org".to_string()];
+        let recommendation = autocrypt::recommend(&mut conn, &account, &recipients, false).await.unwrap();
+        assert_eq!(recommendation.recommendation, EncryptRecommendation::Available);
+
+        let mut email = compose("bob@example.org");
+        email.encrypt = true;
+        let sent = send_protected(&mut conn, &vault, &email).await;
+        assert!(sent.contains("Content-Type: multipart/encrypted; protocol=\"application/pgp-encrypted\""));
+    }
+
+    #[test]
+    fn test_message() {
+        let account = crate::email::imap::test_server::account(0);
+        let email = ComposeEmail {
+            account_id: account.id,
+            to: vec![EmailAddress { name: Some("Doe, Jane".to_string()), address: "jane@example.com".to_string() }],
+            cc: Some(vec![]),
+            bcc: Some(vec![EmailAddress { name: None, address: "secret@example.com".to_string() }]),
+            subject: "Grüße".to_string(),
+            body_text: Some("Hi".to_string()),
+            body_html: None,
+            attachments: vec![],
+            in_reply_to: Some("<parent@example.com>".to_string()),
+            references: None,
+            sign: false,
+            encrypt: false,
+            smime: false,
+            age: false,
+            age_passphrase: None,
+        };
+        let handler = SmtpHandler::new().with_autocrypt("addr=a@b; keydata=\r\n a2V5".to_string());
+        let body = mime::text_entity(Some("Hi"), None);
+        let (message_id, message) = handler.message(&account, &email, &body);
+        let message = String::from_utf8(message).unwrap();
+        let (headers, body) = mime::split_message(message.as_bytes());
+        let headers = String::from_utf8_lossy(headers);
+
+        assert_eq!(mime::header_value(&headers, "To").as_deref(), Some("\"Doe, Jane\" <jane@example.com>"));
+        assert_eq!(mime::header_value(&headers, "Subject").as_deref(), Some("=?utf-8?B?R3LDvMOfZQ==?="));
+        assert_eq!(mime::header_value(&headers, "Message-ID"), Some(message_id));
+        assert_eq!(mime::header_value(&headers, "Autocrypt").as_deref(), Some("addr=a@b; keydata= a2V5"));
+        assert_eq!(mime::header_value(&headers, "Content-Type").as_deref(), Some("text/plain; charset=utf-8"));
+        assert_eq!(mime::header_value(&headers, "Cc"), None);
+        assert!(!message.contains("secret@example.com"));
+        assert_eq!(body, b"Hi");
+    }
+}
@@ -0,0 +1,201 @@
+//! TLS for the IMAP and SMTP clients.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context, Result};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use tokio_rustls::rustls::client::WebPkiServerVerifier;
+use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use tokio_rustls::rustls::{self, ClientConfig, DigitallySignedStruct, ProtocolVersion, RootCertStore, SignatureScheme};
+use tokio_rustls::TlsConnector;
+
+use crate::crypto::smime;
+use crate::db::Account;
+
+/// A connection to a mail server, in the clear or over TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// What a handshake settled on, and the certificates the server presented,
+/// its own first. The chain is kept even when it doesn't verify.
+#[derive(Debug, Clone, Default)]
+pub struct Handshake {
+    pub version: Option<String>,
+    pub cipher: Option<String>,
+    pub chain: Vec<Vec<u8>>,
+}
+
+/// What an account trusts for one of its servers besides the public CAs.
+#[derive(Debug, Clone, Default)]
+pub struct Trust {
+    /// The fingerprint the server's certificate must have. It stands in for
+    /// every other check, so self-signed and expired certificates pass.
+    pub pin: Option<String>,
+    /// PEM certificates of further CAs.
+    pub ca_bundle: Option<String>,
+}
+
+impl Trust {
+    pub fn for_host(account: &Account, host: &str) -> Self {
+        Trust {
+            pin: account.certificate_pins.iter().find(|pin| pin.host.eq_ignore_ascii_case(host)).map(|pin| pin.fingerprint.clone()),
+            ca_bundle: account.tls_ca_bundle.clone(),
+        }
+    }
+}
+
+/// Why a server's certificate was refused. The fingerprint is what to pin
+/// to trust it anyway.
+#[derive(Debug, thiserror::Error)]
+pub enum CertificateError {
+    #[error("The certificate of {host} isn't trusted ({reason}). Its SHA-256 fingerprint is {fingerprint}")]
+    Untrusted { host: String, fingerprint: String, reason: String },
+    #[error(
+        "The certificate of {host} has changed since it was pinned: expected {expected}, got {fingerprint}. \
+         Someone may be intercepting the connection"
+    )]
+    PinMismatch { host: String, expected: String, fingerprint: String },
+}
+
+/// The SHA-256 fingerprint of a DER certificate, as uppercase hex.
+pub fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// A fingerprint as the user may have copied it, with colons or spaces, in
+/// the form we store.
+pub fn normalize_fingerprint(text: &str) -> Result<String> {
+    let hex: String = text.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect::<String>().to_ascii_uppercase();
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Not a SHA-256 fingerprint: {}", text);
+    }
+    Ok(hex)
+}
+
+/// Verifies certificates against the pin if there is one, or else the
+/// public and the account's own CAs, noting the chain and any refusal on
+/// the way.
+#[derive(Debug)]
+struct Verifier {
+    webpki: Arc<WebPkiServerVerifier>,
+    pin: Option<String>,
+    chain: Mutex<Vec<Vec<u8>>>,
+    refusal: Mutex<Option<String>>,
+}
+
+impl ServerCertVerifier for Verifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        *self.chain.lock().unwrap() = std::iter::once(end_entity).chain(intermediates).map(|der| der.to_vec()).collect();
+        let result = match &self.pin {
+            Some(pin) if *pin == fingerprint(end_entity) => Ok(ServerCertVerified::assertion()),
+            Some(_) => Err(rustls::Error::InvalidCertificate(rustls::CertificateError::ApplicationVerificationFailure)),
+            None => self.webpki.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now),
+        };
+        if let Err(e) = &result {
+            *self.refusal.lock().unwrap() = Some(e.to_string());
+        }
+        result
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls12_signature(message, certificate, signature)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        certificate: &CertificateDer<'_>,
+        signature: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.webpki.verify_tls13_signature(message, certificate, signature)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.webpki.supported_verify_schemes()
+    }
+}
+
+fn verifier(trust: &Trust) -> Result<Arc<Verifier>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(bundle) = trust.ca_bundle.as_deref().filter(|bundle| !bundle.trim().is_empty()) {
+        for certificate in smime::read_certificates(bundle.as_bytes()).context("Failed to read the account's CA bundle")? {
+            roots.add(CertificateDer::from(certificate.data))?;
+        }
+    }
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let webpki = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider).build()?;
+    Ok(Arc::new(Verifier {
+        webpki,
+        pin: trust.pin.as_deref().map(normalize_fingerprint).transpose()?,
+        chain: Mutex::new(Vec::new()),
+        refusal: Mutex::new(None),
+    }))
+}
+
+fn config(verifier: Arc<Verifier>) -> Result<Arc<ClientConfig>> {
+    let config = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+/// Starts TLS with `host` over `stream`, at connect time or after STARTTLS.
+/// A refused certificate fails with a `CertificateError`.
+pub async fn connect(host: &str, stream: Box<dyn Stream>, trust: &Trust) -> Result<Box<dyn Stream>> {
+    handshake(host, stream, trust).await.0
+}
+
+/// Like `connect`, but also says what was negotiated, and which
+/// certificates the server sent whether or not they verified.
+pub async fn handshake(host: &str, stream: Box<dyn Stream>, trust: &Trust) -> (Result<Box<dyn Stream>>, Handshake) {
+    let verifier = match verifier(trust) {
+        Ok(verifier) => verifier,
+        Err(e) => return (Err(e), Handshake::default()),
+    };
+    let connect = async {
+        let server_name = ServerName::try_from(host.to_string())?;
+        Ok(TlsConnector::from(config(verifier.clone())?).connect(server_name, stream).await?)
+    };
+    let result: Result<_> = connect.await;
+    let mut handshake = Handshake { chain: verifier.chain.lock().unwrap().clone(), ..Handshake::default() };
+    let refusal = verifier.refusal.lock().unwrap().take();
+    let stream = match (result, refusal, handshake.chain.first()) {
+        (Ok(stream), _, _) => {
+            let (_, session) = stream.get_ref();
+            handshake.version = session.protocol_version().map(|version| match version {
+                ProtocolVersion::TLSv1_2 => "TLS 1.2".to_string(),
+                ProtocolVersion::TLSv1_3 => "TLS 1.3".to_string(),
+                other => format!("{:?}", other),
+            });
+            handshake.cipher = session.negotiated_cipher_suite().map(|suite| format!("{:?}", suite.suite()));
+            Ok(Box::new(stream) as Box<dyn Stream>)
+        }
+        (Err(_), Some(reason), Some(der)) => {
+            let (host, fingerprint) = (host.to_string(), fingerprint(der));
+            Err(match verifier.pin.clone() {
+                Some(expected) => CertificateError::PinMismatch { host, expected, fingerprint },
+                None => CertificateError::Untrusted { host, fingerprint, reason },
+            }
+            .into())
+        }
+        (Err(e), _, _) => Err(e),
+    };
+    (stream, handshake)
+}
@@ -0,0 +1,558 @@
+//! Heuristics that flag dangerous attachments and phishing tricks. None of
+//! these prove a message is malicious; they tell the user to look twice.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+
+use lol_html::{element, rewrite_str, text, RewriteStrSettings};
+
+use crate::db::{Attachment, Email, SecurityWarning, WarningKind};
+
+/// Extensions that run code when opened on some platform. Disk images are
+/// here because they are used to get executables past mail filters.
+const EXECUTABLE_EXTENSIONS: &[&str] = &[
+    "exe", "scr", "com", "pif", "bat", "cmd", "msi", "msp", "js", "jse", "vbs", "vbe", "wsf", "wsh",
+    "hta", "ps1", "psm1", "jar", "lnk", "cpl", "reg", "scf", "apk", "app", "command", "sh",
+    "iso", "img", "vhd", "vhdx",
+];
+
+/// Extensions a double-extension name pretends to have.
+const DOCUMENT_EXTENSIONS: &[&str] = &[
+    "pdf", "doc", "docx", "xls", "xlsx", "ppt", "pptx", "odt", "ods", "rtf", "txt", "csv",
+    "jpg", "jpeg", "png", "gif", "zip", "htm", "html",
+];
+
+const MACRO_EXTENSIONS: &[&str] = &[
+    "docm", "dotm", "xlsm", "xltm", "xlam", "pptm", "potm", "ppsm", "ppam", "sldm",
+];
+
+/// Brands commonly impersonated in display names, with the domains they
+/// mail from besides `<brand>.<tld>`.
+const BRANDS: &[(&str, &[&str])] = &[
+    ("paypal", &[]),
+    ("apple", &["icloud.com"]),
+    ("microsoft", &["outlook.com", "office.com", "live.com", "microsoftonline.com"]),
+    ("office365", &["microsoft.com", "office.com"]),
+    ("amazon", &["amazonaws.com"]),
+    ("google", &["youtube.com"]),
+    ("netflix", &[]),
+    ("docusign", &[]),
+    ("dropbox", &["dropboxmail.com"]),
+    ("dhl", &[]),
+    ("fedex", &[]),
+    ("ups", &[]),
+    ("usps", &[]),
+    ("chase", &[]),
+    ("wellsfargo", &["wf.com"]),
+];
+
+/// Public suffixes with two labels, so `example.co.uk` is not read as
+/// belonging to `co.uk`.
+const SECOND_LEVEL_SUFFIXES: &[&str] = &[
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "com.au", "net.au", "org.au", "co.nz", "co.jp",
+    "co.in", "co.za", "com.br", "com.cn", "com.mx", "com.tr",
+];
+
+/// The warnings for a message. `archives` lists the entries of attached
+/// archives that have been downloaded, by attachment id.
+pub fn analyze(email: &Email, attachments: &[Attachment], archives: &[(String, Vec<String>)]) -> Vec<SecurityWarning> {
+    let mut warnings = Vec::new();
+    for attachment in attachments {
+        warnings.extend(attachment_warning(&attachment.filename, &attachment.content_type));
+        if let Some((_, entries)) = archives.iter().find(|(id, _)| *id == attachment.id) {
+            warnings.extend(archive_warning(&attachment.filename, entries));
+        }
+    }
+    warnings.extend(display_name_warning(email.from_name.as_deref(), &email.from_address));
+    if let Some((_, domain)) = email.from_address.rsplit_once('@') {
+        warnings.extend(lookalike_warning(domain));
+    }
+    if let Some(html) = email.body_html.as_deref() {
+        for (href, text) in links(html) {
+            warnings.extend(link_warnings(&href, &text));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    warnings.retain(|warning| seen.insert((warning.kind, warning.subject.clone())));
+    warnings
+}
+
+fn extension(name: &str) -> Option<String> {
+    let (_, extension) = name.trim_end_matches(['.', ' ']).rsplit_once('.')?;
+    Some(extension.trim().to_ascii_lowercase())
+}
+
+fn is_executable(name: &str) -> bool {
+    extension(name).is_some_and(|extension| EXECUTABLE_EXTENSIONS.contains(&extension.as_str()))
+}
+
+pub fn attachment_warning(filename: &str, content_type: &str) -> Option<SecurityWarning> {
+    let warning = |kind, detail: String| Some(SecurityWarning { kind, subject: filename.to_string(), detail });
+
+    // U+202E turns "invoice\u{202E}fdp.exe" into "invoiceexe.pdf" on screen
+    if filename.chars().any(|c| matches!(c, '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}')) {
+        return warning(WarningKind::DoubleExtension, "The file name hides its real extension with right-to-left characters".to_string());
+    }
+    if is_executable(filename) {
+        let stem = &filename[..filename.trim_end_matches(['.', ' ']).rfind('.').unwrap_or(0)];
+        let disguise = extension(stem).filter(|extension| DOCUMENT_EXTENSIONS.contains(&extension.as_str()));
+        return match disguise {
+            Some(disguise) => warning(
+                WarningKind::DoubleExtension,
+                format!("Looks like a .{} file but is a .{} program", disguise, extension(filename).unwrap_or_default()),
+            ),
+            None => warning(WarningKind::ExecutableAttachment, "Opening this file runs a program".to_string()),
+        };
+    }
+    let macro_enabled = extension(filename).is_some_and(|extension| MACRO_EXTENSIONS.contains(&extension.as_str()))
+        || content_type.to_ascii_lowercase().contains("macroenabled");
+    if macro_enabled {
+        return warning(WarningKind::MacroDocument, "This document can run macros".to_string());
+    }
+    None
+}
+
+pub fn archive_warning(filename: &str, entries: &[String]) -> Option<SecurityWarning> {
+    let executables: Vec<&str> = entries
+        .iter()
+        .map(|entry| entry.rsplit(['/', '\\']).next().unwrap_or(entry))
+        .filter(|name| is_executable(name))
+        .collect();
+    (!executables.is_empty()).then(|| SecurityWarning {
+        kind: WarningKind::ArchiveExecutable,
+        subject: filename.to_string(),
+        detail: format!("The archive contains programs: {}", executables.join(", ")),
+    })
+}
+
+pub fn is_archive(filename: &str, content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    extension(filename).as_deref() == Some("zip") || content_type.contains("/zip") || content_type.contains("x-zip")
+}
+
+/// File names in a zip archive, read from its central directory. `None`
+/// when the data is not a zip archive.
+pub fn archive_entries(data: &[u8]) -> Option<Vec<String>> {
+    let u16_at = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as usize);
+    let u32_at = |at: usize| data.get(at..at + 4).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as usize);
+
+    // The end of central directory record is in the last 64 KiB + 22 bytes
+    let search_from = data.len().saturating_sub(22 + 0xFFFF);
+    let end = (search_from..=data.len().checked_sub(22)?).rev().find(|&at| u32_at(at) == Some(0x0605_4b50))?;
+    let count = u16_at(end + 10)?;
+    let mut at = u32_at(end + 16)?;
+
+    let mut entries = Vec::with_capacity(count.min(1024));
+    for _ in 0..count {
+        if u32_at(at)? != 0x0201_4b50 {
+            return None;
+        }
+        let name_len = u16_at(at + 28)?;
+        let extra_len = u16_at(at + 30)?;
+        let comment_len = u16_at(at + 32)?;
+        let name = data.get(at + 46..at + 46 + name_len)?;
+        entries.push(String::from_utf8_lossy(name).into_owned());
+        at += 46 + name_len + extra_len + comment_len;
+    }
+    Some(entries)
+}
+
+/// A display name that carries another address, or names a brand the
+/// sending domain does not belong to.
+pub fn display_name_warning(name: Option<&str>, address: &str) -> Option<SecurityWarning> {
+    let name = name?.trim();
+    let domain = address.rsplit_once('@')?.1.to_ascii_lowercase();
+    let warning = |detail: String| Some(SecurityWarning {
+        kind: WarningKind::DisplayNameSpoof,
+        subject: name.to_string(),
+        detail,
+    });
+
+    let shown = name
+        .split(|c: char| c.is_whitespace() || matches!(c, '<' | '>' | '"' | '\'' | '(' | ')' | ','))
+        .find(|word| word.contains('@') && word.contains('.'));
+    if let Some(shown) = shown {
+        if !shown.eq_ignore_ascii_case(address) {
+            return warning(format!("The name shows {} but the mail is from {}", shown, address));
+        }
+    }
+
+    let words: Vec<String> = name
+        .split(|c: char| !c.is_alphanumeric())
+        .map(|word| word.to_lowercase())
+        .collect();
+    let registrable = registrable_domain(&domain);
+    for (brand, domains) in BRANDS {
+        if !words.iter().any(|word| word == brand) {
+            continue;
+        }
+        let owned = registrable.split('.').next() == Some(*brand) || domains.contains(&registrable);
+        if !owned {
+            return warning(format!("The name claims to be {} but the mail is from {}", name, domain));
+        }
+    }
+    None
+}
+
+/// The part of a host name its owner registered, e.g. `example.co.uk` for
+/// `mail.example.co.uk`.
+pub fn registrable_domain(host: &str) -> &str {
+    let host = host.trim_end_matches('.');
+    let labels = if SECOND_LEVEL_SUFFIXES.iter().any(|suffix| host.ends_with(&format!(".{}", suffix))) { 3 } else { 2 };
+    match host.rmatch_indices('.').nth(labels - 1) {
+        Some((at, _)) => &host[at + 1..],
+        None => host,
+    }
+}
+
+/// A domain that uses non-ASCII letters to pass for an ASCII one, such as
+/// `xn--pypal-4ve.com` (with a Cyrillic "а").
+pub fn lookalike_warning(host: &str) -> Option<SecurityWarning> {
+    let host = host.trim().trim_end_matches('.').to_lowercase();
+    let unicode = host
+        .split('.')
+        .map(|label| match label.strip_prefix("xn--") {
+            Some(encoded) => decode_punycode(encoded),
+            None => Some(label.to_string()),
+        })
+        .collect::<Option<Vec<String>>>()?
+        .join(".");
+    if unicode.is_ascii() {
+        return None;
+    }
+
+    let skeleton: String = unicode.chars().map(|c| confusable(c).unwrap_or(c)).collect();
+    let detail = if skeleton.is_ascii() {
+        format!("{} imitates {}", unicode, skeleton)
+    } else if unicode.split('.').any(mixes_scripts) {
+        format!("{} mixes letters from different alphabets", unicode)
+    } else {
+        return None;
+    };
+    Some(SecurityWarning { kind: WarningKind::LookalikeDomain, subject: host, detail })
+}
+
+/// An ASCII letter that `c` is easily mistaken for.
+fn confusable(c: char) -> Option<char> {
+    Some(match c {
+        // Cyrillic
+        'а' => 'a', 'ԁ' => 'd', 'е' => 'e', 'һ' => 'h', 'і' => 'i', 'ј' => 'j', 'ӏ' => 'l', 'о' => 'o',
+        'р' => 'p', 'ԛ' => 'q', 'ѕ' => 's', 'с' => 'c', 'у' => 'y', 'ԝ' => 'w', 'х' => 'x',
+        // Greek
+        'α' => 'a', 'ι' => 'i', 'κ' => 'k', 'ν' => 'v', 'ο' => 'o', 'ρ' => 'p', 'υ' => 'u', 'χ' => 'x',
+        // Latin lookalikes
+        'ı' => 'i', 'ɑ' => 'a', 'ɡ' => 'g', 'ℓ' => 'l', 'ǀ' => 'l',
+        // Fullwidth
+        'ａ'..='ｚ' => char::from_u32(c as u32 - 'ａ' as u32 + 'a' as u32)?,
+        _ => return None,
+    })
+}
+
+fn mixes_scripts(label: &str) -> bool {
+    let latin = label.chars().any(|c| c.is_ascii_alphabetic());
+    let cyrillic = label.chars().any(|c| ('\u{0400}'..='\u{052F}').contains(&c));
+    let greek = label.chars().any(|c| ('\u{0370}'..='\u{03FF}').contains(&c));
+    [latin, cyrillic, greek].iter().filter(|&&script| script).count() > 1
+}
+
+/// Punycode (RFC 3492), the encoding behind `xn--` labels.
+fn decode_punycode(input: &str) -> Option<String> {
+    const BASE: u32 = 36;
+    const TMIN: u32 = 1;
+    const TMAX: u32 = 26;
+
+    let (basic, extended) = match input.rfind('-') {
+        Some(at) => (&input[..at], &input[at + 1..]),
+        None => ("", input),
+    };
+    if !basic.is_ascii() {
+        return None;
+    }
+    let mut output: Vec<char> = basic.chars().collect();
+    let (mut n, mut i, mut bias) = (128u32, 0u32, 72u32);
+    let mut digits = extended.bytes().peekable();
+    while digits.peek().is_some() {
+        let old_i = i;
+        let mut weight = 1u32;
+        let mut k = BASE;
+        loop {
+            let digit = match digits.next()? {
+                byte @ b'a'..=b'z' => byte - b'a',
+                byte @ b'A'..=b'Z' => byte - b'A',
+                byte @ b'0'..=b'9' => byte - b'0' + 26,
+                _ => return None,
+            } as u32;
+            i = i.checked_add(digit.checked_mul(weight)?)?;
+            let threshold = if k <= bias { TMIN } else if k >= bias + TMAX { TMAX } else { k - bias };
+            if digit < threshold {
+                break;
+            }
+            weight = weight.checked_mul(BASE - threshold)?;
+            k += BASE;
+        }
+
+        let len = output.len() as u32 + 1;
+        // Bias adaptation, section 6.1
+        let mut delta = if old_i == 0 { (i - old_i) / 700 } else { (i - old_i) / 2 };
+        delta += delta / len;
+        let mut k = 0;
+        while delta > ((BASE - TMIN) * TMAX) / 2 {
+            delta /= BASE - TMIN;
+            k += BASE;
+        }
+        bias = k + ((BASE - TMIN + 1) * delta) / (delta + 38);
+
+        n = n.checked_add(i / len)?;
+        i %= len;
+        output.insert(i as usize, char::from_u32(n)?);
+        i += 1;
+    }
+    Some(output.into_iter().collect())
+}
+
+/// Each link in `html` with its visible text.
+fn links(html: &str) -> Vec<(String, String)> {
+    let links = RefCell::new(Vec::<(String, String)>::new());
+    let result = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("a[href]", |el| {
+                    links.borrow_mut().push((el.get_attribute("href").unwrap_or_default(), String::new()));
+                    Ok(())
+                }),
+                text!("a[href]", |chunk| {
+                    if let Some((_, text)) = links.borrow_mut().last_mut() {
+                        text.push_str(chunk.as_str());
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    );
+    if result.is_err() {
+        return Vec::new();
+    }
+    links.into_inner()
+}
+
+/// The host a link's text shows, if the text is written as an address.
+fn shown_host(text: &str) -> Option<String> {
+    let text = text.trim().to_lowercase();
+    let rest = text.strip_prefix("https://").or_else(|| text.strip_prefix("http://")).unwrap_or(&text);
+    let host = rest.split(['/', '?', '#', ':']).next()?;
+    let tld = host.rsplit_once('.')?.1;
+    let valid = !host.is_empty()
+        && tld.len() >= 2
+        && tld.chars().all(|c| c.is_alphabetic())
+        && host.chars().all(|c| c.is_alphanumeric() || matches!(c, '.' | '-'));
+    valid.then(|| host.to_string())
+}
+
+fn link_warnings(href: &str, text: &str) -> Vec<SecurityWarning> {
+    let Ok(url) = url::Url::parse(href.trim()) else {
+        return Vec::new();
+    };
+    let Some(host) = url.host_str().filter(|_| matches!(url.scheme(), "http" | "https")) else {
+        return Vec::new();
+    };
+    let host = host.to_ascii_lowercase();
+
+    let mut warnings: Vec<SecurityWarning> = lookalike_warning(&host).into_iter().collect();
+    if let Some(shown) = shown_host(text) {
+        // Compare the shown name in the form the URL parser gives the host
+        let shown_ascii = url::Url::parse(&format!("http://{}/", shown))
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or(shown.clone());
+        if registrable_domain(&shown_ascii) != registrable_domain(&host) {
+            warnings.push(SecurityWarning {
+                kind: WarningKind::LinkMismatch,
+                subject: href.trim().to_string(),
+                detail: format!("The link shows {} but opens {}", shown, host),
+            });
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(warnings: &[SecurityWarning]) -> Vec<WarningKind> {
+        warnings.iter().map(|warning| warning.kind).collect()
+    }
+
+    #[test]
+    fn test_attachment_warnings() {
+        let kind = |name: &str, content_type: &str| attachment_warning(name, content_type).map(|warning| warning.kind);
+        assert_eq!(kind("invoice.pdf.exe", "application/octet-stream"), Some(WarningKind::DoubleExtension));
+        assert_eq!(kind("invoice.PDF   .Exe ", "application/octet-stream"), Some(WarningKind::DoubleExtension));
+        assert_eq!(kind("invoice\u{202E}fdp.exe", "application/pdf"), Some(WarningKind::DoubleExtension));
+        assert_eq!(kind("setup.exe", "application/octet-stream"), Some(WarningKind::ExecutableAttachment));
+        assert_eq!(kind("v1.2.scr", "application/octet-stream"), Some(WarningKind::ExecutableAttachment));
+        assert_eq!(kind("budget.xlsm", "application/vnd.ms-excel"), Some(WarningKind::MacroDocument));
+        assert_eq!(kind("budget.bin", "application/vnd.ms-excel.sheet.macroEnabled.12"), Some(WarningKind::MacroDocument));
+        assert_eq!(kind("report.pdf", "application/pdf"), None);
+        assert_eq!(kind("README", "text/plain"), None);
+    }
+
+    #[test]
+    fn test_archive_entries() {
+        // A zip with two empty stored files, "docs/readme.txt" and "docs/invoice.pdf.exe"
+        let mut zip = Vec::new();
+        let mut central = Vec::new();
+        for name in ["docs/readme.txt", "docs/invoice.pdf.exe"] {
+            let offset = zip.len() as u32;
+            zip.extend_from_slice(&0x0403_4b50u32.to_le_bytes());
+            zip.extend_from_slice(&[0; 22]);
+            zip.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            zip.extend_from_slice(&0u16.to_le_bytes());
+            zip.extend_from_slice(name.as_bytes());
+
+            central.extend_from_slice(&0x0201_4b50u32.to_le_bytes());
+            central.extend_from_slice(&[0; 24]);
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let central_offset = zip.len() as u32;
+        zip.extend_from_slice(&central);
+        zip.extend_from_slice(&0x0605_4b50u32.to_le_bytes());
+        zip.extend_from_slice(&[0; 4]);
+        zip.extend_from_slice(&2u16.to_le_bytes());
+        zip.extend_from_slice(&2u16.to_le_bytes());
+        zip.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        zip.extend_from_slice(&central_offset.to_le_bytes());
+        zip.extend_from_slice(&0u16.to_le_bytes());
+
+        let entries = archive_entries(&zip).unwrap();
+        assert_eq!(entries, vec!["docs/readme.txt", "docs/invoice.pdf.exe"]);
+        let warning = archive_warning("files.zip", &entries).unwrap();
+        assert_eq!(warning.kind, WarningKind::ArchiveExecutable);
+        assert!(warning.detail.contains("invoice.pdf.exe"));
+        assert!(archive_warning("files.zip", &entries[..1]).is_none());
+
+        assert_eq!(archive_entries(b"not a zip"), None);
+        assert_eq!(archive_entries(&zip[..zip.len() - 30]), None);
+    }
+
+    #[test]
+    fn test_display_name_spoofing() {
+        let kind = |name: &str, address: &str| display_name_warning(Some(name), address).map(|warning| warning.kind);
+        assert_eq!(kind("PayPal", "x@evil.tld"), Some(WarningKind::DisplayNameSpoof));
+        assert_eq!(kind("PayPal Support", "service@paypal.com"), None);
+        assert_eq!(kind("PayPal", "service@mail.paypal.de"), None);
+        assert_eq!(kind("Apple", "no_reply@email.apple.com"), None);
+        assert_eq!(kind("Apple", "noreply@icloud.com"), None);
+        assert_eq!(kind("Microsoft account team", "security@microsoft-verify.net"), Some(WarningKind::DisplayNameSpoof));
+        assert_eq!(kind("ceo@company.com", "ceo@c0mpany.com"), Some(WarningKind::DisplayNameSpoof));
+        assert_eq!(kind("\"ceo@company.com\"", "CEO@company.com"), None);
+        // Whole words only
+        assert_eq!(kind("Support Groups", "team@example.org"), None);
+        assert_eq!(display_name_warning(None, "x@evil.tld"), None);
+    }
+
+    #[test]
+    fn test_lookalike_domains() {
+        assert_eq!(decode_punycode("pypal-4ve").as_deref(), Some("pаypal"));
+        assert_eq!(decode_punycode("bcher-kva").as_deref(), Some("bücher"));
+
+        let warning = lookalike_warning("xn--pypal-4ve.com").unwrap();
+        assert_eq!(warning.kind, WarningKind::LookalikeDomain);
+        assert_eq!(warning.detail, "pаypal.com imitates paypal.com");
+        // All-Cyrillic "аррӏе"
+        assert!(lookalike_warning("xn--80ak6aa92e.com").unwrap().detail.ends_with("imitates apple.com"));
+        assert!(lookalike_warning("pаypal.com").is_some());
+        // Real internationalized names are fine
+        assert_eq!(lookalike_warning("xn--bcher-kva.de"), None);
+        assert_eq!(lookalike_warning("пример.рф"), None);
+        assert_eq!(lookalike_warning("example.com"), None);
+
+        assert_eq!(registrable_domain("mail.example.co.uk"), "example.co.uk");
+        assert_eq!(registrable_domain("a.b.example.com"), "example.com");
+        assert_eq!(registrable_domain("localhost"), "localhost");
+    }
+
+    #[test]
+    fn test_analyze() {
+        let email = Email {
+            id: 1,
+            account_id: 1,
+            folder_id: 1,
+            message_id: "msg-1@example.com".to_string(),
+            thread_id: None,
+            subject: "Your account".to_string(),
+            from_address: "security@xn--pypal-4ve.com".to_string(),
+            from_name: Some("PayPal".to_string()),
+            to_addresses: "[]".to_string(),
+            cc_addresses: None,
+            bcc_addresses: None,
+            body_text: None,
+            body_html: Some(
+                "<a href=\"https://evil.tld/login\">https://www.paypal.com/login</a> \
+                 <a href=\"https://www.paypal.com/help\">www.paypal.com</a> \
+                 <a href=\"https://evil.tld/x\">Click <b>here</b></a> \
+                 <a href=\"https://evil.tld/login\">paypal.com</a>"
+                    .to_string(),
+            ),
+            attachments: None,
+            size_bytes: 100,
+            internal_date: chrono::Utc::now(),
+            received_date: chrono::Utc::now(),
+            is_read: false,
+            is_flagged: false,
+            is_answered: false,
+            is_draft: false,
+            is_deleted: false,
+            uid: Some(1),
+            mod_seq: None,
+            trackers_blocked: None,
+            auth_verdict: None,
+            auth_results: None,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+            keywords: vec![],
+            labels: vec![],
+            warnings: vec![],
+        };
+        let attachments = vec![
+            Attachment {
+                id: "2".to_string(),
+                filename: "statement.zip".to_string(),
+                content_type: "application/zip".to_string(),
+                size_bytes: 100,
+                content_id: None,
+                is_inline: false,
+                data: None,
+            },
+            Attachment {
+                id: "3".to_string(),
+                filename: "statement.pdf.exe".to_string(),
+                content_type: "application/octet-stream".to_string(),
+                size_bytes: 100,
+                content_id: None,
+                is_inline: false,
+                data: None,
+            },
+        ];
+        let archives = vec![("2".to_string(), vec!["statement.scr".to_string()])];
+
+        let warnings = analyze(&email, &attachments, &archives);
+        assert_eq!(
+            kinds(&warnings),
+            vec![
+                WarningKind::ArchiveExecutable,
+                WarningKind::DoubleExtension,
+                WarningKind::DisplayNameSpoof,
+                WarningKind::LookalikeDomain,
+                WarningKind::LinkMismatch,
+            ]
+        );
+        assert_eq!(warnings[4].detail, "The link shows www.paypal.com but opens evil.tld");
+    }
+}
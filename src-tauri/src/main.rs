@@ -1,11 +1,17 @@
 // Prevents additional console window on Windows in release, DO NOT REMOVE!!
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
+use std::sync::Arc;
+use tauri::Manager;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
+mod attachments;
 mod commands;
+mod crypto;
 mod db;
 mod email;
+mod offline;
+mod render;
 
 #[tokio::main]
 async fn main() {
@@ -22,21 +28,128 @@ async fn main() {
     let db_pool = db::init_database("sqlite:slopmail.db").await
         .expect("Failed to initialize database");
 
+    // Unlocked with the master password; holds the key to sealed secrets
+    let vault = Arc::new(crypto::vault::Vault::new());
+
+    // Signed-in IMAP sessions, kept open between commands
+    let sessions = Arc::new(email::SessionPool::new());
+
+    // Replay actions recorded while offline in the background
+    let offline_queue = Arc::new(offline::OfflineQueue::new(db_pool.clone(), vault.clone(), sessions.clone()));
+    tokio::spawn(offline_queue.clone().run());
+
+    // Inline images and attachments, from the local store or fetched on first use
+    let (content_pool, content_vault, content_sessions) = (db_pool.clone(), vault.clone(), sessions.clone());
+
     tauri::Builder::default()
         .plugin(tauri_plugin_shell::init())
+        .register_asynchronous_uri_scheme_protocol(render::content::SCHEME, move |_ctx, request, responder| {
+            let (pool, vault, sessions) = (content_pool.clone(), content_vault.clone(), content_sessions.clone());
+            let uri = request.uri().to_string();
+            tauri::async_runtime::spawn(async move {
+                let content = render::content::respond(&pool, &vault, &sessions, &uri).await;
+                let response = tauri::http::Response::builder()
+                    .status(content.status)
+                    .header(tauri::http::header::CONTENT_TYPE, content.content_type)
+                    .header(tauri::http::header::CONTENT_SECURITY_POLICY, render::content::CONTENT_SECURITY_POLICY)
+                    .header(tauri::http::header::X_CONTENT_TYPE_OPTIONS, "nosniff")
+                    .body(content.body)
+                    .expect("valid response parts");
+                responder.respond(response);
+            });
+        })
         .manage(db_pool)
+        .manage(offline_queue)
+        .manage(vault)
+        .manage(sessions)
         .invoke_handler(tauri::generate_handler![
             commands::greet,
             commands::add_account,
             commands::get_accounts,
+            commands::delete_account,
             commands::test_account_connection,
+            commands::discover_account_settings,
+            commands::get_certificate_pins,
+            commands::pin_certificate,
+            commands::unpin_certificate,
+            commands::set_account_ca_bundle,
+            commands::set_account_proxy,
+            commands::get_global_proxy,
+            commands::set_global_proxy,
+            commands::authorize_oauth_account,
             commands::sync_folders,
             commands::get_folders,
+            commands::set_folder_type,
+            commands::create_folder,
+            commands::rename_folder,
+            commands::delete_folder,
+            commands::subscribe_folder,
             commands::fetch_emails,
             commands::get_emails,
+            commands::get_label_emails,
+            commands::render_email,
+            commands::check_email_auth,
+            commands::open_secure_email,
+            commands::unlock_vault,
+            commands::lock_vault,
+            commands::is_vault_unlocked,
+            commands::get_pgp_keys,
+            commands::import_pgp_keys,
+            commands::generate_pgp_key,
+            commands::set_pgp_key_trust,
+            commands::revoke_pgp_key,
+            commands::export_pgp_keys,
+            commands::delete_pgp_key,
+            commands::lookup_pgp_keys,
+            commands::get_smime_certificates,
+            commands::import_smime_identity,
+            commands::import_smime_certificates,
+            commands::set_smime_certificate_trusted,
+            commands::delete_smime_certificate,
+            commands::lookup_smime_certificates,
+            commands::get_age_keys,
+            commands::generate_age_key,
+            commands::import_age_identity,
+            commands::add_age_recipient,
+            commands::delete_age_key,
+            commands::lookup_age_keys,
+            commands::encrypt_file_with_age,
+            commands::get_age_attachment,
+            commands::get_encrypt_recommendation,
+            commands::set_autocrypt_prefer_encrypt,
+            commands::get_attachment,
+            commands::save_attachment,
+            commands::save_all_attachments,
+            commands::open_attachment,
+            commands::get_remote_content_rules,
+            commands::allow_remote_content,
+            commands::remove_remote_content_rule,
             commands::send_email,
-            commands::mark_email_read
+            commands::mark_email_read,
+            commands::set_email_flags,
+            commands::sync_email_flags,
+            commands::move_emails,
+            commands::copy_emails,
+            commands::archive_emails,
+            commands::delete_email,
+            commands::get_pending_ops,
+            commands::replay_pending_ops,
+            commands::get_labels,
+            commands::create_label,
+            commands::update_label,
+            commands::delete_label,
+            commands::set_email_label,
+            commands::sync_labels
         ])
-        .run(tauri::generate_context!())
-        .expect("error while running tauri application");
+        .build(tauri::generate_context!())
+        .expect("error while running tauri application")
+        .run(|app, event| {
+            if let tauri::RunEvent::Exit = event {
+                if let Ok(cache) = app.path().app_cache_dir() {
+                    attachments::clean_open_dir(&cache.join(attachments::OPEN_DIR));
+                }
+                let sessions = app.state::<Arc<email::SessionPool>>();
+                tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(sessions.close_all()));
+            }
+        });
 }
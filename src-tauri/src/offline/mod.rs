@@ -0,0 +1,3 @@
+pub mod queue;
+
+pub use queue::{LocalAction, OfflineQueue, ReplayConflict, ReplayReport};
@@ -0,0 +1,906 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{SqliteConnection, SqlitePool};
+use tokio::sync::{Mutex, Notify};
+
+use crate::crypto::vault::Vault;
+use crate::db::{self, Account, DbPool, Email, Folder, PendingOp, PendingOpKind};
+use crate::email::{self, EmailProtocol, Flag, ProtocolError, SessionPool};
+
+/// How long the queue waits before retrying when nothing wakes it earlier.
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A user action on a single message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LocalAction {
+    SetFlags { add: Vec<Flag>, remove: Vec<Flag> },
+    Move { target_folder_id: i64 },
+    Delete,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplayReport {
+    pub applied: usize,
+    pub conflicts: Vec<ReplayConflict>,
+    pub remaining: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplayConflict {
+    pub email_id: i64,
+    pub reason: String,
+}
+
+/// Applies user actions to the local store straight away and journals them in
+/// `pending_ops` until the server has caught up.
+pub struct OfflineQueue {
+    pool: DbPool,
+    vault: Arc<Vault>,
+    sessions: Arc<SessionPool>,
+    wake: Notify,
+    replay_lock: Mutex<()>,
+}
+
+impl OfflineQueue {
+    pub fn new(pool: DbPool, vault: Arc<Vault>, sessions: Arc<SessionPool>) -> Self {
+        Self {
+            pool,
+            vault,
+            sessions,
+            wake: Notify::new(),
+            replay_lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn record(&self, email_id: i64, action: LocalAction) -> Result<Email> {
+        let email = record_action(&self.pool, email_id, action).await?;
+        self.wake.notify_one();
+        Ok(email)
+    }
+
+    pub async fn pending(&self, account_id: i64) -> Result<Vec<PendingOp>> {
+        let ops = sqlx::query_as("SELECT * FROM pending_ops WHERE account_id = ? ORDER BY id")
+            .bind(account_id)
+            .fetch_all(self.pool.as_ref())
+            .await?;
+        Ok(ops)
+    }
+
+    /// Fetches the server's flags for a folder and merges them into the local
+    /// store. Returns how many messages changed.
+    pub async fn sync_flags(&self, folder_id: i64) -> Result<usize> {
+        let folder = load_folder(&self.pool, folder_id).await?;
+        let mut conn = self.pool.acquire().await?;
+        let account = email::load_account(&mut conn, &self.vault, folder.account_id).await?;
+        drop(conn);
+        let handler = email::handler_for(&account, &self.sessions).ok_or_else(|| anyhow!("Unsupported protocol {}", account.protocol))?;
+
+        let server = handler.fetch_flags(&account, &folder).await?;
+        merge_server_flags(&self.pool, folder_id, &server).await
+    }
+
+    pub async fn replay(&self) -> Result<ReplayReport> {
+        let _guard = self.replay_lock.lock().await;
+        let mut report = ReplayReport::default();
+
+        let account_ids: Vec<i64> = sqlx::query_scalar("SELECT DISTINCT account_id FROM pending_ops")
+            .fetch_all(self.pool.as_ref())
+            .await?;
+        // One account failing, such as an OAuth2 token that can't be refreshed
+        // while offline, mustn't hold up the others
+        for account_id in account_ids {
+            let mut conn = self.pool.acquire().await?;
+            let account = match email::load_account(&mut conn, &self.vault, account_id).await {
+                Ok(account) => account,
+                Err(e) => {
+                    tracing::warn!("Not replaying operations of account {}: {:#}", account_id, e);
+                    continue;
+                }
+            };
+            drop(conn);
+            let Some(handler) = email::handler_for(&account, &self.sessions) else {
+                continue;
+            };
+            if let Err(e) = replay_account(&self.pool, &account, handler.as_ref(), &mut report).await {
+                tracing::warn!("Replaying operations of account {} failed: {:#}", account_id, e);
+            }
+        }
+
+        report.remaining = sqlx::query_scalar("SELECT COUNT(*) FROM pending_ops")
+            .fetch_one(self.pool.as_ref())
+            .await?;
+        Ok(report)
+    }
+
+    /// Replays the journal whenever an action is recorded, and periodically so
+    /// that ops queued while offline go out once connectivity returns.
+    pub async fn run(self: Arc<Self>) {
+        loop {
+            match self.replay().await {
+                Ok(report) if report.applied > 0 || !report.conflicts.is_empty() => {
+                    tracing::info!(
+                        "Replayed {} pending operations ({} conflicts, {} remaining)",
+                        report.applied,
+                        report.conflicts.len(),
+                        report.remaining
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Replaying pending operations failed: {}", e),
+            }
+
+            tokio::select! {
+                _ = self.wake.notified() => {}
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+            }
+        }
+    }
+}
+
+async fn record_action(pool: &SqlitePool, email_id: i64, action: LocalAction) -> Result<Email> {
+    let mut tx = pool.begin().await?;
+    let email = db::load_email(&mut tx, email_id)
+        .await?
+        .ok_or_else(|| anyhow!("Email {} not found", email_id))?;
+
+    // Only journal actions that change something, so the first op for a
+    // message also tells coalesce() what the server's state was.
+    let mut ops: Vec<(PendingOpKind, Option<Flag>, Option<i64>)> = Vec::new();
+    match action {
+        LocalAction::SetFlags { add, mut remove } => {
+            remove.extend(add.iter().filter_map(Flag::opposite));
+            let changes = add
+                .iter()
+                .map(|flag| (flag, true))
+                .chain(remove.iter().filter(|flag| !add.contains(flag)).map(|flag| (flag, false)));
+            for (flag, set) in changes {
+                if has_flag(&email, flag) == set {
+                    continue;
+                }
+                set_local_flag(&mut tx, email_id, flag, set).await?;
+                let kind = if set { PendingOpKind::AddFlag } else { PendingOpKind::RemoveFlag };
+                ops.push((kind, Some(flag.clone()), None));
+            }
+        }
+        LocalAction::Move { target_folder_id } => {
+            if email.folder_id != target_folder_id {
+                let target: Option<i64> = sqlx::query_scalar("SELECT id FROM folders WHERE id = ? AND account_id = ?")
+                    .bind(target_folder_id)
+                    .bind(email.account_id)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+                if target.is_none() {
+                    return Err(anyhow!("Folder {} does not belong to account {}", target_folder_id, email.account_id));
+                }
+                // A hidden original left behind by an earlier move gives way
+                sqlx::query(
+                    "DELETE FROM emails WHERE folder_id = ? AND message_id = ? AND is_deleted = 1 \
+                     AND id NOT IN (SELECT email_id FROM pending_ops)",
+                )
+                .bind(target_folder_id)
+                .bind(&email.message_id)
+                .execute(&mut *tx)
+                .await?;
+                // The UID is only valid in the source folder; the op keeps it.
+                sqlx::query("UPDATE emails SET folder_id = ?, uid = NULL, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+                    .bind(target_folder_id)
+                    .bind(email_id)
+                    .execute(&mut *tx)
+                    .await?;
+                ops.push((PendingOpKind::Move, None, Some(target_folder_id)));
+            }
+        }
+        LocalAction::Delete => {
+            if !email.is_deleted {
+                sqlx::query("UPDATE emails SET is_deleted = 1, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+                    .bind(email_id)
+                    .execute(&mut *tx)
+                    .await?;
+                ops.push((PendingOpKind::Delete, None, None));
+            }
+        }
+    }
+    if ops.is_empty() {
+        return Ok(email);
+    }
+
+    for (kind, flag, target_folder_id) in ops {
+        sqlx::query(
+            "INSERT INTO pending_ops (account_id, email_id, kind, flag, folder_id, uid, target_folder_id) VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(email.account_id)
+        .bind(email_id)
+        .bind(kind)
+        .bind(flag.map(String::from))
+        .bind(email.folder_id)
+        .bind(email.uid)
+        .bind(target_folder_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+
+    let updated = db::load_email(&mut tx, email_id)
+        .await?
+        .ok_or_else(|| anyhow!("Email {} not found", email_id))?;
+    tx.commit().await?;
+    Ok(updated)
+}
+
+fn has_flag(email: &Email, flag: &Flag) -> bool {
+    match flag {
+        Flag::Seen => email.is_read,
+        Flag::Answered => email.is_answered,
+        Flag::Flagged => email.is_flagged,
+        Flag::Draft => email.is_draft,
+        Flag::Keyword(keyword) => email.keywords.iter().any(|k| k.eq_ignore_ascii_case(keyword)),
+    }
+}
+
+async fn set_local_flag(conn: &mut SqliteConnection, email_id: i64, flag: &Flag, set: bool) -> Result<()> {
+    match flag.column() {
+        Some(column) => {
+            sqlx::query(&format!("UPDATE emails SET {} = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ?", column))
+                .bind(set)
+                .bind(email_id)
+                .execute(&mut *conn)
+                .await?;
+        }
+        None if set => {
+            sqlx::query("INSERT OR IGNORE INTO email_keywords (email_id, keyword) VALUES (?, ?)")
+                .bind(email_id)
+                .bind(flag.as_imap())
+                .execute(&mut *conn)
+                .await?;
+            db::labels::ensure_keyword_label(conn, email_id, flag.as_imap()).await?;
+        }
+        None => {
+            sqlx::query("DELETE FROM email_keywords WHERE email_id = ? AND keyword = ? COLLATE NOCASE")
+                .bind(email_id)
+                .bind(flag.as_imap())
+                .execute(&mut *conn)
+                .await?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies flags fetched from the server to the local copies in `folder_id`.
+/// Flags with local changes that have not been replayed yet are left alone.
+async fn merge_server_flags(pool: &SqlitePool, folder_id: i64, server: &[(i64, Vec<Flag>)]) -> Result<usize> {
+    let mut tx = pool.begin().await?;
+    let mut updated = 0;
+
+    for (uid, flags) in server {
+        let email_id: Option<i64> = sqlx::query_scalar("SELECT id FROM emails WHERE folder_id = ? AND uid = ?")
+            .bind(folder_id)
+            .bind(uid)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(email) = (match email_id {
+            Some(email_id) => db::load_email(&mut tx, email_id).await?,
+            None => None,
+        }) else {
+            continue;
+        };
+
+        let pending: Vec<String> = sqlx::query_scalar("SELECT flag FROM pending_ops WHERE email_id = ? AND flag IS NOT NULL")
+            .bind(email.id)
+            .fetch_all(&mut *tx)
+            .await?;
+        let pending: Vec<Flag> = pending.iter().filter_map(|flag| Flag::parse(flag).ok()).collect();
+
+        let local_keywords = email.keywords.iter().filter_map(|keyword| Flag::parse(keyword).ok());
+        let candidates: Vec<Flag> = [Flag::Seen, Flag::Answered, Flag::Flagged, Flag::Draft]
+            .into_iter()
+            .chain(local_keywords)
+            .chain(flags.iter().cloned())
+            .collect();
+
+        let mut changed = false;
+        for flag in candidates.iter().filter(|flag| !pending.contains(flag)) {
+            let set = flags.contains(flag);
+            if has_flag(&email, flag) != set {
+                set_local_flag(&mut tx, email.id, flag, set).await?;
+                changed = true;
+            }
+        }
+        updated += changed as usize;
+    }
+
+    tx.commit().await?;
+    Ok(updated)
+}
+
+/// Collapses the ops recorded for one message into the fewest server
+/// operations. `ops` must be non-empty, belong to one email and be in id
+/// order. Every returned op addresses the message where the server still has
+/// it, i.e. the folder and UID of the first recorded op.
+fn coalesce(ops: &[PendingOp]) -> Vec<PendingOp> {
+    let first = &ops[0];
+    // (flag, state before the first op, state after the last op)
+    let mut flags: Vec<(Flag, bool, bool)> = Vec::new();
+    let mut target = None;
+    let mut deleted = false;
+
+    for op in ops {
+        match op.kind {
+            PendingOpKind::AddFlag | PendingOpKind::RemoveFlag => {
+                let Some(flag) = op.flag.as_deref().and_then(|flag| Flag::parse(flag).ok()) else {
+                    continue;
+                };
+                let set = op.kind == PendingOpKind::AddFlag;
+                match flags.iter_mut().find(|(f, _, _)| *f == flag) {
+                    Some((_, _, now)) => *now = set,
+                    None => flags.push((flag, !set, set)),
+                }
+            }
+            PendingOpKind::Move => target = op.target_folder_id,
+            PendingOpKind::Delete => deleted = true,
+        }
+    }
+
+    let at_origin = |kind, flag: Option<&Flag>, target_folder_id| PendingOp {
+        kind,
+        flag: flag.map(|flag| flag.as_imap().to_string()),
+        target_folder_id,
+        ..first.clone()
+    };
+
+    if deleted {
+        return vec![at_origin(PendingOpKind::Delete, None, None)];
+    }
+
+    // Flag changes go first: the UID stops being valid once the message moves.
+    let mut planned: Vec<PendingOp> = flags
+        .iter()
+        .filter(|(_, before, now)| before != now)
+        .map(|(flag, _, now)| at_origin(if *now { PendingOpKind::AddFlag } else { PendingOpKind::RemoveFlag }, Some(flag), None))
+        .collect();
+    if let Some(target) = target.filter(|target| *target != first.folder_id) {
+        planned.push(at_origin(PendingOpKind::Move, None, Some(target)));
+    }
+    planned
+}
+
+async fn replay_account(
+    pool: &SqlitePool,
+    account: &Account,
+    handler: &dyn EmailProtocol,
+    report: &mut ReplayReport,
+) -> Result<()> {
+    let ops: Vec<PendingOp> = sqlx::query_as("SELECT * FROM pending_ops WHERE account_id = ? ORDER BY id")
+        .bind(account.id)
+        .fetch_all(pool)
+        .await?;
+
+    // Group by message, keeping the order in which messages were first touched.
+    let mut groups: Vec<Vec<PendingOp>> = Vec::new();
+    for op in ops {
+        match groups.iter_mut().find(|group| group[0].email_id == op.email_id) {
+            Some(group) => group.push(op),
+            None => groups.push(vec![op]),
+        }
+    }
+
+    for group in groups {
+        let email_id = group[0].email_id;
+        let last_id = group[group.len() - 1].id;
+        match replay_email(pool, account, handler, &group).await {
+            Ok(true) => report.applied += 1,
+            Ok(false) => {}
+            Err(e) => match e.downcast_ref::<ProtocolError>().and_then(|error| Some((Conflict::of(error)?, error))) {
+                Some((conflict, error)) => {
+                    resolve_conflict(pool, &group, conflict).await?;
+                    report.conflicts.push(ReplayConflict {
+                        email_id,
+                        reason: error.to_string(),
+                    });
+                }
+                _ => {
+                    // Most likely offline, or signed out; keep everything queued in order and
+                    // try again later.
+                    sqlx::query(
+                        "UPDATE pending_ops SET attempts = attempts + 1, last_error = ? WHERE email_id = ? AND id <= ?",
+                    )
+                    .bind(format!("{:#}", e))
+                    .bind(email_id)
+                    .bind(last_id)
+                    .execute(pool)
+                    .await?;
+                    return Ok(());
+                }
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Sends the coalesced ops for one message. Returns `false` when the message
+/// cannot be addressed on the server yet and has to wait for the next sync.
+async fn replay_email(
+    pool: &SqlitePool,
+    account: &Account,
+    handler: &dyn EmailProtocol,
+    group: &[PendingOp],
+) -> Result<bool> {
+    let origin = &group[0];
+    let last_id = group[group.len() - 1].id;
+    let planned = coalesce(group);
+    let mut moved_to = None;
+    let mut left_behind = None;
+
+    if !planned.is_empty() {
+        let uid = match origin.uid {
+            Some(uid) => Some(uid),
+            None => sqlx::query_scalar("SELECT uid FROM emails WHERE id = ? AND folder_id = ?")
+                .bind(origin.email_id)
+                .bind(origin.folder_id)
+                .fetch_optional(pool)
+                .await?
+                .flatten(),
+        };
+        let Some(uid) = uid else {
+            return Ok(false);
+        };
+
+        let folder = load_folder(pool, origin.folder_id).await?;
+        let flags_of = |kind| -> Vec<Flag> {
+            planned
+                .iter()
+                .filter(|op| op.kind == kind)
+                .filter_map(|op| op.flag.as_deref().and_then(|flag| Flag::parse(flag).ok()))
+                .collect()
+        };
+        let (add, remove) = (flags_of(PendingOpKind::AddFlag), flags_of(PendingOpKind::RemoveFlag));
+        if !add.is_empty() || !remove.is_empty() {
+            handler.set_flags(account, &folder, &[uid], &add, &remove).await?;
+        }
+
+        for op in &planned {
+            match op.kind {
+                PendingOpKind::AddFlag | PendingOpKind::RemoveFlag => {}
+                PendingOpKind::Move => {
+                    let target_id = op.target_folder_id.ok_or_else(|| anyhow!("Move op {} has no target", op.id))?;
+                    let target = load_folder(pool, target_id).await?;
+                    let mapping = handler.move_emails(account, &folder, &[uid], &target).await?;
+                    // Without UIDPLUS the server may only have flagged the
+                    // original \Deleted; see whether it is still there
+                    if mapping.is_empty() {
+                        let listed = handler.fetch_flags(account, &folder).await;
+                        if listed.is_ok_and(|flags| flags.iter().any(|(kept, _)| *kept == uid)) {
+                            left_behind = Some((folder.id, uid));
+                        }
+                    }
+                    moved_to = Some((target.id, mapping.first().map(|m| m.target_uid)));
+                }
+                PendingOpKind::Delete => handler.delete_email(account, &folder, uid).await?,
+            }
+        }
+    }
+
+    let mut tx = pool.begin().await?;
+    if planned.iter().any(|op| op.kind == PendingOpKind::Delete) {
+        sqlx::query("DELETE FROM emails WHERE id = ?")
+            .bind(origin.email_id)
+            .execute(&mut *tx)
+            .await?;
+    } else if let Some((target_folder_id, Some(uid))) = moved_to {
+        // COPYUID told us the new UID, so there is no need to refetch.
+        sqlx::query("UPDATE emails SET uid = ?, updated_at = CURRENT_TIMESTAMP WHERE id = ? AND folder_id = ?")
+            .bind(uid)
+            .bind(origin.email_id)
+            .bind(target_folder_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    if let Some((folder_id, uid)) = left_behind {
+        // A hidden row for the original, so the store matches the server
+        sqlx::query(
+            "INSERT OR IGNORE INTO emails \
+             (account_id, folder_id, message_id, thread_id, subject, from_address, from_name, to_addresses, size_bytes, internal_date, uid, is_deleted) \
+             SELECT account_id, ?, message_id, thread_id, subject, from_address, from_name, to_addresses, size_bytes, internal_date, ?, 1 \
+             FROM emails WHERE id = ?",
+        )
+        .bind(folder_id)
+        .bind(uid)
+        .bind(origin.email_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    sqlx::query("DELETE FROM pending_ops WHERE email_id = ? AND id <= ?")
+        .bind(origin.email_id)
+        .bind(last_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(true)
+}
+
+/// A refusal that retrying won't fix, because what the op targets is gone.
+enum Conflict {
+    MessageGone,
+    FolderGone,
+}
+
+impl Conflict {
+    fn of(error: &ProtocolError) -> Option<Self> {
+        match error {
+            ProtocolError::MessageNotFound { .. } => Some(Conflict::MessageGone),
+            ProtocolError::FolderNotFound(_) => Some(Conflict::FolderGone),
+            _ => None,
+        }
+    }
+}
+
+/// The server wins: drop the queued ops and bring the local row back in line
+/// with what the server reported.
+async fn resolve_conflict(pool: &SqlitePool, group: &[PendingOp], conflict: Conflict) -> Result<()> {
+    let origin = &group[0];
+    let last_id = group[group.len() - 1].id;
+    let mut tx = pool.begin().await?;
+
+    match conflict {
+        Conflict::MessageGone => {
+            sqlx::query("DELETE FROM emails WHERE id = ?")
+                .bind(origin.email_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        Conflict::FolderGone => {
+            sqlx::query("UPDATE emails SET folder_id = ?, uid = ?, is_deleted = 0, updated_at = CURRENT_TIMESTAMP WHERE id = ?")
+                .bind(origin.folder_id)
+                .bind(origin.uid)
+                .bind(origin.email_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    sqlx::query("DELETE FROM pending_ops WHERE email_id = ? AND id <= ?")
+        .bind(origin.email_id)
+        .bind(last_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+async fn load_folder(pool: &SqlitePool, folder_id: i64) -> Result<Folder> {
+    sqlx::query_as("SELECT * FROM folders WHERE id = ?")
+        .bind(folder_id)
+        .fetch_optional(pool)
+        .await?
+        .ok_or_else(|| anyhow!("Folder {} not found", folder_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::ComposeEmail;
+    use crate::email::imap::test_server;
+    use crate::email::UidMapping;
+    use async_trait::async_trait;
+    use std::sync::Mutex as StdMutex;
+
+    fn op(id: i64, kind: PendingOpKind, folder_id: i64, target_folder_id: Option<i64>) -> PendingOp {
+        PendingOp {
+            id,
+            account_id: 1,
+            email_id: 1,
+            kind,
+            flag: None,
+            folder_id,
+            uid: Some(42),
+            target_folder_id,
+            attempts: 0,
+            last_error: None,
+            created_at: chrono::Utc::now(),
+        }
+    }
+
+    fn flag_op(id: i64, kind: PendingOpKind, flag: &str, folder_id: i64) -> PendingOp {
+        PendingOp {
+            flag: Some(flag.to_string()),
+            ..op(id, kind, folder_id, None)
+        }
+    }
+
+    fn kinds(ops: &[PendingOp]) -> Vec<PendingOpKind> {
+        ops.iter().map(|op| op.kind).collect()
+    }
+
+    #[test]
+    fn test_coalesce_cancels_toggles() {
+        let ops = vec![
+            flag_op(1, PendingOpKind::AddFlag, "\\Seen", 1),
+            flag_op(2, PendingOpKind::RemoveFlag, "\\Seen", 1),
+            flag_op(3, PendingOpKind::AddFlag, "$Label1", 1),
+            flag_op(4, PendingOpKind::RemoveFlag, "\\Flagged", 1),
+        ];
+        let planned = coalesce(&ops);
+        assert_eq!(kinds(&planned), vec![PendingOpKind::AddFlag, PendingOpKind::RemoveFlag]);
+        assert_eq!(planned[0].flag.as_deref(), Some("$Label1"));
+        assert_eq!(planned[1].flag.as_deref(), Some("\\Flagged"));
+    }
+
+    #[test]
+    fn test_coalesce_moves_from_origin() {
+        let ops = vec![
+            op(1, PendingOpKind::Move, 1, Some(2)),
+            flag_op(2, PendingOpKind::AddFlag, "\\Seen", 2),
+            op(3, PendingOpKind::Move, 2, Some(3)),
+        ];
+        let planned = coalesce(&ops);
+        assert_eq!(kinds(&planned), vec![PendingOpKind::AddFlag, PendingOpKind::Move]);
+        assert!(planned.iter().all(|op| op.folder_id == 1 && op.uid == Some(42)));
+        assert_eq!(planned[1].target_folder_id, Some(3));
+
+        let back = vec![op(1, PendingOpKind::Move, 1, Some(2)), op(2, PendingOpKind::Move, 2, Some(1))];
+        assert!(coalesce(&back).is_empty());
+    }
+
+    #[test]
+    fn test_coalesce_delete_wins() {
+        let ops = vec![
+            flag_op(1, PendingOpKind::AddFlag, "\\Flagged", 1),
+            op(2, PendingOpKind::Move, 1, Some(2)),
+            op(3, PendingOpKind::Delete, 2, None),
+        ];
+        let planned = coalesce(&ops);
+        assert_eq!(kinds(&planned), vec![PendingOpKind::Delete]);
+        assert_eq!(planned[0].folder_id, 1);
+    }
+
+    /// Records the calls it receives and fails them all with `error`, if set.
+    /// Without UIDPLUS, moves report no UIDs and leave the originals behind.
+    struct FakeHandler {
+        calls: StdMutex<Vec<String>>,
+        error: Option<fn() -> anyhow::Error>,
+        uidplus: bool,
+        left_behind: StdMutex<Vec<i64>>,
+    }
+
+    impl FakeHandler {
+        fn new(error: Option<fn() -> anyhow::Error>) -> Self {
+            Self { calls: StdMutex::new(Vec::new()), error, uidplus: true, left_behind: StdMutex::new(Vec::new()) }
+        }
+
+        fn call(&self, call: String) -> Result<()> {
+            self.calls.lock().unwrap().push(call);
+            match self.error {
+                Some(error) => Err(error()),
+                None => Ok(()),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl EmailProtocol for FakeHandler {
+        async fn test_connection(&self, _account: &Account) -> Result<bool> {
+            Ok(true)
+        }
+        async fn fetch_folders(&self, _account: &Account) -> Result<Vec<Folder>> {
+            Ok(vec![])
+        }
+        async fn fetch_emails(&self, _account: &Account, _folder: &Folder, _limit: u32, _offset: u32) -> Result<Vec<Email>> {
+            Ok(vec![])
+        }
+        async fn send_email(&self, _account: &Account, _email: &ComposeEmail) -> Result<String> {
+            Ok(String::new())
+        }
+        async fn set_flags(&self, _account: &Account, folder: &Folder, uids: &[i64], add: &[Flag], remove: &[Flag]) -> Result<()> {
+            self.call(format!("flags {} {:?} +{:?} -{:?}", folder.name, uids, add, remove))
+        }
+        async fn fetch_flags(&self, _account: &Account, _folder: &Folder) -> Result<Vec<(i64, Vec<Flag>)>> {
+            Ok(self.left_behind.lock().unwrap().iter().map(|&uid| (uid, vec![])).collect())
+        }
+        async fn move_emails(&self, _account: &Account, folder: &Folder, uids: &[i64], target: &Folder) -> Result<Vec<UidMapping>> {
+            self.call(format!("move {} {:?} -> {}", folder.name, uids, target.name))?;
+            if !self.uidplus {
+                self.left_behind.lock().unwrap().extend(uids);
+                return Ok(vec![]);
+            }
+            Ok(uids.iter().map(|&uid| UidMapping { source_uid: uid, target_uid: uid + 100 }).collect())
+        }
+        async fn copy_emails(&self, _account: &Account, folder: &Folder, uids: &[i64], target: &Folder) -> Result<Vec<UidMapping>> {
+            self.call(format!("copy {} {:?} -> {}", folder.name, uids, target.name))?;
+            Ok(vec![])
+        }
+        async fn delete_email(&self, _account: &Account, folder: &Folder, uid: i64) -> Result<()> {
+            self.call(format!("delete {} {}", folder.name, uid))
+        }
+        async fn create_folder(&self, _account: &Account, _parent: Option<&Folder>, _name: &str) -> Result<Folder> {
+            Err(anyhow!("Not used by the queue"))
+        }
+        async fn rename_folder(&self, _account: &Account, _folder: &Folder, _name: &str) -> Result<String> {
+            Err(anyhow!("Not used by the queue"))
+        }
+        async fn delete_folder(&self, _account: &Account, _folder: &Folder) -> Result<()> {
+            Err(anyhow!("Not used by the queue"))
+        }
+        async fn subscribe_folder(&self, _account: &Account, _folder: &Folder, _subscribed: bool) -> Result<()> {
+            Err(anyhow!("Not used by the queue"))
+        }
+        async fn fetch_labels(&self, _account: &Account, _folder: &Folder) -> Result<Vec<(i64, Vec<String>)>> {
+            Ok(vec![])
+        }
+        async fn set_labels(&self, _account: &Account, _folder: &Folder, _uids: &[i64], _add: &[String], _remove: &[String]) -> Result<()> {
+            Err(anyhow!("Not used by the queue"))
+        }
+        async fn fetch_part(&self, _account: &Account, _folder: &Folder, _uid: i64, _section: &str) -> Result<Vec<u8>> {
+            Err(anyhow!("Not used by the queue"))
+        }
+        async fn fetch_message(&self, _account: &Account, _folder: &Folder, _uid: i64) -> Result<Vec<u8>> {
+            Err(anyhow!("Not used by the queue"))
+        }
+    }
+
+    async fn test_pool() -> DbPool {
+        let pool = crate::db::test_pool_with_account().await;
+        for (id, name, folder_type) in [(1, "INBOX", "INBOX"), (2, "Archive", "CUSTOM")] {
+            sqlx::query("INSERT INTO folders (id, account_id, name, display_name, folder_type) VALUES (?, 1, ?, ?, ?)")
+                .bind(id)
+                .bind(name)
+                .bind(name)
+                .bind(folder_type)
+                .execute(pool.as_ref())
+                .await
+                .unwrap();
+        }
+        sqlx::query(
+            "INSERT INTO emails (id, account_id, folder_id, message_id, subject, from_address, to_addresses, size_bytes, internal_date, uid) \
+             VALUES (1, 1, 1, 'msg-1@example.com', 'Hello', 'sender@example.com', '[]', 100, CURRENT_TIMESTAMP, 42)",
+        )
+        .execute(pool.as_ref())
+        .await
+        .unwrap();
+        pool
+    }
+
+    async fn replay_with(queue: &OfflineQueue, handler: &FakeHandler) -> ReplayReport {
+        let account: Account = sqlx::query_as("SELECT * FROM accounts WHERE id = 1")
+            .fetch_one(queue.pool.as_ref())
+            .await
+            .unwrap();
+        let mut report = ReplayReport::default();
+        replay_account(&queue.pool, &account, handler, &mut report).await.unwrap();
+        report
+    }
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let queue = OfflineQueue::new(test_pool().await, Arc::new(Vault::new()), Default::default());
+
+        let add = vec![Flag::Seen, Flag::Keyword("$Junk".to_string())];
+        let email = queue.record(1, LocalAction::SetFlags { add, remove: vec![] }).await.unwrap();
+        assert!(email.is_read);
+        assert_eq!(email.keywords, vec!["$Junk"]);
+        let email = queue.record(1, LocalAction::Move { target_folder_id: 2 }).await.unwrap();
+        assert_eq!(email.folder_id, 2);
+        assert_eq!(email.uid, None);
+        assert_eq!(queue.pending(1).await.unwrap().len(), 3);
+
+        let offline = FakeHandler::new(Some(|| anyhow!("connection refused")));
+        let report = replay_with(&queue, &offline).await;
+        assert_eq!(report.applied, 0);
+        let pending = queue.pending(1).await.unwrap();
+        assert_eq!(pending.len(), 3);
+        assert_eq!(pending[0].attempts, 1);
+
+        let online = FakeHandler::new(None);
+        let report = replay_with(&queue, &online).await;
+        assert_eq!(report.applied, 1);
+        assert_eq!(
+            *online.calls.lock().unwrap(),
+            vec!["flags INBOX [42] +[Seen, Keyword(\"$Junk\")] -[]", "move INBOX [42] -> Archive"]
+        );
+        assert!(queue.pending(1).await.unwrap().is_empty());
+
+        let uid: Option<i64> = sqlx::query_scalar("SELECT uid FROM emails WHERE id = 1")
+            .fetch_one(queue.pool.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(uid, Some(142));
+    }
+
+    #[tokio::test]
+    async fn test_replay_conflict_when_expunged() {
+        let queue = OfflineQueue::new(test_pool().await, Arc::new(Vault::new()), Default::default());
+        queue.record(1, LocalAction::SetFlags { add: vec![Flag::Flagged], remove: vec![] }).await.unwrap();
+
+        let handler = FakeHandler::new(Some(|| {
+            ProtocolError::MessageNotFound {
+                folder: "INBOX".to_string(),
+                uid: 42,
+            }
+            .into()
+        }));
+        let report = replay_with(&queue, &handler).await;
+        assert_eq!(report.conflicts.len(), 1);
+        assert!(queue.pending(1).await.unwrap().is_empty());
+
+        let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM emails")
+            .fetch_one(queue.pool.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_move_without_uidplus() {
+        let queue = OfflineQueue::new(test_pool().await, Arc::new(Vault::new()), Default::default());
+        queue.record(1, LocalAction::Move { target_folder_id: 2 }).await.unwrap();
+
+        let handler = FakeHandler { uidplus: false, ..FakeHandler::new(None) };
+        assert_eq!(replay_with(&queue, &handler).await.applied, 1);
+
+        // The original is still in INBOX, flagged \Deleted, so it stays hidden
+        let rows: Vec<(i64, Option<i64>, bool)> = sqlx::query_as("SELECT folder_id, uid, is_deleted FROM emails ORDER BY folder_id")
+            .fetch_all(queue.pool.as_ref())
+            .await
+            .unwrap();
+        assert_eq!(rows, vec![(1, Some(42), true), (2, None, false)]);
+        let email = queue.record(1, LocalAction::Move { target_folder_id: 1 }).await.unwrap();
+        assert_eq!(email.folder_id, 1);
+    }
+
+    #[tokio::test]
+    async fn test_replay_skips_accounts_that_fail_to_load() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID STORE 7 +FLAGS.SILENT (\\Seen)", vec!["{tag} OK STORE completed"]),
+            ("UID FETCH 7 (UID FLAGS)", vec!["* 1 FETCH (UID 7 FLAGS (\\Seen))", "{tag} OK FETCH completed"]),
+        ]).await;
+        let pool = test_pool().await;
+        // Account 1 signs in with OAuth2 but has no token to refresh
+        sqlx::query("UPDATE accounts SET auth_method = 'OAUTH2' WHERE id = 1")
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO accounts (id, name, email, protocol, imap_server, imap_port, use_ssl, username, password_encrypted) \
+             VALUES (2, 'Other', 'other@example.com', 'IMAP', '127.0.0.1', ?, 0, 'other@example.com', 'password')",
+        )
+        .bind(port)
+        .execute(pool.as_ref())
+        .await
+        .unwrap();
+        sqlx::query("INSERT INTO folders (id, account_id, name, display_name, folder_type) VALUES (3, 2, 'INBOX', 'INBOX', 'INBOX')")
+            .execute(pool.as_ref())
+            .await
+            .unwrap();
+        sqlx::query(
+            "INSERT INTO emails (id, account_id, folder_id, message_id, subject, from_address, to_addresses, size_bytes, internal_date, uid) \
+             VALUES (2, 2, 3, 'msg-2@example.com', 'Hi', 'sender@example.com', '[]', 100, CURRENT_TIMESTAMP, 7)",
+        )
+        .execute(pool.as_ref())
+        .await
+        .unwrap();
+        let queue = OfflineQueue::new(pool, Arc::new(Vault::new()), Default::default());
+        queue.record(1, LocalAction::SetFlags { add: vec![Flag::Flagged], remove: vec![] }).await.unwrap();
+        queue.record(2, LocalAction::SetFlags { add: vec![Flag::Seen], remove: vec![] }).await.unwrap();
+
+        let report = queue.replay().await.unwrap();
+        assert_eq!((report.applied, report.remaining), (1, 1));
+        assert_eq!(queue.pending(1).await.unwrap().len(), 1);
+        assert!(queue.pending(2).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_merge_server_flags_keeps_pending_changes() {
+        let queue = OfflineQueue::new(test_pool().await, Arc::new(Vault::new()), Default::default());
+        queue.record(1, LocalAction::SetFlags { add: vec![Flag::Flagged], remove: vec![] }).await.unwrap();
+
+        let server = vec![(42, vec![Flag::Seen, Flag::Keyword("$Forwarded".to_string())])];
+        assert_eq!(merge_server_flags(&queue.pool, 1, &server).await.unwrap(), 1);
+
+        let mut conn = queue.pool.acquire().await.unwrap();
+        let email = db::load_email(&mut conn, 1).await.unwrap().unwrap();
+        assert!(email.is_read);
+        assert!(email.is_flagged, "pending local flag must survive the merge");
+        assert_eq!(email.keywords, vec!["$Forwarded"]);
+    }
+}
@@ -0,0 +1,254 @@
+//! The `slopmail-content://` scheme, which serves message parts from the
+//! local store so inline images render without the webview touching the
+//! network. Inline parts not in the store yet are downloaded from the
+//! account's server first.
+//!
+//! URLs look like `slopmail-content://message/<email id>/cid/<content id>`
+//! or `slopmail-content://message/<email id>/part/<part id>`. On platforms
+//! where the webview maps custom schemes onto
+//! `http://slopmail-content.localhost/`, the same path follows the host.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+
+use crate::attachments;
+use crate::crypto::vault::Vault;
+use crate::db::{self, DbPool, EmailPart};
+use crate::email::SessionPool;
+
+pub const SCHEME: &str = "slopmail-content";
+
+/// Content types served as themselves. Anything else is served as a
+/// download so the webview never interprets it.
+const INLINE_TYPES: &[&str] = &[
+    "image/png", "image/gif", "image/jpeg", "image/jpg", "image/webp", "image/bmp", "image/svg+xml",
+    "text/plain", "application/pdf",
+];
+
+/// Sent with every response, so a part opened on its own cannot run script.
+pub const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; img-src 'self' data:; style-src 'unsafe-inline'; sandbox";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentRef {
+    Cid { email_id: i64, content_id: String },
+    Part { email_id: i64, part_id: i64 },
+}
+
+#[derive(Debug)]
+pub struct ContentResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl ContentResponse {
+    fn not_found() -> Self {
+        Self::error(404, "Not found")
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        ContentResponse {
+            status,
+            content_type: "text/plain".to_string(),
+            body: message.as_bytes().to_vec(),
+        }
+    }
+}
+
+/// URL serving the part with Content-ID `content_id`.
+pub fn cid_url(email_id: i64, content_id: &str) -> String {
+    format!("{}://message/{}/cid/{}", SCHEME, email_id, utf8_percent_encode(content_id, NON_ALPHANUMERIC))
+}
+
+pub fn part_url(email_id: i64, part_id: i64) -> String {
+    format!("{}://message/{}/part/{}", SCHEME, email_id, part_id)
+}
+
+/// Rewrites a `cid:` URL from a message body to the scheme. The Content-ID
+/// in a `cid:` URL is itself percent-encoded (RFC 2392).
+pub fn rewrite_cid(email_id: i64, url: &str) -> Option<String> {
+    let content_id = url.get(..4).filter(|scheme| scheme.eq_ignore_ascii_case("cid:")).map(|_| &url[4..])?;
+    let content_id = percent_decode_str(content_id).decode_utf8_lossy();
+    (!content_id.is_empty()).then(|| cid_url(email_id, &content_id))
+}
+
+pub fn parse(uri: &str) -> Option<ContentRef> {
+    let rest = uri
+        .strip_prefix(&format!("{}://", SCHEME))
+        .or_else(|| uri.strip_prefix(&format!("http://{}.localhost/", SCHEME)))
+        .or_else(|| uri.strip_prefix(&format!("https://{}.localhost/", SCHEME)))?;
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let rest = rest.strip_prefix("localhost/").unwrap_or(rest);
+
+    let segments: Vec<&str> = rest.split('/').collect();
+    let ["message", email_id, kind, id] = segments.as_slice() else {
+        return None;
+    };
+    let email_id = email_id.parse().ok()?;
+    match *kind {
+        "cid" => Some(ContentRef::Cid {
+            email_id,
+            content_id: percent_decode_str(id).decode_utf8().ok()?.into_owned(),
+        }),
+        "part" => Some(ContentRef::Part { email_id, part_id: id.parse().ok()? }),
+        _ => None,
+    }
+}
+
+/// Answers a request to the scheme from the local store, fetching inline
+/// parts it doesn't have yet.
+pub async fn respond(pool: &DbPool, vault: &Vault, sessions: &Arc<SessionPool>, uri: &str) -> ContentResponse {
+    let Some(content) = parse(uri) else {
+        return ContentResponse::not_found();
+    };
+    let mut conn = match pool.acquire().await {
+        Ok(conn) => conn,
+        Err(e) => return ContentResponse::error(500, &e.to_string()),
+    };
+    let part = match &content {
+        ContentRef::Cid { email_id, content_id } => db::parts::find_by_content_id(&mut conn, *email_id, content_id).await,
+        ContentRef::Part { email_id, part_id } => db::parts::find(&mut conn, *email_id, *part_id).await,
+    };
+    drop(conn);
+    let fetched = match (part, content) {
+        (Ok(Some(EmailPart { data: Some(data), content_type, .. })), _) => Ok(Some((content_type, data))),
+        (Ok(_), ContentRef::Cid { email_id, content_id }) => fetch_inline(pool, vault, sessions, email_id, &content_id).await,
+        (Ok(_), ContentRef::Part { .. }) => Ok(None),
+        (Err(e), _) => Err(e),
+    };
+    match fetched {
+        Ok(Some((content_type, data))) => ContentResponse {
+            status: 200,
+            content_type: served_type(&content_type),
+            body: data,
+        },
+        Ok(None) => ContentResponse::not_found(),
+        Err(e) => ContentResponse::error(500, &e.to_string()),
+    }
+}
+
+/// Downloads the part of the message with `content_id` and caches it, as
+/// `attachments::load` does. `None` when the message has no such part.
+async fn fetch_inline(
+    pool: &DbPool,
+    vault: &Vault,
+    sessions: &Arc<SessionPool>,
+    email_id: i64,
+    content_id: &str,
+) -> Result<Option<(String, Vec<u8>)>> {
+    let mut conn = pool.acquire().await?;
+    let Some(email) = db::load_email(&mut conn, email_id).await? else {
+        return Ok(None);
+    };
+    drop(conn);
+    let bare = |id: &str| id.trim().trim_start_matches('<').trim_end_matches('>').to_ascii_lowercase();
+    let attachment = attachments::list(&email)
+        .into_iter()
+        .find(|attachment| attachment.content_id.as_deref().is_some_and(|id| bare(id) == bare(content_id)));
+    let Some(attachment) = attachment else {
+        return Ok(None);
+    };
+    let data = attachments::load(pool, vault, sessions, &email, &attachment).await?;
+    Ok(Some((attachment.content_type, data)))
+}
+
+fn served_type(content_type: &str) -> String {
+    let essence = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+    if INLINE_TYPES.contains(&essence.as_str()) {
+        essence
+    } else {
+        "application/octet-stream".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::imap::test_server;
+    use crate::db::test_pool_with_account;
+
+    #[test]
+    fn test_urls() {
+        let url = cid_url(7, "image001.png@01D9A1B2.3C4D5E60");
+        assert_eq!(url, "slopmail-content://message/7/cid/image001%2Epng%4001D9A1B2%2E3C4D5E60");
+        let expected = ContentRef::Cid { email_id: 7, content_id: "image001.png@01D9A1B2.3C4D5E60".to_string() };
+        assert_eq!(parse(&url), Some(expected.clone()));
+        assert_eq!(parse("http://slopmail-content.localhost/message/7/cid/image001.png%4001D9A1B2.3C4D5E60"), Some(expected));
+        assert_eq!(rewrite_cid(7, "CID:image001.png%4001D9A1B2.3C4D5E60").as_deref(), Some(url.as_str()));
+
+        assert_eq!(parse(&part_url(7, 3)), Some(ContentRef::Part { email_id: 7, part_id: 3 }));
+        assert_eq!(parse("slopmail-content://message/x/part/3"), None);
+        assert_eq!(parse("slopmail-content://message/7/cid/a/b"), None);
+        assert_eq!(parse("https://example.com/message/7/part/3"), None);
+        assert_eq!(rewrite_cid(7, "cid:"), None);
+    }
+
+    #[tokio::test]
+    async fn test_serves_stored_parts() {
+        let pool = test_pool_with_account().await;
+        let (vault, sessions) = (Vault::new(), Arc::new(SessionPool::new()));
+        let respond = |uri: String| {
+            let (pool, vault, sessions) = (&pool, &vault, &sessions);
+            async move { respond(pool, vault, sessions, &uri).await }
+        };
+        for sql in [
+            "INSERT INTO folders (id, account_id, name, display_name, folder_type) VALUES (1, 1, 'INBOX', 'Inbox', 'INBOX')",
+            "INSERT INTO emails (id, account_id, folder_id, message_id, subject, from_address, to_addresses, size_bytes, internal_date) \
+             VALUES (1, 1, 1, 'msg-1@example.com', 'Hello', 'sender@example.com', '[]', 100, CURRENT_TIMESTAMP)",
+            "INSERT INTO email_parts (id, email_id, section, content_id, content_type, is_inline, size_bytes, data) VALUES \
+             (1, 1, '2', 'logo@example.com', 'image/PNG; name=logo.png', 1, 4, X'89504E47'), \
+             (2, 1, '3', 'page@example.com', 'text/html', 1, 8, CAST('<b>x</b>' AS BLOB)), \
+             (3, 1, '4', NULL, 'application/zip', 0, 100, NULL)",
+        ] {
+            sqlx::query(sql).execute(pool.as_ref()).await.unwrap();
+        }
+
+        let response = respond(cid_url(1, "Logo@example.com")).await;
+        assert_eq!((response.status, response.content_type.as_str()), (200, "image/png"));
+        assert_eq!(response.body, vec![0x89, b'P', b'N', b'G']);
+
+        let response = respond(cid_url(1, "page@example.com")).await;
+        assert_eq!(response.content_type, "application/octet-stream");
+
+        // Not downloaded yet, another message's part, unknown cid
+        assert_eq!(respond(part_url(1, 3)).await.status, 404);
+        assert_eq!(respond(part_url(2, 1)).await.status, 404);
+        assert_eq!(respond(cid_url(1, "missing@example.com")).await.status, 404);
+    }
+
+    #[tokio::test]
+    async fn test_fetches_missing_inline_parts() {
+        let port = test_server::serve("IMAP4rev1", vec![
+            ("SELECT \"INBOX\"", vec!["{tag} OK [READ-WRITE] SELECT completed"]),
+            ("UID FETCH 42 (BODY.PEEK[2.MIME] BODY.PEEK[2])", vec![
+                "* 3 FETCH (UID 42 BODY[2.MIME] {62}\r\nContent-Type: image/png\r\nContent-Transfer-Encoding: base64\r\n\r\n BODY[2] {8}\r\niVBORw==)",
+                "{tag} OK FETCH completed",
+            ]),
+        ]).await;
+        let pool = test_pool_with_account().await;
+        let attachments = r#"[{"id":"2","filename":"logo.png","content_type":"image/png","size_bytes":4,"content_id":"<logo@example.com>","is_inline":true}]"#;
+        for sql in [
+            format!("UPDATE accounts SET imap_server = '127.0.0.1', imap_port = {}, use_ssl = 0 WHERE id = 1", port),
+            "INSERT INTO folders (id, account_id, name, display_name, folder_type) VALUES (1, 1, 'INBOX', 'Inbox', 'INBOX')".to_string(),
+            format!(
+                "INSERT INTO emails (id, account_id, folder_id, message_id, subject, from_address, to_addresses, size_bytes, internal_date, uid, attachments) \
+                 VALUES (1, 1, 1, 'msg-1@example.com', 'Hello', 'sender@example.com', '[]', 100, CURRENT_TIMESTAMP, 42, '{}')",
+                attachments
+            ),
+        ] {
+            sqlx::query(&sql).execute(pool.as_ref()).await.unwrap();
+        }
+        let (vault, sessions) = (Vault::new(), Arc::new(SessionPool::new()));
+
+        let response = respond(&pool, &vault, &sessions, &cid_url(1, "logo@example.com")).await;
+        assert_eq!((response.status, response.content_type.as_str()), (200, "image/png"));
+        assert_eq!(response.body, vec![0x89, b'P', b'N', b'G']);
+
+        // Cached, so the server isn't asked again
+        let mut conn = pool.acquire().await.unwrap();
+        let part = db::parts::find_by_content_id(&mut conn, 1, "logo@example.com").await.unwrap().unwrap();
+        assert_eq!(part.data, Some(vec![0x89, b'P', b'N', b'G']));
+    }
+}
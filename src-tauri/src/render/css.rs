@@ -0,0 +1,406 @@
+//! Just enough CSS handling to keep a message's styles inside its own
+//! container: selectors are scoped and prefixed, dangerous declarations and
+//! at-rules are dropped. Anything it cannot make sense of is left out.
+
+use std::ops::Range;
+
+use super::remote;
+use super::sanitize::{is_safe_image_url, strip_url, Context, RemovalKind, CONTAINER_CLASS, NAME_PREFIX};
+
+/// At-rules whose body is another list of rules.
+const NESTED_AT_RULES: &[&str] = &["@media", "@supports"];
+
+/// At-rules whose body is a list of declarations, or of keyframe blocks.
+const KEPT_AT_RULES: &[&str] = &["@font-face", "@keyframes", "@-webkit-keyframes"];
+
+/// Properties that run code or bind behavior in some engine.
+const BLOCKED_PROPERTIES: &[&str] = &["behavior", "-moz-binding", "-ms-behavior"];
+
+enum Rule<'a> {
+    /// A statement ending in `;`, e.g. `@import url(x);`.
+    Statement(&'a str),
+    Block { prelude: &'a str, body: &'a str },
+}
+
+/// Scopes a `<style>` element's content. `<` is escaped so the result can
+/// never close the element early.
+pub fn scope_stylesheet(css: &str, cx: &mut Context) -> String {
+    let mut scoped = String::new();
+    scope_rules(&strip_comments(css), cx, &mut scoped);
+    scoped.replace('<', "\\3c ")
+}
+
+fn scope_rules(css: &str, cx: &mut Context, out: &mut String) {
+    for rule in split_rules(css) {
+        match rule {
+            Rule::Statement(statement) => {
+                if let Some(name) = at_keyword(statement) {
+                    cx.removed.add(RemovalKind::Css, &name);
+                }
+            }
+            Rule::Block { prelude, body } => {
+                let prelude = prelude.trim();
+                match at_keyword(prelude) {
+                    Some(name) if NESTED_AT_RULES.contains(&name.as_str()) => {
+                        out.push_str(prelude);
+                        out.push('{');
+                        scope_rules(body, cx, out);
+                        out.push('}');
+                    }
+                    Some(name) if name == "@font-face" => {
+                        out.push_str(&format!("{}{{{}}}", prelude, clean_declarations(body, cx)));
+                    }
+                    Some(name) if KEPT_AT_RULES.contains(&name.as_str()) => {
+                        out.push_str(prelude);
+                        out.push('{');
+                        for frame in split_rules(body) {
+                            if let Rule::Block { prelude, body } = frame {
+                                out.push_str(&format!("{}{{{}}}", prelude.trim(), clean_declarations(body, cx)));
+                            }
+                        }
+                        out.push('}');
+                    }
+                    Some(name) => cx.removed.add(RemovalKind::Css, &name),
+                    None => {
+                        let selectors = scope_selectors(prelude);
+                        let declarations = clean_declarations(body, cx);
+                        if !selectors.is_empty() && !declarations.is_empty() {
+                            out.push_str(&format!("{}{{{}}}", selectors, declarations));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Filters a declaration list, as found in a `style` attribute or a rule
+/// body. Remote `url()`s that may not load become `none`.
+pub fn clean_declarations(declarations: &str, cx: &mut Context) -> String {
+    let mut kept = Vec::new();
+    for declaration in split_top_level(&strip_comments(declarations), ';') {
+        let Some((property, value)) = declaration.split_once(':') else {
+            continue;
+        };
+        let property = property.trim().to_ascii_lowercase();
+        let value = value.trim();
+        if property.is_empty() || value.is_empty() {
+            continue;
+        }
+        match blocked_reason(&property, value) {
+            Some(reason) => cx.removed.add(RemovalKind::Css, &reason),
+            None => {
+                let mut value = value.to_string();
+                for (span, url) in url_spans(&value).into_iter().rev() {
+                    if let Some(local) = cx.local_url(&url) {
+                        value.replace_range(span, &format!("url({})", local));
+                    } else if remote::is_remote(&url) && !cx.allow_remote(&url) {
+                        value.replace_range(span, "none");
+                    }
+                }
+                kept.push(format!("{}:{}", property, value));
+            }
+        }
+    }
+    kept.join(";")
+}
+
+fn blocked_reason(property: &str, value: &str) -> Option<String> {
+    let lower = value.to_ascii_lowercase();
+    if BLOCKED_PROPERTIES.contains(&property) {
+        return Some(property.to_string());
+    }
+    // Escapes could spell out anything checked below
+    if lower.contains('\\') {
+        return Some("escaped value".to_string());
+    }
+    if lower.contains("expression(") {
+        return Some("expression()".to_string());
+    }
+    if lower.contains("javascript:") || lower.contains("vbscript:") {
+        return Some("script url".to_string());
+    }
+    if property == "position" && (lower.starts_with("fixed") || lower.starts_with("sticky")) {
+        return Some(format!("position: {}", lower.split_whitespace().next().unwrap_or_default()));
+    }
+    // Takes bare strings as URLs, so nothing can be checked
+    if lower.contains("image-set(") {
+        return Some("image-set()".to_string());
+    }
+    if url_spans(value).iter().any(|(_, url)| !is_safe_image_url(url)) {
+        return Some("url()".to_string());
+    }
+    None
+}
+
+/// Each `url(...)` in a value: its span, including `url(` and `)`, and
+/// the URL inside.
+fn url_spans(value: &str) -> Vec<(Range<usize>, String)> {
+    let lower = value.to_ascii_lowercase();
+    let mut found = Vec::new();
+    let mut from = 0;
+    while let Some(start) = lower[from..].find("url(").map(|i| from + i) {
+        let inner = start + 4;
+        let end = lower[inner..].find(')').map_or(value.len(), |i| inner + i);
+        let url = value[inner..end].trim().trim_matches(|c| c == '"' || c == '\'');
+        found.push((start..(end + 1).min(value.len()), strip_url(url)));
+        from = end;
+    }
+    found
+}
+
+/// Scopes a selector list to the message container.
+fn scope_selectors(selectors: &str) -> String {
+    split_top_level(selectors, ',')
+        .into_iter()
+        .map(str::trim)
+        .filter(|selector| !selector.is_empty())
+        .filter_map(scope_selector)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Prefixes class and id names and roots the selector at the container.
+/// Leading `html`, `body` and `:root` stand for the container itself, so a
+/// sibling combinator after them would reach outside it and the selector is
+/// dropped.
+fn scope_selector(selector: &str) -> Option<String> {
+    let container = format!(".{}", CONTAINER_CLASS);
+    let selector = prefix_names(selector);
+    let mut rest = selector.as_str();
+    let mut rooted = false;
+    while let Some(after) = strip_root(rest) {
+        rooted = true;
+        rest = after.trim_start().trim_start_matches('>').trim_start();
+    }
+
+    if rooted && rest.starts_with(['~', '+', '|']) {
+        None
+    } else if rooted && rest.is_empty() {
+        Some(container)
+    } else if rooted && rest.starts_with(['.', '#', '[', ':']) {
+        Some(format!("{}{}", container, rest))
+    } else {
+        Some(format!("{} {}", container, rest))
+    }
+}
+
+fn strip_root(selector: &str) -> Option<&str> {
+    ["html", "body", ":root"].iter().find_map(|root| {
+        let head = selector.get(..root.len())?;
+        let rest = &selector[root.len()..];
+        let ends = !rest.starts_with(|c: char| c.is_alphanumeric() || c == '-' || c == '_');
+        (head.eq_ignore_ascii_case(root) && ends).then_some(rest)
+    })
+}
+
+/// Adds [`NAME_PREFIX`] to `.class` and `#id` names outside of attribute
+/// selectors and strings.
+fn prefix_names(selector: &str) -> String {
+    let mut prefixed = String::with_capacity(selector.len());
+    let mut in_brackets = false;
+    let mut quote = None;
+    let mut chars = selector.chars().peekable();
+    while let Some(c) = chars.next() {
+        prefixed.push(c);
+        match (quote, c) {
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => in_brackets = true,
+            (None, ']') => in_brackets = false,
+            (None, '.' | '#')
+                if !in_brackets && chars.peek().is_some_and(|next| next.is_alphabetic() || matches!(next, '_' | '-' | '\\')) =>
+            {
+                prefixed.push_str(NAME_PREFIX);
+            }
+            _ => {}
+        }
+    }
+    prefixed
+}
+
+/// The lowercased at-keyword a rule starts with, e.g. `@media`.
+fn at_keyword(rule: &str) -> Option<String> {
+    let rule = rule.trim_start();
+    if !rule.starts_with('@') {
+        return None;
+    }
+    let end = rule[1..]
+        .find(|c: char| !(c.is_alphanumeric() || c == '-' || c == '_'))
+        .map_or(rule.len(), |i| i + 1);
+    Some(rule[..end].to_ascii_lowercase())
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut stripped = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        stripped.push_str(&rest[..start]);
+        match rest[start + 2..].find("*/") {
+            Some(end) => rest = &rest[start + 2 + end + 2..],
+            None => return stripped,
+        }
+        // A comment separates tokens
+        stripped.push(' ');
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// Splits a stylesheet into top-level rules. An unterminated block runs to
+/// the end, as it does in a browser.
+fn split_rules(css: &str) -> Vec<Rule<'_>> {
+    let mut rules = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut brace = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in css.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (_, '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '{') => {
+                if depth == 0 {
+                    brace = i;
+                }
+                depth += 1;
+            }
+            (None, '}') if depth > 0 => {
+                depth -= 1;
+                if depth == 0 {
+                    rules.push(Rule::Block { prelude: &css[start..brace], body: &css[brace + 1..i] });
+                    start = i + 1;
+                }
+            }
+            (None, ';') if depth == 0 => {
+                rules.push(Rule::Statement(&css[start..i]));
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    if depth > 0 {
+        rules.push(Rule::Block { prelude: &css[start..brace], body: &css[brace + 1..] });
+    } else if !css[start..].trim().is_empty() {
+        rules.push(Rule::Statement(&css[start..]));
+    }
+    rules
+}
+
+/// Splits on `separator` outside of strings, parentheses and brackets.
+fn split_top_level(input: &str, separator: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in input.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match (quote, c) {
+            (_, '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '(' | '[') => depth += 1,
+            (None, ')' | ']') => depth -= 1,
+            (None, _) if c == separator && depth <= 0 => {
+                parts.push(&input[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&input[start..]);
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::render::sanitize::{RenderOptions, Removals};
+
+    fn context(options: &RenderOptions) -> Context<'_> {
+        Context { options, removed: Removals::default(), remote_blocked: 0, trackers_blocked: 0 }
+    }
+
+    fn scope(css: &str) -> (String, Vec<String>) {
+        let options = RenderOptions { load_remote: true, ..Default::default() };
+        let mut cx = context(&options);
+        let scoped = scope_stylesheet(css, &mut cx);
+        (scoped, cx.removed.into_vec().into_iter().map(|r| r.name).collect())
+    }
+
+    #[test]
+    fn test_selectors() {
+        let scope = |selector| scope_selector(selector).unwrap();
+        assert_eq!(scope("html body"), ".slopmail-message");
+        assert_eq!(scope("body > table.main td"), ".slopmail-message table.m-main td");
+        assert_eq!(scope(":root"), ".slopmail-message");
+        assert_eq!(scope("bodytext"), ".slopmail-message bodytext");
+        assert_eq!(scope("a[href='#top'].x:not(#y)"), ".slopmail-message a[href='#top'].m-x:not(#m-y)");
+        assert_eq!(scope("p.5"), ".slopmail-message p.5");
+        assert_eq!(scope_selector("body ~ *"), None);
+        assert_eq!(scope_selector("html > body + div"), None);
+    }
+
+    #[test]
+    fn test_at_rules() {
+        let (scoped, removed) = scope(
+            "@charset \"utf-8\"; @import 'https://evil.example/a.css'; \
+             @font-face{font-family:X;src:url(https://fonts.example/x.woff)} \
+             @keyframes spin{from{opacity:0}to{opacity:1}} \
+             @supports (display:grid){@media print{.a{color:red}}} \
+             @page{margin:0} @namespace svg url(http://www.w3.org/2000/svg);",
+        );
+        assert_eq!(
+            scoped,
+            "@font-face{font-family:X;src:url(https://fonts.example/x.woff)}\
+             @keyframes spin{from{opacity:0}to{opacity:1}}\
+             @supports (display:grid){@media print{.slopmail-message .m-a{color:red}}}"
+        );
+        assert_eq!(removed, vec!["@charset", "@import", "@namespace", "@page"]);
+    }
+
+    #[test]
+    fn test_cannot_break_out() {
+        let (scoped, _) = scope("p{color:red}</style><script>alert(1)</script>");
+        assert!(!scoped.contains('<'));
+        let (scoped, _) = scope("p{font-family:\"}\"; color:red} q{x:y");
+        assert_eq!(scoped, ".slopmail-message p{font-family:\"}\";color:red}.slopmail-message q{x:y}");
+        let (scoped, removed) = scope("/* } */ div { background: URL( 'javascript:alert(1)' ) } /* unterminated");
+        assert_eq!(scoped, "");
+        assert_eq!(removed, vec!["script url"]);
+    }
+
+    #[test]
+    fn test_remote_urls() {
+        let options = RenderOptions { load_remote: false, recipients: vec!["bob@example.com".to_string()], email_id: Some(4) };
+        let mut cx = context(&options);
+        let cleaned = clean_declarations(
+            "background: #fff url('https://cdn.example.com/bg.png') no-repeat; \
+             background-image: url(data:image/png;base64,iVBORw0KGgo=); \
+             border-image: url(\"cid:border@example.com\") 30; \
+             list-style: url(https://t.example.com/p.png?u=bob%40example.com); \
+             content: image-set(\"https://cdn.example.com/x.png\" 1x)",
+            &mut cx,
+        );
+        assert_eq!(
+            cleaned,
+            "background:#fff none no-repeat;background-image:url(data:image/png;base64,iVBORw0KGgo=);\
+             border-image:url(slopmail-content://message/4/cid/border%40example%2Ecom) 30;list-style:none"
+        );
+        assert_eq!((cx.remote_blocked, cx.trackers_blocked), (1, 1));
+    }
+}
@@ -0,0 +1,9 @@
+//! Turns stored message bodies into HTML that is safe to show in the
+//! webview.
+
+pub mod content;
+pub mod css;
+pub mod remote;
+pub mod sanitize;
+
+pub use sanitize::{sanitize, Removal, RemovalKind, RenderOptions, SanitizedHtml};
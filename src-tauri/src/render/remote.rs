@@ -0,0 +1,150 @@
+//! Recognizes remote images that exist only to report that a message was
+//! opened.
+
+use base64::engine::general_purpose::{STANDARD_NO_PAD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use url::Url;
+
+/// Hosts that serve open-tracking pixels. Subdomains match too.
+const TRACKER_HOSTS: &[&str] = &[
+    "app.bananatag.com",
+    "ct.sendgrid.net",
+    "email.mg.mailgun.net",
+    "getnotify.com",
+    "links.iterable.com",
+    "list-manage.com",
+    "mailfoogae.appspot.com",
+    "mailstat.us",
+    "mailtrack.io",
+    "mandrillapp.com",
+    "mixmax.com",
+    "open.convertkit-mail.com",
+    "pstmrk.it",
+    "r.superhuman.com",
+    "sendibt3.com",
+    "t.hubspotemail.net",
+    "t.yesware.com",
+    "track.hubspot.com",
+    "trk.klclick.com",
+];
+
+/// Path fragments used by open-tracking endpoints on any host.
+const TRACKER_PATHS: &[&str] = &["/track/open", "/wf/open", "/open.aspx", "/open.gif", "/o.gif", "/pixel.gif", "/beacon"];
+
+const IMAGE_EXTENSIONS: &[&str] = &[".png", ".gif", ".jpg", ".jpeg", ".webp", ".svg", ".bmp", ".ico"];
+
+/// Shortest run of opaque characters taken to be a per-recipient token.
+const MIN_TOKEN_LEN: usize = 24;
+
+/// Whether `url` is an http(s) URL, i.e. something that would be fetched
+/// from the network.
+pub fn is_remote(url: &str) -> bool {
+    matches!(super::sanitize::scheme(url).as_deref(), Some("http") | Some("https"))
+}
+
+/// Whether loading `url` would tell a tracker the message was opened: a
+/// known tracking host or endpoint, or a URL unique to the recipient.
+pub fn is_tracker(url: &str, recipients: &[String]) -> bool {
+    let Ok(parsed) = Url::parse(url) else {
+        return false;
+    };
+    let host = parsed.host_str().unwrap_or_default().to_ascii_lowercase();
+    let path = parsed.path().to_ascii_lowercase();
+
+    TRACKER_HOSTS.iter().any(|tracker| host == *tracker || host.ends_with(&format!(".{}", tracker)))
+        || TRACKER_PATHS.iter().any(|fragment| path.contains(fragment))
+        || names_recipient(url, recipients)
+        || has_opaque_token(&parsed)
+}
+
+/// Whether `url` carries one of the recipients' addresses, plainly,
+/// percent-encoded or in base64.
+fn names_recipient(url: &str, recipients: &[String]) -> bool {
+    let lower = url.to_ascii_lowercase();
+    recipients.iter().filter(|address| !address.is_empty()).any(|address| {
+        let address = address.to_ascii_lowercase();
+        lower.contains(&address)
+            || lower.contains(&address.replace('@', "%40"))
+            || url.contains(&STANDARD_NO_PAD.encode(&address))
+            || url.contains(&URL_SAFE_NO_PAD.encode(&address))
+    })
+}
+
+/// An image URL whose path is not an image file but contains a long id,
+/// e.g. `/open/7f3a9c...`: the same picture sent to everyone would not
+/// need one.
+fn has_opaque_token(url: &Url) -> bool {
+    let path = url.path().to_ascii_lowercase();
+    if IMAGE_EXTENSIONS.iter().any(|extension| path.ends_with(extension)) {
+        return false;
+    }
+    let segments = url.path_segments().into_iter().flatten().map(str::to_string);
+    let values = url.query_pairs().map(|(_, value)| value.into_owned());
+    segments.chain(values).any(|part| is_opaque_token(&part))
+}
+
+fn is_opaque_token(part: &str) -> bool {
+    part.len() >= MIN_TOKEN_LEN
+        && part.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '=' | '.'))
+        && part.chars().any(|c| c.is_ascii_digit())
+        && part.chars().any(|c| c.is_ascii_alphabetic())
+}
+
+/// Whether an image is too small or hidden to be meant for the reader,
+/// given its `width`, `height` and `style` attributes.
+pub fn is_invisible(width: Option<&str>, height: Option<&str>, style: Option<&str>) -> bool {
+    let tiny = |value: Option<&str>| {
+        value.is_some_and(|v| v.trim().trim_end_matches("px").trim().parse::<f32>().is_ok_and(|n| n <= 1.0))
+    };
+    let style: String = style.unwrap_or_default().to_ascii_lowercase().split_whitespace().collect();
+    let declared = |property: &str| {
+        style.split(';').find_map(|declaration| declaration.strip_prefix(property)?.strip_prefix(':'))
+    };
+
+    (tiny(width) && tiny(height))
+        || (tiny(declared("width")) && tiny(declared("height")))
+        || declared("display") == Some("none")
+        || declared("visibility") == Some("hidden")
+        || declared("opacity").is_some_and(|v| v.parse::<f32>().is_ok_and(|n| n == 0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trackers() {
+        let recipients = vec!["alice@example.com".to_string()];
+        for url in [
+            "https://mailtrack.io/trace/mail/abc.png",
+            "https://us4.list-manage.com/track/open.php?u=1&id=2",
+            "https://news.example.com/wf/open?upn=x",
+            "https://news.example.com/p.png?to=alice@example.com",
+            "https://news.example.com/p.png?to=Alice%40Example.com",
+            "https://news.example.com/p/YWxpY2VAZXhhbXBsZS5jb20.png",
+            "https://news.example.com/open/7f3a9c0e5b2d4a61b8e0c9d7f6a5b4c3",
+            "https://news.example.com/img?id=Zk3q9XbT2mLp8RwY4vNc6HjA",
+        ] {
+            assert!(is_tracker(url, &recipients), "{} not detected", url);
+        }
+        for url in [
+            "https://cdn.example.com/logo.png",
+            "https://cdn.example.com/assets/7f3a9c0e5b2d4a61b8e0c9d7f6a5b4c3.png",
+            "https://images.example.com/banner?w=600",
+            "not a url",
+        ] {
+            assert!(!is_tracker(url, &recipients), "{} flagged", url);
+        }
+    }
+
+    #[test]
+    fn test_invisible() {
+        assert!(is_invisible(Some("1"), Some("1"), None));
+        assert!(is_invisible(Some("0"), Some("1px"), None));
+        assert!(is_invisible(None, None, Some("width: 1px; height: 1px")));
+        assert!(is_invisible(None, None, Some("display:none")));
+        assert!(is_invisible(Some("600"), None, Some("opacity: 0")));
+        assert!(!is_invisible(Some("1"), Some("200"), None));
+        assert!(!is_invisible(Some("600"), Some("100"), Some("display:block")));
+    }
+}
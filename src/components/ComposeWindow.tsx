@@ -1,10 +1,11 @@
 import type { Component } from 'solid-js';
-import { createSignal } from 'solid-js';
-import type { Account } from '../types/email';
+import { createSignal, For } from 'solid-js';
+import { invoke } from '@tauri-apps/api/core';
+import type { Account, Attachment, AutocryptRecommendation, ComposeEmail } from '../types/email';
 
 interface ComposeWindowProps {
   account: Account | null;
-  onSend: (emailData: any) => void;
+  onSend: (email: ComposeEmail) => void;
   onCancel: () => void;
 }
 
@@ -12,6 +13,14 @@ const ComposeWindow: Component<ComposeWindowProps> = (props) => {
   const [to, setTo] = createSignal('');
   const [subject, setSubject] = createSignal('');
   const [bodyText, setBodyText] = createSignal('');
+  const [sign, setSign] = createSignal(false);
+  const [encrypt, setEncrypt] = createSignal(false);
+  // Autocrypt picks the default until the user sets encryption themselves
+  const [encryptChosen, setEncryptChosen] = createSignal(false);
+  const [smime, setSmime] = createSignal(false);
+  const [age, setAge] = createSignal(false);
+  const [agePassphrase, setAgePassphrase] = createSignal('');
+  const [attachments, setAttachments] = createSignal<Attachment[]>([]);
   const [sending, setSending] = createSignal(false);
 
   const handleSend = async () => {
@@ -22,14 +31,21 @@ const ComposeWindow: Component<ComposeWindowProps> = (props) => {
 
     setSending(true);
     try {
-      const emailData = {
-        to: to().split(',').map(email => email.trim()),
+      const email: ComposeEmail = {
+        account_id: props.account.id,
+        to: recipients().map(address => ({ address })),
         subject: subject(),
-        bodyText: bodyText() || undefined,
-        bodyHtml: undefined, // TODO: Add rich text editor
+        body_text: bodyText() || undefined,
+        body_html: undefined, // TODO: Add rich text editor
+        attachments: attachments(),
+        sign: sign(),
+        encrypt: encrypt(),
+        smime: smime(),
+        age: age(),
+        age_passphrase: age() && agePassphrase() ? agePassphrase() : undefined,
       };
 
-      await props.onSend(emailData);
+      await props.onSend(email);
     } catch (error) {
       console.error('Failed to send email:', error);
       alert('Failed to send email. Please check your settings and try again.');
@@ -38,6 +54,33 @@ const ComposeWindow: Component<ComposeWindowProps> = (props) => {
     }
   };
 
+  const recipients = () => to().split(',').map(address => address.trim()).filter(address => address);
+
+  const updateRecommendation = async () => {
+    if (!props.account || encryptChosen() || recipients().length === 0) return;
+    try {
+      const result = await invoke<AutocryptRecommendation>('get_encrypt_recommendation', {
+        accountId: props.account.id,
+        recipients: recipients(),
+      });
+      setEncrypt(result.recommendation === 'ENCRYPT');
+    } catch (error) {
+      console.error('Failed to get encryption recommendation:', error);
+    }
+  };
+
+  const handleAttach = async (files: FileList | null) => {
+    const added = await Promise.all(Array.from(files ?? []).map(async (file): Promise<Attachment> => ({
+      id: '',
+      filename: file.name,
+      content_type: file.type || 'application/octet-stream',
+      size_bytes: file.size,
+      is_inline: false,
+      data: Array.from(new Uint8Array(await file.arrayBuffer())),
+    })));
+    setAttachments([...attachments(), ...added]);
+  };
+
   const handleKeyDown = (e: KeyboardEvent) => {
     if (e.key === 'Escape') {
       props.onCancel();
@@ -98,6 +141,7 @@ const ComposeWindow: Component<ComposeWindowProps> = (props) => {
               placeholder="recipient@example.com"
               value={to()}
               onInput={(e) => setTo(e.currentTarget.value)}
+              onChange={updateRecommendation}
               multiple
             />
           </div>
@@ -130,6 +174,26 @@ const ComposeWindow: Component<ComposeWindowProps> = (props) => {
             />
           </div>
 
+          {/* Attachments */}
+          {attachments().length > 0 && (
+            <div class="flex flex-wrap gap-2">
+              <For each={attachments()}>
+                {(attachment) => (
+                  <span class="flex items-center px-2 py-1 text-sm bg-gray-100 dark:bg-gray-700 text-gray-700 dark:text-gray-300 rounded">
+                    {attachment.filename}
+                    <button
+                      class="ml-2 text-gray-500 hover:text-gray-700 dark:hover:text-gray-200"
+                      title="Remove"
+                      onClick={() => setAttachments(attachments().filter((other) => other !== attachment))}
+                    >
+                      ×
+                    </button>
+                  </span>
+                )}
+              </For>
+            </div>
+          )}
+
           {/* Formatting Toolbar (placeholder) */}
           <div class="flex items-center space-x-2 p-2 bg-gray-50 dark:bg-gray-700 rounded">
             <button class="p-1 text-gray-600 hover:text-gray-800 dark:text-gray-400 dark:hover:text-gray-200" title="Bold">
@@ -142,12 +206,72 @@ const ComposeWindow: Component<ComposeWindowProps> = (props) => {
               <u>U</u>
             </button>
             <div class="w-px h-4 bg-gray-300 dark:bg-gray-600"></div>
-            <button class="p-1 text-gray-600 hover:text-gray-800 dark:text-gray-400 dark:hover:text-gray-200" title="Attach file">
+            <label class="p-1 cursor-pointer text-gray-600 hover:text-gray-800 dark:text-gray-400 dark:hover:text-gray-200" title="Attach file">
               📎
-            </button>
+              <input
+                type="file"
+                class="hidden"
+                multiple
+                onChange={(e) => {
+                  handleAttach(e.currentTarget.files);
+                  e.currentTarget.value = '';
+                }}
+              />
+            </label>
             <button class="p-1 text-gray-600 hover:text-gray-800 dark:text-gray-400 dark:hover:text-gray-200" title="Insert link">
               🔗
             </button>
+            <div class="w-px h-4 bg-gray-300 dark:bg-gray-600"></div>
+            <label class="flex items-center text-sm text-gray-700 dark:text-gray-300">
+              <input
+                type="checkbox"
+                class="h-4 w-4 mr-1 text-blue-600 focus:ring-blue-500 border-gray-300 rounded"
+                checked={sign()}
+                onChange={(e) => setSign(e.currentTarget.checked)}
+              />
+              Sign
+            </label>
+            <label class="flex items-center text-sm text-gray-700 dark:text-gray-300">
+              <input
+                type="checkbox"
+                class="h-4 w-4 mr-1 text-blue-600 focus:ring-blue-500 border-gray-300 rounded"
+                checked={encrypt()}
+                onChange={(e) => {
+                  setEncrypt(e.currentTarget.checked);
+                  setEncryptChosen(true);
+                }}
+              />
+              Encrypt
+            </label>
+            <label class="flex items-center text-sm text-gray-700 dark:text-gray-300" title="Use S/MIME rather than OpenPGP">
+              <input
+                type="checkbox"
+                class="h-4 w-4 mr-1 text-blue-600 focus:ring-blue-500 border-gray-300 rounded"
+                checked={smime()}
+                disabled={!sign() && !encrypt()}
+                onChange={(e) => setSmime(e.currentTarget.checked)}
+              />
+              S/MIME
+            </label>
+            <label class="flex items-center text-sm text-gray-700 dark:text-gray-300" title="Encrypt with age rather than OpenPGP">
+              <input
+                type="checkbox"
+                class="h-4 w-4 mr-1 text-blue-600 focus:ring-blue-500 border-gray-300 rounded"
+                checked={age()}
+                disabled={!encrypt() || sign()}
+                onChange={(e) => setAge(e.currentTarget.checked)}
+              />
+              age
+            </label>
+            {age() && encrypt() && (
+              <input
+                type="password"
+                class="px-2 py-1 text-sm border border-gray-300 dark:border-gray-600 rounded-md bg-white dark:bg-gray-700 text-gray-900 dark:text-white"
+                placeholder="Passphrase (optional)"
+                value={agePassphrase()}
+                onInput={(e) => setAgePassphrase(e.currentTarget.value)}
+              />
+            )}
           </div>
         </div>
       </div>
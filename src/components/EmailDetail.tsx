@@ -1,11 +1,88 @@
-import type { Component } from 'solid-js';
-import type { Email } from '../types/email';
+import { createResource, createSignal, type Component } from 'solid-js';
+import { invoke } from '@tauri-apps/api/core';
+import { downloadDir } from '@tauri-apps/api/path';
+import { open } from '@tauri-apps/plugin-shell';
+import type { Attachment, AuthVerdict, CommandError, Email, SanitizedHtml } from '../types/email';
 
 interface EmailDetailProps {
   email: Email;
 }
 
 const EmailDetail: Component<EmailDetailProps> = (props) => {
+  // undefined defers to the sender allow-list
+  const [loadRemote, setLoadRemote] = createSignal<boolean | undefined>(undefined);
+  const [rendered, { refetch }] = createResource(
+    () => ({ emailId: props.email.id, loadRemote: loadRemote() }),
+    (args) => invoke<SanitizedHtml | null>('render_email', args)
+  );
+
+  // Checked once per email from the server's Authentication-Results, then kept with it
+  const [authVerdict, { mutate: setAuthVerdict }] = createResource(
+    () => props.email.id,
+    async (emailId) => {
+      if (props.email.auth_verdict) return props.email.auth_verdict;
+      const checked = await invoke<Email>('check_email_auth', { emailId });
+      return checked.auth_verdict;
+    }
+  );
+
+  // DKIM looks up the sender's DNS, so it only runs when asked
+  const verifyDkim = async () => {
+    const checked = await invoke<Email>('check_email_auth', { emailId: props.email.id, verifyDkim: true });
+    setAuthVerdict(checked.auth_verdict);
+  };
+
+  const authBadge = (verdict: AuthVerdict | undefined) => {
+    if (verdict === 'PASS') {
+      return <span class="ml-2 text-xs px-1.5 py-0.5 rounded bg-green-100 text-green-800 dark:bg-green-900 dark:text-green-100" title="DKIM, SPF or DMARC confirmed the sender">Verified</span>;
+    }
+    if (verdict === 'FAIL') {
+      return <span class="ml-2 text-xs px-1.5 py-0.5 rounded bg-red-100 text-red-800 dark:bg-red-900 dark:text-red-100" title="Sender authentication failed; this message may be forged">Unverified sender</span>;
+    }
+    return null;
+  };
+
+  const senderDomain = () => props.email.from_address.split('@').pop() ?? '';
+
+  const allowRemote = async (scope: 'SENDER' | 'DOMAIN') => {
+    const value = scope === 'SENDER' ? props.email.from_address : senderDomain();
+    await invoke('allow_remote_content', { scope, value });
+    setLoadRemote(undefined);
+    refetch();
+  };
+
+  // Links in a message open in the system browser, never in the app
+  const handleBodyClick = (event: MouseEvent) => {
+    const link = (event.target as HTMLElement).closest('a');
+    const href = link?.getAttribute('href');
+    if (!href || href.startsWith('#')) return;
+    event.preventDefault();
+    open(href);
+  };
+
+  // Programs and macro documents are only opened once the user agrees
+  const openAttachment = async (attachment: Attachment) => {
+    const args = { emailId: props.email.id, attachmentId: attachment.id };
+    try {
+      await invoke('open_attachment', { ...args, confirmed: false });
+    } catch (error) {
+      const { code, message } = error as CommandError;
+      if (code !== 'NEEDS_CONFIRMATION') throw error;
+      if (window.confirm(`${message}\n\nOpen it anyway?`)) {
+        await invoke('open_attachment', { ...args, confirmed: true });
+      }
+    }
+  };
+
+  // The backend names the file, renaming rather than overwriting
+  const saveAttachment = async (attachment: Attachment) => {
+    await invoke<string>('save_attachment', { emailId: props.email.id, attachmentId: attachment.id, dir: await downloadDir() });
+  };
+
+  const saveAllAttachments = async () => {
+    await invoke<string[]>('save_all_attachments', { emailId: props.email.id, dir: await downloadDir() });
+  };
+
   const formatDate = (dateString: string) => {
     const date = new Date(dateString);
     return date.toLocaleString();
@@ -50,6 +127,10 @@ const EmailDetail: Component<EmailDetailProps> = (props) => {
                 </div>
                 <div class="text-sm text-gray-500 dark:text-gray-400">
                   {props.email.from_address}
+                  {authBadge(authVerdict())}
+                  <button class="ml-2 text-xs text-blue-600 hover:underline" onClick={verifyDkim}>
+                    Verify DKIM
+                  </button>
                 </div>
               </div>
               <div class="text-sm text-gray-500 dark:text-gray-400">
@@ -94,12 +175,49 @@ const EmailDetail: Component<EmailDetailProps> = (props) => {
         </div>
       </div>
 
+      {/* Security warnings */}
+      {props.email.warnings?.length > 0 && (
+        <div class="px-6 py-2 text-sm bg-red-50 text-red-800 border-b border-red-200 dark:bg-red-900 dark:text-red-100 dark:border-red-800">
+          <p class="font-medium">This message may be dangerous</p>
+          <ul class="mt-1 list-disc list-inside">
+            {props.email.warnings.map((warning) => (
+              <li title={warning.subject}>{warning.detail}</li>
+            ))}
+          </ul>
+        </div>
+      )}
+
+      {/* Remote content notice */}
+      {rendered() && (rendered()!.remote_blocked > 0 || rendered()!.trackers_blocked > 0) && (
+        <div class="px-6 py-2 flex items-center space-x-3 text-sm bg-yellow-50 text-yellow-800 border-b border-yellow-200 dark:bg-yellow-900 dark:text-yellow-100 dark:border-yellow-800">
+          <span class="flex-1">
+            {rendered()!.remote_blocked > 0 && 'Remote images are hidden to protect your privacy. '}
+            {rendered()!.trackers_blocked > 0 &&
+              `${rendered()!.trackers_blocked} tracker${rendered()!.trackers_blocked === 1 ? '' : 's'} blocked.`}
+          </span>
+          {rendered()!.remote_blocked > 0 && (
+            <>
+              <button class="hover:underline" onClick={() => setLoadRemote(true)}>
+                Load images
+              </button>
+              <button class="hover:underline" onClick={() => allowRemote('SENDER')}>
+                Always from {props.email.from_address}
+              </button>
+              <button class="hover:underline" onClick={() => allowRemote('DOMAIN')}>
+                Always from {senderDomain()}
+              </button>
+            </>
+          )}
+        </div>
+      )}
+
       {/* Email Body */}
       <div class="flex-1 overflow-y-auto p-6">
-        {props.email.body_html ? (
-          <div 
-            class="prose dark:prose-invert max-w-none"
-            innerHTML={props.email.body_html}
+        {rendered() ? (
+          <div
+            class="slopmail-message prose dark:prose-invert max-w-none"
+            innerHTML={rendered()!.html}
+            onClick={handleBodyClick}
           />
         ) : props.email.body_text ? (
           <div class="whitespace-pre-wrap text-gray-800 dark:text-gray-200 font-mono text-sm">
@@ -116,12 +234,17 @@ const EmailDetail: Component<EmailDetailProps> = (props) => {
       {/* Attachments */}
       {props.email.attachments && (
         <div class="p-6 border-t border-gray-200 dark:border-gray-700">
-          <h3 class="text-sm font-medium text-gray-700 dark:text-gray-300 mb-3">Attachments</h3>
+          <div class="flex items-center justify-between mb-3">
+            <h3 class="text-sm font-medium text-gray-700 dark:text-gray-300">Attachments</h3>
+            <button class="text-blue-600 hover:text-blue-700 text-sm" onClick={saveAllAttachments}>
+              Save all
+            </button>
+          </div>
           <div class="space-y-2">
             {(() => {
               try {
                 const attachments = JSON.parse(props.email.attachments!);
-                return attachments.map((attachment: any) => (
+                return attachments.map((attachment: Attachment) => (
                   <div class="flex items-center space-x-3 p-2 bg-gray-50 dark:bg-gray-700 rounded">
                     <span class="text-2xl">📎</span>
                     <div class="flex-1">
@@ -132,7 +255,10 @@ const EmailDetail: Component<EmailDetailProps> = (props) => {
                         {attachment.content_type} • {attachment.size_bytes} bytes
                       </div>
                     </div>
-                    <button class="text-blue-600 hover:text-blue-700 text-sm">
+                    <button class="text-blue-600 hover:text-blue-700 text-sm" onClick={() => openAttachment(attachment)}>
+                      Open
+                    </button>
+                    <button class="text-blue-600 hover:text-blue-700 text-sm" onClick={() => saveAttachment(attachment)}>
                       Download
                     </button>
                   </div>
@@ -20,6 +20,10 @@ const FolderTree: Component<FolderTreeProps> = (props) => {
         return '🗑️';
       case 'SPAM':
         return '🚫';
+      case 'ARCHIVE':
+        return '🗄️';
+      case 'ALL':
+        return '📚';
       default:
         return '📁';
     }
@@ -42,27 +46,39 @@ const FolderTree: Component<FolderTreeProps> = (props) => {
     }
   };
 
-  // Sort folders: INBOX first, then SENT, then other special folders, then custom folders alphabetically
-  const sortedFolders = () => {
-    return [...props.folders].sort((a, b) => {
-      const priority = {
-        'INBOX': 0,
-        'SENT': 1,
-        'DRAFTS': 2,
-        'TRASH': 3,
-        'SPAM': 4,
-        'CUSTOM': 5
-      };
+  // Sort siblings: INBOX first, then SENT, then other special folders, then custom folders alphabetically
+  const compareFolders = (a: Folder, b: Folder) => {
+    const priority = {
+      'INBOX': 0,
+      'SENT': 1,
+      'DRAFTS': 2,
+      'TRASH': 3,
+      'SPAM': 4,
+      'ARCHIVE': 5,
+      'ALL': 6,
+      'CUSTOM': 7
+    };
 
-      const aPriority = priority[a.folder_type as keyof typeof priority] ?? 5;
-      const bPriority = priority[b.folder_type as keyof typeof priority] ?? 5;
+    const aPriority = priority[a.folder_type as keyof typeof priority] ?? 7;
+    const bPriority = priority[b.folder_type as keyof typeof priority] ?? 7;
 
-      if (aPriority !== bPriority) {
-        return aPriority - bPriority;
-      }
+    if (aPriority !== bPriority) {
+      return aPriority - bPriority;
+    }
 
-      return a.display_name.localeCompare(b.display_name);
-    });
+    return a.display_name.localeCompare(b.display_name);
+  };
+
+  // Flatten the hierarchy depth-first. Folders whose parent is not listed sit at the top.
+  const sortedFolders = () => {
+    const names = new Set(props.folders.map((f) => f.name));
+    const parentOf = (f: Folder) => (f.parent_name && names.has(f.parent_name) ? f.parent_name : null);
+    const walk = (parent: string | null, depth: number): { folder: Folder; depth: number }[] =>
+      props.folders
+        .filter((f) => parentOf(f) === parent)
+        .sort(compareFolders)
+        .flatMap((folder) => [{ folder, depth }, ...walk(folder.name, depth + 1)]);
+    return walk(null, 0);
   };
 
   return (
@@ -72,13 +88,15 @@ const FolderTree: Component<FolderTreeProps> = (props) => {
       </h3>
       
       <div class="space-y-1">
-        {sortedFolders().map((folder) => (
+        {sortedFolders().map(({ folder, depth }) => (
           <button
             class={`w-full flex items-center justify-between px-3 py-2 text-sm rounded-md transition-colors ${
               props.selectedFolder?.id === folder.id
                 ? 'bg-blue-100 dark:bg-blue-900/30 text-blue-700 dark:text-blue-300'
                 : 'text-gray-700 dark:text-gray-300 hover:bg-gray-100 dark:hover:bg-gray-700'
-            }`}
+            } ${folder.is_subscribed ? '' : 'opacity-60'}`}
+            style={{ 'padding-left': `${0.75 + depth}rem` }}
+            disabled={!folder.is_selectable}
             onClick={() => props.onFolderSelect(folder)}
           >
             <div class="flex items-center space-x-2">
@@ -10,21 +10,39 @@ export interface Account {
   jmap_url?: string;
   username: string;
   password_encrypted: string;
+  auth_method: AuthMethod;
   use_ssl: boolean;
+  tls_ca_bundle?: string;
+  proxy_url?: string; // socks5://, tor:// or http://
+  authserv_id?: string;
+  autocrypt_prefer_encrypt: boolean;
   created_at: string;
   updated_at: string;
 }
 
+export type AuthMethod = 'PASSWORD' | 'OAUTH2';
+
+export interface CertificatePin {
+  account_id: number;
+  host: string;
+  fingerprint: string;
+  created_at: string;
+}
+
 export interface Folder {
   id: number;
   account_id: number;
   name: string;
   display_name: string;
-  folder_type: 'INBOX' | 'SENT' | 'DRAFTS' | 'TRASH' | 'SPAM' | 'CUSTOM';
+  folder_type: 'INBOX' | 'SENT' | 'DRAFTS' | 'TRASH' | 'SPAM' | 'ARCHIVE' | 'ALL' | 'CUSTOM';
   message_count: number;
   unread_count: number;
   uid_validity?: number;
   uid_next?: number;
+  delimiter?: string;
+  parent_name?: string;
+  is_subscribed: boolean;
+  is_selectable: boolean;
   created_at: string;
   updated_at: string;
 }
@@ -54,8 +72,45 @@ export interface Email {
   is_deleted: boolean;
   uid?: number;
   mod_seq?: number;
+  trackers_blocked?: number;
+  auth_verdict?: AuthVerdict;
+  auth_results?: string; // JSON array of AuthResult
   created_at: string;
   updated_at: string;
+  keywords: string[];
+  labels: Label[];
+  warnings: SecurityWarning[];
+}
+
+export interface Label {
+  id: number;
+  account_id: number;
+  name: string;
+  color?: string;
+  source: 'LOCAL' | 'GMAIL' | 'KEYWORD';
+  remote_name?: string;
+  created_at: string;
+  updated_at: string;
+}
+
+export interface Removal {
+  kind: 'ELEMENT' | 'ATTRIBUTE' | 'CSS' | 'LINK';
+  name: string;
+  count: number;
+}
+
+export interface SanitizedHtml {
+  html: string;
+  removed: Removal[];
+  remote_blocked: number;
+  trackers_blocked: number;
+}
+
+export interface RemoteContentRule {
+  id: number;
+  scope: 'SENDER' | 'DOMAIN';
+  value: string;
+  created_at: string;
 }
 
 export interface EmailAddress {
@@ -63,6 +118,31 @@ export interface EmailAddress {
   address: string;
 }
 
+export type AuthVerdict = 'PASS' | 'FAIL' | 'NONE';
+
+export interface AuthResult {
+  method: string;
+  result: string;
+  domain?: string;
+  reason?: string;
+  local: boolean;
+}
+
+export type WarningKind =
+  | 'EXECUTABLE_ATTACHMENT'
+  | 'DOUBLE_EXTENSION'
+  | 'MACRO_DOCUMENT'
+  | 'ARCHIVE_EXECUTABLE'
+  | 'DISPLAY_NAME_SPOOF'
+  | 'LOOKALIKE_DOMAIN'
+  | 'LINK_MISMATCH';
+
+export interface SecurityWarning {
+  kind: WarningKind;
+  subject: string;
+  detail: string;
+}
+
 export interface Attachment {
   id: string;
   filename: string;
@@ -70,6 +150,7 @@ export interface Attachment {
   size_bytes: number;
   content_id?: string;
   is_inline: boolean;
+  data?: number[]; // Contents of an outgoing attachment
 }
 
 export interface ComposeEmail {
@@ -83,6 +164,76 @@ export interface ComposeEmail {
   attachments: Attachment[];
   in_reply_to?: string;
   references?: string;
+  sign?: boolean;
+  encrypt?: boolean;
+  smime?: boolean;
+  age?: boolean;
+  age_passphrase?: string;
+}
+
+export type KeyTrust = 'UNKNOWN' | 'NEVER' | 'MARGINAL' | 'FULL' | 'ULTIMATE';
+
+export interface PgpKey {
+  fingerprint: string;
+  user_ids: string; // JSON array
+  has_secret: boolean;
+  trust: KeyTrust;
+  key_created_at: string;
+  expires_at?: string;
+  revoked: boolean;
+  created_at: string;
+  updated_at: string;
+}
+
+export interface AgeKey {
+  recipient: string;
+  name: string;
+  address?: string;
+  has_identity: boolean;
+  created_at: string;
+  updated_at: string;
+}
+
+export interface SmimeCertificate {
+  fingerprint: string;
+  subject: string;
+  issuer: string;
+  addresses: string; // JSON array
+  not_before: string;
+  not_after: string;
+  is_ca: boolean;
+  trusted: boolean;
+  has_private_key: boolean;
+  created_at: string;
+  updated_at: string;
+}
+
+export type EncryptRecommendation = 'DISABLE' | 'DISCOURAGE' | 'AVAILABLE' | 'ENCRYPT';
+
+export interface RecipientRecommendation {
+  address: string;
+  recommendation: EncryptRecommendation;
+}
+
+export interface AutocryptRecommendation {
+  recommendation: EncryptRecommendation;
+  recipients: RecipientRecommendation[];
+}
+
+export type SignatureStatus = 'GOOD' | 'BAD' | 'UNKNOWN_KEY' | 'EXPIRED' | 'UNTRUSTED' | 'SIGNER_MISMATCH';
+
+export interface SignatureInfo {
+  status: SignatureStatus;
+  fingerprint?: string;
+  signer?: string;
+  created_at?: string;
+}
+
+export interface SecureContent {
+  encrypted: boolean;
+  signatures: SignatureInfo[];
+  body_text?: string;
+  body_html?: string;
 }
 
 export interface AddAccountRequest {
@@ -95,7 +246,18 @@ export interface AddAccountRequest {
   smtp_port?: number;
   username: string;
   password: string;
+  auth_method?: AuthMethod;
   use_ssl: boolean;
+  tls_ca_bundle?: string;
+  proxy_url?: string;
+}
+
+export type DiscoverySource = 'ISPDB' | 'AUTOCONFIG' | 'SRV' | 'GUESS';
+
+export interface DiscoveredAccount {
+  source: DiscoverySource;
+  confidence: number;
+  settings: AddAccountRequest;
 }
 
 export interface TestAccountRequest {
@@ -107,4 +269,74 @@ export interface TestAccountRequest {
   username: string;
   password: string;
   use_ssl: boolean;
+  tls_ca_bundle?: string;
+  proxy_url?: string;
+  certificate_pins?: Record<string, string>;
+}
+
+export type DiagnosticStep = 'DNS' | 'CONNECT' | 'TLS' | 'GREETING' | 'CAPABILITIES' | 'STARTTLS' | 'AUTHENTICATE';
+
+export interface StepResult {
+  kind: DiagnosticStep;
+  elapsed_ms: number;
+  error?: string;
+}
+
+export interface CertificateInfo {
+  subject: string;
+  issuer: string;
+  fingerprint: string;
+  not_before: string;
+  not_after: string;
+}
+
+export interface TlsReport {
+  version?: string;
+  cipher?: string;
+  certificates: CertificateInfo[];
+  verified: boolean;
+}
+
+export interface ServerReport {
+  host: string;
+  port: number;
+  proxy?: string;
+  addresses: string[]; // Empty when a proxy looked the host up
+  connect_ms?: number;
+  tls?: TlsReport;
+  greeting?: string;
+  capabilities: string[];
+  auth_mechanisms: string[];
+  size_limit?: number;
+  authenticated: boolean;
+  steps: StepResult[];
+}
+
+export interface ConnectionReport {
+  ok: boolean;
+  imap?: ServerReport;
+  smtp?: ServerReport;
+}
+
+export type ErrorCode =
+  | 'AUTH_FAILED'
+  | 'NETWORK'
+  | 'TIMEOUT'
+  | 'TLS'
+  | 'PROTOCOL'
+  | 'NOT_FOUND'
+  | 'LOCKED'
+  | 'CONFLICT'
+  | 'QUOTA_EXCEEDED'
+  | 'UNAVAILABLE'
+  | 'INVALID_INPUT'
+  | 'UNSUPPORTED'
+  | 'NEEDS_CONFIRMATION'
+  | 'INTERNAL';
+
+/** What a failed command rejects with. */
+export interface CommandError {
+  code: ErrorCode;
+  message: string;
+  details: Record<string, unknown> | null;
 }
\ No newline at end of file